@@ -0,0 +1,365 @@
+//! DEFLATE encoder (RFC 1951).
+//!
+//! Greedy LZ77 over a 32K window with hash chains, then every block is sent
+//! whichever way is smallest: stored, fixed Huffman codes, or dynamic codes
+//! built from the block's own symbol frequencies.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use super::inflate::{dist_tables, fixed_lengths, length_tables, CODE_LENGTH_ORDER};
+
+const WINDOW: usize = 32768;
+const HASH_BITS: usize = 14;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const MAX_CHAIN: usize = 64;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const NONE: u32 = u32::MAX;
+
+/// Symbols collected before a block is emitted
+const BLOCK_SYMBOLS: usize = 16384;
+
+/// A literal when `dist` is 0, otherwise a back reference
+#[derive(Clone, Copy)]
+struct Symbol {
+    value: u16,
+    dist: u16,
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bit_buf: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.bit_buf |= (value as u64) << self.bit_count;
+        self.bit_count += bits;
+        while self.bit_count >= 8 {
+            self.out.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.bit_count > 0 {
+            self.out.push(self.bit_buf as u8);
+            self.bit_buf = 0;
+            self.bit_count = 0;
+        }
+    }
+}
+
+fn length_code(len: usize) -> (usize, u32, u32) {
+    let (base, extra) = length_tables();
+    let index = (0..29).rev().find(|&i| base[i] as usize <= len).unwrap_or(0);
+    (index, (len - base[index] as usize) as u32, extra[index] as u32)
+}
+
+fn dist_code(dist: usize) -> (usize, u32, u32) {
+    let (base, extra) = dist_tables();
+    let index = (0..30).rev().find(|&i| base[i] as usize <= dist).unwrap_or(0);
+    (index, (dist - base[index] as usize) as u32, extra[index] as u32)
+}
+
+/// Huffman code lengths for `freqs`, no longer than `max_bits`. At least two
+/// symbols always get a code so every tree is complete.
+fn build_lengths(freqs: &[u32], max_bits: usize) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    let used = freqs.iter().filter(|&&f| f > 0).count();
+    for f in freqs.iter_mut().filter(|f| **f == 0).take(2usize.saturating_sub(used)) {
+        *f = 1;
+    }
+
+    // Plain Huffman tree: nodes are (weight, leaves below it)
+    let mut depth = vec![0usize; freqs.len()];
+    let mut nodes: Vec<(u64, Vec<usize>)> = freqs
+        .iter()
+        .enumerate()
+        .filter(|(_, &f)| f > 0)
+        .map(|(i, &f)| (f as u64, vec![i]))
+        .collect();
+    while nodes.len() > 1 {
+        nodes.sort_by(|a, b| b.0.cmp(&a.0));
+        let (wa, la) = nodes.pop().unwrap();
+        let (wb, lb) = nodes.pop().unwrap();
+        let mut leaves = la;
+        leaves.extend(lb);
+        for &leaf in &leaves {
+            depth[leaf] += 1;
+        }
+        nodes.push((wa + wb, leaves));
+    }
+
+    // Clamp to max_bits, then shuffle counts until the code fits again
+    let mut bl_count = vec![0u32; max_bits + 1];
+    for &d in depth.iter().filter(|&&d| d > 0) {
+        bl_count[d.min(max_bits)] += 1;
+    }
+    let kraft = |counts: &[u32]| -> u64 {
+        counts.iter().enumerate().skip(1).map(|(l, &c)| (c as u64) << (max_bits - l)).sum()
+    };
+    while kraft(&bl_count) > 1u64 << max_bits {
+        let bits = match (1..max_bits).rev().find(|&b| bl_count[b] > 0) {
+            Some(b) => b,
+            None => break,
+        };
+        bl_count[bits] -= 1;
+        bl_count[bits + 1] += 2;
+        bl_count[max_bits] -= 1;
+    }
+
+    // Most frequent symbols get the shortest codes
+    let mut order: Vec<usize> = (0..freqs.len()).filter(|&i| freqs[i] > 0).collect();
+    order.sort_by(|&a, &b| freqs[b].cmp(&freqs[a]).then(a.cmp(&b)));
+    let mut lengths = vec![0u8; freqs.len()];
+    let mut next = order.into_iter();
+    for (len, &count) in bl_count.iter().enumerate().skip(1) {
+        for _ in 0..count {
+            if let Some(symbol) = next.next() {
+                lengths[symbol] = len as u8;
+            }
+        }
+    }
+    lengths
+}
+
+/// Canonical codes for a set of lengths, bit-reversed for LSB-first output
+fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut bl_count = [0u32; 16];
+    for &l in lengths {
+        bl_count[l as usize] += 1;
+    }
+    bl_count[0] = 0;
+    let mut next_code = [0u32; 16];
+    let mut code = 0;
+    for bits in 1..16 {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+    lengths
+        .iter()
+        .map(|&len| {
+            if len == 0 {
+                return 0;
+            }
+            let c = next_code[len as usize];
+            next_code[len as usize] += 1;
+            c.reverse_bits() >> (32 - len as u32)
+        })
+        .collect()
+}
+
+/// Run-length encode code lengths with symbols 16, 17 and 18
+fn rle_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&l| l == len).count();
+        if len == 0 && run >= 11 {
+            let n = run.min(138);
+            out.push((18, (n - 11) as u8));
+            i += n;
+        } else if len == 0 && run >= 3 {
+            out.push((17, (run - 3) as u8));
+            i += run;
+        } else if len != 0 && run >= 4 {
+            out.push((len, 0));
+            let n = (run - 1).min(6);
+            out.push((16, (n - 3) as u8));
+            i += 1 + n;
+        } else {
+            out.push((len, 0));
+            i += 1;
+        }
+    }
+    out
+}
+
+fn rle_extra_bits(symbol: u8) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+fn data_cost(symbols: &[Symbol], lit: &[u8], dist: &[u8]) -> u64 {
+    symbols
+        .iter()
+        .map(|s| {
+            if s.dist == 0 {
+                lit[s.value as usize] as u64
+            } else {
+                let (li, _, le) = length_code(s.value as usize);
+                let (di, _, de) = dist_code(s.dist as usize);
+                (lit[257 + li] as u32 + le + dist[di] as u32 + de) as u64
+            }
+        })
+        .sum::<u64>()
+        + lit[256] as u64
+}
+
+fn write_symbols(w: &mut BitWriter, symbols: &[Symbol], lit: &[u8], dist: &[u8]) {
+    let lit_codes = canonical_codes(lit);
+    let dist_codes = canonical_codes(dist);
+    for s in symbols {
+        if s.dist == 0 {
+            w.write(lit_codes[s.value as usize], lit[s.value as usize] as u32);
+        } else {
+            let (li, lv, le) = length_code(s.value as usize);
+            w.write(lit_codes[257 + li], lit[257 + li] as u32);
+            w.write(lv, le);
+            let (di, dv, de) = dist_code(s.dist as usize);
+            w.write(dist_codes[di], dist[di] as u32);
+            w.write(dv, de);
+        }
+    }
+    w.write(lit_codes[256], lit[256] as u32);
+}
+
+fn write_block(w: &mut BitWriter, symbols: &[Symbol], raw: &[u8], last: bool) {
+    let mut lit_freq = [0u32; 286];
+    let mut dist_freq = [0u32; 30];
+    for s in symbols {
+        if s.dist == 0 {
+            lit_freq[s.value as usize] += 1;
+        } else {
+            lit_freq[257 + length_code(s.value as usize).0] += 1;
+            dist_freq[dist_code(s.dist as usize).0] += 1;
+        }
+    }
+    lit_freq[256] = 1;
+
+    let lit = build_lengths(&lit_freq, 15);
+    let dist = build_lengths(&dist_freq, 15);
+    let nlen = 257 + lit[257..].iter().rposition(|&l| l != 0).map(|p| p + 1).unwrap_or(0);
+    let ndist = 1 + dist.iter().rposition(|&l| l != 0).unwrap_or(0);
+
+    let mut all = lit[..nlen].to_vec();
+    all.extend_from_slice(&dist[..ndist]);
+    let rle = rle_lengths(&all);
+    let mut cl_freq = [0u32; 19];
+    for &(symbol, _) in &rle {
+        cl_freq[symbol as usize] += 1;
+    }
+    let cl = build_lengths(&cl_freq, 7);
+    let ncode = 4.max(1 + CODE_LENGTH_ORDER.iter().rposition(|&i| cl[i] != 0).unwrap_or(0));
+
+    let dynamic_cost = 14
+        + 3 * ncode as u64
+        + rle.iter().map(|&(s, _)| cl[s as usize] as u64 + rle_extra_bits(s) as u64).sum::<u64>()
+        + data_cost(symbols, &lit, &dist);
+    let (fixed_lit, fixed_dist) = fixed_lengths();
+    let fixed_cost = data_cost(symbols, &fixed_lit, &fixed_dist);
+    let stored_cost = 8 * (raw.len() as u64 + 5 * (raw.len() as u64 / 65535 + 1)) + 7;
+
+    if stored_cost <= fixed_cost.min(dynamic_cost) {
+        let chunks: Vec<&[u8]> = if raw.is_empty() { vec![raw] } else { raw.chunks(65535).collect() };
+        let count = chunks.len();
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            w.write((last && i + 1 == count) as u32, 1);
+            w.write(0, 2);
+            w.align();
+            let len = chunk.len() as u16;
+            w.out.extend_from_slice(&len.to_le_bytes());
+            w.out.extend_from_slice(&(!len).to_le_bytes());
+            w.out.extend_from_slice(chunk);
+        }
+    } else if fixed_cost <= dynamic_cost {
+        w.write(last as u32, 1);
+        w.write(1, 2);
+        write_symbols(w, symbols, &fixed_lit, &fixed_dist);
+    } else {
+        w.write(last as u32, 1);
+        w.write(2, 2);
+        w.write((nlen - 257) as u32, 5);
+        w.write((ndist - 1) as u32, 5);
+        w.write((ncode - 4) as u32, 4);
+        for &index in CODE_LENGTH_ORDER.iter().take(ncode) {
+            w.write(cl[index] as u32, 3);
+        }
+        let cl_codes = canonical_codes(&cl);
+        for &(symbol, extra) in &rle {
+            w.write(cl_codes[symbol as usize], cl[symbol as usize] as u32);
+            w.write(extra as u32, rle_extra_bits(symbol));
+        }
+        write_symbols(w, symbols, &lit, &dist);
+    }
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let v = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compress `data` into a raw DEFLATE stream
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter { out: Vec::with_capacity(data.len() / 2 + 64), bit_buf: 0, bit_count: 0 };
+    let mut head = vec![NONE; HASH_SIZE];
+    let mut prev = vec![NONE; WINDOW];
+    let mut symbols = Vec::with_capacity(BLOCK_SYMBOLS);
+    let mut block_start = 0;
+
+    let insert = |head: &mut [u32], prev: &mut [u32], i: usize| {
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(data, i);
+            prev[i % WINDOW] = head[h];
+            head[h] = i as u32;
+        }
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+        if i + MIN_MATCH <= data.len() {
+            let max_len = MAX_MATCH.min(data.len() - i);
+            let mut candidate = head[hash(data, i)];
+            let mut chain = MAX_CHAIN;
+            while candidate != NONE && chain > 0 {
+                let c = candidate as usize;
+                if c >= i || i - c > WINDOW {
+                    break;
+                }
+                if data[c + best_len.min(max_len - 1)] == data[i + best_len.min(max_len - 1)] {
+                    let len = data[c..].iter().zip(&data[i..i + max_len]).take_while(|(a, b)| a == b).count();
+                    if len > best_len {
+                        best_len = len;
+                        best_dist = i - c;
+                        if len == max_len {
+                            break;
+                        }
+                    }
+                }
+                candidate = prev[c % WINDOW];
+                chain -= 1;
+            }
+        }
+
+        if best_len >= MIN_MATCH {
+            symbols.push(Symbol { value: best_len as u16, dist: best_dist as u16 });
+            for k in i..i + best_len {
+                insert(&mut head, &mut prev, k);
+            }
+            i += best_len;
+        } else {
+            symbols.push(Symbol { value: data[i] as u16, dist: 0 });
+            insert(&mut head, &mut prev, i);
+            i += 1;
+        }
+
+        if symbols.len() >= BLOCK_SYMBOLS && i < data.len() {
+            write_block(&mut w, &symbols, &data[block_start..i], false);
+            symbols.clear();
+            block_start = i;
+        }
+    }
+    write_block(&mut w, &symbols, &data[block_start..], true);
+    w.align();
+    w.out
+}
@@ -0,0 +1,253 @@
+//! DEFLATE decoder (RFC 1951).
+//!
+//! Canonical Huffman codes are decoded a bit at a time from per-length
+//! counts, which needs no lookup tables and is fast enough for the sizes we
+//! move around.
+
+use alloc::vec::Vec;
+use super::CompressError;
+
+const MAX_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Order the code length code lengths are stored in
+pub(super) const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+pub(super) fn length_tables() -> (&'static [u16; 29], &'static [u8; 29]) {
+    (&LENGTH_BASE, &LENGTH_EXTRA)
+}
+
+pub(super) fn dist_tables() -> (&'static [u16; 30], &'static [u8; 30]) {
+    (&DIST_BASE, &DIST_EXTRA)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, need: u32) -> Result<u32, CompressError> {
+        while self.bit_count < need {
+            let byte = *self.data.get(self.pos).ok_or(CompressError::Truncated)?;
+            self.pos += 1;
+            self.bit_buf |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1u32 << need) - 1);
+        self.bit_buf = if need == 32 { 0 } else { self.bit_buf >> need };
+        self.bit_count -= need;
+        Ok(value)
+    }
+
+    /// Drop the rest of the current byte
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+struct Huffman {
+    /// Number of codes of each length
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, CompressError> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Reject over-subscribed sets; incomplete ones are legal (single distance code)
+        let mut left: i32 = 1;
+        for &count in counts.iter().skip(1) {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(CompressError::BadData);
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = alloc::vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, CompressError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..=MAX_BITS {
+            code |= input.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(CompressError::BadData)
+    }
+}
+
+/// Code lengths of the fixed literal/length and distance codes
+pub(super) fn fixed_lengths() -> ([u8; 288], [u8; 30]) {
+    let mut lit = [0u8; 288];
+    for (i, len) in lit.iter_mut().enumerate() {
+        *len = match i {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (lit, [5u8; 30])
+}
+
+/// Decompress a raw DEFLATE stream. Returns the data and how many input
+/// bytes the stream used, so callers can find a trailer after it.
+pub fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize), CompressError> {
+    let mut input = BitReader { data, pos: 0, bit_buf: 0, bit_count: 0 };
+    let mut out = Vec::with_capacity(data.len() * 3);
+
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => stored(&mut input, &mut out)?,
+            1 => {
+                let (lit, dist) = fixed_lengths();
+                codes(&mut input, &mut out, &Huffman::new(&lit)?, &Huffman::new(&dist)?)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_tables(&mut input)?;
+                codes(&mut input, &mut out, &lit, &dist)?;
+            }
+            _ => return Err(CompressError::BadData),
+        }
+        if last {
+            break;
+        }
+    }
+    Ok((out, input.pos))
+}
+
+fn stored(input: &mut BitReader, out: &mut Vec<u8>) -> Result<(), CompressError> {
+    input.align();
+    let header = input.data.get(input.pos..input.pos + 4).ok_or(CompressError::Truncated)?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let nlen = u16::from_le_bytes([header[2], header[3]]);
+    if len != !nlen {
+        return Err(CompressError::BadData);
+    }
+    input.pos += 4;
+    let block = input.data.get(input.pos..input.pos + len as usize).ok_or(CompressError::Truncated)?;
+    out.extend_from_slice(block);
+    input.pos += len as usize;
+    Ok(())
+}
+
+fn dynamic_tables(input: &mut BitReader) -> Result<(Huffman, Huffman), CompressError> {
+    let nlen = input.bits(5)? as usize + 257;
+    let ndist = input.bits(5)? as usize + 1;
+    let ncode = input.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(CompressError::BadData);
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &index in CODE_LENGTH_ORDER.iter().take(ncode) {
+        code_lengths[index] = input.bits(3)? as u8;
+    }
+    let code_huffman = Huffman::new(&code_lengths)?;
+
+    let mut lengths = [0u8; 286 + 30];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let symbol = code_huffman.decode(input)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if i == 0 {
+                    return Err(CompressError::BadData);
+                }
+                (lengths[i - 1], 3 + input.bits(2)? as usize)
+            }
+            17 => (0, 3 + input.bits(3)? as usize),
+            _ => (0, 11 + input.bits(7)? as usize),
+        };
+        if i + repeat > nlen + ndist {
+            return Err(CompressError::BadData);
+        }
+        lengths[i..i + repeat].iter_mut().for_each(|l| *l = value);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(CompressError::BadData);
+    }
+
+    Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..nlen + ndist])?))
+}
+
+fn codes(input: &mut BitReader, out: &mut Vec<u8>, lit: &Huffman, dist: &Huffman) -> Result<(), CompressError> {
+    loop {
+        let symbol = lit.decode(input)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let index = symbol - 257;
+        if index >= 29 {
+            return Err(CompressError::BadData);
+        }
+        let len = LENGTH_BASE[index] as usize + input.bits(LENGTH_EXTRA[index] as u32)? as usize;
+        let dsym = dist.decode(input)? as usize;
+        if dsym >= 30 {
+            return Err(CompressError::BadData);
+        }
+        let distance = DIST_BASE[dsym] as usize + input.bits(DIST_EXTRA[dsym] as u32)? as usize;
+        if distance > out.len() {
+            return Err(CompressError::BadData);
+        }
+        // Copies may overlap their own output, so go byte by byte
+        let start = out.len() - distance;
+        for k in 0..len {
+            let byte = out[start + k];
+            out.push(byte);
+        }
+    }
+}
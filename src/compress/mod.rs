@@ -0,0 +1,150 @@
+//! DEFLATE compression with gzip and zlib framing.
+
+pub mod inflate;
+pub mod deflate;
+
+use alloc::vec::Vec;
+
+pub use deflate::deflate;
+pub use inflate::inflate;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressError {
+    Truncated,
+    BadData,
+    BadHeader,
+    BadChecksum,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const GZIP_DEFLATE: u8 = 8;
+
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+/// Unix, as far as the gzip header is concerned
+const OS_UNIX: u8 = 3;
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the most bytes that can be summed before b could overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.len() >= 3 && data[..2] == GZIP_MAGIC && data[2] == GZIP_DEFLATE
+}
+
+/// Wrap `data` in a single gzip member. `mtime` is a Unix timestamp, 0 if unknown.
+pub fn gzip(data: &[u8], mtime: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 32);
+    out.extend_from_slice(&GZIP_MAGIC);
+    out.push(GZIP_DEFLATE);
+    out.push(0);
+    out.extend_from_slice(&mtime.to_le_bytes());
+    out.push(0);
+    out.push(OS_UNIX);
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Decompress gzip data. Concatenated members are joined, as gunzip does.
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>, CompressError> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let member = &data[pos..];
+        if !is_gzip(member) || member.len() < 10 {
+            return Err(CompressError::BadHeader);
+        }
+        let flags = member[3];
+        let mut header = 10;
+
+        if flags & FEXTRA != 0 {
+            let len = member.get(header..header + 2).ok_or(CompressError::Truncated)?;
+            header += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+        }
+        for flag in [FNAME, FCOMMENT] {
+            if flags & flag != 0 {
+                let end = member.get(header..).ok_or(CompressError::Truncated)?
+                    .iter().position(|&b| b == 0).ok_or(CompressError::Truncated)?;
+                header += end + 1;
+            }
+        }
+        if flags & FHCRC != 0 {
+            header += 2;
+        }
+
+        let body = member.get(header..).ok_or(CompressError::Truncated)?;
+        let (chunk, used) = inflate(body)?;
+        let trailer = body.get(used..used + 8).ok_or(CompressError::Truncated)?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+        if crc != crc32(&chunk) || size != chunk.len() as u32 {
+            return Err(CompressError::BadChecksum);
+        }
+        out.extend_from_slice(&chunk);
+        pos += header + used + 8;
+
+        // Some writers pad the end of the file with zeros
+        if data[pos..].iter().all(|&b| b == 0) {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+/// Decompress a zlib stream (RFC 1950), which is what HTTP calls `deflate`
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, CompressError> {
+    if data.len() < 6 || data[0] & 0x0F != GZIP_DEFLATE || u16::from_be_bytes([data[0], data[1]]) % 31 != 0 {
+        return Err(CompressError::BadHeader);
+    }
+    if data[1] & 0x20 != 0 {
+        // Preset dictionaries are never used for HTTP content
+        return Err(CompressError::BadHeader);
+    }
+    let (out, used) = inflate(&data[2..])?;
+    let trailer = data.get(2 + used..2 + used + 4).ok_or(CompressError::Truncated)?;
+    if u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) != adler32(&out) {
+        return Err(CompressError::BadChecksum);
+    }
+    Ok(out)
+}
+
+#[test_case]
+fn test_gzip_round_trip() {
+    let mut text = Vec::new();
+    for i in 0..2000u32 {
+        text.extend_from_slice(alloc::format!("line {} of some repetitive text\n", i % 37).as_bytes());
+    }
+    let packed = gzip(&text, 0);
+    assert!(packed.len() < text.len() / 4);
+    assert_eq!(gunzip(&packed).unwrap(), text);
+
+    assert_eq!(gunzip(&gzip(b"", 0)).unwrap(), b"");
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
@@ -1,5 +1,7 @@
 use alloc::string::{String, ToString};
 use crate::{fs, serial_println};
+use crate::fs::cache::{CachePolicy, DEFAULT_CACHE_SECTORS};
+use crate::net::config::NetConfig;
 
 const CONFIG_FILE: &str = "system.ini";
 
@@ -7,6 +9,19 @@ const CONFIG_FILE: &str = "system.ini";
 pub struct SystemConfig {
     pub hostname: String,
     pub keyboard_layout: String,
+    /// Directory `~` stands for in paths
+    pub home: String,
+    /// Sectors the block cache may hold
+    pub cache_sectors: usize,
+    pub cache_policy: CachePolicy,
+    /// Seconds between background flushes of dirty sectors, 0 to disable
+    pub flush_interval: u64,
+    /// The `net.*` keys
+    pub net: NetConfig,
+    /// Where `ntpdate` and the boot sync get the time
+    pub ntp_server: String,
+    /// Set the clock over SNTP at boot
+    pub ntp_sync: bool,
 }
 
 impl Default for SystemConfig {
@@ -14,6 +29,13 @@ impl Default for SystemConfig {
         Self {
             hostname: String::from("myos"),
             keyboard_layout: String::from("us"),
+            home: String::from("/"),
+            cache_sectors: DEFAULT_CACHE_SECTORS,
+            cache_policy: CachePolicy::WriteBack,
+            flush_interval: 5,
+            net: NetConfig::default(),
+            ntp_server: String::from(crate::net::sntp::DEFAULT_SERVER),
+            ntp_sync: false,
         }
     }
 }
@@ -44,7 +66,11 @@ impl SystemConfig {
                 match key.trim() {
                     "hostname" => config.hostname = value.trim().to_string(),
                     "keyboard_layout" => config.keyboard_layout = value.trim().to_string(),
-                    _ => serial_println!("[config] Unknown key: {}", key.trim()),
+                    "home" => config.home = fs::path::normalize(value.trim()),
+                    "ntp_server" => config.ntp_server = value.trim().to_string(),
+                    key => if !config.set(key, value.trim()) {
+                        serial_println!("[config] Ignoring {}={}", key, value.trim());
+                    },
                 }
             }
         }
@@ -53,12 +79,49 @@ impl SystemConfig {
         config
     }
 
+    /// Set a numeric, boolean, enum or `net.*` setting from its text form. Returns false for an
+    /// unknown key or a value that doesn't parse.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "cache_sectors" => match value.parse::<usize>() {
+                Ok(n) if n > 0 => self.cache_sectors = n,
+                _ => return false,
+            },
+            "cache_policy" => match CachePolicy::parse(value) {
+                Some(p) => self.cache_policy = p,
+                None => return false,
+            },
+            "flush_interval" => match value.parse::<u64>() {
+                Ok(n) => self.flush_interval = n,
+                Err(_) => return false,
+            },
+            "ntp_sync" => match value.parse::<bool>() {
+                Ok(b) => self.ntp_sync = b,
+                Err(_) => return false,
+            },
+            key => match key.strip_prefix("net.") {
+                Some(net_key) => return self.net.set(net_key, value),
+                None => return false,
+            },
+        }
+        true
+    }
+
     pub fn save(&self) -> bool {
-        let contents = alloc::format!(
-            "# System configuration\nhostname={}\nkeyboard_layout={}\n",
+        let mut contents = alloc::format!(
+            "# System configuration\nhostname={}\nkeyboard_layout={}\nhome={}\ncache_sectors={}\ncache_policy={}\nflush_interval={}\nntp_server={}\nntp_sync={}\n",
             self.hostname,
             self.keyboard_layout,
+            self.home,
+            self.cache_sectors,
+            self.cache_policy.name(),
+            self.flush_interval,
+            self.ntp_server,
+            self.ntp_sync,
         );
+        for key in NetConfig::KEYS {
+            contents.push_str(&alloc::format!("net.{}={}\n", key, self.net.get(key).unwrap_or_default()));
+        }
         fs::write_file(CONFIG_FILE, contents.as_bytes())
     }
 }
\ No newline at end of file
@@ -0,0 +1,230 @@
+//! AES-128/256 (FIPS 197) and GCM mode (NIST SP 800-38D).
+
+use alloc::vec::Vec;
+use super::ct_eq;
+
+/// Multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1
+const fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        let high = a & 0x80;
+        a <<= 1;
+        if high != 0 {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    p
+}
+
+const fn make_sbox() -> [u8; 256] {
+    let mut sbox = [0u8; 256];
+    let mut x = 0;
+    while x < 256 {
+        // Multiplicative inverse as x^254, then the affine transform
+        let mut inv = 1u8;
+        let mut i = 0;
+        while i < 7 {
+            inv = gmul(gmul(inv, x as u8), gmul(inv, x as u8));
+            i += 1;
+        }
+        sbox[x] = inv
+            ^ inv.rotate_left(1)
+            ^ inv.rotate_left(2)
+            ^ inv.rotate_left(3)
+            ^ inv.rotate_left(4)
+            ^ 0x63;
+        x += 1;
+    }
+    sbox
+}
+
+static SBOX: [u8; 256] = make_sbox();
+
+/// An expanded AES key; only encryption is needed for GCM
+pub struct Aes {
+    round_keys: Vec<[u8; 16]>,
+}
+
+impl Aes {
+    /// 16- or 32-byte keys. None for any other length.
+    pub fn new(key: &[u8]) -> Option<Aes> {
+        let nk = match key.len() {
+            16 => 4,
+            32 => 8,
+            _ => return None,
+        };
+        let rounds = nk + 6;
+        let mut words: Vec<[u8; 4]> = key.chunks(4).map(|w| [w[0], w[1], w[2], w[3]]).collect();
+        let mut rcon = 1u8;
+        for i in nk..4 * (rounds + 1) {
+            let mut t = words[i - 1];
+            if i % nk == 0 {
+                t = [SBOX[t[1] as usize] ^ rcon, SBOX[t[2] as usize], SBOX[t[3] as usize], SBOX[t[0] as usize]];
+                rcon = gmul(rcon, 2);
+            } else if nk > 6 && i % nk == 4 {
+                t = t.map(|b| SBOX[b as usize]);
+            }
+            let prev = words[i - nk];
+            words.push([prev[0] ^ t[0], prev[1] ^ t[1], prev[2] ^ t[2], prev[3] ^ t[3]]);
+        }
+        let round_keys = words
+            .chunks(4)
+            .map(|w| {
+                let mut k = [0u8; 16];
+                for (i, word) in w.iter().enumerate() {
+                    k[4 * i..4 * i + 4].copy_from_slice(word);
+                }
+                k
+            })
+            .collect();
+        Some(Aes { round_keys })
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let last = self.round_keys.len() - 1;
+        xor_into(block, &self.round_keys[0]);
+        for round in 1..=last {
+            for b in block.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            // ShiftRows: row r of the column-major state rotates left by r
+            let s = *block;
+            for c in 0..4 {
+                for r in 0..4 {
+                    block[4 * c + r] = s[4 * ((c + r) % 4) + r];
+                }
+            }
+            if round != last {
+                for c in 0..4 {
+                    let col = [block[4 * c], block[4 * c + 1], block[4 * c + 2], block[4 * c + 3]];
+                    for r in 0..4 {
+                        block[4 * c + r] = gmul(col[r], 2)
+                            ^ gmul(col[(r + 1) % 4], 3)
+                            ^ col[(r + 2) % 4]
+                            ^ col[(r + 3) % 4];
+                    }
+                }
+            }
+            xor_into(block, &self.round_keys[round]);
+        }
+    }
+}
+
+fn xor_into(block: &mut [u8; 16], other: &[u8; 16]) {
+    for (b, o) in block.iter_mut().zip(other) {
+        *b ^= o;
+    }
+}
+
+/// Multiply in GHASH's GF(2^128), bit-reflected as the spec describes
+fn gf_mul(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xE1 << 120;
+    let mut z = 0;
+    let mut v = y;
+    for i in 0..128 {
+        let bit = (x >> (127 - i)) & 1;
+        z ^= v & 0u128.wrapping_sub(bit);
+        let lsb = v & 1;
+        v = (v >> 1) ^ (R & 0u128.wrapping_sub(lsb));
+    }
+    z
+}
+
+/// AES in Galois/Counter mode with 96-bit nonces and 16-byte tags
+pub struct AesGcm {
+    aes: Aes,
+    h: u128,
+}
+
+impl AesGcm {
+    pub fn new(key: &[u8]) -> Option<AesGcm> {
+        let aes = Aes::new(key)?;
+        let mut h = [0u8; 16];
+        aes.encrypt_block(&mut h);
+        Some(AesGcm { aes, h: u128::from_be_bytes(h) })
+    }
+
+    fn counter_block(nonce: &[u8; 12], counter: u32) -> [u8; 16] {
+        let mut block = [0u8; 16];
+        block[..12].copy_from_slice(nonce);
+        block[12..].copy_from_slice(&counter.to_be_bytes());
+        block
+    }
+
+    fn ctr(&self, nonce: &[u8; 12], data: &mut [u8]) {
+        for (i, chunk) in data.chunks_mut(16).enumerate() {
+            let mut stream = Self::counter_block(nonce, 2 + i as u32);
+            self.aes.encrypt_block(&mut stream);
+            for (b, k) in chunk.iter_mut().zip(stream.iter()) {
+                *b ^= k;
+            }
+        }
+    }
+
+    fn tag(&self, nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+        let mut y = 0u128;
+        for part in [aad, ciphertext] {
+            for chunk in part.chunks(16) {
+                let mut block = [0u8; 16];
+                block[..chunk.len()].copy_from_slice(chunk);
+                y = gf_mul(y ^ u128::from_be_bytes(block), self.h);
+            }
+        }
+        let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        y = gf_mul(y ^ lengths, self.h);
+        let mut mask = Self::counter_block(nonce, 1);
+        self.aes.encrypt_block(&mut mask);
+        (y ^ u128::from_be_bytes(mask)).to_be_bytes()
+    }
+
+    /// Encrypt `plaintext`, returning ciphertext followed by the 16-byte tag
+    pub fn seal(&self, nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut out = plaintext.to_vec();
+        self.ctr(nonce, &mut out);
+        let tag = self.tag(nonce, aad, &out);
+        out.extend_from_slice(&tag);
+        out
+    }
+
+    /// Check the tag and decrypt. None if the data was tampered with.
+    pub fn open(&self, nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < 16 {
+            return None;
+        }
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - 16);
+        if !ct_eq(&self.tag(nonce, aad, ciphertext), tag) {
+            return None;
+        }
+        let mut out = ciphertext.to_vec();
+        self.ctr(nonce, &mut out);
+        Some(out)
+    }
+}
+
+#[test_case]
+fn test_aes_gcm() {
+    use super::{hex, unhex};
+    // FIPS 197 appendix C.1
+    let aes = Aes::new(&unhex("000102030405060708090a0b0c0d0e0f").unwrap()).unwrap();
+    let mut block = [0u8; 16];
+    block.copy_from_slice(&unhex("00112233445566778899aabbccddeeff").unwrap());
+    aes.encrypt_block(&mut block);
+    assert_eq!(hex(&block), "69c4e0d86a7b0430d8cdb78070b4c55a");
+
+    // GCM spec test case 4
+    let gcm = AesGcm::new(&unhex("feffe9928665731c6d6a8f9467308308").unwrap()).unwrap();
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&unhex("cafebabefacedbaddecaf888").unwrap());
+    let aad = unhex("feedfacedeadbeeffeedfacedeadbeefabaddad2").unwrap();
+    let plaintext = unhex(
+        "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+    )
+    .unwrap();
+    let sealed = gcm.seal(&nonce, &aad, &plaintext);
+    assert_eq!(hex(&sealed[plaintext.len()..]), "5bc94fbc3221a5db94fae95ae7121a47");
+    assert_eq!(gcm.open(&nonce, &aad, &sealed), Some(plaintext));
+}
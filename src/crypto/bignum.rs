@@ -0,0 +1,276 @@
+//! Unsigned big integers and Montgomery arithmetic modulo an odd number,
+//! enough for RSA and elliptic-curve signature checks.
+
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+/// An unsigned integer in little-endian 64-bit limbs. Leading zero limbs
+/// don't affect comparisons.
+#[derive(Clone, Debug)]
+pub struct BigUint {
+    limbs: Vec<u64>,
+}
+
+impl BigUint {
+    pub fn zero(len: usize) -> BigUint {
+        BigUint { limbs: alloc::vec![0; len.max(1)] }
+    }
+
+    pub fn from_u64(value: u64) -> BigUint {
+        BigUint { limbs: alloc::vec![value] }
+    }
+
+    /// Big-endian bytes, as found in DER and on the wire
+    pub fn from_be_bytes(bytes: &[u8]) -> BigUint {
+        let mut limbs: Vec<u64> = bytes
+            .rchunks(8)
+            .map(|chunk| chunk.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+            .collect();
+        if limbs.is_empty() {
+            limbs.push(0);
+        }
+        BigUint { limbs }
+    }
+
+    /// Big-endian bytes, left-padded to `len`. Bytes above `len` are dropped.
+    pub fn to_be_bytes(&self, len: usize) -> Vec<u8> {
+        let mut out = alloc::vec![0u8; len];
+        for i in 0..len.min(self.limbs.len() * 8) {
+            out[len - 1 - i] = (self.limbs[i / 8] >> (8 * (i % 8))) as u8;
+        }
+        out
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.iter().all(|&l| l == 0)
+    }
+
+    /// Number of significant bits
+    pub fn bits(&self) -> usize {
+        for (i, &limb) in self.limbs.iter().enumerate().rev() {
+            if limb != 0 {
+                return 64 * i + 64 - limb.leading_zeros() as usize;
+            }
+        }
+        0
+    }
+
+    pub fn bit(&self, i: usize) -> bool {
+        self.limbs.get(i / 64).is_some_and(|l| (l >> (i % 64)) & 1 == 1)
+    }
+
+    fn limb(&self, i: usize) -> u64 {
+        self.limbs.get(i).copied().unwrap_or(0)
+    }
+
+    fn resized(&self, len: usize) -> BigUint {
+        let mut limbs = self.limbs.clone();
+        limbs.resize(len, 0);
+        BigUint { limbs }
+    }
+
+    /// self - other, assuming self >= other
+    fn sub_assign(&mut self, other: &BigUint) {
+        let mut borrow = 0u64;
+        for i in 0..self.limbs.len() {
+            let (d1, b1) = self.limbs[i].overflowing_sub(other.limb(i));
+            let (d2, b2) = d1.overflowing_sub(borrow);
+            self.limbs[i] = d2;
+            borrow = (b1 | b2) as u64;
+        }
+    }
+
+    /// self + other, returning the carry out of the top limb
+    fn add_assign(&mut self, other: &BigUint) -> u64 {
+        let mut carry = 0u64;
+        for i in 0..self.limbs.len() {
+            let (s1, c1) = self.limbs[i].overflowing_add(other.limb(i));
+            let (s2, c2) = s1.overflowing_add(carry);
+            self.limbs[i] = s2;
+            carry = (c1 | c2) as u64;
+        }
+        carry
+    }
+}
+
+impl Ord for BigUint {
+    fn cmp(&self, other: &BigUint) -> Ordering {
+        let len = self.limbs.len().max(other.limbs.len());
+        for i in (0..len).rev() {
+            match self.limb(i).cmp(&other.limb(i)) {
+                Ordering::Equal => {}
+                unequal => return unequal,
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl PartialEq for BigUint {
+    fn eq(&self, other: &BigUint) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for BigUint {}
+
+impl PartialOrd for BigUint {
+    fn partial_cmp(&self, other: &BigUint) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Arithmetic modulo an odd `n`. Values passed to `mul`, `add` and `sub`
+/// must already be reduced; `mul` works on Montgomery forms from `to_mont`.
+pub struct Modulus {
+    n: BigUint,
+    /// -n^-1 mod 2^64
+    n0: u64,
+    /// R^2 mod n, where R = 2^(64 * limbs)
+    r2: BigUint,
+}
+
+impl Modulus {
+    /// None if `n` is even or zero
+    pub fn new(n: &BigUint) -> Option<Modulus> {
+        if !n.bit(0) {
+            return None;
+        }
+        let len = n.bits().div_ceil(64);
+        let n = n.resized(len);
+        // Newton's iteration for the inverse of n[0] mod 2^64
+        let mut inv = 1u64;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u64.wrapping_sub(n.limbs[0].wrapping_mul(inv)));
+        }
+        let mut modulus = Modulus { n0: inv.wrapping_neg(), r2: BigUint::zero(len), n };
+        let mut r2 = BigUint::from_u64(1).resized(len);
+        for _ in 0..128 * len {
+            r2 = modulus.add(&r2, &r2);
+        }
+        modulus.r2 = r2;
+        Some(modulus)
+    }
+
+    pub fn modulus(&self) -> &BigUint {
+        &self.n
+    }
+
+    /// Bytes needed to hold a value below the modulus
+    pub fn byte_len(&self) -> usize {
+        self.n.bits().div_ceil(8)
+    }
+
+    /// `a` mod n for any size of `a`
+    pub fn reduce(&self, a: &BigUint) -> BigUint {
+        if *a < self.n {
+            return a.resized(self.n.limbs.len());
+        }
+        let mut r = BigUint::zero(self.n.limbs.len());
+        for i in (0..a.bits()).rev() {
+            r = self.add(&r, &r);
+            if a.bit(i) {
+                r = self.add(&r, &BigUint::from_u64(1));
+            }
+        }
+        r
+    }
+
+    pub fn add(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        let mut sum = a.resized(self.n.limbs.len());
+        let carry = sum.add_assign(b);
+        if carry != 0 || sum >= self.n {
+            sum.sub_assign(&self.n);
+        }
+        sum
+    }
+
+    pub fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        let mut diff = a.resized(self.n.limbs.len());
+        if *a < *b {
+            diff.add_assign(&self.n);
+        }
+        diff.sub_assign(b);
+        diff
+    }
+
+    /// Montgomery product a * b / R mod n
+    pub fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        let k = self.n.limbs.len();
+        let n = &self.n.limbs;
+        let mut t = alloc::vec![0u64; k + 2];
+        for i in 0..k {
+            let ai = a.limb(i) as u128;
+            let mut carry = 0u128;
+            for (j, tj) in t[..k].iter_mut().enumerate() {
+                let v = *tj as u128 + ai * b.limb(j) as u128 + carry;
+                *tj = v as u64;
+                carry = v >> 64;
+            }
+            let v = t[k] as u128 + carry;
+            t[k] = v as u64;
+            t[k + 1] = (v >> 64) as u64;
+
+            let m = t[0].wrapping_mul(self.n0) as u128;
+            let mut carry = (t[0] as u128 + m * n[0] as u128) >> 64;
+            for j in 1..k {
+                let v = t[j] as u128 + m * n[j] as u128 + carry;
+                t[j - 1] = v as u64;
+                carry = v >> 64;
+            }
+            let v = t[k] as u128 + carry;
+            t[k - 1] = v as u64;
+            t[k] = t[k + 1] + (v >> 64) as u64;
+        }
+        let top = t[k];
+        t.truncate(k);
+        let mut result = BigUint { limbs: t };
+        if top != 0 || result >= self.n {
+            result.sub_assign(&self.n);
+        }
+        result
+    }
+
+    pub fn to_mont(&self, a: &BigUint) -> BigUint {
+        self.mul(&self.reduce(a), &self.r2)
+    }
+
+    pub fn from_mont(&self, a: &BigUint) -> BigUint {
+        self.mul(a, &BigUint::from_u64(1))
+    }
+
+    /// base^exp mod n, in and out of Montgomery form
+    pub fn pow(&self, base: &BigUint, exp: &BigUint) -> BigUint {
+        let base = self.to_mont(base);
+        let mut result = self.to_mont(&BigUint::from_u64(1));
+        for i in (0..exp.bits()).rev() {
+            result = self.mul(&result, &result);
+            if exp.bit(i) {
+                result = self.mul(&result, &base);
+            }
+        }
+        self.from_mont(&result)
+    }
+
+    /// The inverse of `a` when n is prime, by Fermat's little theorem
+    pub fn inverse(&self, a: &BigUint) -> BigUint {
+        let mut exp = self.n.clone();
+        exp.sub_assign(&BigUint::from_u64(2));
+        self.pow(a, &exp)
+    }
+}
+
+#[test_case]
+fn test_modpow() {
+    use super::unhex;
+    // 3^(p-1) mod p == 1 for the P-256 prime, and a small case by hand
+    let p = BigUint::from_be_bytes(&unhex("ffffffff00000001000000000000000000000000ffffffffffffffffffffffff").unwrap());
+    let field = Modulus::new(&p).unwrap();
+    let mut exp = p.clone();
+    exp.sub_assign(&BigUint::from_u64(1));
+    assert_eq!(field.pow(&BigUint::from_u64(3), &exp), BigUint::from_u64(1));
+    let small = Modulus::new(&BigUint::from_u64(1_000_003)).unwrap();
+    assert_eq!(small.pow(&BigUint::from_u64(2), &BigUint::from_u64(20)), BigUint::from_u64(48_573));
+    let inv = small.inverse(&BigUint::from_u64(12345));
+    assert_eq!(small.from_mont(&small.mul(&small.to_mont(&inv), &small.to_mont(&BigUint::from_u64(12345)))), BigUint::from_u64(1));
+}
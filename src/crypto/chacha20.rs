@@ -0,0 +1,228 @@
+//! ChaCha20, Poly1305 and their AEAD combination (RFC 8439).
+
+use alloc::vec::Vec;
+use super::ct_eq;
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// One 64-byte keystream block
+pub fn block(key: &[u8; 32], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let word = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for i in 0..8 {
+        state[4 + i] = word(&key[4 * i..]);
+    }
+    state[12] = counter;
+    for i in 0..3 {
+        state[13 + i] = word(&nonce[4 * i..]);
+    }
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        out[4 * i..4 * i + 4].copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    out
+}
+
+/// XOR `data` with the keystream starting at block `counter`
+pub fn apply_keystream(key: &[u8; 32], counter: u32, nonce: &[u8; 12], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let stream = block(key, counter.wrapping_add(i as u32), nonce);
+        for (b, k) in chunk.iter_mut().zip(stream.iter()) {
+            *b ^= k;
+        }
+    }
+}
+
+/// Poly1305 with 26-bit limbs, after poly1305-donna
+struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+}
+
+impl Poly1305 {
+    fn new(key: &[u8; 32]) -> Self {
+        let word = |i: usize| u32::from_le_bytes([key[i], key[i + 1], key[i + 2], key[i + 3]]);
+        Poly1305 {
+            r: [
+                word(0) & 0x3ffffff,
+                (word(3) >> 2) & 0x3ffff03,
+                (word(6) >> 4) & 0x3ffc0ff,
+                (word(9) >> 6) & 0x3f03fff,
+                (word(12) >> 8) & 0x00fffff,
+            ],
+            h: [0; 5],
+            pad: [word(16), word(20), word(24), word(28)],
+        }
+    }
+
+    /// Absorb one block of up to 16 bytes
+    fn block(&mut self, data: &[u8]) {
+        let mut m = [0u8; 17];
+        m[..data.len()].copy_from_slice(data);
+        m[data.len()] = 1;
+        let word = |i: usize| u32::from_le_bytes([m[i], m[i + 1], m[i + 2], m[i + 3]]);
+        let h = &mut self.h;
+        h[0] += word(0) & 0x3ffffff;
+        h[1] += (word(3) >> 2) & 0x3ffffff;
+        h[2] += (word(6) >> 4) & 0x3ffffff;
+        h[3] += (word(9) >> 6) & 0x3ffffff;
+        h[4] += (word(12) >> 8) | ((m[16] as u32) << 24);
+
+        let r = self.r.map(|x| x as u64);
+        let s: [u64; 5] = [0, r[1] * 5, r[2] * 5, r[3] * 5, r[4] * 5];
+        let h64 = h.map(|x| x as u64);
+        let d0 = h64[0] * r[0] + h64[1] * s[4] + h64[2] * s[3] + h64[3] * s[2] + h64[4] * s[1];
+        let mut d1 = h64[0] * r[1] + h64[1] * r[0] + h64[2] * s[4] + h64[3] * s[3] + h64[4] * s[2];
+        let mut d2 = h64[0] * r[2] + h64[1] * r[1] + h64[2] * r[0] + h64[3] * s[4] + h64[4] * s[3];
+        let mut d3 = h64[0] * r[3] + h64[1] * r[2] + h64[2] * r[1] + h64[3] * r[0] + h64[4] * s[4];
+        let mut d4 = h64[0] * r[4] + h64[1] * r[3] + h64[2] * r[2] + h64[3] * r[1] + h64[4] * r[0];
+
+        let mut c = d0 >> 26;
+        h[0] = d0 as u32 & 0x3ffffff;
+        d1 += c;
+        c = d1 >> 26;
+        h[1] = d1 as u32 & 0x3ffffff;
+        d2 += c;
+        c = d2 >> 26;
+        h[2] = d2 as u32 & 0x3ffffff;
+        d3 += c;
+        c = d3 >> 26;
+        h[3] = d3 as u32 & 0x3ffffff;
+        d4 += c;
+        c = d4 >> 26;
+        h[4] = d4 as u32 & 0x3ffffff;
+        h[0] += c as u32 * 5;
+        c = (h[0] >> 26) as u64;
+        h[0] &= 0x3ffffff;
+        h[1] += c as u32;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(16) {
+            self.block(chunk);
+        }
+    }
+
+    fn finish(self) -> [u8; 16] {
+        let mut h = self.h;
+        // Fully carry h
+        let mut c = h[1] >> 26;
+        h[1] &= 0x3ffffff;
+        for limb in &mut h[2..] {
+            *limb += c;
+            c = *limb >> 26;
+            *limb &= 0x3ffffff;
+        }
+        h[0] += c * 5;
+        c = h[0] >> 26;
+        h[0] &= 0x3ffffff;
+        h[1] += c;
+
+        // g = h + 5 - 2^130; use it if it didn't go negative
+        let mut g = [0u32; 5];
+        let mut c = 5;
+        for i in 0..4 {
+            g[i] = h[i] + c;
+            c = g[i] >> 26;
+            g[i] &= 0x3ffffff;
+        }
+        g[4] = (h[4] + c).wrapping_sub(1 << 26);
+        let mask = (g[4] >> 31).wrapping_sub(1);
+        for i in 0..5 {
+            h[i] = (h[i] & !mask) | (g[i] & mask);
+        }
+
+        // h mod 2^128, plus the pad
+        let h0 = h[0] | (h[1] << 26);
+        let h1 = (h[1] >> 6) | (h[2] << 20);
+        let h2 = (h[2] >> 12) | (h[3] << 14);
+        let h3 = (h[3] >> 18) | (h[4] << 8);
+        let mut out = [0u8; 16];
+        let mut carry = 0u64;
+        for (i, (hv, pv)) in [h0, h1, h2, h3].iter().zip(self.pad.iter()).enumerate() {
+            let sum = *hv as u64 + *pv as u64 + carry;
+            out[4 * i..4 * i + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+            carry = sum >> 32;
+        }
+        out
+    }
+}
+
+/// The Poly1305 tag over AAD and ciphertext as laid out by the AEAD
+fn aead_tag(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let mut otk = [0u8; 32];
+    otk.copy_from_slice(&block(key, 0, nonce)[..32]);
+    // Each part is padded to a whole number of blocks
+    let padded = |len: usize| len.div_ceil(16) * 16;
+    let mut message = alloc::vec![0u8; padded(aad.len()) + padded(ciphertext.len()) + 16];
+    message[..aad.len()].copy_from_slice(aad);
+    let start = padded(aad.len());
+    message[start..start + ciphertext.len()].copy_from_slice(ciphertext);
+    let lengths = message.len() - 16;
+    message[lengths..lengths + 8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+    message[lengths + 8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    let mut mac = Poly1305::new(&otk);
+    mac.update(&message);
+    mac.finish()
+}
+
+/// Encrypt `plaintext`, returning ciphertext followed by the 16-byte tag
+pub fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = plaintext.to_vec();
+    apply_keystream(key, 1, nonce, &mut out);
+    let tag = aead_tag(key, nonce, aad, &out);
+    out.extend_from_slice(&tag);
+    out
+}
+
+/// Check the tag and decrypt. None if the data was tampered with.
+pub fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < 16 {
+        return None;
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - 16);
+    if !ct_eq(&aead_tag(key, nonce, aad, ciphertext), tag) {
+        return None;
+    }
+    let mut out = ciphertext.to_vec();
+    apply_keystream(key, 1, nonce, &mut out);
+    Some(out)
+}
+
+#[test_case]
+fn test_chacha20_poly1305() {
+    use super::{hex, unhex};
+    // RFC 8439 section 2.8.2
+    let key: [u8; 32] = core::array::from_fn(|i| 0x80 + i as u8);
+    let nonce = [0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+    let aad = unhex("50515253c0c1c2c3c4c5c6c7").unwrap();
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+    let sealed = seal(&key, &nonce, &aad, plaintext);
+    assert_eq!(hex(&sealed[sealed.len() - 16..]), "1ae10b594f09e26a7e902ecbd0600691");
+    assert_eq!(open(&key, &nonce, &aad, &sealed).as_deref(), Some(&plaintext[..]));
+    let mut forged = sealed.clone();
+    forged[0] ^= 1;
+    assert!(open(&key, &nonce, &aad, &forged).is_none());
+}
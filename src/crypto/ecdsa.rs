@@ -0,0 +1,270 @@
+//! ECDSA signature verification on P-256 and P-384 (FIPS 186-4).
+
+use super::bignum::{BigUint, Modulus};
+use super::sha2::HashAlg;
+use super::unhex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    P256,
+    P384,
+}
+
+/// Curve parameters as big-endian hex: p, b, n, Gx, Gy. Both curves have a = -3.
+fn params(curve: Curve) -> [&'static str; 5] {
+    match curve {
+        Curve::P256 => [
+            "ffffffff00000001000000000000000000000000ffffffffffffffffffffffff",
+            "5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b",
+            "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551",
+            "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+            "4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5",
+        ],
+        Curve::P384 => [
+            "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffeffffffff0000000000000000ffffffff",
+            "b3312fa7e23ee7e4988e056be3f82d19181d9c6efe8141120314088f5013875ac656398d8a2ed19d2a85c8edd3ec2aef",
+            "ffffffffffffffffffffffffffffffffffffffffffffffffc7634d81f4372ddf581a0db248b0a77aecec196accc52973",
+            "aa87ca22be8b05378eb1c71ef320ad746e1d3b628ba79b9859f741e082542a385502f25dbf55296c3a545e3872760ab7",
+            "3617de4a96262c6f5d9e98bf9292dc29f8f41dbd289a147ce9da3113b5f0b8c00a60b1ce1d7e819d7a431d7c90ea0e5f",
+        ],
+    }
+}
+
+impl Curve {
+    /// Bytes in a coordinate or scalar
+    pub fn size(self) -> usize {
+        match self {
+            Curve::P256 => 32,
+            Curve::P384 => 48,
+        }
+    }
+}
+
+/// A point in Jacobian coordinates, each in Montgomery form. Z = 0 is the
+/// point at infinity.
+#[derive(Clone)]
+struct Point {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+}
+
+struct Field {
+    p: Modulus,
+    b: BigUint,
+    one: BigUint,
+}
+
+impl Field {
+    fn infinity(&self) -> Point {
+        Point { x: self.one.clone(), y: self.one.clone(), z: BigUint::zero(1) }
+    }
+
+    fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        self.p.mul(a, b)
+    }
+
+    fn add(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        self.p.add(a, b)
+    }
+
+    fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        self.p.sub(a, b)
+    }
+
+    /// y^2 == x^3 - 3x + b for affine coordinates in Montgomery form
+    fn on_curve(&self, x: &BigUint, y: &BigUint) -> bool {
+        let x3 = self.mul(&self.mul(x, x), x);
+        let three_x = self.add(&self.add(x, x), x);
+        let rhs = self.add(&self.sub(&x3, &three_x), &self.b);
+        self.mul(y, y) == rhs
+    }
+
+    /// Doubling for a = -3 (dbl-2001-b)
+    fn double(&self, pt: &Point) -> Point {
+        if pt.z.is_zero() || pt.y.is_zero() {
+            return self.infinity();
+        }
+        let delta = self.mul(&pt.z, &pt.z);
+        let gamma = self.mul(&pt.y, &pt.y);
+        let beta = self.mul(&pt.x, &gamma);
+        let t = self.mul(&self.sub(&pt.x, &delta), &self.add(&pt.x, &delta));
+        let alpha = self.add(&self.add(&t, &t), &t);
+        let beta4 = self.add(&self.add(&beta, &beta), &self.add(&beta, &beta));
+        let x = self.sub(&self.mul(&alpha, &alpha), &self.add(&beta4, &beta4));
+        let yz = self.add(&pt.y, &pt.z);
+        let z = self.sub(&self.sub(&self.mul(&yz, &yz), &gamma), &delta);
+        let gamma2 = self.mul(&gamma, &gamma);
+        let gamma8 = {
+            let g2 = self.add(&gamma2, &gamma2);
+            let g4 = self.add(&g2, &g2);
+            self.add(&g4, &g4)
+        };
+        let y = self.sub(&self.mul(&alpha, &self.sub(&beta4, &x)), &gamma8);
+        Point { x, y, z }
+    }
+
+    /// General addition (add-2007-bl), falling back to doubling
+    fn add_points(&self, a: &Point, b: &Point) -> Point {
+        if a.z.is_zero() {
+            return b.clone();
+        }
+        if b.z.is_zero() {
+            return a.clone();
+        }
+        let z1z1 = self.mul(&a.z, &a.z);
+        let z2z2 = self.mul(&b.z, &b.z);
+        let u1 = self.mul(&a.x, &z2z2);
+        let u2 = self.mul(&b.x, &z1z1);
+        let s1 = self.mul(&self.mul(&a.y, &b.z), &z2z2);
+        let s2 = self.mul(&self.mul(&b.y, &a.z), &z1z1);
+        let h = self.sub(&u2, &u1);
+        let r_half = self.sub(&s2, &s1);
+        if h.is_zero() {
+            return if r_half.is_zero() { self.double(a) } else { self.infinity() };
+        }
+        let h2 = self.add(&h, &h);
+        let i = self.mul(&h2, &h2);
+        let j = self.mul(&h, &i);
+        let r = self.add(&r_half, &r_half);
+        let v = self.mul(&u1, &i);
+        let x = self.sub(&self.sub(&self.mul(&r, &r), &j), &self.add(&v, &v));
+        let s1j = self.mul(&s1, &j);
+        let y = self.sub(&self.mul(&r, &self.sub(&v, &x)), &self.add(&s1j, &s1j));
+        let zz = self.add(&a.z, &b.z);
+        let z = self.mul(&self.sub(&self.sub(&self.mul(&zz, &zz), &z1z1), &z2z2), &h);
+        Point { x, y, z }
+    }
+
+    /// The affine x coordinate, out of Montgomery form
+    fn affine_x(&self, pt: &Point) -> BigUint {
+        let z = self.p.from_mont(&pt.z);
+        let z_inv = self.p.to_mont(&self.p.inverse(&z));
+        self.p.from_mont(&self.mul(&pt.x, &self.mul(&z_inv, &z_inv)))
+    }
+}
+
+/// Check an ECDSA signature. `public_key` is an uncompressed SEC1 point
+/// (0x04 || x || y) and `signature` the DER `SEQUENCE { r, s }`, as found in
+/// certificates and TLS.
+pub fn verify(curve: Curve, public_key: &[u8], alg: HashAlg, message: &[u8], signature: &[u8]) -> bool {
+    let (r, s) = match parse_signature(signature) {
+        Some(rs) => rs,
+        None => return false,
+    };
+    verify_digest(curve, public_key, &alg.digest(message), &r, &s)
+}
+
+/// The two integers of a DER-encoded ECDSA signature
+fn parse_signature(der: &[u8]) -> Option<(BigUint, BigUint)> {
+    fn read(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+        if data.len() < 2 || data[0] != tag || data[1] & 0x80 != 0 && data[1] != 0x81 {
+            return None;
+        }
+        let (len, start) = if data[1] == 0x81 { (*data.get(2)? as usize, 3) } else { (data[1] as usize, 2usize) };
+        let end = start.checked_add(len)?;
+        if end > data.len() {
+            return None;
+        }
+        Some((&data[start..end], &data[end..]))
+    }
+    let (body, rest) = read(der, 0x30)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    let (r, body) = read(body, 0x02)?;
+    let (s, body) = read(body, 0x02)?;
+    if !body.is_empty() {
+        return None;
+    }
+    Some((BigUint::from_be_bytes(r), BigUint::from_be_bytes(s)))
+}
+
+fn verify_digest(curve: Curve, public_key: &[u8], digest: &[u8], r: &BigUint, s: &BigUint) -> bool {
+    let size = curve.size();
+    if public_key.len() != 1 + 2 * size || public_key[0] != 0x04 {
+        return false;
+    }
+    let [p_hex, b_hex, n_hex, gx_hex, gy_hex] = params(curve);
+    let parse = |hex: &str| BigUint::from_be_bytes(&unhex(hex).unwrap());
+    let (p, n) = (parse(p_hex), parse(n_hex));
+    let (p_mod, n_mod) = match (Modulus::new(&p), Modulus::new(&n)) {
+        (Some(p_mod), Some(n_mod)) => (p_mod, n_mod),
+        _ => return false,
+    };
+    if r.is_zero() || s.is_zero() || *r >= n || *s >= n {
+        return false;
+    }
+
+    let qx = BigUint::from_be_bytes(&public_key[1..1 + size]);
+    let qy = BigUint::from_be_bytes(&public_key[1 + size..]);
+    if qx >= p || qy >= p {
+        return false;
+    }
+    let field = Field { b: p_mod.to_mont(&parse(b_hex)), one: p_mod.to_mont(&BigUint::from_u64(1)), p: p_mod };
+    let q = Point { x: field.p.to_mont(&qx), y: field.p.to_mont(&qy), z: field.one.clone() };
+    if !field.on_curve(&q.x, &q.y) {
+        return false;
+    }
+    let g = Point {
+        x: field.p.to_mont(&parse(gx_hex)),
+        y: field.p.to_mont(&parse(gy_hex)),
+        z: field.one.clone(),
+    };
+
+    // z is the leftmost bits of the digest, as many as n has. Both orders
+    // fill their bytes exactly, so no shift is needed.
+    let z = n_mod.reduce(&BigUint::from_be_bytes(&digest[..digest.len().min(size)]));
+
+    let s_inv = n_mod.inverse(s);
+    let mont = |a: &BigUint| n_mod.to_mont(a);
+    let u1 = n_mod.from_mont(&n_mod.mul(&mont(&z), &mont(&s_inv)));
+    let u2 = n_mod.from_mont(&n_mod.mul(&mont(r), &mont(&s_inv)));
+
+    // u1*G + u2*Q, sharing the doublings
+    let g_plus_q = field.add_points(&g, &q);
+    let mut acc = field.infinity();
+    for i in (0..u1.bits().max(u2.bits())).rev() {
+        acc = field.double(&acc);
+        match (u1.bit(i), u2.bit(i)) {
+            (true, true) => acc = field.add_points(&acc, &g_plus_q),
+            (true, false) => acc = field.add_points(&acc, &g),
+            (false, true) => acc = field.add_points(&acc, &q),
+            (false, false) => {}
+        }
+    }
+    if acc.z.is_zero() {
+        return false;
+    }
+    n_mod.reduce(&field.affine_x(&acc)) == *r
+}
+
+#[test_case]
+fn test_verify_rfc6979() {
+    // RFC 6979 A.2.5 and A.2.6: the key pairs and the signatures of "sample"
+    let cases = [
+        (
+            Curve::P256,
+            HashAlg::Sha256,
+            "0460fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6\
+             7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299",
+            "3046022100efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716\
+             022100f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8",
+        ),
+        (
+            Curve::P384,
+            HashAlg::Sha384,
+            "04ec3a4e415b4e19a4568618029f427fa5da9a8bc4ae92e02e06aae5286b300c64def8f0ea9055866064a254515480bc13\
+             8015d9b72d7d57244ea8ef9ac0c621896708a59367f9dfb9f54ca84b3f1c9db1288b231c3ae0d4fe7344fd2533264720",
+            "306602310094edbb92a5ecb8aad4736e56c691916b3f88140666ce9fa73d64c4ea95ad133c81a648152e44acf96e36dd1e80fabe46\
+             02310099ef4aeb15f178cea1fe40db2603138f130e740a19624526203b6351d0a3a94fa329c145786e679e7b82c71a38628ac8",
+        ),
+    ];
+    for &(curve, alg, key, sig) in &cases {
+        let (key, mut sig) = (unhex(key).unwrap(), unhex(sig).unwrap());
+        assert!(verify(curve, &key, alg, b"sample", &sig));
+        let last = sig.len() - 1;
+        sig[last] ^= 1;
+        assert!(!verify(curve, &key, alg, b"sample", &sig));
+    }
+}
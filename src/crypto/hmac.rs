@@ -0,0 +1,79 @@
+//! HMAC (RFC 2104) and HKDF (RFC 5869) over the SHA-2 hashes.
+
+use alloc::vec::Vec;
+use super::sha2::{HashAlg, Hasher};
+
+/// Incremental HMAC
+pub struct Hmac {
+    inner: Hasher,
+    outer: Hasher,
+}
+
+impl Hmac {
+    pub fn new(alg: HashAlg, key: &[u8]) -> Hmac {
+        let mut block = alloc::vec![0u8; alg.block_len()];
+        if key.len() > block.len() {
+            let digest = alg.digest(key);
+            block[..digest.len()].copy_from_slice(&digest);
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut inner = alg.hasher();
+        let mut outer = alg.hasher();
+        inner.update(&block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+        outer.update(&block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+        Hmac { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finish());
+        outer.finish()
+    }
+}
+
+pub fn hmac(alg: HashAlg, key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::new(alg, key);
+    mac.update(data);
+    mac.finish()
+}
+
+pub fn hkdf_extract(alg: HashAlg, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+    hmac(alg, salt, ikm)
+}
+
+pub fn hkdf_expand(alg: HashAlg, prk: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut previous = Vec::new();
+    let mut counter = 1u8;
+    while out.len() < len {
+        let mut mac = Hmac::new(alg, prk);
+        mac.update(&previous);
+        mac.update(info);
+        mac.update(&[counter]);
+        previous = mac.finish();
+        out.extend_from_slice(&previous);
+        counter += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+#[test_case]
+fn test_hkdf() {
+    use super::hex;
+    // RFC 5869 test case 1
+    let ikm = [0x0bu8; 22];
+    let salt: Vec<u8> = (0..13).collect();
+    let info: Vec<u8> = (0xf0..=0xf9).collect();
+    let prk = hkdf_extract(HashAlg::Sha256, &salt, &ikm);
+    assert_eq!(hex(&prk), "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5");
+    assert_eq!(
+        hex(&hkdf_expand(HashAlg::Sha256, &prk, &info, 42)),
+        "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"
+    );
+}
@@ -0,0 +1,54 @@
+//! The cryptographic primitives the TLS client needs: hashes, MACs, AEAD
+//! ciphers, X25519 key exchange and RSA/ECDSA signature checks, plus a
+//! random number generator.
+//!
+//! Everything here is written for correctness against the published test
+//! vectors rather than speed. The symmetric code and X25519 avoid
+//! secret-dependent branches; the signature checks only handle public data.
+
+pub mod aes;
+pub mod bignum;
+pub mod chacha20;
+pub mod ecdsa;
+pub mod hmac;
+pub mod rng;
+pub mod rsa;
+pub mod sha2;
+pub mod x25519;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Compare two byte strings without stopping at the first difference
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Lowercase hex, as used for fingerprints
+pub fn hex(bytes: &[u8]) -> String {
+    use core::fmt::Write;
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02x}", b).ok();
+    }
+    s
+}
+
+/// Parse hex digits, ignoring `:` separators. None on anything else.
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = s.bytes().filter(|&b| b != b':').collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let high = (pair[0] as char).to_digit(16)?;
+            let low = (pair[1] as char).to_digit(16)?;
+            Some((high * 16 + low) as u8)
+        })
+        .collect()
+}
@@ -0,0 +1,76 @@
+//! Random bytes for keys and nonces.
+//!
+//! A ChaCha20 generator seeded by hashing whatever entropy the machine
+//! offers: RDRAND when the CPU has it, the RTC, the PIT tick count and the
+//! jitter between TSC reads around port I/O. Every request also mixes in the
+//! current TSC, and the key is replaced after each use so earlier output
+//! can't be recovered from the state.
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::random::RdRand;
+use super::chacha20;
+use super::sha2::Sha256;
+use crate::interrupts::rdtsc;
+
+/// TSC samples taken for the initial seed
+const JITTER_SAMPLES: usize = 256;
+
+struct Generator {
+    key: [u8; 32],
+    counter: u64,
+}
+
+static RNG: Mutex<Option<Generator>> = Mutex::new(None);
+
+fn seed() -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    if let Some(rdrand) = RdRand::new() {
+        for _ in 0..8 {
+            if let Some(value) = rdrand.get_u64() {
+                hasher.update(&value.to_le_bytes());
+            }
+        }
+    }
+    let now = crate::time::get_time();
+    hasher.update(&crate::time::to_unix(&now).to_le_bytes());
+    hasher.update(&crate::task::timer::uptime_ms().to_le_bytes());
+    // Port I/O takes a slightly different number of cycles each time
+    let mut delay_port = Port::<u8>::new(0x80);
+    let mut previous = rdtsc();
+    for _ in 0..JITTER_SAMPLES {
+        unsafe { delay_port.write(0) };
+        let now = rdtsc();
+        hasher.update(&now.wrapping_sub(previous).to_le_bytes());
+        previous = now;
+    }
+    hasher.finish()
+}
+
+/// Fill `buf` with random bytes
+pub fn fill(buf: &mut [u8]) {
+    let mut rng = RNG.lock();
+    let state = rng.get_or_insert_with(|| Generator { key: seed(), counter: 0 });
+
+    let mut hasher = Sha256::new();
+    hasher.update(&state.key);
+    hasher.update(&rdtsc().to_le_bytes());
+    let key = hasher.finish();
+
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&state.counter.to_le_bytes());
+    state.counter += 1;
+    buf.iter_mut().for_each(|b| *b = 0);
+    chacha20::apply_keystream(&key, 1, &nonce, buf);
+    state.key.copy_from_slice(&chacha20::block(&key, 0, &nonce)[..32]);
+}
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut out = [0u8; N];
+    fill(&mut out);
+    out
+}
+
+pub fn random_u64() -> u64 {
+    u64::from_le_bytes(random_bytes())
+}
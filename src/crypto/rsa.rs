@@ -0,0 +1,156 @@
+//! RSA signature verification: PKCS#1 v1.5 and PSS (RFC 8017).
+
+use alloc::vec::Vec;
+use super::bignum::{BigUint, Modulus};
+use super::sha2::HashAlg;
+
+pub struct RsaPublicKey {
+    pub n: BigUint,
+    pub e: BigUint,
+}
+
+/// DER prefixes of the DigestInfo structure for each hash
+fn digest_info_prefix(alg: HashAlg) -> &'static [u8] {
+    match alg {
+        HashAlg::Sha256 => &[
+            0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00,
+            0x04, 0x20,
+        ],
+        HashAlg::Sha384 => &[
+            0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02, 0x05, 0x00,
+            0x04, 0x30,
+        ],
+        HashAlg::Sha512 => &[
+            0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03, 0x05, 0x00,
+            0x04, 0x40,
+        ],
+    }
+}
+
+impl RsaPublicKey {
+    /// Bytes in the modulus, which is also the signature length
+    pub fn size(&self) -> usize {
+        self.n.bits().div_ceil(8)
+    }
+
+    /// s^e mod n as `size()` bytes, or None if the signature is malformed
+    fn encoded_message(&self, signature: &[u8]) -> Option<Vec<u8>> {
+        let modulus = Modulus::new(&self.n)?;
+        if signature.len() != self.size() || self.n.bits() < 1024 {
+            return None;
+        }
+        let s = BigUint::from_be_bytes(signature);
+        if s >= self.n {
+            return None;
+        }
+        Some(modulus.pow(&s, &self.e).to_be_bytes(self.size()))
+    }
+
+    /// RSASSA-PKCS1-v1_5 over `message`
+    pub fn verify_pkcs1(&self, alg: HashAlg, message: &[u8], signature: &[u8]) -> bool {
+        let em = match self.encoded_message(signature) {
+            Some(em) => em,
+            None => return false,
+        };
+        let mut expected = alloc::vec![0xffu8; em.len()];
+        let prefix = digest_info_prefix(alg);
+        let digest = alg.digest(message);
+        let tail = prefix.len() + digest.len();
+        if em.len() < tail + 11 {
+            return false;
+        }
+        expected[0] = 0;
+        expected[1] = 1;
+        let start = em.len() - tail;
+        expected[start - 1] = 0;
+        expected[start..start + prefix.len()].copy_from_slice(prefix);
+        expected[start + prefix.len()..].copy_from_slice(&digest);
+        em == expected
+    }
+
+    /// RSASSA-PSS with MGF1 over the same hash and any salt length
+    pub fn verify_pss(&self, alg: HashAlg, message: &[u8], signature: &[u8]) -> bool {
+        let em_bits = self.n.bits() - 1;
+        let mut em = match self.encoded_message(signature) {
+            Some(em) => em,
+            None => return false,
+        };
+        // When the modulus is a whole number of bytes the encoding is one shorter
+        let em_len = em_bits.div_ceil(8);
+        if em.len() > em_len {
+            if em[0] != 0 {
+                return false;
+            }
+            em.remove(0);
+        }
+        let h_len = alg.output_len();
+        if em_len < h_len + 2 || em[em_len - 1] != 0xbc {
+            return false;
+        }
+        let (masked_db, rest) = em.split_at_mut(em_len - h_len - 1);
+        let h = &rest[..h_len];
+        let top_bits = 8 * em_len - em_bits;
+        if top_bits > 0 && masked_db[0] >> (8 - top_bits) != 0 {
+            return false;
+        }
+        let mask = mgf1(alg, h, masked_db.len());
+        for (b, m) in masked_db.iter_mut().zip(mask) {
+            *b ^= m;
+        }
+        masked_db[0] &= 0xff >> top_bits;
+        let db = masked_db;
+        let separator = match db.iter().position(|&b| b != 0) {
+            Some(i) if db[i] == 1 => i,
+            _ => return false,
+        };
+        let salt = &db[separator + 1..];
+
+        let mut hasher = alg.hasher();
+        hasher.update(&[0u8; 8]);
+        hasher.update(&alg.digest(message));
+        hasher.update(salt);
+        super::ct_eq(&hasher.finish(), h)
+    }
+}
+
+fn mgf1(alg: HashAlg, seed: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len + alg.output_len());
+    let mut counter = 0u32;
+    while out.len() < len {
+        let mut hasher = alg.hasher();
+        hasher.update(seed);
+        hasher.update(&counter.to_be_bytes());
+        out.extend_from_slice(&hasher.finish());
+        counter += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+#[test_case]
+fn test_verify_rsa() {
+    use super::unhex;
+    // A 1024-bit key with e = 65537, and "sample" signed with SHA-256 by
+    // OpenSSL, once with PKCS#1 v1.5 and once with PSS and a 32-byte salt.
+    // "sampld" differs from "sample" in one bit.
+    let key = RsaPublicKey {
+        n: BigUint::from_be_bytes(&unhex(
+            "add7131c39b4a2b95012ccd4d0484199b159494d5d488b94a5283be03af3adefc40ebbc648de7d2ef0c895af66ce50728d4197e88f5cd403a580246f1096d8b8\
+             15b954eea82249a1dc01406a8678be8c206ae07d07ecaa85371455706a88e1e72b10d82d9bb626bfb7ff84f9e9b136d65057b1193a75f1a2f9628c5bd0349b39",
+        ).unwrap()),
+        e: BigUint::from_u64(65537),
+    };
+    let pkcs1 = unhex(
+        "2d294d05e43f3be37191fedf022daea46f64da00bce7bfe13176a410b7f993338cb184516409d0f7c4ea3823adfa6d93378bbab5df3adef7c5511ededb3fde92\
+         34d042582ab45ad65a8484c33e827099d10182939dcc8865807929875deeea1e9031dc8335850539e90fd8462fc793ad788a83b1f9d684ada4771f1754ac444b",
+    ).unwrap();
+    let pss = unhex(
+        "7398f29c14ca889bd17d1d47888c31449e548b43a8767bf7a3b4d1d66bb9dae9019e7d1f122a77ad51aa9720273f054b0eb6f3dfb4bc11917c38b08cf322bb5e\
+         9a6e1991f4b29887758a616b313e32a0c56e72df238d9937bd6aa6957be422878546efd1c6700374ab220b8e106567e76fd896fa3162d496446a137941d64389",
+    ).unwrap();
+
+    assert!(key.verify_pkcs1(HashAlg::Sha256, b"sample", &pkcs1));
+    assert!(!key.verify_pkcs1(HashAlg::Sha256, b"sampld", &pkcs1));
+    assert!(key.verify_pss(HashAlg::Sha256, b"sample", &pss));
+    assert!(!key.verify_pss(HashAlg::Sha256, b"sampld", &pss));
+}
@@ -0,0 +1,209 @@
+//! X25519 Diffie-Hellman (RFC 7748), the only key exchange the TLS client
+//! offers.
+
+/// An element of GF(2^255 - 19) in five 51-bit limbs
+#[derive(Clone, Copy)]
+struct Fe([u64; 5]);
+
+const MASK51: u64 = (1 << 51) - 1;
+
+impl Fe {
+    const ZERO: Fe = Fe([0; 5]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    fn from_bytes(bytes: &[u8; 32]) -> Fe {
+        let load = |i: usize| {
+            let mut w = [0u8; 8];
+            w.copy_from_slice(&bytes[i..i + 8]);
+            u64::from_le_bytes(w)
+        };
+        Fe([
+            load(0) & MASK51,
+            (load(6) >> 3) & MASK51,
+            (load(12) >> 6) & MASK51,
+            (load(19) >> 1) & MASK51,
+            (load(24) >> 12) & MASK51,
+        ])
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut t = self.carry().carry().0;
+        // Subtract p if t >= p: compute t + 19 and look at bit 255
+        let mut q = (t[0] + 19) >> 51;
+        for limb in &t[1..] {
+            q = (limb + q) >> 51;
+        }
+        t[0] += 19 * q;
+        for i in 0..4 {
+            t[i + 1] += t[i] >> 51;
+            t[i] &= MASK51;
+        }
+        t[4] &= MASK51;
+
+        let mut out = [0u8; 32];
+        let words = [
+            t[0] | (t[1] << 51),
+            (t[1] >> 13) | (t[2] << 38),
+            (t[2] >> 26) | (t[3] << 25),
+            (t[3] >> 39) | (t[4] << 12),
+        ];
+        for (i, w) in words.iter().enumerate() {
+            out[8 * i..8 * i + 8].copy_from_slice(&w.to_le_bytes());
+        }
+        out
+    }
+
+    fn carry(self) -> Fe {
+        let mut t = self.0;
+        for i in 0..4 {
+            t[i + 1] += t[i] >> 51;
+            t[i] &= MASK51;
+        }
+        t[0] += 19 * (t[4] >> 51);
+        t[4] &= MASK51;
+        Fe(t)
+    }
+
+    fn add(self, other: Fe) -> Fe {
+        let mut t = self.0;
+        for (t, o) in t.iter_mut().zip(other.0) {
+            *t += o;
+        }
+        Fe(t)
+    }
+
+    fn sub(self, other: Fe) -> Fe {
+        // Add 2p first so no limb goes negative
+        const TWO_P: [u64; 5] = [0xFFFFFFFFFFFDA, 0xFFFFFFFFFFFFE, 0xFFFFFFFFFFFFE, 0xFFFFFFFFFFFFE, 0xFFFFFFFFFFFFE];
+        let mut t = self.0;
+        for i in 0..5 {
+            t[i] = t[i] + TWO_P[i] - other.0[i];
+        }
+        Fe(t).carry()
+    }
+
+    fn mul(self, other: Fe) -> Fe {
+        let a = self.0.map(|x| x as u128);
+        let b = other.0.map(|x| x as u128);
+        let b19 = b.map(|x| x * 19);
+        let r = [
+            a[0] * b[0] + a[1] * b19[4] + a[2] * b19[3] + a[3] * b19[2] + a[4] * b19[1],
+            a[0] * b[1] + a[1] * b[0] + a[2] * b19[4] + a[3] * b19[3] + a[4] * b19[2],
+            a[0] * b[2] + a[1] * b[1] + a[2] * b[0] + a[3] * b19[4] + a[4] * b19[3],
+            a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + a[4] * b19[4],
+            a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
+        ];
+        let mut out = [0u64; 5];
+        let mut carry = 0u128;
+        for i in 0..5 {
+            let v = r[i] + carry;
+            out[i] = v as u64 & MASK51;
+            carry = v >> 51;
+        }
+        out[0] += carry as u64 * 19;
+        Fe(out).carry()
+    }
+
+    fn square(self) -> Fe {
+        self.mul(self)
+    }
+
+    fn mul_small(self, k: u64) -> Fe {
+        let mut out = [0u64; 5];
+        let mut carry = 0u128;
+        for (o, &limb) in out.iter_mut().zip(&self.0) {
+            let v = limb as u128 * k as u128 + carry;
+            *o = v as u64 & MASK51;
+            carry = v >> 51;
+        }
+        out[0] += carry as u64 * 19;
+        Fe(out).carry()
+    }
+
+    /// self^(p-2), the inverse
+    fn invert(self) -> Fe {
+        // p - 2 = 2^255 - 21; square-and-multiply over its bits
+        let mut result = Fe::ONE;
+        for bit in (0..255).rev() {
+            result = result.square();
+            // Every bit is set except bits 2 and 4
+            let set = bit != 2 && bit != 4;
+            if set {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+
+    /// Swap a and b when `swap` is 1, without branching
+    fn cswap(a: &mut Fe, b: &mut Fe, swap: u64) {
+        let mask = 0u64.wrapping_sub(swap);
+        for i in 0..5 {
+            let t = mask & (a.0[i] ^ b.0[i]);
+            a.0[i] ^= t;
+            b.0[i] ^= t;
+        }
+    }
+}
+
+/// The Montgomery ladder: scalar * u
+pub fn x25519(scalar: &[u8; 32], u: &[u8; 32]) -> [u8; 32] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+    let mut u_bytes = *u;
+    u_bytes[31] &= 127;
+
+    let x1 = Fe::from_bytes(&u_bytes);
+    let mut x2 = Fe::ONE;
+    let mut z2 = Fe::ZERO;
+    let mut x3 = x1;
+    let mut z3 = Fe::ONE;
+    let mut swap = 0u64;
+    for t in (0..255).rev() {
+        let bit = ((k[t / 8] >> (t % 8)) & 1) as u64;
+        swap ^= bit;
+        Fe::cswap(&mut x2, &mut x3, swap);
+        Fe::cswap(&mut z2, &mut z3, swap);
+        swap = bit;
+
+        let a = x2.add(z2);
+        let aa = a.square();
+        let b = x2.sub(z2);
+        let bb = b.square();
+        let e = aa.sub(bb);
+        let c = x3.add(z3);
+        let d = x3.sub(z3);
+        let da = d.mul(a);
+        let cb = c.mul(b);
+        x3 = da.add(cb).square();
+        z3 = x1.mul(da.sub(cb).square());
+        x2 = aa.mul(bb);
+        z2 = e.mul(aa.add(e.mul_small(121665)));
+    }
+    Fe::cswap(&mut x2, &mut x3, swap);
+    Fe::cswap(&mut z2, &mut z3, swap);
+    x2.mul(z2.invert()).to_bytes()
+}
+
+/// The public key for a private scalar
+pub fn public_key(private: &[u8; 32]) -> [u8; 32] {
+    let mut base = [0u8; 32];
+    base[0] = 9;
+    x25519(private, &base)
+}
+
+#[test_case]
+fn test_x25519() {
+    use super::{hex, unhex};
+    // RFC 7748 section 6.1
+    let mut alice = [0u8; 32];
+    alice.copy_from_slice(&unhex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a").unwrap());
+    let mut bob = [0u8; 32];
+    bob.copy_from_slice(&unhex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb").unwrap());
+    assert_eq!(hex(&public_key(&alice)), "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+    let shared = x25519(&alice, &public_key(&bob));
+    assert_eq!(hex(&shared), "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+    assert_eq!(shared, x25519(&bob, &public_key(&alice)));
+}
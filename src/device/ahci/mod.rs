@@ -1,363 +1,320 @@
-use core::ptr::{read_volatile, write_volatile};
-
-use x86_64::{structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB}, VirtAddr};
-
-use crate::{serial_print, serial_println};
-
-use super::pci_config_read;
-
-// pub const AHCI_CONTROLLER_DRIVE_COUNT: usize = 6;
-// pub const AHCI_CONTROLLER_MEMORY_PER_DRIVE: usize = 0x1000;
-// const ADDITIONAL_SPACE: usize = 0x1000; // Example additional space
-// pub const AHCI_MEMORY_SIZE: usize = AHCI_CONTROLLER_MEMORY_PER_DRIVE * AHCI_CONTROLLER_MEMORY_PER_DRIVE;
-
-pub const AHCI_CONTROLLER_DRIVE_COUNT: usize = 6;
-pub const AHCI_CONTROLLER_MEMORY_PER_DRIVE: usize = 4096;
-pub const AHCI_MEMORY_SIZE: usize = AHCI_CONTROLLER_DRIVE_COUNT * AHCI_CONTROLLER_MEMORY_PER_DRIVE; // Adjusted total size for AHCI controller
-
-const PORT_COMMAND_REGISTER_OFFSET: u32 = 0x04; // Command register offset
-const PORT_DETECTED_MASK: u32 = 0x1; // Mask for detecting a device
-
-const _AHCI_CTRL_OFFSET: u32 = 0x00; // Controller offset
-const _AHCI_IS_OFFSET: u32 = 0x08; // Interrupt Status
-const _AHCI_PI_OFFSET: u32 = 0x0C; // Port Implementation
-
-const _AHCI_PI_MASK: u32 = 0x0000003F; // Port Implemented Mask
-
-const _DELAY_COUNT: u64 = 100_000_000;
-
-const _AHCI_CAP_OFFSET: u64 = 0x00; // Capability register offset
-const _AHCI_GHC_OFFSET: u64 = 0x04; // Global Host Control register offset
-const AHCI_GHC_AE: u32 = 1 << 31; // AHCI Enable bit
-const AHCI_GHC_HR: u32 = 0x01; // Host Reset bit
+//! AHCI SATA host controller.
+//!
+//! The controller is driven without interrupts: each disk issues one command
+//! at a time from slot 0 and polls for completion, which is all the sector
+//! cache in front of it needs.
 
-const PORT_REG_BASE: u64 = 0x1000; // Base offset for port registers
-const PORT_SIG_OFFSET: u32 = 0xA0; // Signature register offset
-const PORT_CMD_OFFSET: u32 = 0x08; // Command register offset
-const PORT_CMD_ST: u32 = 0x0001; // Start bit in command register
-const PORT_SIG_SATA: u32 = 0x00000101; // SATA signature (adjust as needed)
+mod port;
 
-const ATA_FLAG_SATA: u32 = 1 << 1;
-const ATA_FLAG_PIO_DMA: u32 = 1 << 7;
-const ATA_FLAG_ACPI_SATA: u32 = 1 << 17;
-const ATA_FLAG_AN: u32 = 1 << 18;
+use core::ptr::{read_volatile, write_volatile};
 
-const AHCI_FLAG_COMMON: u32 = ATA_FLAG_SATA | ATA_FLAG_PIO_DMA | ATA_FLAG_ACPI_SATA | ATA_FLAG_AN;
+use crate::fs::block::BlockDevice;
+use crate::serial_println;
+use crate::task::timer;
+
+use super::virtio_hal::get_phys_mem_offset;
+use super::{pci_config_read, pci_config_write};
+
+pub use port::AhciDisk;
+
+/// ABAR bytes covering the global registers and all 32 port register sets
+pub const AHCI_MEMORY_SIZE: usize = 0x1100;
+
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0C;
+const HBA_VS: u64 = 0x10;
+
+const CAP_S64A: u32 = 1 << 31;
+const GHC_HR: u32 = 1 << 0;
+const GHC_AE: u32 = 1 << 31;
+
+const PORT_BASE: u64 = 0x100;
+const PORT_STRIDE: u64 = 0x80;
+
+// Port registers, relative to the port's base
+const PX_CLB: u64 = 0x00;
+const PX_CLBU: u64 = 0x04;
+const PX_FB: u64 = 0x08;
+const PX_FBU: u64 = 0x0C;
+const PX_IS: u64 = 0x10;
+const PX_IE: u64 = 0x14;
+const PX_CMD: u64 = 0x18;
+const PX_TFD: u64 = 0x20;
+const PX_SIG: u64 = 0x24;
+const PX_SSTS: u64 = 0x28;
+const PX_SERR: u64 = 0x30;
+const PX_CI: u64 = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_POD: u32 = 1 << 2;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const SSTS_DET_PRESENT: u32 = 0x3;
+const TFD_BSY: u32 = 1 << 7;
+const SSTS_IPM_ACTIVE: u32 = 0x1;
+
+const SIG_ATA: u32 = 0x0000_0101;
+const SIG_ATAPI: u32 = 0xEB14_0101;
+const SIG_SEMB: u32 = 0xC33C_0101;
+const SIG_PM: u32 = 0x9669_0101;
+
+const PCI_COMMAND: u8 = 0x04;
+const PCI_COMMAND_MEMORY: u32 = 1 << 1;
+const PCI_COMMAND_BUS_MASTER: u32 = 1 << 2;
+const PCI_VENDOR_INTEL: u32 = 0x8086;
+/// Intel's port control and status register is the upper half of this
+/// config dword, at 0x92
+const PCI_INTEL_MAP_PCS: u8 = 0x90;
+
+/// Polls allowed for the controller or a port to change state
+const SPIN_LIMIT: u32 = 10_000_000;
+/// How long a port gets to bring its link back up after a reset
+const LINK_TIMEOUT_MS: u64 = 200;
+/// How long a disk with a link gets to finish spinning up and report in
+const BUSY_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AhciError {
+    DeviceNotFound,
+    NoDisk,
+    InvalidSignature(u32),
+    Timeout,
+    /// The device failed a command; holds the port's task file register
+    TaskFile(u32),
+    /// DMA memory above 4 GiB on a controller without 64-bit addressing
+    AddressTooHigh,
+    Unsupported(&'static str),
+}
 
-const ATA_PIO4: u32 = 1 << 4;
-const ATA_UDMA6: u32 = 1 << 6;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    Empty,
+    Sata,
+    Satapi,
+    EnclosureBridge,
+    PortMultiplier,
+    Unknown(u32),
+}
 
-const AHCI_HFLAG_INTEL_PCS_QUIRK: u32 = 1 << 28;
+impl DeviceKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceKind::Empty => "empty",
+            DeviceKind::Sata => "SATA disk",
+            DeviceKind::Satapi => "SATAPI device",
+            DeviceKind::EnclosureBridge => "enclosure bridge",
+            DeviceKind::PortMultiplier => "port multiplier",
+            DeviceKind::Unknown(_) => "unknown device",
+        }
+    }
+}
 
-#[derive(Debug)]
 pub struct AhciController {
-    base_address: u64,
-    _hflags: u32,
-    flags: u32,
-    pio_mask: u32,
-    udma_mask: u32,
+    base_virt: u64,
+    cap: u32,
+    ports_implemented: u32,
 }
 
 impl AhciController {
-    pub fn new(base_address: u64, _hflags: u32, flags: u32, pio_mask: u32, udma_mask: u32) -> Self {
-        Self {
-            base_address,
-            _hflags,
-            flags,
-            pio_mask,
-            udma_mask,
-        }
+    /// Attach to the controller whose ABAR is at physical address `abar`.
+    /// Nothing is written, so this is safe while a disk on it is in use.
+    pub fn new(abar: u64) -> Self {
+        let mut hba = AhciController { base_virt: abar + get_phys_mem_offset(), cap: 0, ports_implemented: 0 };
+        hba.cap = hba.read(HBA_CAP);
+        hba.ports_implemented = hba.read(HBA_PI);
+        hba
     }
 
-    pub fn initialize(&self) -> Result<(), AhciError> {
-        // Read the CAP register
-        let cap = ahci_register_read(self.base_address, 0x00)?;
-        serial_println!("CAP register: 0x{:08X}", cap);
-
-        // Read the Global Host Control register
-        let mut ghc = ahci_register_read(self.base_address, 0x04)?;
-        serial_println!("GHC before reset: 0x{:08X}", ghc);
-
-        // Set the AHCI Enable bit and reset the controller
-        ghc |= AHCI_GHC_AE | AHCI_GHC_HR; // AHCI Enable and Host Reset bits
-        ahci_register_write(self.base_address, 0x04, ghc)?;
-
-        // Wait for the reset bit to clear
-        serial_println!("Waiting for reset...");
-        let mut attempts = 0;
-        const MAX_ATTEMPTS: u32 = 10000; // Maximum number of attempts
-
-        while attempts < MAX_ATTEMPTS {
-            ghc = ahci_register_read(self.base_address, 0x04)?;
-            if (ghc & AHCI_GHC_AE) == 0 {
-                serial_println!("Reset completed.");
-                break;
-            }
-            busy_wait(10000); // Delay between checks
-            attempts += 1;
+    /// Reset the controller and put it in AHCI mode. Any running port stops.
+    pub fn reset(&mut self) -> Result<(), AhciError> {
+        self.write(HBA_GHC, GHC_AE);
+        self.write(HBA_GHC, GHC_AE | GHC_HR);
+        spin_until(|| self.read(HBA_GHC) & GHC_HR == 0)?;
+        // The reset clears AE on controllers that also support legacy mode
+        self.write(HBA_GHC, GHC_AE);
+        self.write(HBA_IS, !0);
+
+        self.cap = self.read(HBA_CAP);
+        self.ports_implemented = self.read(HBA_PI);
+        // Spin up every port in case the controller staggers it
+        for port in self.ports() {
+            let cmd = self.port_read(port, PX_CMD);
+            self.port_write(port, PX_CMD, cmd | CMD_SUD | CMD_POD);
         }
-
-        if attempts == MAX_ATTEMPTS {
-            serial_println!("Reset did not complete.");
-        
-            serial_println!("GHC after failed reset: 0x{:08X}", ahci_register_read(self.base_address, 0x04)?);
-            
-            return Err(AhciError::RegisterReadError); // Reset did not complete
-        }
-            
-        serial_println!("GHC after reset: 0x{:08X}", ahci_register_read(self.base_address, 0x04)?);
-
         Ok(())
     }
 
-    fn apply_intel_pcs_quirk(&self) -> Result<(), AhciError> {
-        // Assuming the PCS register is at offset 0x92 in PCI configuration space
-        const PCS_REGISTER_OFFSET: u64 = 0x92;
-        let pcs = ahci_register_read(self.base_address, PCS_REGISTER_OFFSET)?;
-        
-        // Perform the necessary quirk adjustments
-        // This might involve setting or clearing certain bits in the PCS register
-        // For now, let's just print the value
-        serial_println!("PCS register: 0x{:08X}", pcs);
-
-        // Write back the modified PCS register if necessary
-        // ahci_register_write(self.base_address, PCS_REGISTER_OFFSET, modified_pcs)?;
-
-        Ok(())
+    pub fn version(&self) -> (u16, u16) {
+        let vs = self.read(HBA_VS);
+        ((vs >> 16) as u16, vs as u16)
     }
-}
 
-#[derive(Debug)]
-pub enum AhciError {
-    PciReadError,
-    MemoryMappingError,
-    RegisterReadError,
-    DeviceNotFound,
-    InvalidSignature,
-    InvalidMemoryAddress,
-    PortInitializationFailed,
-    AeBitNotSet,
-    GhcBitNotSet,
-}
+    pub fn supports_64bit(&self) -> bool {
+        self.cap & CAP_S64A != 0
+    }
 
-pub fn get_ahci_base_address() -> Option<u64> {
-    let pci_base_address = 0xfebf1000; // Use the address from your `info pci` output
-    Some(pci_base_address)
-}
+    /// Ports wired up on this controller
+    pub fn ports(&self) -> impl Iterator<Item = usize> {
+        let pi = self.ports_implemented;
+        (0..32).filter(move |port| pi & (1 << port) != 0)
+    }
 
+    /// Wait for `port` to renegotiate its link after a reset and for the
+    /// device to clear BSY, after which its signature is valid. False if no
+    /// link came up.
+    pub fn wait_for_link(&self, port: usize) -> bool {
+        if wait_until(LINK_TIMEOUT_MS, || self.port_read(port, PX_SSTS) & 0xF == SSTS_DET_PRESENT).is_err() {
+            return false;
+        }
+        if wait_until(BUSY_TIMEOUT_MS, || self.port_read(port, PX_TFD) & TFD_BSY == 0).is_err() {
+            serial_println!("[ahci] port {}: device stayed busy", port);
+        }
+        true
+    }
 
+    pub fn device_kind(&self, port: usize) -> DeviceKind {
+        let ssts = self.port_read(port, PX_SSTS);
+        if ssts & 0xF != SSTS_DET_PRESENT || (ssts >> 8) & 0xF != SSTS_IPM_ACTIVE {
+            return DeviceKind::Empty;
+        }
+        match self.port_read(port, PX_SIG) {
+            SIG_ATA => DeviceKind::Sata,
+            SIG_ATAPI => DeviceKind::Satapi,
+            SIG_SEMB => DeviceKind::EnclosureBridge,
+            SIG_PM => DeviceKind::PortMultiplier,
+            sig => DeviceKind::Unknown(sig),
+        }
+    }
 
+    /// Negotiated link speed in units of 1.5 Gbps generations, 0 if no link
+    pub fn link_generation(&self, port: usize) -> u32 {
+        (self.port_read(port, PX_SSTS) >> 4) & 0xF
+    }
 
-// Scanning the PCI bus for AHCI controllers
-pub fn find_ahci_controller() -> Option<(u8, u8, u8, u64)> {
-    for bus in 0..255 {
-        for slot in 0..32 {
-            let vendor_device_id = pci_config_read(bus, slot, 0, 0);
-            if vendor_device_id != 0xFFFFFFFF {
-                let class_code = (pci_config_read(bus, slot, 0, 8) >> 24) as u8;
-                if class_code == 0x01 { // Mass Storage Controller
-                    let subclass = (pci_config_read(bus, slot, 0, 8) >> 16) as u8;
-                    if subclass == 0x06 { // SATA
-                        let prog_if = (pci_config_read(bus, slot, 0, 8) >> 8) as u8;
-                        if prog_if == 0x01 { // AHCI
-                            let bar5 = pci_config_read(bus, slot, 0, 0x24);
-                            return Some((bus, slot, 0, bar5 as u64 & 0xFFFFFFF0));
-                        }
-                    }
-                }
-            }
+    /// Start the command engine on `port` and identify the disk behind it
+    pub fn open(&self, port: usize) -> Result<AhciDisk, AhciError> {
+        match self.device_kind(port) {
+            DeviceKind::Sata => AhciDisk::open(self.port_base(port), port, self.supports_64bit()),
+            DeviceKind::Empty => Err(AhciError::NoDisk),
+            _ => Err(AhciError::InvalidSignature(self.port_read(port, PX_SIG))),
         }
     }
-    None
-}
 
-pub fn map_ahci_memory(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>, ahci_base: u64, size: usize) -> Result<(), MapToError<Size4KiB>> {
-    let start = VirtAddr::new(ahci_base);
-    let end = start + size as u64;
-    let start_page = Page::containing_address(start);
-    let end_page = Page::containing_address(end);
-
-    serial_println!("Mapping AHCI memory from 0x{:X} to 0x{:X}", start.as_u64(), end.as_u64());
-
-    for page in Page::range_inclusive(start_page, end_page) {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        serial_println!("Mapping virtual page 0x{:X} to physical frame 0x{:X}", page.start_address().as_u64(), frame.start_address().as_u64());
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    fn port_base(&self, port: usize) -> u64 {
+        self.base_virt + PORT_BASE + port as u64 * PORT_STRIDE
     }
 
-    Ok(())
-}
+    fn port_read(&self, port: usize, offset: u64) -> u32 {
+        unsafe { read_volatile((self.port_base(port) + offset) as *const u32) }
+    }
 
-fn interpret_port_signature(sig: u32) -> &'static str {
-    match sig {
-        0x00000101 => "SATA Device",
-        0xEB140101 => "ATAPI Device",
-        0x00008000 => "SCSI Device",
-        _ => "Unknown or Non-SATA Device",
+    fn port_write(&self, port: usize, offset: u64, value: u32) {
+        unsafe { write_volatile((self.port_base(port) + offset) as *mut u32, value) }
     }
-}
 
-pub fn read_ahci_memory(base_address: u64, size: usize) {
-    let mut addr = base_address;
-    let end = base_address + size as u64;
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { read_volatile((self.base_virt + offset) as *const u32) }
+    }
 
-    while addr < end {
-        while addr < end {
-            unsafe {
-                let value = read_volatile(addr as *const u8);
-                // Print address and value
-                serial_print!("0x{:X}: 0x{:08X} ", addr, value);
-            }
-            
-            addr += 4;
-    
-            // Print a new line every 16 bytes for better readability
-            if (addr - base_address) % 16 == 0 {
-                let _ = serial_println!("");
-            }
-        }
+    fn write(&self, offset: u64, value: u32) {
+        unsafe { write_volatile((self.base_virt + offset) as *mut u32, value) }
     }
 }
 
-fn read_port_register(base_address: u64, port: usize, offset: u32) -> Result<u32, AhciError> {
-    // Calculate the address of the port register
-    let register_address = base_address + (port as u64 * 0x1000) + offset as u64;
-    
-    // Debug print to verify address calculation
-    serial_println!("Reading port register at address: 0x{:X}", register_address);
-    
-    // Ensure the address is within a reasonable range
-    if register_address < 0x100000000 && register_address >= base_address {
-        unsafe {
-            let reg_ptr = register_address as *const u32;
-            let value = read_volatile(reg_ptr);
-            // Debug print to verify value read
-            serial_println!("Read value: 0x{:X}", value);
-            Ok(value)
+fn spin_until(mut done: impl FnMut() -> bool) -> Result<(), AhciError> {
+    for _ in 0..SPIN_LIMIT {
+        if done() {
+            return Ok(());
         }
-    } else {
-        Err(AhciError::InvalidMemoryAddress)
+        core::hint::spin_loop();
     }
+    Err(AhciError::Timeout)
 }
 
-fn is_device_present(base_address: u64, port: usize) -> Result<bool, AhciError> {
-    let port_command = read_port_register(base_address, port, PORT_COMMAND_REGISTER_OFFSET)?;
-
-    serial_println!("port command for port {} is {}", port, port_command);
-
-    Ok((port_command & PORT_DETECTED_MASK) != 0)
+/// Like `spin_until`, but also gives up after `ms` milliseconds of uptime
+fn wait_until(ms: u64, mut done: impl FnMut() -> bool) -> Result<(), AhciError> {
+    let deadline = timer::uptime_ms() + ms;
+    spin_until(|| done() || timer::uptime_ms() >= deadline)?;
+    if done() { Ok(()) } else { Err(AhciError::Timeout) }
 }
 
-fn ahci_register_read(base_address: u64, offset: u64) -> Result<u32, AhciError> {
-    unsafe {
-        let reg_ptr = (base_address + offset as u64) as *const u32;
-        if reg_ptr.is_null() {
-            return Err(AhciError::InvalidMemoryAddress);
+/// Scan the PCI bus for an AHCI controller: (bus, slot, function, ABAR)
+pub fn find_ahci_controller() -> Option<(u8, u8, u8, u64)> {
+    for bus in 0..255 {
+        for slot in 0..32 {
+            for function in 0..8 {
+                if pci_config_read(bus, slot, function, 0) == 0xFFFFFFFF {
+                    continue;
+                }
+                let class = pci_config_read(bus, slot, function, 8);
+                // Mass storage, SATA, AHCI 1.0 programming interface
+                if class >> 8 == 0x01_06_01 {
+                    let bar5 = pci_config_read(bus, slot, function, 0x24);
+                    return Some((bus, slot, function, bar5 as u64 & 0xFFFFFFF0));
+                }
+            }
         }
-        let value = read_volatile(reg_ptr);
-        Ok(value)
     }
+    None
 }
 
-fn ahci_register_write(base_address: u64, offset: u64, value: u32) -> Result<(), AhciError> {
-    unsafe {
-        let reg_ptr = (base_address + offset) as *mut u32;
-        if reg_ptr.is_null() {
-            return Err(AhciError::InvalidMemoryAddress);
-        }
-        write_volatile(reg_ptr, value);
-        Ok(())
-    }
+/// Let the controller decode its ABAR and master the bus for DMA
+pub fn enable_controller(bus: u8, slot: u8, function: u8) {
+    let command = pci_config_read(bus, slot, function, PCI_COMMAND);
+    pci_config_write(bus, slot, function, PCI_COMMAND, command | PCI_COMMAND_MEMORY | PCI_COMMAND_BUS_MASTER);
 }
 
-fn busy_wait(count: u64) {
-    let mut i = 0;
-    while i < count {
-        i += 1;
+/// Intel controllers only connect a port once its enable bit in PCS is set,
+/// which firmware doesn't always do for every port in PI
+pub fn apply_intel_pcs_quirk(bus: u8, slot: u8, function: u8, ports_implemented: u32) {
+    if pci_config_read(bus, slot, function, 0) & 0xFFFF != PCI_VENDOR_INTEL {
+        return;
+    }
+    let map_pcs = pci_config_read(bus, slot, function, PCI_INTEL_MAP_PCS);
+    let pcs = map_pcs >> 16;
+    let enabled = pcs | (ports_implemented & 0xFFFF);
+    if enabled != pcs {
+        pci_config_write(bus, slot, function, PCI_INTEL_MAP_PCS, (map_pcs & 0xFFFF) | (enabled << 16));
+        serial_println!("[ahci] Intel PCS {:#06x} -> {:#06x}", pcs, enabled);
     }
 }
 
-
-pub fn initialize_ahci_controller(base_address: u64) -> Result<AhciController, AhciError> {
-    let ahci_controller = AhciController::new(
-        base_address,
-        AHCI_HFLAG_INTEL_PCS_QUIRK, // Apply the quirk
-        AHCI_FLAG_COMMON,
-        ATA_PIO4,
-        ATA_UDMA6,
-    );
-
-    ahci_controller.initialize()?;
-
-    // busy_wait(100_000);
-
-    // let val = ahci_register_read(base_address, 0x04);
-
-    
-    // serial_println!("new val after init is {:#010x}", val.unwrap());
-
-    Ok(ahci_controller)
+/// Reset the first AHCI controller and open the first SATA disk on it
+pub fn open_first_disk() -> Result<AhciDisk, AhciError> {
+    let (bus, slot, function, abar) = find_ahci_controller().ok_or(AhciError::DeviceNotFound)?;
+    enable_controller(bus, slot, function);
+
+    let mut hba = AhciController::new(abar);
+    apply_intel_pcs_quirk(bus, slot, function, hba.ports_implemented);
+    hba.reset()?;
+    let (major, minor) = hba.version();
+    serial_println!("[ahci] controller {:02x}:{:02x}.{} AHCI {}.{}, ports {:#x}",
+        bus, slot, function, major, minor, hba.ports_implemented);
+
+    // Links take a while to come back after the reset
+    let port = hba
+        .ports()
+        .find(|&port| hba.wait_for_link(port) && hba.device_kind(port) == DeviceKind::Sata)
+        .ok_or(AhciError::NoDisk)?;
+    let disk = hba.open(port)?;
+    serial_println!("[ahci] port {}: {} ({} sectors)", port, disk.model(), disk.sector_count());
+    Ok(disk)
 }
 
-
-
-pub fn find_sata_devices(base_address: u64) {
-    const MAX_PORTS: usize = AHCI_CONTROLLER_DRIVE_COUNT; // Number of ports (adjust as needed)
-
-    for port in 0..MAX_PORTS {
-
-        match read_port_register(base_address, port, PORT_SIG_OFFSET) {
-            Ok(sig) => {
-                serial_println!("Sig for port {} is {}", port, sig);
-                serial_println!("Drive in port {} is {}", port, interpret_port_signature(sig));
-                if sig == PORT_SIG_SATA {        
-                    // Optional: Further interrogation to gather more details
-                    match read_port_register(base_address, port, PORT_CMD_OFFSET) {
-                        Ok(cmd) => {
-                            serial_println!("Port {} Command Register: 0x{:X}", port, cmd);
-                            match is_device_present(base_address, port) {
-                                Ok(present) => { serial_println!("Is device present at port {}? {}", port, present); }
-                                Err(e) => { serial_println!("Failed to get device presence at port {}: {:#?}", port, e); }
-                            }
-                        }
-                        Err(e) => { serial_println!("Failed to read port register for cmd {:#?}", e); }
-                    }
-                    
-                } else {
-                    serial_println!("No SATA device on port {}", port);
-                }
-            }
-            Err(e) => {
-                serial_println!("Failed to read port register {:#?}", e);
-            }
+/// Dump the controller's registers to serial as 32-bit words
+pub fn read_ahci_memory(abar: u64, size: usize) {
+    let base = abar + get_phys_mem_offset();
+    for offset in (0..size as u64).step_by(4) {
+        if offset % 16 == 0 {
+            crate::serial_print!("\n{:#06x}:", offset);
         }
+        let value = unsafe { read_volatile((base + offset) as *const u32) };
+        crate::serial_print!(" {:08x}", value);
     }
-}
-
-pub fn find_and_initialize_ahci_controller() -> Result<(), AhciError> {
-    let controller_info = find_ahci_controller().ok_or(AhciError::DeviceNotFound)?;
-    let (_, _, _, bar5) = controller_info;
-    let ahci_base = bar5 & 0xFFFFFFF0; // Ensure the base address is properly masked
-
-    // Map the AHCI memory
-    // map_ahci_memory(mapper, frame_allocator, ahci_base, AHCI_MEMORY_SIZE)?;
-
-    // Initialize the AHCI controller
-    let ahci_controller = AhciController::new(
-        ahci_base,
-        AHCI_HFLAG_INTEL_PCS_QUIRK, // Apply the quirk
-        AHCI_FLAG_COMMON,
-        ATA_PIO4,
-        ATA_UDMA6,
-    );
-
-    ahci_controller.initialize()?;
-
-    // Optionally, find SATA devices connected to the controller
-    find_sata_devices(ahci_base);
-
-    Ok(())
+    serial_println!();
 }
@@ -0,0 +1,285 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+use crate::device::virtio_hal::virt_to_phys_pub;
+use crate::fs::block::{check_range, BlockDevice, BlockError};
+use crate::serial_println;
+
+use super::{spin_until, AhciError, TFD_BSY};
+use super::{PX_CI, PX_CLB, PX_CLBU, PX_CMD, PX_FB, PX_FBU, PX_IE, PX_IS, PX_SERR, PX_TFD};
+use super::{CMD_CR, CMD_FR, CMD_FRE, CMD_POD, CMD_ST, CMD_SUD};
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const IS_TFES: u32 = 1 << 30;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// Register FIS length in dwords, as the command header wants it
+const FIS_H2D_DWORDS: u32 = 5;
+const HEADER_WRITE: u32 = 1 << 6;
+
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_DEVICE_LBA: u8 = 1 << 6;
+
+// Layout of the control page: the command list (only slot 0 is used), the
+// received FIS area and the command table with its PRDT
+const CMD_LIST: usize = 0x000;
+const RECV_FIS: usize = 0x400;
+const CMD_TABLE: usize = 0x500;
+const PRDT: usize = CMD_TABLE + 0x80;
+
+/// Most sectors one command moves, through the bounce buffer
+const MAX_SECTORS: usize = 128;
+const PAGE_SIZE: usize = 4096;
+const BOUNCE_PAGES: usize = MAX_SECTORS * 512 / PAGE_SIZE;
+
+#[repr(C, align(4096))]
+struct DmaPage([u8; PAGE_SIZE]);
+
+/// SATA disk on one AHCI port
+pub struct AhciDisk {
+    regs: u64,
+    port: usize,
+    sectors: u64,
+    model: String,
+    serial: String,
+    control: Box<DmaPage>,
+    /// Every page is mapped on its own, so each gets a PRDT entry
+    bounce: Box<[DmaPage]>,
+    control_phys: u64,
+    bounce_phys: Vec<u64>,
+}
+
+impl AhciDisk {
+    pub(super) fn open(regs: u64, port: usize, wide: bool) -> Result<Self, AhciError> {
+        let control = Box::new(DmaPage([0; PAGE_SIZE]));
+        let bounce: Box<[DmaPage]> = (0..BOUNCE_PAGES).map(|_| DmaPage([0; PAGE_SIZE])).collect();
+        let control_phys = virt_to_phys_pub(control.0.as_ptr() as u64);
+        let bounce_phys: Vec<u64> = bounce.iter().map(|page| virt_to_phys_pub(page.0.as_ptr() as u64)).collect();
+        if !wide && core::iter::once(&control_phys).chain(&bounce_phys).any(|&addr| addr >> 32 != 0) {
+            return Err(AhciError::AddressTooHigh);
+        }
+
+        let mut disk = AhciDisk {
+            regs, port, sectors: 0, model: String::new(), serial: String::new(),
+            control, bounce, control_phys, bounce_phys,
+        };
+        disk.stop()?;
+        let list = disk.control_phys + CMD_LIST as u64;
+        let fis = disk.control_phys + RECV_FIS as u64;
+        disk.write(PX_CLB, list as u32);
+        disk.write(PX_CLBU, (list >> 32) as u32);
+        disk.write(PX_FB, fis as u32);
+        disk.write(PX_FBU, (fis >> 32) as u32);
+        disk.write(PX_SERR, !0);
+        disk.write(PX_IS, !0);
+        disk.write(PX_IE, 0);
+        disk.start()?;
+        disk.identify()?;
+        Ok(disk)
+    }
+
+    pub fn port(&self) -> usize {
+        self.port
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    /// Stop the command engine and FIS receive, as required before the
+    /// command list or FIS addresses change
+    fn stop(&mut self) -> Result<(), AhciError> {
+        let cmd = self.read(PX_CMD) & !CMD_ST;
+        self.write(PX_CMD, cmd);
+        spin_until(|| self.read(PX_CMD) & CMD_CR == 0)?;
+        self.write(PX_CMD, cmd & !CMD_FRE);
+        spin_until(|| self.read(PX_CMD) & CMD_FR == 0)
+    }
+
+    fn start(&mut self) -> Result<(), AhciError> {
+        let cmd = self.read(PX_CMD) | CMD_SUD | CMD_POD | CMD_FRE;
+        self.write(PX_CMD, cmd);
+        spin_until(|| self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0)?;
+        self.write(PX_CMD, cmd | CMD_ST);
+        Ok(())
+    }
+
+    /// Bring the port back after a failed command
+    fn recover(&mut self) {
+        let _ = self.stop();
+        self.write(PX_SERR, !0);
+        self.write(PX_IS, !0);
+        let _ = self.start();
+    }
+
+    fn identify(&mut self) -> Result<(), AhciError> {
+        self.issue(ATA_IDENTIFY, 0, 0, 512, false)?;
+        let data = &self.bounce[0].0;
+        let word = |i: usize| u16::from_le_bytes([data[2 * i], data[2 * i + 1]]);
+
+        if word(83) & (1 << 10) == 0 {
+            return Err(AhciError::Unsupported("disk without 48-bit LBA"));
+        }
+        // Word 106 is valid when bits 15:14 are 01; bit 12 means long logical sectors
+        if word(106) & 0xC000 == 0x4000 && word(106) & (1 << 12) != 0 {
+            let words = word(117) as u32 | (word(118) as u32) << 16;
+            if words * 2 != 512 {
+                return Err(AhciError::Unsupported("sector size other than 512"));
+            }
+        }
+        let sectors = (0..4).fold(0u64, |acc, i| acc | (word(100 + i) as u64) << (16 * i));
+        let model = ata_string(data, 27..47);
+        let serial = ata_string(data, 10..20);
+
+        self.sectors = sectors;
+        self.model = model;
+        self.serial = serial;
+        Ok(())
+    }
+
+    /// Run one command from slot 0 and wait for it. `bytes` are moved
+    /// to or from the start of the bounce buffer.
+    fn issue(&mut self, command: u8, lba: u64, count: u16, bytes: usize, write: bool) -> Result<(), AhciError> {
+        let entries = (bytes + PAGE_SIZE - 1) / PAGE_SIZE;
+        let table = self.control_phys + CMD_TABLE as u64;
+        let page = &mut self.control.0;
+
+        let mut flags = FIS_H2D_DWORDS | (entries as u32) << 16;
+        if write {
+            flags |= HEADER_WRITE;
+        }
+        put_u32(page, CMD_LIST, flags);
+        put_u32(page, CMD_LIST + 4, 0);
+        put_u32(page, CMD_LIST + 8, table as u32);
+        put_u32(page, CMD_LIST + 12, (table >> 32) as u32);
+
+        let lba = lba.to_le_bytes();
+        let count = count.to_le_bytes();
+        let fis = &mut page[CMD_TABLE..PRDT];
+        fis.fill(0);
+        fis[0] = FIS_TYPE_REG_H2D;
+        fis[1] = 0x80; // this FIS carries a command
+        fis[2] = command;
+        fis[4..7].copy_from_slice(&lba[0..3]);
+        fis[7] = ATA_DEVICE_LBA;
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[12..14].copy_from_slice(&count);
+
+        for (i, &addr) in self.bounce_phys[..entries].iter().enumerate() {
+            let len = (bytes - i * PAGE_SIZE).min(PAGE_SIZE);
+            let entry = PRDT + i * 16;
+            put_u32(page, entry, addr as u32);
+            put_u32(page, entry + 4, (addr >> 32) as u32);
+            put_u32(page, entry + 8, 0);
+            put_u32(page, entry + 12, (len - 1) as u32);
+        }
+
+        // The command must be in memory before the controller is told about it
+        fence(Ordering::SeqCst);
+        spin_until(|| self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0)?;
+        self.write(PX_IS, !0);
+        self.write(PX_CI, 1);
+        let done = spin_until(|| self.read(PX_CI) & 1 == 0 || self.read(PX_IS) & IS_TFES != 0);
+        fence(Ordering::SeqCst);
+
+        let tfd = self.read(PX_TFD);
+        if self.read(PX_IS) & IS_TFES != 0 || tfd & TFD_ERR != 0 {
+            self.recover();
+            return Err(AhciError::TaskFile(tfd));
+        }
+        if done.is_err() {
+            self.recover();
+            return Err(AhciError::Timeout);
+        }
+        Ok(())
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { read_volatile((self.regs + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        unsafe { write_volatile((self.regs + offset) as *mut u32, value) }
+    }
+
+    fn failed(&self, what: &str, sector: u64, err: AhciError) -> BlockError {
+        serial_println!("[ahci] port {}: {} at sector {} failed: {:?}", self.port, what, sector, err);
+        BlockError::Io
+    }
+}
+
+impl Drop for AhciDisk {
+    /// The controller must not DMA into the pages once they are freed
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(&*self, sector, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * 512).enumerate() {
+            let lba = sector + (i * MAX_SECTORS) as u64;
+            self.issue(ATA_READ_DMA_EXT, lba, (chunk.len() / 512) as u16, chunk.len(), false)
+                .map_err(|e| self.failed("read", lba, e))?;
+            for (dst, page) in chunk.chunks_mut(PAGE_SIZE).zip(self.bounce.iter()) {
+                dst.copy_from_slice(&page.0[..dst.len()]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(&*self, sector, buf.len())?;
+        for (i, chunk) in buf.chunks(MAX_SECTORS * 512).enumerate() {
+            let lba = sector + (i * MAX_SECTORS) as u64;
+            for (src, page) in chunk.chunks(PAGE_SIZE).zip(self.bounce.iter_mut()) {
+                page.0[..src.len()].copy_from_slice(src);
+            }
+            self.issue(ATA_WRITE_DMA_EXT, lba, (chunk.len() / 512) as u16, chunk.len(), true)
+                .map_err(|e| self.failed("write", lba, e))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.issue(ATA_FLUSH_CACHE_EXT, 0, 0, 0, false).map_err(|e| self.failed("flush", 0, e))
+    }
+
+    fn max_transfer(&self) -> usize {
+        MAX_SECTORS
+    }
+
+    fn name(&self) -> &str {
+        "ahci"
+    }
+}
+
+fn put_u32(page: &mut [u8], offset: usize, value: u32) {
+    page[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// IDENTIFY strings hold two characters per word, high byte first
+fn ata_string(data: &[u8], words: core::ops::Range<usize>) -> String {
+    let mut s = String::new();
+    for i in words {
+        s.push(data[2 * i + 1] as char);
+        s.push(data[2 * i] as char);
+    }
+    String::from(s.trim())
+}
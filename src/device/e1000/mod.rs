@@ -1,10 +1,14 @@
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use crate::serial_println;
+use super::pci_config_read;
 
 const REG_CTRL:     u32 = 0x0000;
 const REG_STATUS:   u32 = 0x0008;
 const REG_EERD:     u32 = 0x0014;
+const REG_ICR:      u32 = 0x00C0;
+const REG_IMS:      u32 = 0x00D0;
 const REG_IMC:      u32 = 0x00D8;
 const REG_RCTL:     u32 = 0x0100;
 const REG_TCTL:     u32 = 0x0400;
@@ -25,6 +29,10 @@ const REG_RAH:      u32 = 0x5404;
 const CTRL_RST:        u32 = 1 << 26;
 const CTRL_SLU:        u32 = 1 << 6;
 const CTRL_ASDE:       u32 = 1 << 5;
+const ICR_LSC:         u32 = 1 << 2;
+const ICR_RXDMT0:      u32 = 1 << 4;
+const ICR_RXO:         u32 = 1 << 6;
+const ICR_RXT0:        u32 = 1 << 7;
 const RCTL_EN:         u32 = 1 << 1;
 const RCTL_SBP:        u32 = 1 << 2;
 const RCTL_UPE:        u32 = 1 << 3;
@@ -107,9 +115,65 @@ lazy_static! {
     pub static ref E1000_DEV: Mutex<Option<E1000>> = Mutex::new(None);
 }
 
-pub fn init(phys_mem_offset: u64) {
-    let e1000 = unsafe { E1000::new(0xfeb80000, phys_mem_offset) };
+/// Virtual address of the interrupt cause register, for the IRQ handler,
+/// which can't take `E1000_DEV`'s lock
+static ICR_ADDR: AtomicU64 = AtomicU64::new(0);
+
+/// PCI device IDs of the 8254x/8257x parts QEMU emulates
+const DEVICE_IDS: [u32; 3] = [0x100E, 0x100F, 0x10D3];
+
+/// Scan the PCI bus for the NIC: (bus, slot, function, BAR0, IRQ line)
+pub fn find_e1000() -> Option<(u8, u8, u8, u64, u8)> {
+    for bus in 0..=255 {
+        for slot in 0..32 {
+            for function in 0..8 {
+                let id = pci_config_read(bus, slot, function, 0);
+                if id & 0xFFFF != 0x8086 || !DEVICE_IDS.contains(&(id >> 16)) {
+                    continue;
+                }
+                let bar0 = pci_config_read(bus, slot, function, 0x10) as u64 & 0xFFFF_FFF0;
+                let line = pci_config_read(bus, slot, function, 0x3C) as u8;
+                return Some((bus, slot, function, bar0, line));
+            }
+        }
+    }
+    None
+}
+
+/// Bring up the first e1000 and route its interrupt. Returns false if there is none.
+pub fn init(phys_mem_offset: u64) -> bool {
+    let (bus, slot, function, bar0, line) = match find_e1000() {
+        Some(found) => found,
+        None => {
+            serial_println!("[e1000] no device found");
+            return false;
+        }
+    };
+    serial_println!("[e1000] {:02x}:{:02x}.{} at {:#x}, IRQ {}", bus, slot, function, bar0, line);
+    let e1000 = unsafe { E1000::new(bar0, phys_mem_offset) };
+    ICR_ADDR.store(e1000.base_virt + REG_ICR as u64, Ordering::SeqCst);
     *E1000_DEV.lock() = Some(e1000);
+
+    if crate::interrupts::register_irq(line, handle_interrupt) {
+        if let Some(dev) = E1000_DEV.lock().as_ref() {
+            dev.enable_interrupts();
+        }
+    } else {
+        serial_println!("[e1000] IRQ {} can't be routed, relying on timer polling", line);
+    }
+    true
+}
+
+/// Reading ICR acknowledges the interrupt, which also drops the level-triggered line
+fn handle_interrupt() {
+    let addr = ICR_ADDR.load(Ordering::SeqCst);
+    if addr == 0 {
+        return;
+    }
+    let cause = unsafe { core::ptr::read_volatile(addr as *const u32) };
+    if cause != 0 {
+        crate::net::wake();
+    }
 }
 
 impl E1000 {
@@ -125,6 +189,12 @@ impl E1000 {
         e1000
     }
 
+    /// Interrupt on received packets, a filling RX ring and link changes
+    pub fn enable_interrupts(&self) {
+        self.read_reg(REG_ICR);
+        self.write_reg(REG_IMS, ICR_RXT0 | ICR_RXO | ICR_RXDMT0 | ICR_LSC);
+    }
+
     pub fn read_reg_pub(&self, offset: u32) -> u32 {
         self.read_reg(offset)
     }
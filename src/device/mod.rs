@@ -26,6 +26,22 @@ pub fn pci_config_read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
     }
 }
 
+pub fn pci_config_write(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    let address = ((bus as u32) << 16)
+                | ((device as u32) << 11)
+                | ((function as u32) << 8)
+                | ((offset as u32) & 0xfc)
+                | 0x80000000;
+
+    unsafe {
+        let mut address_port = Port::new(PCI_CONFIG_ADDRESS);
+        let mut data_port = Port::new(PCI_CONFIG_DATA);
+
+        address_port.write(address);
+        data_port.write(value);
+    }
+}
+
 pub fn get_all_devices() {
     for bus in 0..=255 {
         for device in 0..32 {
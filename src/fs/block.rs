@@ -0,0 +1,115 @@
+//! Block devices that filesystems can be hosted on.
+//!
+//! A backend only moves whole sectors. Byte-level access, caching and the
+//! fatfs IO traits come from `cache::CachedDevice`, which wraps any backend.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockError {
+    Io,
+    OutOfRange,
+    ReadOnly,
+}
+
+pub trait BlockDevice: Send {
+    /// Bytes per sector. `CachedDevice` needs 512.
+    fn sector_size(&self) -> usize {
+        512
+    }
+
+    fn sector_count(&self) -> u64;
+
+    /// Read `buf.len() / sector_size` sectors starting at `sector`
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf.len() / sector_size` sectors starting at `sector`
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Make completed writes durable, for backends with a cache of their own
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Most sectors one request may move
+    fn max_transfer(&self) -> usize {
+        128
+    }
+
+    fn capacity_bytes(&self) -> u64 {
+        self.sector_count() * self.sector_size() as u64
+    }
+
+    /// Short name for `lsblk` and log messages
+    fn name(&self) -> &str;
+}
+
+impl BlockDevice for Box<dyn BlockDevice> {
+    fn sector_size(&self) -> usize { (**self).sector_size() }
+    fn sector_count(&self) -> u64 { (**self).sector_count() }
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> { (**self).read_sectors(sector, buf) }
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError> { (**self).write_sectors(sector, buf) }
+    fn flush(&mut self) -> Result<(), BlockError> { (**self).flush() }
+    fn max_transfer(&self) -> usize { (**self).max_transfer() }
+    fn name(&self) -> &str { (**self).name() }
+}
+
+/// Check that a request for `buf_len` bytes at `sector` fits the device
+pub fn check_range(dev: &dyn BlockDevice, sector: u64, buf_len: usize) -> Result<(), BlockError> {
+    let size = dev.sector_size();
+    if buf_len % size != 0 || sector + (buf_len / size) as u64 > dev.sector_count() {
+        return Err(BlockError::OutOfRange);
+    }
+    Ok(())
+}
+
+/// Disk kept entirely in memory
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    pub fn new(sectors: u64) -> Self {
+        RamDisk { data: vec![0u8; sectors as usize * 512] }
+    }
+
+    pub fn from_vec(mut data: Vec<u8>) -> Self {
+        let padded = (data.len() + 511) / 512 * 512;
+        data.resize(padded, 0);
+        RamDisk { data }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u64 {
+        (self.data.len() / 512) as u64
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(&*self, sector, buf.len())?;
+        let start = sector as usize * 512;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(&*self, sector, buf.len())?;
+        let start = sector as usize * 512;
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn max_transfer(&self) -> usize {
+        usize::MAX / 512
+    }
+
+    fn name(&self) -> &str {
+        "ram"
+    }
+}
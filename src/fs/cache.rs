@@ -0,0 +1,615 @@
+//! Fixed-size sector cache with O(1) lookup and LRU eviction.
+//!
+//! Slots live in a slab and are threaded on a doubly linked list in use
+//! order; an open-addressed table maps sector numbers to slots. Nothing here
+//! touches a disk: callers are handed dirty victims and write them out.
+//! `CachedDevice` puts one in front of a `BlockDevice`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use fatfs::{IoBase, Read, Seek, SeekFrom, Write};
+use super::block::BlockDevice;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const DEFAULT_CACHE_SECTORS: usize = 64; // 32KB cache
+/// Until a filesystem sets it to its cluster size
+pub const DEFAULT_READ_AHEAD: usize = 8;
+/// Most sectors moved by a single request
+const MAX_BATCH: usize = 128;
+
+/// When writes reach the disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CachePolicy {
+    /// Dirty sectors stay cached until evicted, flushed or synced
+    WriteBack,
+    /// Every write goes straight to disk; the cache only serves reads
+    WriteThrough,
+}
+
+impl CachePolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "write-back" | "writeback" => Some(CachePolicy::WriteBack),
+            "write-through" | "writethrough" => Some(CachePolicy::WriteThrough),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CachePolicy::WriteBack => "write-back",
+            CachePolicy::WriteThrough => "write-through",
+        }
+    }
+}
+
+pub static BLK_READS: AtomicU64 = AtomicU64::new(0);
+pub static BLK_WRITES: AtomicU64 = AtomicU64::new(0);
+pub static BLK_READ_SECTORS: AtomicU64 = AtomicU64::new(0);
+pub static BLK_WRITE_SECTORS: AtomicU64 = AtomicU64::new(0);
+pub static BLK_TICKS: AtomicU64 = AtomicU64::new(0);
+pub static W_CALLS: AtomicU64 = AtomicU64::new(0);
+pub static W_CYCLES: AtomicU64 = AtomicU64::new(0);
+pub static R_CALLS: AtomicU64 = AtomicU64::new(0);
+pub static R_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+const NIL: u32 = u32::MAX;
+
+struct Slot {
+    sector: u64,
+    data: [u8; SECTOR_SIZE],
+    dirty: bool,
+    prev: u32,
+    next: u32,
+}
+
+pub struct SectorCache {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    /// Slot index per bucket, NIL when empty. Always at least twice the capacity.
+    table: Vec<u32>,
+    /// Most recently used slot
+    head: u32,
+    /// Least recently used slot
+    tail: u32,
+    capacity: usize,
+    dirty: usize,
+}
+
+fn hash(sector: u64, buckets: usize) -> usize {
+    (sector.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize & (buckets - 1)
+}
+
+impl SectorCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        SectorCache {
+            slots: Vec::new(),
+            free: Vec::new(),
+            table: vec![NIL; (capacity * 2).next_power_of_two()],
+            head: NIL,
+            tail: NIL,
+            capacity,
+            dirty: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    pub fn dirty_count(&self) -> usize {
+        self.dirty
+    }
+
+    fn bucket_of(&self, sector: u64) -> Option<usize> {
+        let mask = self.table.len() - 1;
+        let mut bucket = hash(sector, self.table.len());
+        loop {
+            let slot = self.table[bucket];
+            if slot == NIL {
+                return None;
+            }
+            if self.slots[slot as usize].sector == sector {
+                return Some(bucket);
+            }
+            bucket = (bucket + 1) & mask;
+        }
+    }
+
+    fn unlink(&mut self, slot: u32) {
+        let (prev, next) = (self.slots[slot as usize].prev, self.slots[slot as usize].next);
+        if prev == NIL { self.head = next; } else { self.slots[prev as usize].next = next; }
+        if next == NIL { self.tail = prev; } else { self.slots[next as usize].prev = prev; }
+    }
+
+    fn push_front(&mut self, slot: u32) {
+        self.slots[slot as usize].prev = NIL;
+        self.slots[slot as usize].next = self.head;
+        if self.head != NIL {
+            self.slots[self.head as usize].prev = slot;
+        }
+        self.head = slot;
+        if self.tail == NIL {
+            self.tail = slot;
+        }
+    }
+
+    /// Whether `sector` is cached, without counting it as a use
+    pub fn contains(&self, sector: u64) -> bool {
+        self.bucket_of(sector).is_some()
+    }
+
+    /// Look at a cached sector without counting it as a use
+    pub fn peek(&self, sector: u64) -> Option<&[u8; SECTOR_SIZE]> {
+        self.bucket_of(sector).map(|b| &self.slots[self.table[b] as usize].data)
+    }
+
+    /// Look a sector up and mark it most recently used
+    pub fn get(&mut self, sector: u64) -> Option<&[u8; SECTOR_SIZE]> {
+        let slot = self.touch(sector)?;
+        Some(&self.slots[slot as usize].data)
+    }
+
+    /// Look a sector up for writing. The sector is marked dirty.
+    pub fn get_mut(&mut self, sector: u64) -> Option<&mut [u8; SECTOR_SIZE]> {
+        let slot = self.touch(sector)? as usize;
+        if !self.slots[slot].dirty {
+            self.slots[slot].dirty = true;
+            self.dirty += 1;
+        }
+        Some(&mut self.slots[slot].data)
+    }
+
+    fn touch(&mut self, sector: u64) -> Option<u32> {
+        let slot = self.table[self.bucket_of(sector)?];
+        if self.head != slot {
+            self.unlink(slot);
+            self.push_front(slot);
+        }
+        Some(slot)
+    }
+
+    pub fn mark_clean(&mut self, sector: u64) {
+        if let Some(bucket) = self.bucket_of(sector) {
+            let slot = &mut self.slots[self.table[bucket] as usize];
+            if slot.dirty {
+                slot.dirty = false;
+                self.dirty -= 1;
+            }
+        }
+    }
+
+    pub fn is_dirty(&self, sector: u64) -> bool {
+        self.bucket_of(sector)
+            .map(|b| self.slots[self.table[b] as usize].dirty)
+            .unwrap_or(false)
+    }
+
+    /// Sector that would be evicted next
+    pub fn lru(&self) -> Option<u64> {
+        if self.tail == NIL { None } else { Some(self.slots[self.tail as usize].sector) }
+    }
+
+    /// Add a sector that is not cached yet. The cache must not be full; use
+    /// `lru` and `remove` to make room first.
+    pub fn insert(&mut self, sector: u64, data: &[u8], dirty: bool) {
+        debug_assert!(!self.is_full() && !self.contains(sector));
+        let mut block = [0u8; SECTOR_SIZE];
+        block.copy_from_slice(&data[..SECTOR_SIZE]);
+        let entry = Slot { sector, data: block, dirty, prev: NIL, next: NIL };
+        let slot = match self.free.pop() {
+            Some(s) => {
+                self.slots[s as usize] = entry;
+                s
+            }
+            None => {
+                self.slots.push(entry);
+                (self.slots.len() - 1) as u32
+            }
+        };
+        if dirty {
+            self.dirty += 1;
+        }
+
+        let mask = self.table.len() - 1;
+        let mut bucket = hash(sector, self.table.len());
+        while self.table[bucket] != NIL {
+            bucket = (bucket + 1) & mask;
+        }
+        self.table[bucket] = slot;
+        self.push_front(slot);
+    }
+
+    /// Drop a sector, returning its data and whether it was dirty
+    pub fn remove(&mut self, sector: u64) -> Option<([u8; SECTOR_SIZE], bool)> {
+        let bucket = self.bucket_of(sector)?;
+        let slot = self.table[bucket];
+        self.table[bucket] = NIL;
+
+        // Backward-shift the rest of the probe run so lookups never hit a gap
+        let mask = self.table.len() - 1;
+        let mut hole = bucket;
+        let mut next = (bucket + 1) & mask;
+        while self.table[next] != NIL {
+            let home = hash(self.slots[self.table[next] as usize].sector, self.table.len());
+            if (next.wrapping_sub(home) & mask) >= (next.wrapping_sub(hole) & mask) {
+                self.table[hole] = self.table[next];
+                self.table[next] = NIL;
+                hole = next;
+            }
+            next = (next + 1) & mask;
+        }
+
+        self.unlink(slot);
+        self.free.push(slot);
+        let entry = &mut self.slots[slot as usize];
+        let dirty = entry.dirty;
+        // Free slots must never look dirty to `dirty_sectors`
+        entry.dirty = false;
+        if dirty {
+            self.dirty -= 1;
+        }
+        Some((entry.data, dirty))
+    }
+
+    /// Dirty sectors in ascending order, so contiguous runs can be batched
+    pub fn dirty_sectors(&self) -> Vec<u64> {
+        let mut sectors: Vec<u64> = self
+            .slots
+            .iter()
+            .filter(|s| s.dirty)
+            .map(|s| s.sector)
+            .collect();
+        sectors.sort_unstable();
+        sectors
+    }
+
+    /// Change the capacity. Returns the sectors that no longer fit, least
+    /// recently used first, with their dirty flag.
+    pub fn resize(&mut self, capacity: usize) -> Vec<(u64, [u8; SECTOR_SIZE], bool)> {
+        let capacity = capacity.max(1);
+        let mut evicted = Vec::new();
+        while self.len() > capacity {
+            let sector = self.lru().unwrap();
+            let (data, dirty) = self.remove(sector).unwrap();
+            evicted.push((sector, data, dirty));
+        }
+
+        let mut kept = Vec::with_capacity(self.len());
+        while let Some(sector) = self.lru() {
+            let (data, dirty) = self.remove(sector).unwrap();
+            kept.push((sector, data, dirty));
+        }
+        *self = SectorCache::new(capacity);
+        // Re-insert oldest first so the use order survives
+        for (sector, data, dirty) in kept {
+            self.insert(sector, &data, dirty);
+        }
+        evicted
+    }
+}
+
+/// Byte-addressed, cached view of a block device that fatfs and the other
+/// filesystem code read and write through.
+pub struct CachedDevice<B: BlockDevice> {
+    dev: B,
+    pos: u64,
+    capacity_bytes: u64,
+    cache: SectorCache,
+    policy: CachePolicy,
+    /// Sectors fetched per miss, so sequential reads take one request per cluster
+    read_ahead: usize,
+}
+
+impl<B: BlockDevice> CachedDevice<B> {
+    pub fn new(dev: B) -> Self {
+        debug_assert_eq!(dev.sector_size(), SECTOR_SIZE);
+        let capacity_bytes = dev.sector_count() * SECTOR_SIZE as u64;
+        Self {
+            dev,
+            pos: 0,
+            capacity_bytes,
+            cache: SectorCache::new(DEFAULT_CACHE_SECTORS),
+            policy: CachePolicy::WriteBack,
+            read_ahead: DEFAULT_READ_AHEAD,
+        }
+    }
+
+    /// Resize the cache and pick when writes reach the disk. Dirty sectors are
+    /// written out first so nothing is lost by shrinking or switching.
+    pub fn configure(&mut self, max_sectors: usize, policy: CachePolicy) -> Result<(), ()> {
+        self.flush_cache()?;
+        // Everything is clean now, so whatever no longer fits can be dropped
+        self.cache.resize(max_sectors);
+        self.policy = policy;
+        Ok(())
+    }
+
+    pub fn policy(&self) -> CachePolicy {
+        self.policy
+    }
+
+    pub fn max_sectors(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    pub fn dirty_sectors(&self) -> usize {
+        self.cache.dirty_count()
+    }
+
+    pub fn set_read_ahead(&mut self, sectors: usize) {
+        self.read_ahead = sectors.clamp(1, self.batch());
+    }
+
+    pub fn read_ahead(&self) -> usize {
+        self.read_ahead
+    }
+
+    /// Sectors per request: our own limit or the backend's, whichever is lower
+    fn batch(&self) -> usize {
+        MAX_BATCH.min(self.dev.max_transfer()).max(1)
+    }
+
+    pub fn capacity_bytes(&self) -> u64 {
+        self.capacity_bytes
+    }
+
+    pub fn device(&self) -> &B {
+        &self.dev
+    }
+
+    /// Flush and hand back the backend
+    pub fn into_inner(mut self) -> Result<B, ()> {
+        self.flush_cache()?;
+        Ok(self.dev)
+    }
+
+    fn disk_read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), ()> {
+        let t = crate::interrupts::TICKS.load(Ordering::Relaxed);
+        self.dev.read_sectors(sector, buf).map_err(|_| ())?;
+        BLK_TICKS.fetch_add(crate::interrupts::TICKS.load(Ordering::Relaxed) - t, Ordering::Relaxed);
+        BLK_READS.fetch_add(1, Ordering::Relaxed);
+        BLK_READ_SECTORS.fetch_add((buf.len() / SECTOR_SIZE) as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn disk_write(&mut self, sector: u64, buf: &[u8]) -> Result<(), ()> {
+        let t = crate::interrupts::TICKS.load(Ordering::Relaxed);
+        self.dev.write_sectors(sector, buf).map_err(|_| ())?;
+        BLK_TICKS.fetch_add(crate::interrupts::TICKS.load(Ordering::Relaxed) - t, Ordering::Relaxed);
+        BLK_WRITES.fetch_add(1, Ordering::Relaxed);
+        BLK_WRITE_SECTORS.fetch_add((buf.len() / SECTOR_SIZE) as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Write dirty sectors, given in ascending order, one request per
+    /// contiguous run.
+    fn write_sorted(&mut self, sectors: &[u64]) -> Result<(), ()> {
+        let mut i = 0;
+        while i < sectors.len() {
+            let first = sectors[i];
+            let mut n = 1;
+            while i + n < sectors.len() && n < self.batch() && sectors[i + n] == first + n as u64 {
+                n += 1;
+            }
+            let mut buf = vec![0u8; n * SECTOR_SIZE];
+            for (k, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+                chunk.copy_from_slice(self.cache.peek(first + k as u64).ok_or(())?);
+            }
+            self.disk_write(first, &buf)?;
+            for k in 0..n as u64 {
+                self.cache.mark_clean(first + k);
+            }
+            i += n;
+        }
+        Ok(())
+    }
+
+    /// Evict least recently used sectors until `needed` more fit. A dirty
+    /// victim is written together with the dirty sectors that follow it.
+    fn make_room(&mut self, needed: usize) -> Result<(), ()> {
+        while self.cache.len() + needed > self.cache.capacity() {
+            let victim = self.cache.lru().ok_or(())?;
+            if self.cache.is_dirty(victim) {
+                let run: Vec<u64> = (victim..victim + self.batch() as u64)
+                    .take_while(|&s| self.cache.is_dirty(s))
+                    .collect();
+                self.write_sorted(&run)?;
+            }
+            self.cache.remove(victim);
+        }
+        Ok(())
+    }
+
+    /// Read the uncached run starting at `first` in one request. At least
+    /// `wanted` sectors are fetched if they are missing, and read-ahead
+    /// extends the run.
+    fn fill(&mut self, first: u64, wanted: usize) -> Result<(), ()> {
+        let total = self.capacity_bytes / SECTOR_SIZE as u64;
+        let limit = wanted
+            .max(self.read_ahead)
+            .min(self.batch())
+            .min((self.cache.capacity() / 2).max(1));
+        let mut n = 0;
+        while n < limit && first + (n as u64) < total && !self.cache.contains(first + n as u64) {
+            n += 1;
+        }
+        if n == 0 {
+            return Err(());
+        }
+
+        self.make_room(n)?;
+        let mut buf = vec![0u8; n * SECTOR_SIZE];
+        self.disk_read(first, &mut buf)?;
+        for (k, chunk) in buf.chunks(SECTOR_SIZE).enumerate() {
+            self.cache.insert(first + k as u64, chunk, false);
+        }
+        Ok(())
+    }
+
+    /// Write all dirty sectors to disk.
+    pub fn flush_cache(&mut self) -> Result<(), ()> {
+        let dirty = self.cache.dirty_sectors();
+        self.write_sorted(&dirty)?;
+        self.dev.flush().map_err(|_| ())
+    }
+
+    fn read_inner(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        let mut total_read = 0;
+        let mut remaining = buf;
+
+        while !remaining.is_empty() {
+            let sector = self.pos / 512;
+            let offset = (self.pos % 512) as usize;
+
+            if !self.cache.contains(sector) {
+                let wanted = (offset + remaining.len() + SECTOR_SIZE - 1) / SECTOR_SIZE;
+                self.fill(sector, wanted)?;
+            }
+            let cached = self.cache.get(sector).ok_or(())?;
+            let available = SECTOR_SIZE - offset;
+            let to_copy = available.min(remaining.len());
+            remaining[..to_copy].copy_from_slice(&cached[offset..offset + to_copy]);
+
+            remaining = &mut remaining[to_copy..];
+            self.pos += to_copy as u64;
+            total_read += to_copy;
+        }
+
+        Ok(total_read)
+    }
+
+    fn write_inner(&mut self, buf: &[u8]) -> Result<usize, ()> {
+        let mut total_written = 0;
+        let mut remaining = buf;
+        let first_sector = self.pos / 512;
+
+        while !remaining.is_empty() {
+            let sector = self.pos / 512;
+            let offset = (self.pos % 512) as usize;
+            let available = SECTOR_SIZE - offset;
+            let to_copy = available.min(remaining.len());
+
+            if !self.cache.contains(sector) {
+                if to_copy == SECTOR_SIZE {
+                    // Whole sector is overwritten, no need to read it first
+                    self.make_room(1)?;
+                    self.cache.insert(sector, &remaining[..SECTOR_SIZE], true);
+                } else {
+                    self.fill(sector, 1)?;
+                }
+            }
+            let cached = self.cache.get_mut(sector).ok_or(())?;
+            cached[offset..offset + to_copy].copy_from_slice(&remaining[..to_copy]);
+
+            remaining = &remaining[to_copy..];
+            self.pos += to_copy as u64;
+            total_written += to_copy;
+        }
+
+        if self.policy == CachePolicy::WriteThrough {
+            let last_sector = (self.pos + 511) / 512;
+            let dirty: Vec<u64> = (first_sector..last_sector).filter(|&s| self.cache.is_dirty(s)).collect();
+            self.write_sorted(&dirty)?;
+        }
+
+        Ok(total_written)
+    }
+}
+
+impl<B: BlockDevice> IoBase for CachedDevice<B> {
+    type Error = ();
+}
+
+impl<B: BlockDevice> Read for CachedDevice<B> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let c0 = crate::interrupts::rdtsc();
+        let result = self.read_inner(buf);
+        R_CYCLES.fetch_add(crate::interrupts::rdtsc() - c0, Ordering::Relaxed);
+        R_CALLS.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<B: BlockDevice> Write for CachedDevice<B> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let c0 = crate::interrupts::rdtsc();
+        let result = self.write_inner(buf);
+        W_CYCLES.fetch_add(crate::interrupts::rdtsc() - c0, Ordering::Relaxed);
+        W_CALLS.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.flush_cache()
+    }
+}
+
+impl<B: BlockDevice> Seek for CachedDevice<B> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => (self.capacity_bytes as i64 + offset) as u64,
+            SeekFrom::Current(offset) => (self.pos as i64 + offset) as u64,
+        };
+        Ok(self.pos)
+    }
+}
+
+#[test_case]
+fn test_sector_cache_lru() {
+    let mut cache = SectorCache::new(3);
+    let block = [7u8; SECTOR_SIZE];
+    for sector in [10, 20, 30] {
+        cache.insert(sector, &block, false);
+    }
+    assert_eq!(cache.lru(), Some(10));
+    cache.get(10);
+    assert_eq!(cache.lru(), Some(20));
+
+    cache.get_mut(20).unwrap()[0] = 1;
+    assert_eq!(cache.dirty_count(), 1);
+    let (data, dirty) = cache.remove(20).unwrap();
+    assert!(dirty && data[0] == 1);
+    assert_eq!(cache.dirty_count(), 0);
+    assert!(!cache.contains(20) && cache.contains(10) && cache.contains(30));
+
+    cache.insert(40, &block, true);
+    assert_eq!(cache.dirty_sectors(), [40u64]);
+    let evicted = cache.resize(1);
+    assert_eq!(evicted.len(), 2);
+    assert!(cache.contains(40));
+}
+
+#[test_case]
+fn test_cached_device_write_back() {
+    use super::block::RamDisk;
+
+    let mut dev = CachedDevice::new(RamDisk::new(64));
+    dev.configure(4, CachePolicy::WriteBack).unwrap();
+    let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+    dev.seek(SeekFrom::Start(100)).unwrap();
+    assert_eq!(dev.write(&data).unwrap(), data.len());
+
+    let mut back = vec![0u8; data.len()];
+    dev.seek(SeekFrom::Start(100)).unwrap();
+    assert_eq!(dev.read(&mut back).unwrap(), data.len());
+    assert_eq!(back, data);
+
+    let disk = dev.into_inner().unwrap().into_vec();
+    assert_eq!(&disk[100..3100], &data[..]);
+}
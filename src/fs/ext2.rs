@@ -0,0 +1,518 @@
+//! Read-only ext2 driver.
+//!
+//! Enough of the on-disk format to walk directories and read files from
+//! images made by `mke2fs` on a Linux host: the superblock, block group
+//! descriptors, inodes with direct and indirect block maps, and symlinks.
+//! Extents (ext4), compression and 64-bit block numbers are refused at mount.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use fatfs::{Read, Seek};
+use super::fat::read_at;
+use super::DirEntry;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT2_MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+const MAX_SYMLINK_HOPS: usize = 8;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_RECOVER: u32 = 0x0004;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_RECOVER | INCOMPAT_FLEX_BG;
+
+const INODE_FLAG_EXTENTS: u32 = 0x0008_0000;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIR: u16 = 0x4000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+
+const DIRECT_BLOCKS: u64 = 12;
+
+#[derive(Debug)]
+pub enum Ext2Error {
+    Io,
+    NotExt2,
+    Unsupported(&'static str),
+    NotFound,
+    NotDir,
+    SymlinkLoop,
+}
+
+impl From<super::fat::FatError> for Ext2Error {
+    fn from(_: super::fat::FatError) -> Self {
+        Ext2Error::Io
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Inode {
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u64,
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub links: u16,
+    pub flags: u32,
+    blocks: [u32; 15],
+    /// 512-byte sectors in use, used to tell fast symlinks apart
+    sectors: u32,
+}
+
+impl Inode {
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_FILE
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_SYMLINK
+    }
+}
+
+pub struct RawDirEntry {
+    pub inode: u32,
+    pub name: String,
+}
+
+/// Check for the ext2 superblock magic
+pub fn probe<D: Read + Seek>(disk: &mut D) -> bool {
+    let mut magic = [0u8; 2];
+    read_at(disk, SUPERBLOCK_OFFSET + 56, &mut magic).is_ok()
+        && u16::from_le_bytes(magic) == EXT2_MAGIC
+}
+
+pub struct Ext2Fs<D> {
+    disk: D,
+    block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// Inode table block of every block group
+    inode_tables: Vec<u32>,
+    has_filetype: bool,
+    pub label: String,
+    pub blocks_count: u32,
+    pub free_blocks: u32,
+}
+
+fn le16(b: &[u8], o: usize) -> u16 {
+    u16::from_le_bytes([b[o], b[o + 1]])
+}
+
+fn le32(b: &[u8], o: usize) -> u32 {
+    u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]])
+}
+
+impl<D: Read + Seek> Ext2Fs<D> {
+    pub fn mount(mut disk: D) -> Result<Self, Ext2Error> {
+        let mut sb = [0u8; 1024];
+        read_at(&mut disk, SUPERBLOCK_OFFSET, &mut sb)?;
+        if le16(&sb, 56) != EXT2_MAGIC {
+            return Err(Ext2Error::NotExt2);
+        }
+
+        let inodes_count = le32(&sb, 0);
+        let blocks_count = le32(&sb, 4);
+        let free_blocks = le32(&sb, 12);
+        let first_data_block = le32(&sb, 20);
+        let log_block_size = le32(&sb, 24);
+        let blocks_per_group = le32(&sb, 32);
+        let inodes_per_group = le32(&sb, 40);
+        let rev_level = le32(&sb, 76);
+        if log_block_size > 6 || blocks_per_group == 0 || inodes_per_group == 0 {
+            return Err(Ext2Error::NotExt2);
+        }
+
+        let (inode_size, incompat) = if rev_level >= 1 {
+            (le16(&sb, 88) as u64, le32(&sb, 96))
+        } else {
+            (128, 0)
+        };
+        let unsupported = incompat & !INCOMPAT_SUPPORTED;
+        if unsupported != 0 {
+            crate::serial_println!("[ext2] unsupported incompat features {:#x}", unsupported);
+            return Err(Ext2Error::Unsupported("incompatible feature flags"));
+        }
+        if incompat & INCOMPAT_RECOVER != 0 {
+            crate::serial_println!("[ext2] journal needs recovery, contents may be stale");
+        }
+
+        let label_bytes = &sb[120..136];
+        let label_len = label_bytes.iter().position(|&b| b == 0).unwrap_or(16);
+        let label = String::from_utf8_lossy(&label_bytes[..label_len]).into_owned();
+
+        let block_size = 1024u64 << log_block_size;
+        let groups = (inodes_count + inodes_per_group - 1) / inodes_per_group;
+        let table_offset = (first_data_block as u64 + 1) * block_size;
+        let mut descs = vec![0u8; groups as usize * 32];
+        read_at(&mut disk, table_offset, &mut descs)?;
+        let inode_tables = (0..groups as usize).map(|g| le32(&descs, g * 32 + 8)).collect();
+
+        crate::serial_println!("[ext2] {} byte blocks, {} groups, label \"{}\"", block_size, groups, label);
+
+        Ok(Ext2Fs {
+            disk,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            has_filetype: incompat & INCOMPAT_FILETYPE != 0,
+            label,
+            blocks_count,
+            free_blocks,
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn read_inode(&mut self, number: u32) -> Result<Inode, Ext2Error> {
+        if number == 0 {
+            return Err(Ext2Error::NotFound);
+        }
+        let group = ((number - 1) / self.inodes_per_group) as usize;
+        let index = ((number - 1) % self.inodes_per_group) as u64;
+        let table = *self.inode_tables.get(group).ok_or(Ext2Error::NotFound)?;
+
+        let mut raw = [0u8; 128];
+        read_at(&mut self.disk, table as u64 * self.block_size + index * self.inode_size, &mut raw)?;
+
+        let mode = le16(&raw, 0);
+        let mut blocks = [0u32; 15];
+        for (i, b) in blocks.iter_mut().enumerate() {
+            *b = le32(&raw, 40 + i * 4);
+        }
+        // For regular files the old dir_acl field holds the upper 32 bits of the size
+        let size_high = if mode & MODE_TYPE_MASK == MODE_FILE { le32(&raw, 108) as u64 } else { 0 };
+
+        let inode = Inode {
+            mode,
+            uid: le16(&raw, 2),
+            size: (size_high << 32) | le32(&raw, 4) as u64,
+            atime: le32(&raw, 8),
+            ctime: le32(&raw, 12),
+            mtime: le32(&raw, 16),
+            gid: le16(&raw, 24),
+            links: le16(&raw, 26),
+            sectors: le32(&raw, 28),
+            flags: le32(&raw, 32),
+            blocks,
+        };
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            return Err(Ext2Error::Unsupported("extent-mapped inode"));
+        }
+        Ok(inode)
+    }
+
+    /// Read one block number out of an indirect block
+    fn indirect(&mut self, block: u32, index: u64) -> Result<u32, Ext2Error> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut b = [0u8; 4];
+        read_at(&mut self.disk, block as u64 * self.block_size + index * 4, &mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    /// Map a file-relative block index to a disk block. 0 means a hole.
+    fn map_block(&mut self, inode: &Inode, index: u64) -> Result<u32, Ext2Error> {
+        let per_block = self.block_size / 4;
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index as usize]);
+        }
+        let index = index - DIRECT_BLOCKS;
+        if index < per_block {
+            return self.indirect(inode.blocks[12], index);
+        }
+        let index = index - per_block;
+        if index < per_block * per_block {
+            let l1 = self.indirect(inode.blocks[13], index / per_block)?;
+            return self.indirect(l1, index % per_block);
+        }
+        let index = index - per_block * per_block;
+        if index < per_block * per_block * per_block {
+            let l1 = self.indirect(inode.blocks[14], index / (per_block * per_block))?;
+            let l2 = self.indirect(l1, (index / per_block) % per_block)?;
+            return self.indirect(l2, index % per_block);
+        }
+        Err(Ext2Error::Io)
+    }
+
+    /// Read from `offset` into `buf`, returning how many bytes were read.
+    pub fn read_at(&mut self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Ext2Error> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(inode.size - offset) as usize;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let in_block = pos % self.block_size;
+            let n = ((self.block_size - in_block) as usize).min(len - done);
+            let block = self.map_block(inode, pos / self.block_size)?;
+            if block == 0 {
+                buf[done..done + n].iter_mut().for_each(|b| *b = 0);
+            } else {
+                read_at(&mut self.disk, block as u64 * self.block_size + in_block, &mut buf[done..done + n])?;
+            }
+            done += n;
+        }
+        Ok(done)
+    }
+
+    pub fn read_all(&mut self, inode: &Inode) -> Result<Vec<u8>, Ext2Error> {
+        let mut data = vec![0u8; inode.size as usize];
+        let n = self.read_at(inode, 0, &mut data)?;
+        data.truncate(n);
+        Ok(data)
+    }
+
+    pub fn read_link(&mut self, inode: &Inode) -> Result<String, Ext2Error> {
+        // Fast symlinks keep the target in the block map itself
+        let target = if inode.sectors == 0 && inode.size < 60 {
+            let mut raw = Vec::with_capacity(60);
+            for b in inode.blocks.iter() {
+                raw.extend_from_slice(&b.to_le_bytes());
+            }
+            raw.truncate(inode.size as usize);
+            raw
+        } else {
+            self.read_all(inode)?
+        };
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    pub fn read_dir(&mut self, inode: &Inode) -> Result<Vec<RawDirEntry>, Ext2Error> {
+        if !inode.is_dir() {
+            return Err(Ext2Error::NotDir);
+        }
+        let data = self.read_all(inode)?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let number = le32(&data, pos);
+            let rec_len = le16(&data, pos + 4) as usize;
+            let name_len = if self.has_filetype {
+                data[pos + 6] as usize
+            } else {
+                le16(&data, pos + 6) as usize
+            };
+            if rec_len < 8 || pos + rec_len > data.len() {
+                break;
+            }
+            if number != 0 && 8 + name_len <= rec_len {
+                let name = String::from_utf8_lossy(&data[pos + 8..pos + 8 + name_len]).into_owned();
+                entries.push(RawDirEntry { inode: number, name });
+            }
+            pos += rec_len;
+        }
+        Ok(entries)
+    }
+
+    /// Resolve an absolute path to an inode number, following symlinks in
+    /// every component, and in the last one too if `follow_last` is set.
+    pub fn lookup(&mut self, path: &str, follow_last: bool) -> Result<u32, Ext2Error> {
+        let mut hops = 0;
+        self.lookup_from(ROOT_INODE, path, follow_last, &mut hops)
+    }
+
+    fn lookup_from(&mut self, start: u32, path: &str, follow_last: bool, hops: &mut usize) -> Result<u32, Ext2Error> {
+        let mut current = if path.starts_with('/') { ROOT_INODE } else { start };
+        let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty() && *p != ".").collect();
+
+        for (i, part) in parts.iter().enumerate() {
+            let dir = self.read_inode(current)?;
+            let next = self
+                .read_dir(&dir)?
+                .into_iter()
+                .find(|e| e.name == *part)
+                .map(|e| e.inode)
+                .ok_or(Ext2Error::NotFound)?;
+
+            let is_last = i + 1 == parts.len();
+            let node = self.read_inode(next)?;
+            if node.is_symlink() && (!is_last || follow_last) {
+                *hops += 1;
+                if *hops > MAX_SYMLINK_HOPS {
+                    return Err(Ext2Error::SymlinkLoop);
+                }
+                let target = self.read_link(&node)?;
+                // Relative targets resolve against the directory holding the link
+                current = self.lookup_from(current, &target, true, hops)?;
+            } else {
+                current = next;
+            }
+        }
+        Ok(current)
+    }
+
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, Ext2Error> {
+        let number = self.lookup(path, true)?;
+        let inode = self.read_inode(number)?;
+        if inode.is_dir() {
+            return Err(Ext2Error::NotFound);
+        }
+        self.read_all(&inode)
+    }
+
+    pub fn is_dir(&mut self, path: &str) -> bool {
+        self.lookup(path, true)
+            .and_then(|n| self.read_inode(n))
+            .map(|i| i.is_dir())
+            .unwrap_or(false)
+    }
+
+    pub fn exists(&mut self, path: &str) -> bool {
+        self.lookup(path, false).is_ok()
+    }
+
+    pub fn list_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Ext2Error> {
+        let number = self.lookup(path, true)?;
+        let dir = self.read_inode(number)?;
+        let mut entries = Vec::new();
+        for raw in self.read_dir(&dir)? {
+            let inode = self.read_inode(raw.inode)?;
+            let t = crate::time::from_unix(inode.mtime as u64);
+            entries.push(DirEntry {
+                name: raw.name,
+                is_dir: inode.is_dir(),
+                size: inode.size,
+                modified: (
+                    t.year as u16,
+                    t.month as u16,
+                    t.day as u16,
+                    t.hour as u16,
+                    t.minute as u16,
+                    t.second as u16,
+                ),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// Tiny image: 1 KiB blocks, one group, inode table at block 5, root
+/// directory at block 10
+#[cfg(test)]
+struct TestImage(Vec<u8>);
+
+#[cfg(test)]
+impl TestImage {
+    const BLOCK: usize = 1024;
+
+    fn new(blocks: usize) -> Self {
+        let mut image = TestImage(vec![0u8; blocks * Self::BLOCK]);
+        let sb = &mut image.0[SUPERBLOCK_OFFSET as usize..][..1024];
+        sb[0..4].copy_from_slice(&16u32.to_le_bytes());
+        sb[4..8].copy_from_slice(&(blocks as u32).to_le_bytes());
+        sb[20..24].copy_from_slice(&1u32.to_le_bytes());
+        sb[32..36].copy_from_slice(&8192u32.to_le_bytes());
+        sb[40..44].copy_from_slice(&16u32.to_le_bytes());
+        sb[56..58].copy_from_slice(&EXT2_MAGIC.to_le_bytes());
+        sb[76..80].copy_from_slice(&1u32.to_le_bytes());
+        sb[88..90].copy_from_slice(&128u16.to_le_bytes());
+        sb[96..100].copy_from_slice(&INCOMPAT_FILETYPE.to_le_bytes());
+        image.0[2 * Self::BLOCK + 8..][..4].copy_from_slice(&5u32.to_le_bytes());
+        image
+    }
+
+    fn inode(&mut self, number: u32, mode: u16, size: u32, sectors: u32, blocks: &[u32]) {
+        let raw = &mut self.0[5 * Self::BLOCK + (number as usize - 1) * 128..][..128];
+        raw[0..2].copy_from_slice(&mode.to_le_bytes());
+        raw[4..8].copy_from_slice(&size.to_le_bytes());
+        raw[28..32].copy_from_slice(&sectors.to_le_bytes());
+        for (i, b) in blocks.iter().enumerate() {
+            raw[40 + i * 4..44 + i * 4].copy_from_slice(&b.to_le_bytes());
+        }
+    }
+
+    /// Store `value` as entry `index` of indirect block `block`
+    fn pointer(&mut self, block: u32, index: usize, value: u32) {
+        self.0[block as usize * Self::BLOCK + index * 4..][..4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn data(&mut self, block: u32, bytes: &[u8]) {
+        self.0[block as usize * Self::BLOCK..][..bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn dir(&mut self, block: u32, entries: &[(u32, &str)]) {
+        let mut pos = block as usize * Self::BLOCK;
+        let end = pos + Self::BLOCK;
+        for (i, &(inode, name)) in entries.iter().enumerate() {
+            let rec_len = if i + 1 == entries.len() { end - pos } else { (8 + name.len() + 3) & !3 };
+            let e = &mut self.0[pos..pos + rec_len];
+            e[0..4].copy_from_slice(&inode.to_le_bytes());
+            e[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+            e[6] = name.len() as u8;
+            e[8..8 + name.len()].copy_from_slice(name.as_bytes());
+            pos += rec_len;
+        }
+    }
+}
+
+#[test_case]
+fn test_indirect_blocks_and_symlinks() {
+    use super::block::RamDisk;
+    use super::cache::CachedDevice;
+
+    let mut image = TestImage::new(32);
+    image.inode(ROOT_INODE, MODE_DIR | 0o755, 1024, 2, &[10]);
+    image.dir(10, &[(2, "."), (2, ".."), (12, "big"), (13, "fast"), (14, "slow"), (15, "loop")]);
+
+    // A sparse file with one block at each level of the block map: direct
+    // block 0, then the first block behind the single, double and triple
+    // indirect pointers (256 pointers per block)
+    let triple_index = 12 + 256 + 256 * 256;
+    let mut map = [0u32; 15];
+    map[0] = 20;
+    map[12..].copy_from_slice(&[21, 23, 26]);
+    image.inode(12, MODE_FILE | 0o644, (triple_index + 1) * 1024, 0, &map);
+    image.data(20, b"direct");
+    for &(chain, data, text) in &[
+        (&[21][..], 22, b"single"),
+        (&[23, 24][..], 25, b"double"),
+        (&[26, 27, 28][..], 29, b"triple"),
+    ] {
+        for pair in chain.windows(2) {
+            image.pointer(pair[0], 0, pair[1]);
+        }
+        image.pointer(*chain.last().unwrap(), 0, data);
+        image.data(data, text);
+    }
+
+    // "fast" keeps its target in the block map, "slow" in a data block
+    image.inode(13, MODE_SYMLINK | 0o777, 3, 0, &[u32::from_le_bytes(*b"big\0")]);
+    image.inode(14, MODE_SYMLINK | 0o777, 5, 2, &[30]);
+    image.data(30, b"/fast");
+    image.inode(15, MODE_SYMLINK | 0o777, 4, 0, &[u32::from_le_bytes(*b"loop")]);
+
+    let disk = CachedDevice::new(RamDisk::from_vec(image.0));
+    let mut fs = Ext2Fs::mount(disk).unwrap();
+    let big = fs.read_inode(12).unwrap();
+    let mut buf = [0u8; 6];
+    for &(index, expected) in &[
+        (0u64, b"direct"),
+        (12, b"single"),
+        (12 + 256, b"double"),
+        (triple_index as u64, b"triple"),
+        (13, b"\0\0\0\0\0\0"),
+    ] {
+        assert_eq!(fs.read_at(&big, index * 1024, &mut buf).unwrap(), 6);
+        assert_eq!(&buf, expected);
+    }
+
+    let (fast, slow) = (fs.read_inode(13).unwrap(), fs.read_inode(14).unwrap());
+    assert_eq!(fs.read_link(&fast).unwrap(), "big");
+    assert_eq!(fs.read_link(&slow).unwrap(), "/fast");
+    assert_eq!(fs.lookup("/slow", false).unwrap(), 14);
+    assert_eq!(fs.lookup("/slow", true).unwrap(), 12);
+    assert!(matches!(fs.lookup("/loop", true), Err(Ext2Error::SymlinkLoop)));
+}
@@ -0,0 +1,496 @@
+//! Raw access to the on-disk FAT structures.
+//!
+//! fatfs hides cluster numbers and the allocation table, which is what the
+//! maintenance tools (fsck, stat) need to look at. Everything here works on
+//! any `Read + Write + Seek` over the volume, mounted or not, but callers
+//! that write must make sure fatfs isn't using the volume at the same time.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use fatfs::{Read, Seek, SeekFrom, Write};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+
+const DIR_ENTRY_SIZE: u64 = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+
+#[derive(Debug)]
+pub enum FatError {
+    Io,
+    NotFat,
+    NotFound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FatKind::Fat12 => "FAT12",
+            FatKind::Fat16 => "FAT16",
+            FatKind::Fat32 => "FAT32",
+        }
+    }
+}
+
+/// Volume geometry, decoded from the BPB in the boot sector.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub kind: FatKind,
+    pub bytes_per_sector: u64,
+    pub cluster_bytes: u64,
+    /// Byte offset of the first FAT
+    pub fat_start: u64,
+    pub fat_bytes: u64,
+    pub num_fats: u8,
+    /// Fixed root directory region (FAT12/16 only)
+    pub root_dir_start: u64,
+    pub root_dir_entries: u32,
+    /// First cluster of the root directory (FAT32 only)
+    pub root_cluster: u32,
+    pub data_start: u64,
+    /// Number of data clusters; valid cluster numbers are 2..=cluster_count+1
+    pub cluster_count: u32,
+    pub fs_info_sector: u16,
+}
+
+/// Where a directory's entries live
+#[derive(Debug, Clone, Copy)]
+pub enum DirLocation {
+    FixedRoot,
+    Cluster(u32),
+}
+
+/// A short directory entry together with its long name, if it had one.
+pub struct RawEntry {
+    pub short_name: [u8; 11],
+    pub long_name: Option<String>,
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// Absolute byte offset of the 32-byte short entry
+    pub offset: u64,
+}
+
+impl RawEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_dot(&self) -> bool {
+        &self.short_name == b".          " || &self.short_name == b"..         "
+    }
+
+    /// "NAME.EXT" form of the 8.3 name
+    pub fn short_name(&self) -> String {
+        let base = core::str::from_utf8(&self.short_name[..8]).unwrap_or("?").trim_end();
+        let ext = core::str::from_utf8(&self.short_name[8..]).unwrap_or("").trim_end();
+        if ext.is_empty() {
+            String::from(base)
+        } else {
+            alloc::format!("{}.{}", base, ext)
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match &self.long_name {
+            Some(name) => name.clone(),
+            None => self.short_name(),
+        }
+    }
+}
+
+pub fn read_at<D: Read + Seek>(disk: &mut D, offset: u64, buf: &mut [u8]) -> Result<(), FatError> {
+    disk.seek(SeekFrom::Start(offset)).map_err(|_| FatError::Io)?;
+    let mut done = 0;
+    while done < buf.len() {
+        match disk.read(&mut buf[done..]) {
+            Ok(0) | Err(_) => return Err(FatError::Io),
+            Ok(n) => done += n,
+        }
+    }
+    Ok(())
+}
+
+pub fn write_at<D: Write + Seek>(disk: &mut D, offset: u64, buf: &[u8]) -> Result<(), FatError> {
+    disk.seek(SeekFrom::Start(offset)).map_err(|_| FatError::Io)?;
+    let mut done = 0;
+    while done < buf.len() {
+        match disk.write(&buf[done..]) {
+            Ok(0) | Err(_) => return Err(FatError::Io),
+            Ok(n) => done += n,
+        }
+    }
+    Ok(())
+}
+
+impl Layout {
+    pub fn read<D: Read + Seek>(disk: &mut D) -> Result<Layout, FatError> {
+        let mut bs = [0u8; 512];
+        read_at(disk, 0, &mut bs)?;
+        if u16::from_le_bytes([bs[510], bs[511]]) != 0xAA55 {
+            return Err(FatError::NotFat);
+        }
+
+        let u16_at = |o: usize| u16::from_le_bytes([bs[o], bs[o + 1]]) as u64;
+        let u32_at = |o: usize| u32::from_le_bytes([bs[o], bs[o + 1], bs[o + 2], bs[o + 3]]) as u64;
+
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = bs[13] as u64;
+        let reserved_sectors = u16_at(14);
+        let num_fats = bs[16];
+        let root_dir_entries = u16_at(17);
+        let total_sectors = if u16_at(19) != 0 { u16_at(19) } else { u32_at(32) };
+        let fat_sectors = if u16_at(22) != 0 { u16_at(22) } else { u32_at(36) };
+
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || sectors_per_cluster == 0
+            || num_fats == 0
+            || fat_sectors == 0
+        {
+            return Err(FatError::NotFat);
+        }
+
+        let root_dir_sectors = (root_dir_entries * DIR_ENTRY_SIZE + bytes_per_sector - 1) / bytes_per_sector;
+        let first_data_sector = reserved_sectors + num_fats as u64 * fat_sectors + root_dir_sectors;
+        if total_sectors <= first_data_sector {
+            return Err(FatError::NotFat);
+        }
+        let cluster_count = ((total_sectors - first_data_sector) / sectors_per_cluster) as u32;
+
+        // The FAT type is defined by the cluster count alone
+        let kind = if cluster_count < 4085 {
+            FatKind::Fat12
+        } else if cluster_count < 65525 {
+            FatKind::Fat16
+        } else {
+            FatKind::Fat32
+        };
+
+        let (root_cluster, fs_info_sector) = if kind == FatKind::Fat32 {
+            (u32_at(44) as u32, u16_at(48) as u16)
+        } else {
+            (0, 0)
+        };
+
+        Ok(Layout {
+            kind,
+            bytes_per_sector,
+            cluster_bytes: bytes_per_sector * sectors_per_cluster,
+            fat_start: reserved_sectors * bytes_per_sector,
+            fat_bytes: fat_sectors * bytes_per_sector,
+            num_fats,
+            root_dir_start: (reserved_sectors + num_fats as u64 * fat_sectors) * bytes_per_sector,
+            root_dir_entries: root_dir_entries as u32,
+            root_cluster,
+            data_start: first_data_sector * bytes_per_sector,
+            cluster_count,
+            fs_info_sector,
+        })
+    }
+
+    pub fn root(&self) -> DirLocation {
+        match self.kind {
+            FatKind::Fat32 => DirLocation::Cluster(self.root_cluster),
+            _ => DirLocation::FixedRoot,
+        }
+    }
+
+    pub fn max_cluster(&self) -> u32 {
+        self.cluster_count + 1
+    }
+
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster <= self.max_cluster()
+    }
+
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_bytes
+    }
+
+    /// Value written to terminate a chain
+    pub fn end_of_chain(&self) -> u32 {
+        match self.kind {
+            FatKind::Fat12 => 0xFFF,
+            FatKind::Fat16 => 0xFFFF,
+            FatKind::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    pub fn is_end_of_chain(&self, value: u32) -> bool {
+        match self.kind {
+            FatKind::Fat12 => value >= 0xFF8,
+            FatKind::Fat16 => value >= 0xFFF8,
+            FatKind::Fat32 => value >= 0x0FFF_FFF8,
+        }
+    }
+
+    pub fn is_bad(&self, value: u32) -> bool {
+        match self.kind {
+            FatKind::Fat12 => value == 0xFF7,
+            FatKind::Fat16 => value == 0xFFF7,
+            FatKind::Fat32 => value == 0x0FFF_FFF7,
+        }
+    }
+
+    /// Byte offset of `cluster`'s entry within a FAT
+    fn entry_offset(&self, cluster: u32) -> u64 {
+        match self.kind {
+            FatKind::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatKind::Fat16 => cluster as u64 * 2,
+            FatKind::Fat32 => cluster as u64 * 4,
+        }
+    }
+}
+
+/// Read the FAT entry for `cluster` from the first FAT
+pub fn read_entry<D: Read + Seek>(disk: &mut D, layout: &Layout, cluster: u32) -> Result<u32, FatError> {
+    let offset = layout.fat_start + layout.entry_offset(cluster);
+    match layout.kind {
+        FatKind::Fat12 => {
+            let mut b = [0u8; 2];
+            read_at(disk, offset, &mut b)?;
+            let v = u16::from_le_bytes(b) as u32;
+            Ok(if cluster & 1 == 1 { v >> 4 } else { v & 0xFFF })
+        }
+        FatKind::Fat16 => {
+            let mut b = [0u8; 2];
+            read_at(disk, offset, &mut b)?;
+            Ok(u16::from_le_bytes(b) as u32)
+        }
+        FatKind::Fat32 => {
+            let mut b = [0u8; 4];
+            read_at(disk, offset, &mut b)?;
+            Ok(u32::from_le_bytes(b) & 0x0FFF_FFFF)
+        }
+    }
+}
+
+/// Write the FAT entry for `cluster` to every FAT copy
+pub fn write_entry<D: Read + Write + Seek>(disk: &mut D, layout: &Layout, cluster: u32, value: u32) -> Result<(), FatError> {
+    for fat in 0..layout.num_fats as u64 {
+        let offset = layout.fat_start + fat * layout.fat_bytes + layout.entry_offset(cluster);
+        match layout.kind {
+            FatKind::Fat12 => {
+                // 12-bit entries share a byte with their neighbour
+                let mut b = [0u8; 2];
+                read_at(disk, offset, &mut b)?;
+                let old = u16::from_le_bytes(b);
+                let new = if cluster & 1 == 1 {
+                    (old & 0x000F) | ((value as u16 & 0xFFF) << 4)
+                } else {
+                    (old & 0xF000) | (value as u16 & 0xFFF)
+                };
+                write_at(disk, offset, &new.to_le_bytes())?;
+            }
+            FatKind::Fat16 => {
+                write_at(disk, offset, &(value as u16).to_le_bytes())?;
+            }
+            FatKind::Fat32 => {
+                // The top four bits are reserved and must be preserved
+                let mut b = [0u8; 4];
+                read_at(disk, offset, &mut b)?;
+                let old = u32::from_le_bytes(b);
+                let new = (old & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                write_at(disk, offset, &new.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Collect the clusters of a chain, stopping at end-of-chain or anything
+/// invalid. Never returns more than `cluster_count` entries so loops end.
+pub fn chain<D: Read + Seek>(disk: &mut D, layout: &Layout, first: u32) -> Result<Vec<u32>, FatError> {
+    let mut clusters = Vec::new();
+    let mut cluster = first;
+    while layout.is_valid_cluster(cluster) && clusters.len() < layout.cluster_count as usize {
+        clusters.push(cluster);
+        let next = read_entry(disk, layout, cluster)?;
+        if layout.is_end_of_chain(next) {
+            break;
+        }
+        cluster = next;
+    }
+    Ok(clusters)
+}
+
+/// Read every live entry of a directory, skipping deleted entries and the volume label.
+pub fn read_dir<D: Read + Seek>(disk: &mut D, layout: &Layout, dir: DirLocation) -> Result<Vec<RawEntry>, FatError> {
+    let regions: Vec<(u64, u64)> = match dir {
+        DirLocation::FixedRoot => vec![(layout.root_dir_start, layout.root_dir_entries as u64 * DIR_ENTRY_SIZE)],
+        DirLocation::Cluster(first) => chain(disk, layout, first)?
+            .into_iter()
+            .map(|c| (layout.cluster_offset(c), layout.cluster_bytes))
+            .collect(),
+    };
+
+    let mut entries = Vec::new();
+    let mut lfn: Vec<(u8, u8, [u16; 13])> = Vec::new();
+    let mut raw = [0u8; DIR_ENTRY_SIZE as usize];
+
+    for (start, len) in regions {
+        let mut pos = 0;
+        while pos < len {
+            let offset = start + pos;
+            pos += DIR_ENTRY_SIZE;
+            read_at(disk, offset, &mut raw)?;
+
+            match raw[0] {
+                ENTRY_END => return Ok(entries),
+                ENTRY_DELETED => { lfn.clear(); continue; }
+                _ => {}
+            }
+
+            let attr = raw[11];
+            if attr & 0x3F == ATTR_LFN {
+                let mut part = [0u16; 13];
+                let ranges = [(1, 5), (14, 6), (28, 2)];
+                let mut i = 0;
+                for &(at, count) in &ranges {
+                    for k in 0..count {
+                        let o = at + k * 2;
+                        part[i] = u16::from_le_bytes([raw[o], raw[o + 1]]);
+                        i += 1;
+                    }
+                }
+                lfn.push((raw[0] & 0x1F, raw[13], part));
+                continue;
+            }
+            if attr & ATTR_VOLUME_ID != 0 {
+                lfn.clear();
+                continue;
+            }
+
+            let mut short_name = [0u8; 11];
+            short_name.copy_from_slice(&raw[..11]);
+            // 0x05 stands in for a real 0xE5 first byte
+            if short_name[0] == 0x05 {
+                short_name[0] = 0xE5;
+            }
+            let hi = u16::from_le_bytes([raw[20], raw[21]]) as u32;
+            let lo = u16::from_le_bytes([raw[26], raw[27]]) as u32;
+            let first_cluster = if layout.kind == FatKind::Fat32 { (hi << 16) | lo } else { lo };
+
+            entries.push(RawEntry {
+                short_name,
+                long_name: assemble_lfn(&mut lfn, lfn_checksum(&raw[..11])),
+                attr,
+                first_cluster,
+                size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]),
+                offset,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Find the entry for `path`, relative to the volume root. Names match
+/// either the long or the short name, ignoring case.
+pub fn lookup<D: Read + Seek>(disk: &mut D, layout: &Layout, path: &str) -> Result<RawEntry, FatError> {
+    let mut dir = layout.root();
+    let mut found = None;
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+        if let Some(entry) = &found {
+            if !entry.is_dir() {
+                return Err(FatError::NotFound);
+            }
+            // ".." of a first-level directory points at cluster 0, the root
+            dir = if entry.first_cluster == 0 { layout.root() } else { DirLocation::Cluster(entry.first_cluster) };
+        }
+        let entries = read_dir(disk, layout, dir)?;
+        found = Some(entries
+            .into_iter()
+            .find(|e| e.name().eq_ignore_ascii_case(part) || e.short_name().eq_ignore_ascii_case(part))
+            .ok_or(FatError::NotFound)?);
+    }
+    found.ok_or(FatError::NotFound)
+}
+
+fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+/// LFN parts are stored last-first; put them in order and decode the UCS-2 name.
+/// Parts left over from a deleted entry don't match the checksum and are dropped.
+fn assemble_lfn(parts: &mut Vec<(u8, u8, [u16; 13])>, checksum: u8) -> Option<String> {
+    if parts.is_empty() || parts.iter().any(|(_, sum, _)| *sum != checksum) {
+        parts.clear();
+        return None;
+    }
+    parts.sort_by_key(|(order, _, _)| *order);
+    let units: Vec<u16> = parts
+        .iter()
+        .flat_map(|(_, _, p)| p.iter().copied())
+        .take_while(|&u| u != 0x0000 && u != 0xFFFF)
+        .collect();
+    parts.clear();
+    Some(char::decode_utf16(units).map(|c| c.unwrap_or('?')).collect())
+}
+
+/// Point a directory entry at a new first cluster and size
+pub fn update_entry<D: Read + Write + Seek>(disk: &mut D, layout: &Layout, entry: &RawEntry, first_cluster: u32, size: u32) -> Result<(), FatError> {
+    if layout.kind == FatKind::Fat32 {
+        write_at(disk, entry.offset + 20, &((first_cluster >> 16) as u16).to_le_bytes())?;
+    }
+    write_at(disk, entry.offset + 26, &(first_cluster as u16).to_le_bytes())?;
+    write_at(disk, entry.offset + 28, &size.to_le_bytes())
+}
+
+/// Pack a date into the on-disk format, which counts years from 1980
+pub fn encode_date(year: u16, month: u16, day: u16) -> u16 {
+    (year.saturating_sub(1980).min(127) << 9) | (month << 5) | day
+}
+
+/// Pack a time into the on-disk format, which has two-second resolution
+pub fn encode_time(hour: u16, min: u16, sec: u16) -> u16 {
+    (hour << 11) | (min << 5) | (sec / 2)
+}
+
+/// Set the last access date and the modification time of an entry
+pub fn set_times<D: Write + Seek>(disk: &mut D, entry: &RawEntry, date: u16, time: u16) -> Result<(), FatError> {
+    write_at(disk, entry.offset + 18, &date.to_le_bytes())?;
+    write_at(disk, entry.offset + 22, &time.to_le_bytes())?;
+    write_at(disk, entry.offset + 24, &date.to_le_bytes())
+}
+
+/// Mark the FAT32 FSInfo free count as unknown so it gets recomputed
+pub fn invalidate_free_count<D: Write + Seek>(disk: &mut D, layout: &Layout) -> Result<(), FatError> {
+    if layout.kind != FatKind::Fat32 || layout.fs_info_sector == 0 {
+        return Ok(());
+    }
+    let base = layout.fs_info_sector as u64 * layout.bytes_per_sector;
+    write_at(disk, base + 488, &u32::MAX.to_le_bytes())?;
+    write_at(disk, base + 492, &u32::MAX.to_le_bytes())
+}
+
+#[test_case]
+fn test_encode_date_time() {
+    let date = |d: u16| (1980 + (d >> 9), (d >> 5) & 0xF, d & 0x1F);
+    let time = |t: u16| (t >> 11, (t >> 5) & 0x3F, (t & 0x1F) * 2);
+
+    assert_eq!(encode_date(1980, 1, 1), 0x0021);
+    assert_eq!(date(encode_date(2024, 2, 29)), (2024, 2, 29));
+    assert_eq!(date(encode_date(2107, 12, 31)), (2107, 12, 31));
+    // Nothing before the epoch can be stored
+    assert_eq!(encode_date(1979, 6, 15), encode_date(1980, 6, 15));
+
+    assert_eq!(encode_time(0, 0, 0), 0);
+    assert_eq!(time(encode_time(13, 45, 30)), (13, 45, 30));
+    // Odd seconds round down to the two-second step
+    assert_eq!(time(encode_time(23, 59, 59)), (23, 59, 58));
+    assert_eq!(encode_time(12, 0, 1), encode_time(12, 0, 0));
+}
@@ -0,0 +1,282 @@
+//! Offline consistency check for FAT volumes.
+//!
+//! Every chain reachable from the root directory is followed and its clusters
+//! marked. A cluster reached a second time is cross-linked; a cluster that is
+//! allocated in the FAT but never reached is lost. Repairs truncate the later
+//! owner of a cross-linked cluster and return lost clusters to the free pool.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use fatfs::{Read, Seek, Write};
+use super::fat::{self, DirLocation, FatKind, Layout, RawEntry};
+
+pub use super::fat::FatError as FsckError;
+
+pub struct FsckReport {
+    pub kind: FatKind,
+    pub cluster_bytes: u64,
+    pub total_clusters: u32,
+    pub used_clusters: u32,
+    pub files: u32,
+    pub dirs: u32,
+    pub lost_clusters: u32,
+    pub cross_links: Vec<String>,
+    pub bad_chains: Vec<String>,
+    pub repaired: u32,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.lost_clusters == 0 && self.cross_links.is_empty() && self.bad_chains.is_empty()
+    }
+}
+
+/// One bit per cluster: set once some chain has claimed it
+struct ClusterMap(Vec<u8>);
+
+impl ClusterMap {
+    fn new(clusters: u32) -> Self {
+        ClusterMap(vec![0u8; (clusters as usize + 8) / 8])
+    }
+
+    fn get(&self, cluster: u32) -> bool {
+        self.0[cluster as usize / 8] & (1 << (cluster % 8)) != 0
+    }
+
+    fn set(&mut self, cluster: u32) {
+        self.0[cluster as usize / 8] |= 1 << (cluster % 8);
+    }
+}
+
+struct Checker<'a, D: Read + Write + Seek> {
+    disk: &'a mut D,
+    layout: Layout,
+    owned: ClusterMap,
+    repair: bool,
+    report: FsckReport,
+}
+
+pub fn check<D: Read + Write + Seek>(disk: &mut D, repair: bool) -> Result<FsckReport, FsckError> {
+    let layout = Layout::read(disk)?;
+    let report = FsckReport {
+        kind: layout.kind,
+        cluster_bytes: layout.cluster_bytes,
+        total_clusters: layout.cluster_count,
+        used_clusters: 0,
+        files: 0,
+        dirs: 0,
+        lost_clusters: 0,
+        cross_links: Vec::new(),
+        bad_chains: Vec::new(),
+        repaired: 0,
+    };
+    let mut checker = Checker {
+        owned: ClusterMap::new(layout.max_cluster() + 1),
+        disk,
+        layout,
+        repair,
+        report,
+    };
+
+    checker.walk_tree()?;
+    checker.find_lost()?;
+
+    if checker.report.repaired > 0 {
+        fat::invalidate_free_count(checker.disk, &checker.layout)?;
+    }
+    Ok(checker.report)
+}
+
+impl<'a, D: Read + Write + Seek> Checker<'a, D> {
+    fn walk_tree(&mut self) -> Result<(), FsckError> {
+        let root = self.layout.root();
+        if let DirLocation::Cluster(first) = root {
+            // The FAT32 root has a chain of its own but no directory entry
+            for cluster in fat::chain(self.disk, &self.layout, first)? {
+                self.owned.set(cluster);
+                self.report.used_clusters += 1;
+            }
+        }
+
+        let mut pending: Vec<(String, DirLocation)> = vec![(String::from(""), root)];
+        while let Some((path, dir)) = pending.pop() {
+            for entry in fat::read_dir(self.disk, &self.layout, dir)? {
+                if entry.is_dot() {
+                    continue;
+                }
+                let entry_path = alloc::format!("{}/{}", path, entry.name());
+                if entry.is_dir() {
+                    self.report.dirs += 1;
+                } else {
+                    self.report.files += 1;
+                }
+
+                if entry.first_cluster == 0 {
+                    if entry.is_dir() || entry.size != 0 {
+                        self.bad(&entry_path, "has no clusters");
+                        if self.repair && !entry.is_dir() {
+                            fat::update_entry(self.disk, &self.layout, &entry, 0, 0)?;
+                            self.report.repaired += 1;
+                        }
+                    }
+                    continue;
+                }
+
+                let kept = self.claim_chain(&entry_path, &entry)?;
+                if entry.is_dir() && kept > 0 {
+                    pending.push((entry_path, DirLocation::Cluster(entry.first_cluster)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Follow and mark an entry's chain. Returns how many clusters it keeps.
+    fn claim_chain(&mut self, path: &str, entry: &RawEntry) -> Result<u32, FsckError> {
+        let needed = if entry.is_dir() {
+            u32::MAX
+        } else {
+            ((entry.size as u64 + self.layout.cluster_bytes - 1) / self.layout.cluster_bytes) as u32
+        };
+
+        let mut prev: Option<u32> = None;
+        let mut cluster = entry.first_cluster;
+        let mut count = 0u32;
+
+        loop {
+            if !self.layout.is_valid_cluster(cluster) {
+                self.bad(path, "points outside the volume");
+                self.truncate(entry, prev, count)?;
+                return Ok(count);
+            }
+            if self.owned.get(cluster) {
+                self.report.cross_links.push(alloc::format!("{} (cluster {})", path, cluster));
+                self.truncate(entry, prev, count)?;
+                return Ok(count);
+            }
+            if count == needed {
+                // File chain is longer than its size says; the tail is unreachable
+                self.bad(path, "chain is longer than the file");
+                self.truncate(entry, prev, count)?;
+                return Ok(count);
+            }
+
+            self.owned.set(cluster);
+            self.report.used_clusters += 1;
+            count += 1;
+
+            let next = fat::read_entry(self.disk, &self.layout, cluster)?;
+            if self.layout.is_end_of_chain(next) {
+                break;
+            }
+            if next == 0 || self.layout.is_bad(next) {
+                self.bad(path, "chain runs into a free or bad cluster");
+                self.truncate(entry, Some(cluster), count)?;
+                return Ok(count);
+            }
+            prev = Some(cluster);
+            cluster = next;
+        }
+
+        if !entry.is_dir() && count < needed {
+            self.bad(path, "file is larger than its chain");
+            self.truncate(entry, None, count)?;
+        }
+        Ok(count)
+    }
+
+    /// End a chain after `last` (or drop it entirely) and shrink the file size
+    /// to match the clusters that are kept.
+    fn truncate(&mut self, entry: &RawEntry, last: Option<u32>, kept: u32) -> Result<(), FsckError> {
+        if !self.repair {
+            return Ok(());
+        }
+        if let Some(cluster) = last {
+            fat::write_entry(self.disk, &self.layout, cluster, self.layout.end_of_chain())?;
+        }
+        let first = if kept == 0 { 0 } else { entry.first_cluster };
+        let size = if entry.is_dir() {
+            0
+        } else {
+            (entry.size as u64).min(kept as u64 * self.layout.cluster_bytes) as u32
+        };
+        if first != entry.first_cluster || size != entry.size {
+            fat::update_entry(self.disk, &self.layout, entry, first, size)?;
+        }
+        self.report.repaired += 1;
+        Ok(())
+    }
+
+    fn find_lost(&mut self) -> Result<(), FsckError> {
+        for cluster in 2..=self.layout.max_cluster() {
+            if self.owned.get(cluster) {
+                continue;
+            }
+            let value = fat::read_entry(self.disk, &self.layout, cluster)?;
+            if value == 0 || self.layout.is_bad(value) {
+                continue;
+            }
+            self.report.lost_clusters += 1;
+            if self.repair {
+                fat::write_entry(self.disk, &self.layout, cluster, 0)?;
+                self.report.repaired += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn bad(&mut self, path: &str, problem: &str) {
+        self.report.bad_chains.push(alloc::format!("{}: {}", path, problem));
+    }
+}
+
+#[test_case]
+fn test_check_cross_link_and_lost_cluster() {
+    use fatfs::{FatType, FormatVolumeOptions};
+    use super::block::RamDisk;
+    use super::cache::CachedDevice;
+
+    let mut disk = CachedDevice::new(RamDisk::new(8192));
+    let options = FormatVolumeOptions::new().fat_type(FatType::Fat16).bytes_per_cluster(512);
+    fatfs::format_volume(&mut disk, options).unwrap();
+    let layout = Layout::read(&mut disk).unwrap();
+    let eoc = layout.end_of_chain();
+    let cluster = layout.cluster_bytes as u32;
+
+    // A.TXT owns 2 -> 3, B.TXT runs 4 -> 3 into it, and 10 belongs to no one
+    let mut slot = layout.root_dir_start;
+    for &(name, first) in &[(b"A       TXT", 2u16), (b"B       TXT", 4)] {
+        let mut raw = [0u8; 32];
+        fat::read_at(&mut disk, slot, &mut raw).unwrap();
+        while raw[0] != 0 {
+            slot += 32;
+            fat::read_at(&mut disk, slot, &mut raw).unwrap();
+        }
+        raw[..11].copy_from_slice(name);
+        raw[11] = fat::ATTR_ARCHIVE;
+        raw[26..28].copy_from_slice(&first.to_le_bytes());
+        raw[28..32].copy_from_slice(&(2 * cluster).to_le_bytes());
+        fat::write_at(&mut disk, slot, &raw).unwrap();
+    }
+    for &(from, to) in &[(2, 3), (3, eoc), (4, 3), (10, eoc)] {
+        fat::write_entry(&mut disk, &layout, from, to).unwrap();
+    }
+
+    let report = check(&mut disk, false).unwrap();
+    assert_eq!(report.files, 2);
+    assert_eq!(report.cross_links.len(), 1);
+    assert!(report.cross_links[0].contains("B.TXT"));
+    assert_eq!(report.lost_clusters, 1);
+    assert_eq!(report.repaired, 0);
+    assert_eq!(fat::read_entry(&mut disk, &layout, 10).unwrap(), eoc);
+
+    let report = check(&mut disk, true).unwrap();
+    assert_eq!(report.repaired, 2);
+    assert!(check(&mut disk, false).unwrap().is_clean());
+    // B.TXT keeps its first cluster and shrinks to match
+    assert_eq!(fat::read_entry(&mut disk, &layout, 4).unwrap(), eoc);
+    assert_eq!(fat::read_entry(&mut disk, &layout, 10).unwrap(), 0);
+    let b = fat::lookup(&mut disk, &layout, "B.TXT").unwrap();
+    assert_eq!((b.first_cluster, b.size), (4, cluster));
+}
@@ -0,0 +1,220 @@
+//! Loop devices: image files on the root FAT volume used as disks.
+//!
+//! The image's cluster chain is resolved once when it is attached, so sector
+//! I/O goes straight to the main disk through its cache instead of through
+//! fatfs. That only holds while the file keeps its clusters, which is why
+//! `fs` refuses to rewrite, rename or delete an attached image.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fatfs::{Read, Seek};
+use spin::Mutex;
+use super::block::{check_range, BlockDevice, BlockError};
+use super::cache::{CachePolicy, CachedDevice};
+use super::fat::{self, FatError};
+use super::virtio_fs::{Disk, DiskHandle};
+
+pub const MAX_LOOPS: usize = 8;
+
+/// Loop caches write straight through to the main disk cache, which already
+/// buffers writes, so they only need to be big enough for metadata reads.
+const LOOP_CACHE_SECTORS: usize = 32;
+
+/// Attached loop devices, indexed by unit number
+pub static LOOPS: Mutex<Vec<Option<LoopUnit>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopError {
+    NoVolume,
+    NotOnRoot,
+    NotFound,
+    IsDir,
+    TooSmall,
+    Busy,
+    NoFreeUnit,
+    NotDir,
+    NoFilesystem,
+    Io,
+}
+
+pub struct LoopUnit {
+    pub path: String,
+    /// Detach when the filesystem on it is unmounted, as `mount -o loop` does
+    pub autoclear: bool,
+    disk: Disk,
+}
+
+impl LoopUnit {
+    pub fn disk(&mut self) -> &mut Disk {
+        &mut self.disk
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.disk.capacity_bytes() / 512
+    }
+}
+
+/// Block device backed by the clusters of one file
+pub struct LoopDevice {
+    /// (image offset, disk offset, length) of each contiguous run, in bytes
+    extents: Vec<(u64, u64, u64)>,
+    sectors: u64,
+}
+
+impl LoopDevice {
+    /// Map the file at `path` on the FAT volume `volume`, which starts at
+    /// byte `volume_start` of the main disk.
+    pub fn open<D: Read + Seek>(volume: &mut D, volume_start: u64, path: &str) -> Result<Self, LoopError> {
+        let layout = fat::Layout::read(volume).map_err(|_| LoopError::NoVolume)?;
+        let entry = fat::lookup(volume, &layout, path).map_err(|e| match e {
+            FatError::NotFound => LoopError::NotFound,
+            _ => LoopError::Io,
+        })?;
+        if entry.is_dir() {
+            return Err(LoopError::IsDir);
+        }
+        let size = entry.size as u64 / 512 * 512;
+        if size == 0 {
+            return Err(LoopError::TooSmall);
+        }
+
+        let clusters = fat::chain(volume, &layout, entry.first_cluster).map_err(|_| LoopError::Io)?;
+        if (clusters.len() as u64) * layout.cluster_bytes < size {
+            // The chain is shorter than the file claims, fsck would flag it
+            return Err(LoopError::Io);
+        }
+
+        let mut extents: Vec<(u64, u64, u64)> = Vec::new();
+        let mut image_pos = 0;
+        for cluster in clusters {
+            if image_pos >= size {
+                break;
+            }
+            let disk_pos = volume_start + layout.cluster_offset(cluster);
+            let len = layout.cluster_bytes.min(size - image_pos);
+            match extents.last_mut() {
+                Some((_, start, run)) if *start + *run == disk_pos => *run += len,
+                _ => extents.push((image_pos, disk_pos, len)),
+            }
+            image_pos += len;
+        }
+        Ok(LoopDevice { extents, sectors: size / 512 })
+    }
+
+    /// Split a request into (disk offset, buffer offset, length) pieces
+    fn pieces(&self, sector: u64, len: usize) -> Vec<(u64, usize, usize)> {
+        let mut pieces = Vec::new();
+        let mut pos = sector * 512;
+        let end = pos + len as u64;
+        let first = self.extents.partition_point(|&(start, _, run)| start + run <= pos);
+        for &(start, disk, run) in &self.extents[first..] {
+            if pos >= end {
+                break;
+            }
+            let n = (start + run - pos).min(end - pos);
+            pieces.push((disk + pos - start, (pos - sector * 512) as usize, n as usize));
+            pos += n;
+        }
+        pieces
+    }
+
+    pub fn extent_count(&self) -> usize {
+        self.extents.len()
+    }
+}
+
+impl BlockDevice for LoopDevice {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(&*self, sector, buf.len())?;
+        let mut disk = DiskHandle::new();
+        for (offset, at, len) in self.pieces(sector, buf.len()) {
+            fat::read_at(&mut disk, offset, &mut buf[at..at + len]).map_err(|_| BlockError::Io)?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(&*self, sector, buf.len())?;
+        let mut disk = DiskHandle::new();
+        for (offset, at, len) in self.pieces(sector, buf.len()) {
+            fat::write_at(&mut disk, offset, &buf[at..at + len]).map_err(|_| BlockError::Io)?;
+        }
+        Ok(())
+    }
+
+    fn max_transfer(&self) -> usize {
+        usize::MAX / 512
+    }
+
+    fn name(&self) -> &str {
+        "loop"
+    }
+}
+
+fn same_path(a: &str, b: &str) -> bool {
+    a.trim_start_matches('/').eq_ignore_ascii_case(b.trim_start_matches('/'))
+}
+
+/// Put `dev` in a free unit and return its number
+pub fn attach(dev: LoopDevice, path: &str, autoclear: bool) -> Result<usize, LoopError> {
+    let mut loops = LOOPS.lock();
+    if loops.iter().flatten().any(|l| same_path(&l.path, path)) {
+        return Err(LoopError::Busy);
+    }
+    let unit = match loops.iter().position(|l| l.is_none()) {
+        Some(unit) => unit,
+        None if loops.len() < MAX_LOOPS => {
+            loops.push(None);
+            loops.len() - 1
+        }
+        None => return Err(LoopError::NoFreeUnit),
+    };
+    let mut disk = CachedDevice::new(Box::new(dev) as Box<dyn BlockDevice>);
+    disk.configure(LOOP_CACHE_SECTORS, CachePolicy::WriteThrough).map_err(|_| LoopError::Io)?;
+    loops[unit] = Some(LoopUnit { path: String::from(path), autoclear, disk });
+    Ok(unit)
+}
+
+/// Free `unit`. The caller makes sure nothing is mounted from it.
+pub fn detach(unit: usize) -> Result<(), LoopError> {
+    let mut loops = LOOPS.lock();
+    let mut lo = loops.get_mut(unit).and_then(|l| l.take()).ok_or(LoopError::NotFound)?;
+    lo.disk.flush_cache().map_err(|_| LoopError::Io)
+}
+
+/// Unit number, image path and size in sectors of every attached device
+pub fn list() -> Vec<(usize, String, u64)> {
+    LOOPS.lock().iter().enumerate()
+        .filter_map(|(unit, l)| l.as_ref().map(|l| (unit, l.path.clone(), l.sectors())))
+        .collect()
+}
+
+pub fn is_attached(path: &str) -> bool {
+    LOOPS.lock().iter().flatten().any(|l| same_path(&l.path, path))
+}
+
+pub fn any_attached() -> bool {
+    LOOPS.lock().iter().any(|l| l.is_some())
+}
+
+pub fn autoclear(unit: usize) -> bool {
+    LOOPS.lock().get(unit).and_then(|l| l.as_ref()).map_or(false, |l| l.autoclear)
+}
+
+/// Parse "loop0" or "/dev/loop0"
+pub fn parse_unit(name: &str) -> Option<usize> {
+    name.trim_start_matches("/dev/").strip_prefix("loop")?.parse().ok()
+}
+
+#[test_case]
+fn test_loop_extents() {
+    let dev = LoopDevice { extents: alloc::vec![(0, 8192, 2048), (2048, 1024, 1024)], sectors: 6 };
+    assert_eq!(dev.pieces(0, 512), alloc::vec![(8192, 0, 512)]);
+    assert_eq!(dev.pieces(3, 1536), alloc::vec![(9728, 0, 512), (1024, 512, 1024)]);
+    assert_eq!(dev.pieces(5, 512), alloc::vec![(1536, 0, 512)]);
+}
@@ -1,16 +1,28 @@
 pub mod virtio_fs;
+pub mod cache;
+pub mod block;
+pub mod fat;
+pub mod fsck;
+pub mod partition;
+pub mod ext2;
+pub mod tar;
+pub mod loopdev;
+pub mod path;
 
+use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use core::sync::atomic::Ordering;
-use fatfs::{FileSystem, FsOptions, FormatVolumeOptions, Read, Write, Seek, TimeProvider, Date, Time, DateTime};
+use fatfs::{FileSystem, FsOptions, FormatVolumeOptions, FatType, Read, Write, Seek, TimeProvider, Date, Time, DateTime};
 use spin::Mutex;
 use virtio_drivers::device::blk::VirtIOBlk;
 use virtio_drivers::transport::pci::PciTransport;
 use crate::device::virtio_hal::OsHal;
 use crate::serial_println;
-use virtio_fs::VirtioBlockDevice;
-use crate::fs::virtio_fs::{R_CALLS, R_CYCLES, W_CALLS, W_CYCLES};
+use virtio_fs::{VirtioBlk, DiskHandle, DISK};
+use block::BlockDevice;
+use cache::{CachePolicy, CachedDevice};
+use partition::{Partition, PartitionEntry, PartitionTable};
+use loopdev::{LoopDevice, LoopError};
 
 #[derive(Debug)]
 pub struct RtcTimeProvider;
@@ -37,49 +49,483 @@ pub struct DirEntry {
     pub modified: (u16, u16, u16, u16, u16, u16),
 }
 
-type Fs = FileSystem<VirtioBlockDevice, RtcTimeProvider>;
+/// Everything `stat` reports about a file or directory. Times are
+/// (year, month, day, hour, minute, second) and all zero where unknown.
+pub struct FileStat {
+    pub name: String,
+    /// 8.3 name, empty on ext2
+    pub short_name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// FAT attribute bits, see `fat::ATTR_*`
+    pub attributes: u8,
+    pub created: (u16, u16, u16, u16, u16, u16),
+    /// FAT only keeps the date of the last access
+    pub accessed: (u16, u16, u16),
+    pub modified: (u16, u16, u16, u16, u16, u16),
+    /// First data cluster on FAT, inode number on ext2
+    pub first_cluster: u32,
+}
+
+/// Size and free space of one mounted filesystem, as `df` shows it
+pub struct FsUsage {
+    /// Name as `lsblk` shows it: "disk", "p1" or "loop0"
+    pub device: String,
+    pub kind: &'static str,
+    pub mount: String,
+    /// Allocation unit: the cluster on FAT, the block on ext2
+    pub block_size: u64,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+}
+
+/// Options for `format_disk`. `None` lets fatfs pick based on the disk size.
+#[derive(Default)]
+pub struct FormatOptions {
+    pub fat_type: Option<FatType>,
+    pub label: Option<String>,
+    pub bytes_per_cluster: Option<u32>,
+}
+
+type Fs = FileSystem<Partition<DiskHandle>, RtcTimeProvider>;
 
 pub static FS: Mutex<Option<Fs>> = Mutex::new(None);
 
+type Ext2 = ext2::Ext2Fs<Partition<DiskHandle>>;
+
+/// Read-only ext2 volume, mounted instead of `FS` when the volume holds one
+static EXT2: Mutex<Option<Ext2>> = Mutex::new(None);
+
+/// Byte range of the disk holding the volume: the whole disk or one partition,
+/// plus the partition number if it is one.
+static VOLUME: Mutex<(u64, u64, Option<usize>)> = Mutex::new((0, 0, None));
+
+/// FAT filesystem on a loop device, mounted on a directory of the root volume
+struct MountPoint {
+    /// Mount directory without the leading slash, e.g. "mnt"
+    dir: String,
+    unit: usize,
+    /// Byte range of the filesystem on the loop device
+    start: u64,
+    len: u64,
+    fs: Fs,
+}
+
+static MOUNTS: Mutex<Vec<MountPoint>> = Mutex::new(Vec::new());
+
 lazy_static::lazy_static! {
     static ref CURRENT_DIR: Mutex<String> = Mutex::new(String::from("/"));
 }
 
 pub fn init(blk: VirtIOBlk<OsHal, PciTransport>) {
-    let mut dev = VirtioBlockDevice::new(blk);
+    init_device(Box::new(VirtioBlk::new(blk)));
+}
+
+/// Put `backend` behind the sector cache and mount what is on it
+pub fn init_device(backend: Box<dyn BlockDevice>) {
+    serial_println!("[fs] Disk: {} ({} sectors)", backend.name(), backend.sector_count());
+    let mut dev = CachedDevice::new(backend);
 
     let mut buf = [0u8; 512];
     dev.read(&mut buf).expect("failed to read sector 0");
     dev.seek(fatfs::SeekFrom::Start(0)).expect("seek failed");
+    let capacity = dev.seek(fatfs::SeekFrom::End(0)).expect("seek failed");
+    dev.seek(fatfs::SeekFrom::Start(0)).expect("seek failed");
+    *DISK.lock() = Some(dev);
+    *VOLUME.lock() = (0, capacity, None);
 
     let sig = u16::from_le_bytes([buf[510], buf[511]]);
 
+    // A bare ext2 volume has no boot signature in sector 0
+    if sig != 0xAA55 && ext2::probe(&mut volume_io()) {
+        serial_println!("[fs] ext2 filesystem found, mounting.");
+        mount();
+        return;
+    }
+
     if sig != 0xAA55 {
-        serial_println!("[fs] No filesystem found, formatting...");
-        fatfs::format_volume(&mut dev, FormatVolumeOptions::new())
-            .expect("format failed");
-        serial_println!("[fs] Formatted.");
-    } else {
-        serial_println!("[fs] Filesystem found, mounting.");
+        // Never format an unknown disk behind the user's back
+        serial_println!("[fs] No filesystem found, run `mkfs -y` to format the disk");
+        crate::println!("No filesystem found on disk, run `mkfs -y` to format it");
+        return;
     }
 
-    let fs = FileSystem::new(dev, FsOptions::new().time_provider(RtcTimeProvider))
-        .expect("mount failed");
-    *FS.lock() = Some(fs);
-    serial_println!("[fs] Mounted.");
+    if let Some(table) = partition_table() {
+        serial_println!("[fs] {:?} partition table, {} partitions", table.kind, table.partitions.len());
+        match table.partitions.iter().find(|p| p.is_fat() || p.is_linux()) {
+            Some(part) => {
+                serial_println!("[fs] Using partition {} ({})", part.number, part.type_name());
+                *VOLUME.lock() = (part.start_bytes(), part.len_bytes(), Some(part.number));
+            }
+            None => {
+                serial_println!("[fs] No FAT or Linux partition found");
+                crate::println!("No FAT or ext2 partition found, see `lsblk`");
+                return;
+            }
+        }
+    }
+
+    serial_println!("[fs] Filesystem found, mounting.");
+    mount();
 }
 
-pub fn resolve_path(path: &str) -> String {
-    if path.starts_with('/') {
-        path.to_string()
-    } else {
-        let current = CURRENT_DIR.lock().clone();
-        if current == "/" {
-            path.to_string()
-        } else {
-            alloc::format!("{}/{}", current, path)
+/// The volume as fatfs and the FAT tools see it
+fn volume_io() -> Partition<DiskHandle> {
+    let (start, len, _) = *VOLUME.lock();
+    Partition::new(DiskHandle::new(), start, len)
+}
+
+/// Size of the whole disk in bytes, 0 without a disk
+pub fn disk_capacity() -> u64 {
+    let mut disk = DiskHandle::new();
+    disk.seek(fatfs::SeekFrom::End(0)).unwrap_or(0)
+}
+
+pub fn partition_table() -> Option<PartitionTable> {
+    partition::read_table(&mut DiskHandle::new())
+}
+
+/// Partition number the mounted volume lives on, None for a bare disk
+pub fn current_partition() -> Option<usize> {
+    VOLUME.lock().2
+}
+
+/// Unmount the current volume and mount partition `number` in its place.
+/// If the partition doesn't mount, the previous volume is mounted again.
+pub fn mount_partition(number: usize) -> bool {
+    let part: PartitionEntry = match partition_table()
+        .and_then(|t| t.partitions.into_iter().find(|p| p.number == number))
+    {
+        Some(p) => p,
+        None => return false,
+    };
+    let was_mounted = is_mounted();
+    if !unmount() {
+        return false;
+    }
+    let previous = core::mem::replace(
+        &mut *VOLUME.lock(),
+        (part.start_bytes(), part.len_bytes(), Some(part.number)),
+    );
+    if mount() {
+        return true;
+    }
+    *VOLUME.lock() = previous;
+    if was_mounted && !mount() {
+        serial_println!("[fs] Could not mount the previous volume again");
+    }
+    false
+}
+
+pub fn is_mounted() -> bool {
+    FS.lock().is_some() || EXT2.lock().is_some()
+}
+
+/// True when the mounted volume is ext2 and therefore read-only
+pub fn is_read_only() -> bool {
+    EXT2.lock().is_some()
+}
+
+/// Mount the volume on `DISK`. Returns false if it holds neither a FAT nor
+/// an ext2 filesystem.
+pub fn mount() -> bool {
+    let mut guard = FS.lock();
+    if guard.is_some() || EXT2.lock().is_some() {
+        return true;
+    }
+    let mut io = volume_io();
+    if ext2::probe(&mut io) {
+        return match ext2::Ext2Fs::mount(io) {
+            Ok(fs) => {
+                set_read_ahead(fs.block_size());
+                *EXT2.lock() = Some(fs);
+                *CURRENT_DIR.lock() = String::from("/");
+                serial_println!("[fs] Mounted ext2 (read-only).");
+                true
+            }
+            Err(e) => {
+                serial_println!("[fs] ext2 mount failed: {:?}", e);
+                false
+            }
+        };
+    }
+    match FileSystem::new(volume_io(), FsOptions::new().time_provider(RtcTimeProvider)) {
+        Ok(fs) => {
+            if let Ok(layout) = fat::Layout::read(&mut volume_io()) {
+                set_read_ahead(layout.cluster_bytes);
+            }
+            *guard = Some(fs);
+            *CURRENT_DIR.lock() = String::from("/");
+            serial_println!("[fs] Mounted.");
+            true
         }
+        Err(e) => {
+            serial_println!("[fs] mount failed: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Read whole clusters (or ext2 blocks) per cache miss
+fn set_read_ahead(bytes: u64) {
+    if let Some(dev) = DISK.lock().as_mut() {
+        dev.set_read_ahead((bytes / 512) as usize);
+    }
+}
+
+/// Unmount the volume and write back everything cached for it.
+pub fn unmount() -> bool {
+    if loopdev::any_attached() {
+        serial_println!("[fs] unmount: loop devices are still attached");
+        return false;
+    }
+    if EXT2.lock().take().is_some() {
+        *CURRENT_DIR.lock() = String::from("/");
+        serial_println!("[fs] Unmounted.");
+        return true;
+    }
+    let fs = match FS.lock().take() {
+        Some(fs) => fs,
+        None => return true,
+    };
+    let mut ok = true;
+    if let Err(e) = fs.unmount() {
+        serial_println!("[fs] unmount failed: {:?}", e);
+        ok = false;
+    }
+    if let Some(dev) = DISK.lock().as_mut() {
+        ok &= dev.flush_cache().is_ok();
+    }
+    *CURRENT_DIR.lock() = String::from("/");
+    serial_println!("[fs] Unmounted.");
+    ok
+}
+
+/// Attach the image file at `path` on the root volume as a loop device
+pub fn attach_loop(path: &str, autoclear: bool) -> Result<usize, LoopError> {
+    let path = resolve_path(path);
+    if FS.lock().is_none() {
+        return Err(LoopError::NoVolume);
+    }
+    if mount_of(&MOUNTS.lock(), &path).is_some() {
+        return Err(LoopError::NotOnRoot);
     }
+    let start = VOLUME.lock().0;
+    let dev = LoopDevice::open(&mut volume_io(), start, &path)?;
+    serial_println!("[fs] loop: {} ({} sectors, {} extents)", path, dev.sector_count(), dev.extent_count());
+    loopdev::attach(dev, &path, autoclear)
+}
+
+pub fn detach_loop(unit: usize) -> Result<(), LoopError> {
+    if MOUNTS.lock().iter().any(|m| m.unit == unit) {
+        return Err(LoopError::Busy);
+    }
+    loopdev::detach(unit)
+}
+
+/// Mount the FAT filesystem on loop device `unit` on directory `dir`. A
+/// partitioned image uses its first FAT partition.
+pub fn mount_loop(unit: usize, dir: &str) -> Result<(), LoopError> {
+    let path = resolve_path(dir);
+    let key = String::from(path.trim_matches('/'));
+    {
+        let mounts = MOUNTS.lock();
+        if mounts.iter().any(|m| m.unit == unit || m.dir.eq_ignore_ascii_case(&key)) {
+            return Err(LoopError::Busy);
+        }
+        if key.is_empty() || mount_of(&mounts, &path).is_some() {
+            return Err(LoopError::NotOnRoot);
+        }
+    }
+    if !is_dir(&path) {
+        return Err(LoopError::NotDir);
+    }
+
+    let mut mounts = MOUNTS.lock();
+    let mut disk = DiskHandle::loop_device(unit);
+    let capacity = disk.seek(fatfs::SeekFrom::End(0)).map_err(|_| LoopError::NotFound)?;
+    let (start, len) = match partition::read_table(&mut disk) {
+        Some(table) => {
+            let part = table.partitions.iter().find(|p| p.is_fat()).ok_or(LoopError::NoFilesystem)?;
+            (part.start_bytes(), part.len_bytes())
+        }
+        None => (0, capacity),
+    };
+    let io = Partition::new(DiskHandle::loop_device(unit), start, len);
+    let fs = FileSystem::new(io, FsOptions::new().time_provider(RtcTimeProvider)).map_err(|e| {
+        serial_println!("[fs] loop{}: mount failed: {:?}", unit, e);
+        LoopError::NoFilesystem
+    })?;
+    serial_println!("[fs] loop{} mounted on /{}", unit, key);
+    mounts.push(MountPoint { dir: key, unit, start, len, fs });
+    Ok(())
+}
+
+/// Unmount the loop filesystem on `dir`, detaching the device if `mount -o
+/// loop` attached it.
+pub fn umount(dir: &str) -> Result<(), LoopError> {
+    let path = resolve_path(dir);
+    let key = path.trim_matches('/');
+    let mount = {
+        let mut mounts = MOUNTS.lock();
+        let index = mounts.iter().position(|m| m.dir.eq_ignore_ascii_case(key)).ok_or(LoopError::NotFound)?;
+        mounts.remove(index)
+    };
+    let unit = mount.unit;
+    let cwd = CURRENT_DIR.lock().clone();
+    if mount_of(core::slice::from_ref(&mount), &cwd).is_some() {
+        *CURRENT_DIR.lock() = String::from("/");
+    }
+    if let Err(e) = mount.fs.unmount() {
+        serial_println!("[fs] loop{}: unmount failed: {:?}", unit, e);
+        return Err(LoopError::Io);
+    }
+    if loopdev::autoclear(unit) {
+        loopdev::detach(unit)?;
+    }
+    serial_println!("[fs] loop{} unmounted", unit);
+    Ok(())
+}
+
+/// (directory, loop unit) of every loop mount
+pub fn loop_mounts() -> Vec<(String, usize)> {
+    MOUNTS.lock().iter().map(|m| (alloc::format!("/{}", m.dir), m.unit)).collect()
+}
+
+fn fat_usage(fs: &Fs, device: String, mount: String) -> Option<FsUsage> {
+    let stats = fs.stats().ok()?;
+    let kind = match fs.fat_type() {
+        FatType::Fat12 => "fat12",
+        FatType::Fat16 => "fat16",
+        FatType::Fat32 => "fat32",
+    };
+    Some(FsUsage {
+        device,
+        kind,
+        mount,
+        block_size: stats.cluster_size() as u64,
+        total_blocks: stats.total_clusters() as u64,
+        free_blocks: stats.free_clusters() as u64,
+    })
+}
+
+/// Usage of the root volume followed by every loop mount
+pub fn usage() -> Vec<FsUsage> {
+    let mut list = Vec::new();
+    let device = match current_partition() {
+        Some(n) => alloc::format!("p{}", n),
+        None => String::from("disk"),
+    };
+    if let Some(ext) = EXT2.lock().as_ref() {
+        list.push(FsUsage {
+            device,
+            kind: "ext2",
+            mount: String::from("/"),
+            block_size: ext.block_size(),
+            total_blocks: ext.blocks_count as u64,
+            free_blocks: ext.free_blocks as u64,
+        });
+    } else if let Some(fs) = FS.lock().as_ref() {
+        list.extend(fat_usage(fs, device, String::from("/")));
+    }
+    for m in MOUNTS.lock().iter() {
+        list.extend(fat_usage(&m.fs, alloc::format!("loop{}", m.unit), alloc::format!("/{}", m.dir)));
+    }
+    list
+}
+
+/// Usage of the filesystem holding `path`
+pub fn usage_of(path: &str) -> Option<FsUsage> {
+    let path = resolve_path(path);
+    let mount = {
+        let mounts = MOUNTS.lock();
+        match mount_of(&mounts, &path) {
+            Some(i) => alloc::format!("/{}", mounts[i].dir),
+            None => String::from("/"),
+        }
+    };
+    usage().into_iter().find(|u| u.mount == mount)
+}
+
+/// Index of the mount holding `path`, None for the root volume
+fn mount_of(mounts: &[MountPoint], path: &str) -> Option<usize> {
+    let path = path.trim_start_matches('/');
+    mounts.iter().position(|m| {
+        let n = m.dir.len();
+        path.get(..n).map_or(false, |head| head.eq_ignore_ascii_case(&m.dir))
+            && (path.len() == n || path.as_bytes()[n] == b'/')
+    })
+}
+
+/// `path` as the filesystem holding it sees it
+fn relative_to_mount(mounts: &[MountPoint], path: &str) -> String {
+    match mount_of(mounts, path) {
+        Some(i) => {
+            let rest = &path.trim_start_matches('/')[mounts[i].dir.len()..];
+            String::from(if rest.is_empty() { "/" } else { rest })
+        }
+        None => path.to_string(),
+    }
+}
+
+/// Run `f` on the FAT filesystem holding `path`, with the path relative to
+/// that filesystem. None when nothing is mounted there.
+fn with_fat<R>(path: &str, f: impl FnOnce(&Fs, &str) -> R) -> Option<R> {
+    with_fat_io(path, |fs, path, _| f(fs, path))
+}
+
+/// `with_fat`, also handing over raw access to the same volume for the
+/// `fat` tools. The filesystem stays locked, so nothing else uses it meanwhile.
+fn with_fat_io<R>(path: &str, f: impl FnOnce(&Fs, &str, Partition<DiskHandle>) -> R) -> Option<R> {
+    {
+        let mounts = MOUNTS.lock();
+        if let Some(i) = mount_of(&mounts, path) {
+            let m = &mounts[i];
+            let io = Partition::new(DiskHandle::loop_device(m.unit), m.start, m.len);
+            return Some(f(&m.fs, &relative_to_mount(&mounts, path), io));
+        }
+    }
+    let guard = FS.lock();
+    guard.as_ref().map(|fs| f(fs, path, volume_io()))
+}
+
+/// An attached image must keep its clusters, so it can't be rewritten or removed
+fn refuse_if_attached(path: &str) -> bool {
+    let attached = loopdev::is_attached(path);
+    if attached {
+        serial_println!("[fs] {} is attached to a loop device", path);
+    }
+    attached
+}
+
+/// Absolute, normalized form of `path`, relative paths being taken from the
+/// current directory and `~` from the configured home directory
+pub fn resolve_path(path: &str) -> String {
+    path::normalize(&joined_path(path))
+}
+
+/// `path` on top of the current directory, with `..` left in for `canonicalize`
+fn joined_path(path: &str) -> String {
+    let cwd = CURRENT_DIR.lock().clone();
+    // Only look at the config when needed: saving it writes a file while it is locked
+    let home = if path.starts_with('~') { crate::CONFIG.lock().home.clone() } else { String::from("/") };
+    path::join(&cwd, path, &home)
+}
+
+/// Whether names must match exactly, as on ext2, rather than ignoring case as FAT does
+pub fn is_case_sensitive() -> bool {
+    EXT2.lock().is_some()
+}
+
+/// Target of the symlink at `path`, None if it is anything else. Only ext2 has them.
+fn read_link(path: &str) -> Option<String> {
+    let mut guard = EXT2.lock();
+    let ext = guard.as_mut()?;
+    let inode = ext.read_inode(ext.lookup(path, false).ok()?).ok()?;
+    if !inode.is_symlink() {
+        return None;
+    }
+    ext.read_link(&inode).ok()
 }
 
 /// Split a resolved path into (parent_dir, filename)
@@ -113,149 +559,311 @@ pub fn get_current_dir() -> String {
     if dir.is_empty() { String::from("/") } else { dir }
 }
 
+/// Change to the directory at `path`, storing its canonical form
 pub fn set_current_dir(path: &str) -> bool {
-    let new_path = if path == ".." {
-        let current = CURRENT_DIR.lock().clone();
-        if current == "/" || current.is_empty() {
-            String::from("/")
+    let new_path = match path::canonicalize(path) {
+        Some(p) => p,
+        None => return false,
+    };
+    if !is_dir(&new_path) {
+        return false;
+    }
+    *CURRENT_DIR.lock() = new_path;
+    true
+}
+
+pub fn read_file(path: &str) -> Option<Vec<u8>> {
+    let path = resolve_path(path);
+    if let Some(ext) = EXT2.lock().as_mut() {
+        return ext.read_file(&path).ok();
+    }
+    with_fat(&path, |fs, path| {
+        let (dir, filename) = split_path(path);
+        let root = fs.root_dir();
+        let parent = if dir.is_empty() || dir == "/" {
+            root
         } else {
-            match current.rfind('/') {
-                Some(0) | None => String::from("/"),
-                Some(pos) => current[..pos].to_string(),
+            root.open_dir(dir).ok()?
+        };
+        let mut file = parent.open_file(filename).ok()?;
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 512];
+        loop {
+            match file.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(_) => return None,
             }
         }
-    } else if path == "/" {
-        String::from("/")
-    } else if path.starts_with('/') {
-        path.to_string()
-    } else {
-        resolve_path(path)
-    };
+        Some(buf)
+    }).flatten()
+}
 
-    if new_path == "/" {
-        *CURRENT_DIR.lock() = String::from("/");
-        return true;
+pub fn write_file(path: &str, data: &[u8]) -> bool {
+    let path = resolve_path(path);
+    if refuse_if_attached(&path) {
+        return false;
     }
+    with_fat(&path, |fs, path| {
+        let (dir, filename) = split_path(path);
+        let root = fs.root_dir();
+        let parent = open_parent!(root, dir);
+        let mut file = match parent.create_file(filename) {
+            Ok(f) => f,
+            Err(e) => {
+                crate::serial_println!("[fs] create_file({}) failed: {:?}", filename, e);
+                return false;
+            }
+        };
+        if file.truncate().is_err() {
+            return false;
+        }
+        let ok = file.write_all(data).is_ok();
+        let _ = file.flush();
+        ok
+    }).unwrap_or(false)
+}
 
-    let exists = {
-        let mut guard = FS.lock();
-        let fs = match guard.as_mut() {
-            Some(fs) => fs,
-            None => return false,
+pub fn append_file(path: &str, data: &[u8]) -> bool {
+    let path = resolve_path(path);
+    with_fat(&path, |fs, path| {
+        let (dir, filename) = split_path(path);
+        let root = fs.root_dir();
+        let parent = open_parent!(root, dir);
+        let mut file = match parent.open_file(filename) {
+            Ok(f) => f,
+            Err(_) => match parent.create_file(filename) {
+                Ok(f) => f,
+                Err(_) => return false,
+            }
         };
+        file.seek(fatfs::SeekFrom::End(0)).ok();
+        let ok = file.write_all(data).is_ok();
+
+        let _ = file.flush();
+        ok
+    }).unwrap_or(false)
+}
+
+pub fn delete_file(path: &str) -> bool {
+    let path = resolve_path(path);
+    if refuse_if_attached(&path) {
+        return false;
+    }
+    with_fat(&path, |fs, path| {
+        let (dir, filename) = split_path(path);
         let root = fs.root_dir();
-        let result = root.open_dir(&new_path).is_ok();
-        result
-    };
+        let parent = open_parent!(root, dir);
+        parent.remove(filename).is_ok()
+    }).unwrap_or(false)
+}
+
+/// Result of a recursive operation. `ok` counts entries that were handled,
+/// `failed` holds the paths that could not be.
+pub struct TreeResult {
+    pub ok: usize,
+    pub failed: Vec<String>,
+}
+
+impl TreeResult {
+    fn new() -> Self {
+        TreeResult { ok: 0, failed: Vec::new() }
+    }
 
-    if exists {
-        *CURRENT_DIR.lock() = new_path;
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    fn record(&mut self, path: &str, ok: bool) -> bool {
+        if ok {
+            self.ok += 1;
+        } else {
+            self.failed.push(path.to_string());
+        }
+        ok
     }
-    exists
 }
 
-pub fn read_file(path: &str) -> Option<Vec<u8>> {
-    let path = resolve_path(path);
-    let (dir, filename) = split_path(&path);
-    let mut guard = FS.lock();
-    let fs = guard.as_mut()?;
-    let root = fs.root_dir();
-    let parent = if dir.is_empty() || dir == "/" {
-        root
+/// Join a directory and a name without doubling the slash
+pub fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else if dir.ends_with('/') {
+        alloc::format!("{}{}", dir, name)
     } else {
-        root.open_dir(dir).ok()?
-    };
-    let mut file = parent.open_file(filename).ok()?;
-    let mut buf = Vec::new();
-    let mut chunk = [0u8; 512];
-    loop {
-        match file.read(&mut chunk) {
-            Ok(0) => break,
-            Ok(n) => buf.extend_from_slice(&chunk[..n]),
-            Err(_) => return None,
-        }
+        alloc::format!("{}/{}", dir, name)
     }
-    Some(buf)
 }
 
-pub fn write_file(path: &str, data: &[u8]) -> bool {
+/// Last component of a path, ignoring trailing slashes
+pub fn base_name(path: &str) -> &str {
+    let trimmed = path.trim_end_matches('/');
+    split_path(trimmed).1
+}
+
+pub fn exists(path: &str) -> bool {
     let path = resolve_path(path);
-    let (dir, filename) = split_path(&path);
-    let mut guard = FS.lock();
-    let fs = match guard.as_mut() {
-        Some(fs) => fs,
-        None => return false,
-    };
-    let root = fs.root_dir();
-    let parent = open_parent!(root, dir);
-    let mut file = match parent.create_file(filename) {
-        Ok(f) => f,
-        Err(e) => {
-            crate::serial_println!("[fs] create_file({}) failed: {:?}", filename, e);
-            return false;
-        }
-    };
-    if file.truncate().is_err() {
-        return false;
+    if path == "/" {
+        return true;
     }
-    let ok = file.write_all(data).is_ok();
-    let _ = file.flush();
-    ok
+    if let Some(ext) = EXT2.lock().as_mut() {
+        return ext.exists(&path);
+    }
+    with_fat(&path, |fs, path| {
+        if path == "/" {
+            return true;
+        }
+        let (dir, name) = split_path(path);
+        let root = fs.root_dir();
+        let parent = open_parent!(root, dir);
+        let found = parent.iter().flatten().any(|e| e.file_name().eq_ignore_ascii_case(name));
+        found
+    }).unwrap_or(false)
 }
 
-pub fn append_file(path: &str, data: &[u8]) -> bool {
+pub fn is_dir(path: &str) -> bool {
     let path = resolve_path(path);
-    let (dir, filename) = split_path(&path);
-    let mut guard = FS.lock();
-    let fs = match guard.as_mut() {
-        Some(fs) => fs,
-        None => return false,
-    };
-    let root = fs.root_dir();
-    let parent = open_parent!(root, dir);
-    let mut file = match parent.open_file(filename) {
-        Ok(f) => f,
-        Err(_) => match parent.create_file(filename) {
-            Ok(f) => f,
-            Err(_) => return false,
+    if path == "/" {
+        return true;
+    }
+    if let Some(ext) = EXT2.lock().as_mut() {
+        return ext.is_dir(&path);
+    }
+    with_fat(&path, |fs, path| path == "/" || fs.root_dir().open_dir(path).is_ok()).unwrap_or(false)
+}
+
+/// Whether `path` lies below the directory `dir`. FAT names ignore case, so
+/// there the comparison does too.
+fn is_below(path: &str, dir: &str) -> bool {
+    let fat = EXT2.lock().is_none() || mount_of(&MOUNTS.lock(), path).is_some();
+    match path.get(..dir.len()) {
+        Some(head) if path.as_bytes().get(dir.len()) == Some(&b'/') => {
+            if fat { head.eq_ignore_ascii_case(dir) } else { head == dir }
+        }
+        _ => false,
+    }
+}
+
+/// Rename or move a file or directory in place using the FAT directory
+/// entries, so no data is copied.
+pub fn rename(src: &str, dst: &str) -> bool {
+    let src = resolve_path(src);
+    let dst = resolve_path(dst);
+    let src_trimmed = src.trim_end_matches('/');
+    let dst_trimmed = dst.trim_end_matches('/');
+
+    // Moving a directory into itself would orphan the whole subtree
+    if dst_trimmed == src_trimmed || is_below(dst_trimmed, src_trimmed) {
+        serial_println!("[fs] rename: {} is inside {}", dst, src);
+        return false;
+    }
+
+    if refuse_if_attached(src_trimmed) {
+        return false;
+    }
+    let (src_rel, dst_rel) = {
+        let mounts = MOUNTS.lock();
+        if mount_of(&mounts, src_trimmed) != mount_of(&mounts, dst_trimmed) {
+            serial_println!("[fs] rename: {} and {} are on different filesystems", src, dst);
+            return false;
         }
+        (relative_to_mount(&mounts, src_trimmed), relative_to_mount(&mounts, dst_trimmed))
     };
-    file.seek(fatfs::SeekFrom::End(0)).ok();
-    let ok = file.write_all(data).is_ok();
 
-    let _ = file.flush();
-    ok
+    let (src_dir, src_name) = split_path(&src_rel);
+    let (dst_dir, dst_name) = split_path(&dst_rel);
+    with_fat(src_trimmed, |fs, _| {
+        let root = fs.root_dir();
+        let src_parent = open_parent!(root.clone(), src_dir);
+        let dst_parent = open_parent!(root, dst_dir);
+        match src_parent.rename(src_name, &dst_parent, dst_name) {
+            Ok(()) => true,
+            Err(e) => {
+                serial_println!("[fs] rename({}, {}) failed: {:?}", src, dst, e);
+                false
+            }
+        }
+    }).unwrap_or(false)
 }
 
-pub fn delete_file(path: &str) -> bool {
+/// Create a directory and any missing parents. Succeeds if it already exists.
+pub fn create_dir_all(path: &str) -> bool {
     let path = resolve_path(path);
-    let (dir, filename) = split_path(&path);
-    let mut guard = FS.lock();
-    let fs = match guard.as_mut() {
-        Some(fs) => fs,
-        None => return false,
-    };
-    let root = fs.root_dir();
-    let parent = open_parent!(root, dir);
-    parent.remove(filename).is_ok()
+    let mut current = String::new();
+    if path.starts_with('/') {
+        current.push('/');
+    }
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+        current = join_path(&current, part);
+        if is_dir(&current) {
+            continue;
+        }
+        if !create_dir(&current) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Delete a file, or a directory together with everything below it.
+/// Keeps going after failures so as much as possible is removed.
+pub fn remove_recursive(path: &str) -> TreeResult {
+    let mut result = TreeResult::new();
+    remove_tree(&resolve_path(path), &mut result);
+    result
+}
+
+fn remove_tree(path: &str, result: &mut TreeResult) {
+    if is_dir(path) {
+        for entry in list_dir(path) {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            remove_tree(&join_path(path, &entry.name), result);
+        }
+    }
+    result.record(path, delete_file(path));
+}
+
+/// Copy a file, or a directory with all of its contents, to `dst`.
+pub fn copy_recursive(src: &str, dst: &str) -> TreeResult {
+    let mut result = TreeResult::new();
+    let src = resolve_path(src);
+    let dst = resolve_path(dst);
+    if is_below(dst.trim_end_matches('/'), src.trim_end_matches('/')) {
+        result.failed.push(dst);
+        return result;
+    }
+    copy_tree(&src, &dst, &mut result);
+    result
 }
 
-pub fn delete_dir(path: &str) -> bool {
-    delete_file(path)
+fn copy_tree(src: &str, dst: &str, result: &mut TreeResult) {
+    if !is_dir(src) {
+        result.record(src, copy_file(src, dst));
+        return;
+    }
+    if !is_dir(dst) && !result.record(dst, create_dir(dst)) {
+        // Nothing below can be copied without the target directory
+        return;
+    }
+    for entry in list_dir(src) {
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        copy_tree(&join_path(src, &entry.name), &join_path(dst, &entry.name), result);
+    }
 }
 
 pub fn create_dir(path: &str) -> bool {
     let path = resolve_path(path);
-    let (dir, dirname) = split_path(&path);
-    let mut guard = FS.lock();
-    let fs = match guard.as_mut() {
-        Some(fs) => fs,
-        None => return false,
-    };
-    let root = fs.root_dir();
-    let parent = open_parent!(root, dir);
-    let result = parent.create_dir(dirname).is_ok();
-    result
+    with_fat(&path, |fs, path| {
+        let (dir, dirname) = split_path(path);
+        let root = fs.root_dir();
+        let parent = open_parent!(root, dir);
+        let result = parent.create_dir(dirname).is_ok();
+        result
+    }).unwrap_or(false)
 }
 
 pub fn copy_file(src: &str, dst: &str) -> bool {
@@ -267,56 +875,267 @@ pub fn copy_file(src: &str, dst: &str) -> bool {
 }
 
 pub fn move_file(src: &str, dst: &str) -> bool {
-    if !copy_file(src, dst) {
+    rename(src, dst)
+}
+
+/// Unmount, write a fresh FAT filesystem to the volume (the whole disk or the
+/// selected partition) and mount it again.
+pub fn format_disk(options: &FormatOptions) -> bool {
+    if DISK.lock().is_none() {
+        serial_println!("[fs] format_disk: no disk");
+        return false;
+    }
+    if !unmount() {
+        return false;
+    }
+
+    let mut fmt = FormatVolumeOptions::new();
+    if let Some(fat_type) = options.fat_type {
+        fmt = fmt.fat_type(fat_type);
+    }
+    if let Some(bytes) = options.bytes_per_cluster {
+        fmt = fmt.bytes_per_cluster(bytes);
+    }
+    if let Some(label) = &options.label {
+        // FAT labels are 11 bytes, uppercase and space padded
+        let mut raw = [b' '; 11];
+        for (dst, src) in raw.iter_mut().zip(label.bytes()) {
+            *dst = src.to_ascii_uppercase();
+        }
+        fmt = fmt.volume_label(raw);
+    }
+
+    let mut disk = volume_io();
+    serial_println!("[fs] Formatting...");
+    if let Err(e) = fatfs::format_volume(&mut disk, fmt) {
+        serial_println!("[fs] format failed: {:?}", e);
+        return false;
+    }
+    if disk.flush().is_err() {
         return false;
     }
-    delete_file(src)
+    serial_println!("[fs] Formatted.");
+    mount()
 }
 
-pub fn format_disk() -> bool {
-    serial_println!("[fs] format_disk: not supported while mounted");
-    false
+/// Run a consistency check on the unmounted volume, optionally repairing it.
+/// The volume is mounted again afterwards if it was mounted before.
+pub fn check_disk(repair: bool) -> Result<fsck::FsckReport, fsck::FsckError> {
+    let was_mounted = is_mounted();
+    if !unmount() {
+        return Err(fsck::FsckError::Io);
+    }
+    let mut disk = volume_io();
+    let result = fsck::check(&mut disk, repair);
+    let _ = disk.flush();
+    if was_mounted {
+        mount();
+    }
+    result
 }
 
-pub fn list_dir(path: &str) -> Vec<DirEntry> {
-    let path = if path.is_empty() {
-        CURRENT_DIR.lock().clone()
-    } else {
-        resolve_path(path)
-    };
-    let mut guard = FS.lock();
-    let fs = match guard.as_mut() {
-        Some(fs) => fs,
-        None => return Vec::new(),
+/// Write every dirty cached sector to disk
+pub fn sync() -> bool {
+    match DISK.lock().as_mut() {
+        Some(dev) => dev.flush_cache().is_ok(),
+        None => true,
+    }
+}
+
+/// Cache size, dirty sectors and policy of the disk cache
+pub fn cache_status() -> Option<(usize, usize, CachePolicy)> {
+    DISK.lock().as_ref().map(|dev| (dev.max_sectors(), dev.dirty_sectors(), dev.policy()))
+}
+
+/// Apply the cache settings from `system.ini`
+pub fn apply_cache_config() {
+    let (sectors, policy) = {
+        let cfg = crate::CONFIG.lock();
+        (cfg.cache_sectors, cfg.cache_policy)
     };
-    let root = fs.root_dir();
-    let dir = if path == "/" || path.is_empty() {
-        root
-    } else {
-        match root.open_dir(&path) {
-            Ok(d) => d,
-            Err(_) => return Vec::new(),
+    if let Some(dev) = DISK.lock().as_mut() {
+        if dev.configure(sectors, policy).is_err() {
+            serial_println!("[fs] failed to flush cache while reconfiguring");
         }
-    };
-    let mut entries = Vec::new();
-    for entry in dir.iter() {
-        if let Ok(e) = entry {
-            let name = e.file_name();
-            let m = e.modified();
-            entries.push(DirEntry {
-                name,
-                is_dir: e.is_dir(),
-                size: e.len(),
-                modified: (
-                    m.date.year,
-                    m.date.month,
-                    m.date.day,
-                    m.time.hour,
-                    m.time.min,
-                    m.time.sec,
-                ),
+        serial_println!("[fs] cache: {} sectors, {}", sectors, policy.name());
+    }
+}
+
+/// Background task that writes dirty sectors out every `flush_interval`
+/// seconds, so a reset loses at most that much work.
+pub async fn flush_task() {
+    loop {
+        let interval = crate::CONFIG.lock().flush_interval;
+        crate::task::timer::sleep_secs(interval.max(1)).await;
+        if interval == 0 {
+            continue;
+        }
+        let dirty = DISK.lock().as_ref().map(|d| d.dirty_sectors()).unwrap_or(0);
+        if dirty > 0 && !sync() {
+            serial_println!("[fs] periodic flush failed");
+        }
+    }
+}
+
+pub fn list_dir(path: &str) -> Vec<DirEntry> {
+    let path = resolve_path(path);
+    if let Some(ext) = EXT2.lock().as_mut() {
+        return ext.list_dir(&path).unwrap_or_default();
+    }
+    with_fat(&path, |fs, path| {
+        let root = fs.root_dir();
+        let dir = if path == "/" || path.is_empty() {
+            root
+        } else {
+            match root.open_dir(path) {
+                Ok(d) => d,
+                Err(_) => return Vec::new(),
+            }
+        };
+        let mut entries = Vec::new();
+        for entry in dir.iter() {
+            if let Ok(e) = entry {
+                entries.push(DirEntry {
+                    name: e.file_name(),
+                    is_dir: e.is_dir(),
+                    size: e.len(),
+                    modified: stamp(e.modified()),
+                });
+            }
+        }
+        entries
+    }).unwrap_or_default()
+}
+
+fn stamp(t: DateTime) -> (u16, u16, u16, u16, u16, u16) {
+    (t.date.year, t.date.month, t.date.day, t.time.hour, t.time.min, t.time.sec)
+}
+
+fn unix_stamp(secs: u32) -> (u16, u16, u16, u16, u16, u16) {
+    let t = crate::time::from_unix(secs as u64);
+    (t.year as u16, t.month as u16, t.day as u16, t.hour as u16, t.minute as u16, t.second as u16)
+}
+
+pub fn stat(path: &str) -> Option<FileStat> {
+    let path = resolve_path(path);
+    if let Some(ext) = EXT2.lock().as_mut() {
+        let number = ext.lookup(&path, false).ok()?;
+        let inode = ext.read_inode(number).ok()?;
+        let mut attributes = fat::ATTR_READ_ONLY;
+        if inode.is_dir() {
+            attributes |= fat::ATTR_DIRECTORY;
+        }
+        // ext2 keeps no creation time; ctime is the last inode change
+        let accessed = unix_stamp(inode.atime);
+        return Some(FileStat {
+            name: String::from(base_name(&path)),
+            short_name: String::new(),
+            is_dir: inode.is_dir(),
+            size: inode.size,
+            attributes,
+            created: unix_stamp(inode.ctime),
+            accessed: (accessed.0, accessed.1, accessed.2),
+            modified: unix_stamp(inode.mtime),
+            first_cluster: number,
+        });
+    }
+
+    with_fat_io(&path, |fs, path, mut io| {
+        let layout = fat::Layout::read(&mut io).ok()?;
+        if path == "/" {
+            // The root has no entry of its own
+            let root_cluster = if layout.kind == fat::FatKind::Fat32 { layout.root_cluster } else { 0 };
+            return Some(FileStat {
+                name: String::from("/"),
+                short_name: String::new(),
+                is_dir: true,
+                size: 0,
+                attributes: fat::ATTR_DIRECTORY,
+                created: (0, 0, 0, 0, 0, 0),
+                accessed: (0, 0, 0),
+                modified: (0, 0, 0, 0, 0, 0),
+                first_cluster: root_cluster,
             });
         }
+        let (dir, name) = split_path(path);
+        let root = fs.root_dir();
+        let parent = if dir.is_empty() || dir == "/" {
+            root
+        } else {
+            root.open_dir(dir).ok()?
+        };
+        let entry = parent.iter().flatten().find(|e| {
+            e.file_name().eq_ignore_ascii_case(name) || e.short_file_name().eq_ignore_ascii_case(name)
+        })?;
+        let raw = fat::lookup(&mut io, &layout, path).ok()?;
+        let accessed = entry.accessed();
+        Some(FileStat {
+            name: entry.file_name(),
+            short_name: entry.short_file_name(),
+            is_dir: entry.is_dir(),
+            size: entry.len(),
+            attributes: entry.attributes().bits(),
+            created: stamp(entry.created()),
+            accessed: (accessed.year, accessed.month, accessed.day),
+            modified: stamp(entry.modified()),
+            first_cluster: raw.first_cluster,
+        })
+    }).flatten()
+}
+
+/// Set the access and modification times of `path` to now, or create it
+/// empty if it doesn't exist.
+pub fn touch(path: &str) -> bool {
+    if !exists(path) {
+        return write_file(path, b"");
+    }
+    if is_read_only() {
+        return false;
     }
-    entries
-}
\ No newline at end of file
+    let path = resolve_path(path);
+    let now = crate::time::get_time();
+    let date = fat::encode_date(now.year as u16, now.month as u16, now.day as u16);
+    let time = fat::encode_time(now.hour as u16, now.minute as u16, now.second as u16);
+    // fatfs only stamps entries when data changes, so write them directly
+    with_fat_io(&path, |_, path, mut io| {
+        let layout = match fat::Layout::read(&mut io) {
+            Ok(layout) => layout,
+            Err(_) => return false,
+        };
+        match fat::lookup(&mut io, &layout, path) {
+            Ok(entry) => fat::set_times(&mut io, &entry, date, time).is_ok(),
+            Err(_) => false,
+        }
+    }).unwrap_or(false)
+}
+
+#[test_case]
+fn test_touch_updates_mtime() {
+    init_device(Box::new(block::RamDisk::new(8192)));
+    assert!(format_disk(&FormatOptions::default()));
+    assert!(write_file("/notes.txt", b"keep me"));
+
+    // Back-date the entry so the new stamp can't match by accident
+    let (date, time) = (fat::encode_date(1990, 1, 1), fat::encode_time(0, 0, 0));
+    assert!(with_fat_io("/notes.txt", |_, path, mut io| {
+        let layout = fat::Layout::read(&mut io).unwrap();
+        let entry = fat::lookup(&mut io, &layout, path).unwrap();
+        fat::set_times(&mut io, &entry, date, time).is_ok()
+    }).unwrap());
+    assert_eq!(stat("/notes.txt").unwrap().modified, (1990, 1, 1, 0, 0, 0));
+
+    assert!(touch("/notes.txt"));
+    let now = crate::time::get_time();
+    let after = stat("/notes.txt").unwrap();
+    assert_eq!(after.modified.0, now.year as u16);
+    assert_eq!(after.size, 7);
+    assert_eq!(read_file("/notes.txt").unwrap(), b"keep me");
+}
+
+#[test_case]
+fn test_is_below_ignores_case_on_fat() {
+    assert!(is_below("/dir/sub", "/Dir"));
+    assert!(is_below("/A/b/c", "/a"));
+    assert!(!is_below("/dir", "/Dir"));
+    assert!(!is_below("/directory", "/dir"));
+}
@@ -0,0 +1,438 @@
+//! MBR and GPT partition tables, and a block device for one partition.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use fatfs::{IoBase, Read, Seek, SeekFrom, Write};
+use super::fat::read_at;
+
+const SECTOR_SIZE: u64 = 512;
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+const MBR_TYPE_EXTENDED_CHS: u8 = 0x05;
+const MBR_TYPE_EXTENDED_LBA: u8 = 0x0F;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// GPT type GUIDs as stored on disk (first three fields little endian)
+const GUID_EFI_SYSTEM: [u8; 16] = guid(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+const GUID_BASIC_DATA: [u8; 16] = guid(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+const GUID_LINUX_FS: [u8; 16] = guid(0x0FC63DAF, 0x8483, 0x4772, [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+const GUID_LINUX_SWAP: [u8; 16] = guid(0x0657FD6D, 0xA4AB, 0x43C4, [0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F]);
+
+const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    [a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Debug, Clone)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt([u8; 16]),
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionEntry {
+    /// 1-based, in table order (logical MBR partitions start at 5)
+    pub number: usize,
+    pub start_lba: u64,
+    pub sectors: u64,
+    pub kind: PartitionType,
+    pub name: String,
+    pub bootable: bool,
+}
+
+impl PartitionEntry {
+    pub fn start_bytes(&self) -> u64 {
+        self.start_lba * SECTOR_SIZE
+    }
+
+    pub fn len_bytes(&self) -> u64 {
+        self.sectors * SECTOR_SIZE
+    }
+
+    /// Whether the type says this partition should hold a FAT filesystem
+    pub fn is_fat(&self) -> bool {
+        match self.kind {
+            PartitionType::Mbr(t) => matches!(t, 0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E | 0xEF),
+            PartitionType::Gpt(g) => g == GUID_BASIC_DATA || g == GUID_EFI_SYSTEM,
+        }
+    }
+
+    /// Whether the type says this partition holds a Linux filesystem
+    pub fn is_linux(&self) -> bool {
+        match self.kind {
+            PartitionType::Mbr(t) => t == 0x83,
+            PartitionType::Gpt(g) => g == GUID_LINUX_FS,
+        }
+    }
+
+    pub fn type_name(&self) -> String {
+        match self.kind {
+            PartitionType::Mbr(t) => String::from(match t {
+                0x01 => "FAT12",
+                0x04 | 0x06 | 0x0E => "FAT16",
+                0x0B | 0x0C => "FAT32",
+                0x07 => "NTFS/exFAT",
+                0x82 => "Linux swap",
+                0x83 => "Linux",
+                0xEF => "EFI System",
+                _ => return alloc::format!("type {:#04x}", t),
+            }),
+            PartitionType::Gpt(g) => String::from(match g {
+                GUID_EFI_SYSTEM => "EFI System",
+                GUID_BASIC_DATA => "Basic data",
+                GUID_LINUX_FS => "Linux filesystem",
+                GUID_LINUX_SWAP => "Linux swap",
+                _ => return format_guid(&g),
+            }),
+        }
+    }
+}
+
+pub struct PartitionTable {
+    pub kind: TableKind,
+    pub partitions: Vec<PartitionEntry>,
+}
+
+pub fn format_guid(g: &[u8; 16]) -> String {
+    alloc::format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+        u16::from_le_bytes([g[4], g[5]]),
+        u16::from_le_bytes([g[6], g[7]]),
+        g[8], g[9], g[10], g[11], g[12], g[13], g[14], g[15],
+    )
+}
+
+/// A FAT boot sector starts with a jump instruction and has a sane BPB.
+/// An MBR has neither, which is how a bare volume is told apart from a
+/// partitioned disk when both end in 0xAA55.
+pub fn is_fat_boot_sector(sector: &[u8; 512]) -> bool {
+    let jump = sector[0] == 0xEB || sector[0] == 0xE9;
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let sectors_per_cluster = sector[13];
+    jump && matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        && sectors_per_cluster.is_power_of_two()
+        && sector[16] != 0
+}
+
+/// Parse the partition table on `disk`. Returns None for a bare volume or a
+/// disk without a valid MBR. Entries that reach past the end of the disk are
+/// left out.
+pub fn read_table<D: Read + Seek>(disk: &mut D) -> Option<PartitionTable> {
+    let disk_sectors = disk.seek(SeekFrom::End(0)).ok()? / SECTOR_SIZE;
+    let mut mbr = [0u8; 512];
+    read_at(disk, 0, &mut mbr).ok()?;
+    if u16::from_le_bytes([mbr[510], mbr[511]]) != 0xAA55 || is_fat_boot_sector(&mbr) {
+        return None;
+    }
+
+    let primary: Vec<(u8, bool, u64, u64)> = (0..4)
+        .map(|i| {
+            let e = &mbr[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+            let start = u32::from_le_bytes([e[8], e[9], e[10], e[11]]) as u64;
+            let sectors = u32::from_le_bytes([e[12], e[13], e[14], e[15]]) as u64;
+            (e[4], e[0] == 0x80, start, sectors)
+        })
+        .collect();
+
+    if primary.iter().any(|&(t, _, _, _)| t == MBR_TYPE_GPT_PROTECTIVE) {
+        if let Some(partitions) = read_gpt(disk, disk_sectors) {
+            return Some(PartitionTable { kind: TableKind::Gpt, partitions });
+        }
+    }
+
+    let mut partitions = Vec::new();
+    for (i, &(kind, bootable, start, sectors)) in primary.iter().enumerate() {
+        if kind == 0 || sectors == 0 {
+            continue;
+        }
+        if !fits(start, sectors, disk_sectors) {
+            continue;
+        }
+        if kind == MBR_TYPE_EXTENDED_CHS || kind == MBR_TYPE_EXTENDED_LBA {
+            read_logical(disk, start, disk_sectors, &mut partitions);
+            continue;
+        }
+        partitions.push(PartitionEntry {
+            number: i + 1,
+            start_lba: start,
+            sectors,
+            kind: PartitionType::Mbr(kind),
+            name: String::new(),
+            bootable,
+        });
+    }
+    Some(PartitionTable { kind: TableKind::Mbr, partitions })
+}
+
+/// Whether `sectors` sectors from `start` lie on a disk of `disk_sectors`
+fn fits(start: u64, sectors: u64, disk_sectors: u64) -> bool {
+    start.checked_add(sectors).is_some_and(|end| end <= disk_sectors)
+}
+
+/// Follow the chain of extended boot records. Each EBR holds one logical
+/// partition relative to itself and a link relative to the extended partition.
+fn read_logical<D: Read + Seek>(disk: &mut D, extended_start: u64, disk_sectors: u64, out: &mut Vec<PartitionEntry>) {
+    let mut ebr_lba = extended_start;
+    let mut number = 5;
+    // Bound the walk so a looping chain can't hang boot
+    for _ in 0..64 {
+        let mut ebr = [0u8; 512];
+        if read_at(disk, ebr_lba * SECTOR_SIZE, &mut ebr).is_err()
+            || u16::from_le_bytes([ebr[510], ebr[511]]) != 0xAA55
+        {
+            return;
+        }
+        let entry = |i: usize| {
+            let e = &ebr[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+            (
+                e[4],
+                u32::from_le_bytes([e[8], e[9], e[10], e[11]]) as u64,
+                u32::from_le_bytes([e[12], e[13], e[14], e[15]]) as u64,
+            )
+        };
+
+        let (kind, start, sectors) = entry(0);
+        if kind != 0 && sectors != 0 {
+            if fits(ebr_lba + start, sectors, disk_sectors) {
+                out.push(PartitionEntry {
+                    number,
+                    start_lba: ebr_lba + start,
+                    sectors,
+                    kind: PartitionType::Mbr(kind),
+                    name: String::new(),
+                    bootable: false,
+                });
+            }
+            number += 1;
+        }
+
+        let (next_kind, next_start, _) = entry(1);
+        if next_kind == 0 || next_start == 0 {
+            return;
+        }
+        ebr_lba = extended_start + next_start;
+    }
+}
+
+fn read_gpt<D: Read + Seek>(disk: &mut D, disk_sectors: u64) -> Option<Vec<PartitionEntry>> {
+    let mut header = [0u8; 512];
+    read_at(disk, SECTOR_SIZE, &mut header).ok()?;
+    if &header[..8] != GPT_SIGNATURE {
+        return None;
+    }
+
+    let u32_at = |o: usize| u32::from_le_bytes([header[o], header[o + 1], header[o + 2], header[o + 3]]);
+    let u64_at = |o: usize| u64::from_le_bytes([
+        header[o], header[o + 1], header[o + 2], header[o + 3],
+        header[o + 4], header[o + 5], header[o + 6], header[o + 7],
+    ]);
+    let entries_lba = u64_at(72);
+    let entry_count = u32_at(80).min(256) as u64;
+    let entry_size = u32_at(84) as u64;
+    if entry_size < 128 {
+        return None;
+    }
+
+    let mut partitions = Vec::new();
+    let mut raw = [0u8; 128];
+    for i in 0..entry_count {
+        read_at(disk, entries_lba * SECTOR_SIZE + i * entry_size, &mut raw).ok()?;
+        let mut type_guid = [0u8; 16];
+        type_guid.copy_from_slice(&raw[..16]);
+        if type_guid == [0u8; 16] {
+            continue;
+        }
+        let mut lba = [0u8; 8];
+        lba.copy_from_slice(&raw[32..40]);
+        let first = u64::from_le_bytes(lba);
+        lba.copy_from_slice(&raw[40..48]);
+        let last = u64::from_le_bytes(lba);
+        if last < first || !fits(first, last - first + 1, disk_sectors) {
+            continue;
+        }
+        let units = raw[56..128]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&u| u != 0);
+        let name: String = char::decode_utf16(units).map(|c| c.unwrap_or('?')).collect();
+
+        partitions.push(PartitionEntry {
+            number: i as usize + 1,
+            start_lba: first,
+            sectors: last - first + 1,
+            kind: PartitionType::Gpt(type_guid),
+            name,
+            // Legacy BIOS bootable attribute
+            bootable: raw[48] & (1 << 2) != 0,
+        });
+    }
+    Some(partitions)
+}
+
+/// A window onto part of a parent device. Offsets are relative to the start
+/// of the partition and accesses never reach past its end.
+pub struct Partition<D> {
+    inner: D,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<D> Partition<D> {
+    pub fn new(inner: D, start: u64, len: u64) -> Self {
+        Partition { inner, start, len, pos: 0 }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+}
+
+impl<D: IoBase> IoBase for Partition<D> {
+    type Error = D::Error;
+}
+
+impl<D: Read + Seek> Read for Partition<D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let n = (buf.len() as u64).min(self.len - self.pos) as usize;
+        self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+        let read = self.inner.read(&mut buf[..n])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<D: Write + Seek> Write for Partition<D> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let n = (buf.len() as u64).min(self.len - self.pos) as usize;
+        self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
+        let written = self.inner.write(&buf[..n])?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+}
+
+impl<D: Seek> Seek for Partition<D> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => (self.len as i64 + offset) as u64,
+            SeekFrom::Current(offset) => (self.pos as i64 + offset) as u64,
+        };
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+fn test_disk(image: Vec<u8>) -> super::cache::CachedDevice<super::block::RamDisk> {
+    super::cache::CachedDevice::new(super::block::RamDisk::from_vec(image))
+}
+
+/// Fill in entry `i` of the MBR-style table in `sector`
+#[cfg(test)]
+fn put_mbr_entry(sector: &mut [u8], i: usize, kind: u8, start: u32, sectors: u32) {
+    let e = &mut sector[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+    e[4] = kind;
+    e[8..12].copy_from_slice(&start.to_le_bytes());
+    e[12..16].copy_from_slice(&sectors.to_le_bytes());
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+}
+
+/// Protective MBR, a header at LBA 1 and four entries at LBA 2
+#[cfg(test)]
+fn gpt_image(sectors: usize, entries: &[([u8; 16], u64, u64, &str)]) -> Vec<u8> {
+    let mut image = alloc::vec![0u8; sectors * 512];
+    put_mbr_entry(&mut image[..512], 0, MBR_TYPE_GPT_PROTECTIVE, 1, sectors as u32 - 1);
+    let header = &mut image[512..1024];
+    header[..8].copy_from_slice(GPT_SIGNATURE);
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&4u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    for (i, &(type_guid, first, last, name)) in entries.iter().enumerate() {
+        let e = &mut image[1024 + i * 128..][..128];
+        e[..16].copy_from_slice(&type_guid);
+        e[32..40].copy_from_slice(&first.to_le_bytes());
+        e[40..48].copy_from_slice(&last.to_le_bytes());
+        for (j, unit) in name.encode_utf16().enumerate() {
+            e[56 + j * 2..58 + j * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    image
+}
+
+#[test_case]
+fn test_read_mbr_with_logical_partitions() {
+    let mut image = alloc::vec![0u8; 2048 * 512];
+    put_mbr_entry(&mut image[..512], 0, 0x0C, 1, 99);
+    image[MBR_TABLE_OFFSET] = 0x80;
+    put_mbr_entry(&mut image[..512], 1, MBR_TYPE_EXTENDED_LBA, 100, 1000);
+    // Two EBRs: the link in the first is relative to the extended partition
+    put_mbr_entry(&mut image[100 * 512..][..512], 0, 0x83, 1, 199);
+    put_mbr_entry(&mut image[100 * 512..][..512], 1, MBR_TYPE_EXTENDED_CHS, 200, 100);
+    put_mbr_entry(&mut image[300 * 512..][..512], 0, 0x0B, 1, 99);
+
+    let table = read_table(&mut test_disk(image)).unwrap();
+    assert_eq!(table.kind, TableKind::Mbr);
+    let found: Vec<(usize, u64, u64, bool)> = table.partitions.iter()
+        .map(|p| (p.number, p.start_lba, p.sectors, p.bootable))
+        .collect();
+    assert_eq!(found, [(1, 1, 99, true), (5, 101, 199, false), (6, 301, 99, false)]);
+    assert!(table.partitions[0].is_fat() && table.partitions[1].is_linux());
+}
+
+#[test_case]
+fn test_read_gpt() {
+    let image = gpt_image(128, &[
+        (GUID_BASIC_DATA, 34, 63, "data"),
+        ([0; 16], 0, 0, ""),
+        (GUID_LINUX_FS, 64, 127, "root"),
+    ]);
+
+    let table = read_table(&mut test_disk(image)).unwrap();
+    assert_eq!(table.kind, TableKind::Gpt);
+    assert_eq!(table.partitions.len(), 2);
+    let (data, root) = (&table.partitions[0], &table.partitions[1]);
+    assert_eq!((data.number, data.start_lba, data.sectors), (1, 34, 30));
+    assert_eq!((root.number, root.start_lba, root.sectors), (3, 64, 64));
+    assert_eq!((data.name.as_str(), root.name.as_str()), ("data", "root"));
+    assert!(data.is_fat() && root.is_linux());
+}
+
+#[test_case]
+fn test_entries_past_end_of_disk() {
+    let mut image = alloc::vec![0u8; 256 * 512];
+    put_mbr_entry(&mut image[..512], 0, 0x0C, 1, 100);
+    put_mbr_entry(&mut image[..512], 1, 0x83, 200, 100);
+    put_mbr_entry(&mut image[..512], 2, MBR_TYPE_EXTENDED_LBA, 101, 99);
+    put_mbr_entry(&mut image[101 * 512..][..512], 0, 0x0B, 1, 500);
+    let table = read_table(&mut test_disk(image)).unwrap();
+    let numbers: Vec<usize> = table.partitions.iter().map(|p| p.number).collect();
+    assert_eq!(numbers, [1]);
+
+    let image = gpt_image(128, &[(GUID_BASIC_DATA, 34, 63, "data"), (GUID_LINUX_FS, 64, 128, "root")]);
+    let table = read_table(&mut test_disk(image)).unwrap();
+    let numbers: Vec<usize> = table.partitions.iter().map(|p| p.number).collect();
+    assert_eq!(numbers, [1]);
+}
@@ -0,0 +1,149 @@
+//! Path handling shared by every `fs` function.
+//!
+//! `normalize` and `absolute` are purely lexical: they collapse repeated
+//! slashes, drop `.` components and trailing slashes, and let `..` remove the
+//! component before it without ever climbing above the root. `canonicalize`
+//! instead walks the mounted tree, so the result has the names as stored on
+//! disk and any ext2 symlinks along the way resolved.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Symlinks followed while canonicalizing before giving up on a loop
+const MAX_LINK_HOPS: usize = 8;
+
+/// Clean up `path` into an absolute path. A relative path is taken as
+/// relative to the root.
+pub fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            name => parts.push(name),
+        }
+    }
+    from_parts(&parts)
+}
+
+/// Replace a leading `~` or `~/` with `home`. Anything else, including
+/// `~name`, is returned unchanged.
+pub fn expand_home(path: &str, home: &str) -> String {
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let mut expanded = String::from(home.trim_end_matches('/'));
+            expanded.push_str(rest);
+            if expanded.is_empty() {
+                expanded.push('/');
+            }
+            expanded
+        }
+        _ => String::from(path),
+    }
+}
+
+/// `path` after home expansion, on top of `cwd` if it is relative, without
+/// any cleanup. `canonicalize` needs the `..` components kept in place.
+pub fn join(cwd: &str, path: &str, home: &str) -> String {
+    let path = expand_home(path, home);
+    if path.starts_with('/') {
+        path
+    } else {
+        alloc::format!("{}/{}", cwd, path)
+    }
+}
+
+/// Absolute, normalized form of `path` as seen from `cwd`
+pub fn absolute(cwd: &str, path: &str, home: &str) -> String {
+    normalize(&join(cwd, path, home))
+}
+
+/// Parent directory of a normalized absolute path; the root is its own parent
+pub fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(pos) => &path[..pos],
+    }
+}
+
+fn from_parts<S: AsRef<str>>(parts: &[S]) -> String {
+    let mut path = String::new();
+    for part in parts {
+        path.push('/');
+        path.push_str(part.as_ref());
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// Resolve `path` against the real tree: every component must exist, each
+/// name takes the spelling stored on disk and symlinks are followed. None if
+/// some component is missing or the links loop.
+pub fn canonicalize(path: &str) -> Option<String> {
+    let mut resolved = Vec::new();
+    let mut hops = 0;
+    walk(&mut resolved, &super::joined_path(path), &mut hops)?;
+    Some(from_parts(&resolved))
+}
+
+/// Apply the components of `path` to `resolved`, which is always a real,
+/// link-free directory, so `..` can simply drop its last component.
+fn walk(resolved: &mut Vec<String>, path: &str, hops: &mut usize) -> Option<()> {
+    if path.starts_with('/') {
+        resolved.clear();
+    }
+    let exact = super::is_case_sensitive();
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => {
+                resolved.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let dir = from_parts(resolved);
+        let entries = super::list_dir(&dir);
+        let entry = entries.iter().find(|e| e.name == part)
+            .or_else(|| entries.iter().find(|e| !exact && e.name.eq_ignore_ascii_case(part)))?;
+
+        let full = super::join_path(&dir, &entry.name);
+        match super::read_link(&full) {
+            Some(target) => {
+                *hops += 1;
+                if *hops > MAX_LINK_HOPS {
+                    return None;
+                }
+                // Relative targets resolve against the directory holding the link
+                walk(resolved, &target, hops)?;
+            }
+            None => resolved.push(entry.name.clone()),
+        }
+    }
+    Some(())
+}
+
+#[test_case]
+fn test_normalize() {
+    assert_eq!(normalize(""), "/");
+    assert_eq!(normalize("/"), "/");
+    assert_eq!(normalize("a//b/"), "/a/b");
+    assert_eq!(normalize("/a/./b/../c"), "/a/c");
+    assert_eq!(normalize("/../../x"), "/x");
+    assert_eq!(absolute("/home/user", "../a/./b", "/"), "/home/a/b");
+    assert_eq!(parent("/a/b"), "/a");
+    assert_eq!(parent("/a"), "/");
+}
+
+#[test_case]
+fn test_expand_home() {
+    assert_eq!(expand_home("~", "/home/user/"), "/home/user");
+    assert_eq!(expand_home("~/docs", "/home/user"), "/home/user/docs");
+    assert_eq!(expand_home("~", "/"), "/");
+    assert_eq!(expand_home("~other", "/home/user"), "~other");
+    assert_eq!(absolute("/tmp", "~/x", "/"), "/x");
+}
@@ -0,0 +1,364 @@
+//! ustar archives.
+//!
+//! Archives are built and unpacked in memory, the same way `read_file` and
+//! `write_file` move whole files. Regular files and directories are
+//! supported; other entry types (links, devices) are listed but skipped on
+//! extract. GNU long-name records are understood and written for paths that
+//! don't fit the ustar name/prefix split.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use super::TreeResult;
+
+const BLOCK: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Other(u8),
+}
+
+pub struct TarEntry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mtime: u64,
+    pub mode: u32,
+    /// Where the entry's data starts in the archive
+    pub offset: usize,
+}
+
+impl TarEntry {
+    pub fn data<'a>(&self, archive: &'a [u8]) -> &'a [u8] {
+        &archive[self.offset..self.offset + self.size as usize]
+    }
+}
+
+#[derive(Debug)]
+pub enum TarError {
+    Truncated,
+    BadChecksum(usize),
+    BadHeader(usize),
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    let mut seen = false;
+    for &b in field {
+        match b {
+            b'0'..=b'7' => {
+                value = value.checked_mul(8)? + (b - b'0') as u64;
+                seen = true;
+            }
+            b' ' | 0 if !seen => continue,
+            b' ' | 0 => break,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+fn write_octal(field: &mut [u8], value: u64) {
+    // Zero padded, leaving room for the terminating NUL
+    let digits = field.len() - 1;
+    let mut v = value;
+    for i in (0..digits).rev() {
+        field[i] = b'0' + (v & 7) as u8;
+        v >>= 3;
+    }
+    field[digits] = 0;
+}
+
+fn c_string(field: &[u8]) -> String {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+        .sum()
+}
+
+fn padded(size: usize) -> usize {
+    (size + BLOCK - 1) / BLOCK * BLOCK
+}
+
+/// Walk the headers of an archive
+pub fn parse(archive: &[u8]) -> Result<Vec<TarEntry>, TarError> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    let mut long_name: Option<String> = None;
+
+    while pos + BLOCK <= archive.len() {
+        let header = &archive[pos..pos + BLOCK];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let stored = parse_octal(&header[148..156]).ok_or(TarError::BadHeader(pos))?;
+        if stored != checksum(header) {
+            return Err(TarError::BadChecksum(pos));
+        }
+
+        let size = parse_octal(&header[124..136]).ok_or(TarError::BadHeader(pos))?;
+        let offset = pos + BLOCK;
+        if offset + size as usize > archive.len() {
+            return Err(TarError::Truncated);
+        }
+        let next = offset + padded(size as usize);
+        let type_flag = header[156];
+
+        match type_flag {
+            // GNU long name: the data is the name of the following entry
+            b'L' => {
+                long_name = Some(c_string(&archive[offset..offset + size as usize]));
+                pos = next;
+                continue;
+            }
+            // pax headers carry metadata we don't use
+            b'x' | b'g' => {
+                pos = next;
+                continue;
+            }
+            _ => {}
+        }
+
+        let path = match long_name.take() {
+            Some(name) => name,
+            None => {
+                let name = c_string(&header[0..100]);
+                let prefix = if &header[257..262] == b"ustar" { c_string(&header[345..500]) } else { String::new() };
+                if prefix.is_empty() { name } else { alloc::format!("{}/{}", prefix, name) }
+            }
+        };
+
+        let kind = match type_flag {
+            b'0' | 0 | b'7' if path.ends_with('/') => EntryKind::Dir,
+            b'0' | 0 | b'7' => EntryKind::File,
+            b'5' => EntryKind::Dir,
+            b'2' => EntryKind::Symlink,
+            t => EntryKind::Other(t),
+        };
+
+        entries.push(TarEntry {
+            path,
+            kind,
+            size: if kind == EntryKind::Dir { 0 } else { size },
+            mtime: parse_octal(&header[136..148]).unwrap_or(0),
+            mode: parse_octal(&header[100..108]).unwrap_or(0) as u32,
+            offset,
+        });
+        pos = next;
+    }
+    Ok(entries)
+}
+
+/// Builds an archive in memory
+#[derive(Default)]
+pub struct Builder {
+    out: Vec<u8>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder { out: Vec::new() }
+    }
+
+    fn header(&mut self, path: &str, type_flag: u8, size: u64, mtime: u64, mode: u32) {
+        let mut header = [0u8; BLOCK];
+        let bytes = path.as_bytes();
+
+        if bytes.len() <= 100 {
+            header[..bytes.len()].copy_from_slice(bytes);
+        } else {
+            // Split at a slash so the tail fits in name and the head in prefix
+            let split = bytes
+                .iter()
+                .enumerate()
+                .filter(|&(i, &b)| b == b'/' && i <= 155 && bytes.len() - i - 1 <= 100)
+                .map(|(i, _)| i)
+                .next();
+            match split {
+                Some(i) if i > 0 => {
+                    header[345..345 + i].copy_from_slice(&bytes[..i]);
+                    header[..bytes.len() - i - 1].copy_from_slice(&bytes[i + 1..]);
+                }
+                _ => {
+                    self.long_name(bytes);
+                    header[..100].copy_from_slice(&bytes[..100]);
+                }
+            }
+        }
+
+        write_octal(&mut header[100..108], mode as u64);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], size);
+        write_octal(&mut header[136..148], mtime);
+        header[156] = type_flag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        let sum = checksum(&header);
+        write_octal(&mut header[148..155], sum);
+        header[155] = b' ';
+        self.out.extend_from_slice(&header);
+    }
+
+    fn long_name(&mut self, name: &[u8]) {
+        self.header("././@LongLink", b'L', name.len() as u64 + 1, 0, 0o644);
+        self.append_data(name);
+        // The name is NUL terminated; padding already provides the NUL
+        if name.len() % BLOCK == 0 {
+            self.out.extend_from_slice(&[0u8; BLOCK]);
+        }
+    }
+
+    fn append_data(&mut self, data: &[u8]) {
+        self.out.extend_from_slice(data);
+        let pad = padded(data.len()) - data.len();
+        self.out.resize(self.out.len() + pad, 0);
+    }
+
+    pub fn append_file(&mut self, path: &str, data: &[u8], mtime: u64) {
+        self.header(path, b'0', data.len() as u64, mtime, 0o644);
+        self.append_data(data);
+    }
+
+    pub fn append_dir(&mut self, path: &str, mtime: u64) {
+        let path = if path.ends_with('/') { path.to_string() } else { alloc::format!("{}/", path) };
+        self.header(&path, b'5', 0, mtime, 0o755);
+    }
+
+    /// Write the two zero blocks that end an archive
+    pub fn finish(mut self) -> Vec<u8> {
+        self.out.extend_from_slice(&[0u8; BLOCK * 2]);
+        self.out
+    }
+}
+
+fn entry_mtime(entry: &super::DirEntry) -> u64 {
+    let (year, month, day, hour, minute, second) = entry.modified;
+    crate::time::to_unix(&crate::time::cmos::RTCDateTime {
+        year: year as usize,
+        month: month as u8,
+        day: day as u8,
+        hour: hour as u8,
+        minute: minute as u8,
+        second: second as u8,
+    })
+}
+
+/// Name a path gets inside the archive: relative, without `.` components
+fn archive_name(path: &str) -> String {
+    path.split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Archive files and directory trees. Paths are stored as given, minus any
+/// leading slash.
+pub fn create(paths: &[&str]) -> (Vec<u8>, TreeResult) {
+    let mut builder = Builder::new();
+    let mut result = TreeResult::new();
+    for path in paths {
+        add_tree(&mut builder, &super::resolve_path(path), &archive_name(path), None, &mut result);
+    }
+    (builder.finish(), result)
+}
+
+fn add_tree(builder: &mut Builder, path: &str, name: &str, mtime: Option<u64>, result: &mut TreeResult) {
+    if super::is_dir(path) {
+        let entries = super::list_dir(path);
+        let own_mtime = entries.iter().find(|e| e.name == ".").map(entry_mtime);
+        if !name.is_empty() {
+            builder.append_dir(name, mtime.or(own_mtime).unwrap_or(0));
+            result.record(path, true);
+        }
+        for entry in entries {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            add_tree(
+                builder,
+                &super::join_path(path, &entry.name),
+                &super::join_path(name, &entry.name),
+                Some(entry_mtime(&entry)),
+                result,
+            );
+        }
+        return;
+    }
+
+    match super::read_file(path) {
+        Some(data) => {
+            builder.append_file(name, &data, mtime.unwrap_or(0));
+            result.record(path, true);
+        }
+        None => {
+            result.record(path, false);
+        }
+    }
+}
+
+/// Unpack an archive below `dest`. Entries that would land outside `dest`
+/// (absolute paths are made relative, `..` is refused) count as failures.
+pub fn extract(archive: &[u8], dest: &str) -> Result<TreeResult, TarError> {
+    let entries = parse(archive)?;
+    let mut result = TreeResult::new();
+    let dest = super::resolve_path(dest);
+
+    for entry in &entries {
+        if entry.path.split('/').any(|p| p == "..") {
+            result.record(&entry.path, false);
+            continue;
+        }
+        let name = archive_name(&entry.path);
+        if name.is_empty() {
+            continue;
+        }
+        let target = super::join_path(&dest, &name);
+
+        match entry.kind {
+            EntryKind::Dir => {
+                result.record(&entry.path, super::create_dir_all(&target));
+            }
+            EntryKind::File => {
+                let (parent, _) = super::split_path(&target);
+                let ok = (parent.is_empty() || super::create_dir_all(parent))
+                    && super::write_file(&target, entry.data(archive));
+                result.record(&entry.path, ok);
+            }
+            _ => {
+                crate::serial_println!("[tar] skipping {} ({:?})", entry.path, entry.kind);
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[test_case]
+fn test_tar_round_trip() {
+    let long = "a/".repeat(70) + "file.txt";
+    let mut builder = Builder::new();
+    builder.append_dir("docs", 1_700_000_000);
+    builder.append_file("docs/readme.txt", b"hello tar", 1_700_000_000);
+    builder.append_file(&long, b"deep", 0);
+    let archive = builder.finish();
+    assert_eq!(archive.len() % BLOCK, 0);
+
+    let entries = parse(&archive).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].path, "docs/");
+    assert_eq!(entries[0].kind, EntryKind::Dir);
+    assert_eq!(entries[1].path, "docs/readme.txt");
+    assert_eq!(entries[1].data(&archive), b"hello tar");
+    assert_eq!(entries[1].mtime, 1_700_000_000);
+    assert_eq!(entries[2].path, long);
+    assert_eq!(entries[2].data(&archive), b"deep");
+}
@@ -1,178 +1,145 @@
-use alloc::collections::BTreeMap;
-use alloc::vec::Vec;
-use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::boxed::Box;
 use fatfs::{IoBase, Read, Seek, SeekFrom, Write};
+use spin::Mutex;
 use virtio_drivers::device::blk::VirtIOBlk;
 use virtio_drivers::transport::pci::PciTransport;
 use crate::device::virtio_hal::OsHal;
+use super::block::{check_range, BlockDevice, BlockError};
+use super::cache::CachedDevice;
 
-const SECTOR_SIZE: usize = 512;
-const CACHE_MAX_SECTORS: usize = 64; // 32KB cache
-
-pub static BLK_READS: AtomicU64 = AtomicU64::new(0);
-pub static BLK_WRITES: AtomicU64 = AtomicU64::new(0);
-pub static BLK_TICKS: AtomicU64 = AtomicU64::new(0);
-pub static W_CALLS: AtomicU64 = AtomicU64::new(0);
-pub static W_CYCLES: AtomicU64 = AtomicU64::new(0);
-pub static R_CALLS: AtomicU64 = AtomicU64::new(0);
-pub static R_CYCLES: AtomicU64 = AtomicU64::new(0);
-
-struct CachedSector {
-    data: [u8; SECTOR_SIZE],
-    dirty: bool,
-    last_used: u64,
-}
+/// The block device behind the volume. It is kept outside the `FileSystem`
+/// so the volume can be unmounted, reformatted or checked and mounted again.
+pub static DISK: Mutex<Option<Disk>> = Mutex::new(None);
+
+/// Whatever backend the volume lives on, behind the sector cache
+pub type Disk = CachedDevice<Box<dyn BlockDevice>>;
+
+const PAGE_SIZE: usize = 4096;
+const SECTORS_PER_PAGE: usize = PAGE_SIZE / 512;
 
-pub struct VirtioBlockDevice {
+#[repr(C, align(4096))]
+struct DmaPage([u8; PAGE_SIZE]);
+
+/// virtio-blk disk on the PCI bus
+pub struct VirtioBlk {
     blk: VirtIOBlk<OsHal, PciTransport>,
-    pos: u64,
-    capacity_bytes: u64,
-    cache: BTreeMap<u64, CachedSector>,
-    clock: u64,
+    /// The HAL only translates a buffer's start address, so every request
+    /// goes through this page to stay physically contiguous
+    bounce: Box<DmaPage>,
 }
 
-impl VirtioBlockDevice {
+impl VirtioBlk {
     pub fn new(blk: VirtIOBlk<OsHal, PciTransport>) -> Self {
-        let capacity_bytes = blk.capacity() * 512;
-        Self {
-            blk,
-            pos: 0,
-            capacity_bytes,
-            cache: BTreeMap::new(),
-            clock: 0,
-        }
+        VirtioBlk { blk, bounce: Box::new(DmaPage([0; PAGE_SIZE])) }
     }
+}
 
-    /// Get a sector into cache (reading from disk on miss), return mutable ref.
-    fn sector(&mut self, sector: u64) -> Result<&mut CachedSector, ()> {
-        self.clock += 1;
-        let clock = self.clock;
-
-        if !self.cache.contains_key(&sector) {
-            self.evict_if_full()?;
-            let mut data = [0u8; SECTOR_SIZE];
-            let t = crate::interrupts::TICKS.load(Ordering::Relaxed);
-            self.blk.read_blocks(sector as usize, &mut data).map_err(|_| ())?;
-            BLK_TICKS.fetch_add(crate::interrupts::TICKS.load(Ordering::Relaxed) - t, Ordering::Relaxed);
-            BLK_READS.fetch_add(1, Ordering::Relaxed);
-            self.cache.insert(sector, CachedSector { data, dirty: false, last_used: clock });
-        }
-
-        let entry = self.cache.get_mut(&sector).unwrap();
-        entry.last_used = clock;
-        Ok(entry)
+impl BlockDevice for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        self.blk.capacity()
     }
 
-    fn evict_if_full(&mut self) -> Result<(), ()> {
-        while self.cache.len() >= CACHE_MAX_SECTORS {
-            let lru = self.cache.iter()
-                .min_by_key(|(_, e)| e.last_used)
-                .map(|(&s, _)| s)
-                .ok_or(())?;
-            if let Some(entry) = self.cache.remove(&lru) {
-                if entry.dirty {
-                    self.blk.write_blocks(lru as usize, &entry.data).map_err(|_| ())?;
-                    BLK_WRITES.fetch_add(1, Ordering::Relaxed);
-                }
-            }
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(&*self, sector, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(PAGE_SIZE).enumerate() {
+            let bounce = &mut self.bounce.0[..chunk.len()];
+            let at = sector as usize + i * SECTORS_PER_PAGE;
+            self.blk.read_blocks(at, bounce).map_err(|_| BlockError::Io)?;
+            chunk.copy_from_slice(bounce);
         }
         Ok(())
     }
 
-    /// Write all dirty sectors to disk.
-    pub fn flush_cache(&mut self) -> Result<(), ()> {
-        let dirty: Vec<u64> = self.cache.iter()
-            .filter(|(_, e)| e.dirty)
-            .map(|(&s, _)| s)
-            .collect();
-        for sector in dirty {
-            let entry = self.cache.get_mut(&sector).unwrap();
-            self.blk.write_blocks(sector as usize, &entry.data).map_err(|_| ())?;
-            BLK_WRITES.fetch_add(1, Ordering::Relaxed);
-            entry.dirty = false;
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(&*self, sector, buf.len())?;
+        for (i, chunk) in buf.chunks(PAGE_SIZE).enumerate() {
+            let bounce = &mut self.bounce.0[..chunk.len()];
+            bounce.copy_from_slice(chunk);
+            let at = sector as usize + i * SECTORS_PER_PAGE;
+            self.blk.write_blocks(at, bounce).map_err(|_| BlockError::Io)?;
         }
         Ok(())
     }
 
-    fn read_inner(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
-        let mut total_read = 0;
-        let mut remaining = buf;
-
-        while !remaining.is_empty() {
-            let sector = self.pos / 512;
-            let offset = (self.pos % 512) as usize;
-
-            let cached = self.sector(sector)?;
-            let available = SECTOR_SIZE - offset;
-            let to_copy = available.min(remaining.len());
-            remaining[..to_copy].copy_from_slice(&cached.data[offset..offset + to_copy]);
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.blk.flush().map_err(|_| BlockError::Io)
+    }
 
-            remaining = &mut remaining[to_copy..];
-            self.pos += to_copy as u64;
-            total_read += to_copy;
-        }
+    /// One bounce page per request
+    fn max_transfer(&self) -> usize {
+        SECTORS_PER_PAGE
+    }
 
-        Ok(total_read)
+    fn name(&self) -> &str {
+        "virtio"
     }
+}
 
-    fn write_inner(&mut self, buf: &[u8]) -> Result<usize, ()> {
-        let mut total_written = 0;
-        let mut remaining = buf;
+/// Cursor into `DISK`, or into one of the loop devices, that is handed to
+/// fatfs and the raw FAT tools.
+pub struct DiskHandle {
+    pos: u64,
+    unit: Option<usize>,
+}
 
-        while !remaining.is_empty() {
-            let sector = self.pos / 512;
-            let offset = (self.pos % 512) as usize;
+impl DiskHandle {
+    pub fn new() -> Self {
+        DiskHandle { pos: 0, unit: None }
+    }
 
-            let cached = self.sector(sector)?;
-            let available = SECTOR_SIZE - offset;
-            let to_copy = available.min(remaining.len());
-            cached.data[offset..offset + to_copy].copy_from_slice(&remaining[..to_copy]);
-            cached.dirty = true;
+    /// Handle on loop device `unit` instead of the main disk
+    pub fn loop_device(unit: usize) -> Self {
+        DiskHandle { pos: 0, unit: Some(unit) }
+    }
 
-            remaining = &remaining[to_copy..];
-            self.pos += to_copy as u64;
-            total_written += to_copy;
+    fn with_disk<R>(&self, f: impl FnOnce(&mut Disk) -> Result<R, ()>) -> Result<R, ()> {
+        match self.unit {
+            None => f(DISK.lock().as_mut().ok_or(())?),
+            Some(unit) => f(super::loopdev::LOOPS.lock().get_mut(unit).and_then(|l| l.as_mut()).ok_or(())?.disk()),
         }
-
-        Ok(total_written)
     }
 }
 
-impl IoBase for VirtioBlockDevice {
+impl IoBase for DiskHandle {
     type Error = ();
 }
 
-impl Read for VirtioBlockDevice {
+impl Read for DiskHandle {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let c0 = crate::interrupts::rdtsc();
-        let result = self.read_inner(buf);
-        R_CYCLES.fetch_add(crate::interrupts::rdtsc() - c0, Ordering::Relaxed);
-        R_CALLS.fetch_add(1, Ordering::Relaxed);
-        result
+        let pos = self.pos;
+        let n = self.with_disk(|dev| {
+            dev.seek(SeekFrom::Start(pos))?;
+            dev.read(buf)
+        })?;
+        self.pos += n as u64;
+        Ok(n)
     }
 }
 
-impl Write for VirtioBlockDevice {
+impl Write for DiskHandle {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        let c0 = crate::interrupts::rdtsc();
-        let result = self.write_inner(buf);
-        W_CYCLES.fetch_add(crate::interrupts::rdtsc() - c0, Ordering::Relaxed);
-        W_CALLS.fetch_add(1, Ordering::Relaxed);
-        result
+        let pos = self.pos;
+        let n = self.with_disk(|dev| {
+            dev.seek(SeekFrom::Start(pos))?;
+            dev.write(buf)
+        })?;
+        self.pos += n as u64;
+        Ok(n)
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        self.flush_cache()
+        self.with_disk(|dev| dev.flush_cache())
     }
 }
 
-impl Seek for VirtioBlockDevice {
+impl Seek for DiskHandle {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let capacity = self.with_disk(|dev| Ok(dev.capacity_bytes()))?;
         self.pos = match pos {
             SeekFrom::Start(offset) => offset,
-            SeekFrom::End(offset) => (self.capacity_bytes as i64 + offset) as u64,
+            SeekFrom::End(offset) => (capacity as i64 + offset) as u64,
             SeekFrom::Current(offset) => (self.pos as i64 + offset) as u64,
         };
         Ok(self.pos)
     }
-}
\ No newline at end of file
+}
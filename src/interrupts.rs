@@ -1,4 +1,4 @@
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use crate::{gdt, hlt_loop, println, serial_println};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
@@ -24,6 +24,13 @@ pub enum InterruptIndex {
     Keyboard,
 }
 
+/// Handlers for device IRQ lines, stored as `fn()` pointers, 0 when unused
+static IRQ_HANDLERS: [AtomicUsize; 16] = [const { AtomicUsize::new(0) }; 16];
+
+/// Lines a device can be given. 0-2 are the timer, keyboard and cascade, and
+/// 7 and 15 also receive the PICs' spurious interrupts.
+const DEVICE_IRQS: [u8; 11] = [3, 4, 5, 6, 8, 9, 10, 11, 12, 13, 14];
+
 impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
@@ -53,6 +60,13 @@ lazy_static! {
 
         idt.page_fault.set_handler_fn(page_fault_handler);
 
+        let stubs: [extern "x86-interrupt" fn(InterruptStackFrame); 11] = [
+            irq3, irq4, irq5, irq6, irq8, irq9, irq10, irq11, irq12, irq13, irq14,
+        ];
+        for (&line, &stub) in DEVICE_IRQS.iter().zip(stubs.iter()) {
+            idt[PIC_1_OFFSET + line].set_handler_fn(stub);
+        }
+
         idt
     };
 }
@@ -75,6 +89,8 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     //print!(".");
 
     TICKS.fetch_add(1, Ordering::Relaxed);
+    crate::task::timer::on_tick();
+    crate::net::on_tick();
 
     unsafe {
         PICS
@@ -96,6 +112,60 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+/// Call `handler` from now on when IRQ `line` fires, and unmask the line
+pub fn register_irq(line: u8, handler: fn()) -> bool {
+    if !DEVICE_IRQS.contains(&line) {
+        return false;
+    }
+    IRQ_HANDLERS[line as usize].store(handler as usize, Ordering::SeqCst);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            let [mut master, mut slave] = pics.read_masks();
+            if line < 8 {
+                master &= !(1 << line);
+            } else {
+                // The slave PIC reaches the CPU through line 2 of the master
+                master &= !(1 << 2);
+                slave &= !(1 << (line - 8));
+            }
+            pics.write_masks(master, slave);
+        }
+    });
+    true
+}
+
+fn dispatch_irq(line: u8) {
+    let handler = IRQ_HANDLERS[line as usize].load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + line);
+    }
+}
+
+macro_rules! irq_stub {
+    ($name:ident, $line:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+            dispatch_irq($line);
+        }
+    };
+}
+
+irq_stub!(irq3, 3);
+irq_stub!(irq4, 4);
+irq_stub!(irq5, 5);
+irq_stub!(irq6, 6);
+irq_stub!(irq8, 8);
+irq_stub!(irq9, 9);
+irq_stub!(irq10, 10);
+irq_stub!(irq11, 11);
+irq_stub!(irq12, 12);
+irq_stub!(irq13, 13);
+irq_stub!(irq14, 14);
+
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2;
 
@@ -28,6 +28,8 @@ pub mod time;
 pub mod shell;
 pub mod util;
 pub mod fs;
+pub mod compress;
+pub mod crypto;
 pub mod device;
 pub mod net;
 
@@ -44,6 +46,7 @@ lazy_static! {
 
 pub fn load_config() {
     *CONFIG.lock() = config::SystemConfig::load();
+    fs::apply_cache_config();
 }
 
 pub fn init() {
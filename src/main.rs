@@ -7,7 +7,9 @@
 
 extern crate alloc;
 
-use test_os::device::ahci::{find_ahci_controller, initialize_ahci_controller, map_ahci_memory, AHCI_MEMORY_SIZE};
+use alloc::boxed::Box;
+
+use test_os::device::ahci::{self, find_ahci_controller};
 use test_os::{memory, println, allocator, register_kb_hook, serial_println};
 use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
@@ -53,27 +55,6 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         BootInfoFrameAllocator::init(&boot_info.memory_map)
     };
 
-    println!("Please wait, checking for AHCI...");
-
-    match find_ahci_controller() {
-        Some((bus, slot, function, base_addr)) => {
-            let base_addr = 0xFEBB1000;
-            serial_println!("Found AHCI controller at bus {}, slot {}, function {}. base addr is {:#X}", bus, slot, function, base_addr);
-            println!("Please wait, mapping AHCI memory...");
-            match map_ahci_memory(&mut mapper, &mut frame_allocator, base_addr, AHCI_MEMORY_SIZE) {
-                Ok(_) => {
-                    serial_println!("Mapped AHCI memory");
-                    match initialize_ahci_controller(base_addr) {
-                        Ok(controller) => { serial_println!("Initialized AHCI controller {:#?}", controller); }
-                        Err(e) => { serial_println!("Failed to initialize AHCI controller: {:#?}", e); }
-                    }
-                },
-                Err(e) => { serial_println!("Failed to map AHCI memory: {:#?}", e); }
-            }
-        }
-        None => { println!("No AHCI controller found"); }
-    }
-
     println!("Please wait, mapping heap...");
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
@@ -91,9 +72,22 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     init_hal(boot_info.physical_memory_offset);
     mark_dma_pool_uncached(boot_info.physical_memory_offset);
 
-    if let Some(blk) = find_and_init_blk(0xB000_0000) {
+    if let Some((_bus, _slot, _function, abar)) = find_ahci_controller() {
+        test_os::memory::split_and_remap_as_uncached(&mut frame_allocator, phys_mem_offset, abar & !0xFFF, 2);
+    }
+
+    let have_disk = if let Some(blk) = find_and_init_blk(0xB000_0000) {
         test_os::fs::init(blk);
+        true
+    } else {
+        println!("Please wait, checking for AHCI...");
+        match ahci::open_first_disk() {
+            Ok(disk) => { test_os::fs::init_device(Box::new(disk)); true }
+            Err(e) => { println!("No disk found: {:?}", e); false }
+        }
+    };
 
+    if have_disk {
         test_os::load_config();
 
         // Test it
@@ -109,10 +103,13 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_os::net::init();
     test_os::net::wait_for_dhcp();
     test_os::net::load_dns_cache();
+    test_os::net::sntp::boot_sync();
 
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
     executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(test_os::fs::flush_task()));
+    executor.spawn(Task::new(test_os::net::net_task()));
 
     register_kb_hook!(|| {
         serial_println!("Hello from hook");
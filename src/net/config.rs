@@ -0,0 +1,123 @@
+//! How the interface gets its address: from DHCP, or statically from the
+//! `net.*` keys in system.ini.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use smoltcp::wire::Ipv4Address;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetMode {
+    Dhcp,
+    Static,
+}
+
+impl NetMode {
+    pub fn parse(s: &str) -> Option<NetMode> {
+        match s {
+            "dhcp" => Some(NetMode::Dhcp),
+            "static" => Some(NetMode::Static),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NetMode::Dhcp => "dhcp",
+            NetMode::Static => "static",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetConfig {
+    pub mode: NetMode,
+    /// Only used in static mode
+    pub address: Option<Ipv4Address>,
+    pub prefix: u8,
+    pub gateway: Option<Ipv4Address>,
+    /// Name servers to query, in order. In DHCP mode these take precedence
+    /// over the lease's.
+    pub dns: Vec<Ipv4Address>,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        Self { mode: NetMode::Dhcp, address: None, prefix: 24, gateway: None, dns: Vec::new() }
+    }
+}
+
+/// Optional addresses are written as an empty value when unset
+fn parse_optional(value: &str) -> Option<Option<Ipv4Address>> {
+    match value {
+        "" | "none" => Some(None),
+        _ => value.parse().ok().map(Some),
+    }
+}
+
+fn format_optional(ip: Option<Ipv4Address>) -> String {
+    ip.map_or_else(String::new, |ip| alloc::format!("{}", ip))
+}
+
+impl NetConfig {
+    /// Settings as they appear in system.ini, after the `net.` prefix
+    pub const KEYS: [&'static str; 5] = ["mode", "address", "prefix", "gateway", "dns"];
+
+    /// Set one setting from its text form. Returns false for an unknown key
+    /// or a value that doesn't parse.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "mode" => match NetMode::parse(value) {
+                Some(mode) => self.mode = mode,
+                None => return false,
+            },
+            "address" => match parse_optional(value) {
+                Some(ip) => self.address = ip,
+                None => return false,
+            },
+            "prefix" => match value.parse::<u8>() {
+                Ok(n) if n <= 32 => self.prefix = n,
+                _ => return false,
+            },
+            "gateway" => match parse_optional(value) {
+                Some(ip) => self.gateway = ip,
+                None => return false,
+            },
+            "dns" => {
+                let servers: Option<Vec<Ipv4Address>> =
+                    value.split(',').map(str::trim).filter(|s| !s.is_empty()).map(|s| s.parse().ok()).collect();
+                match servers {
+                    Some(servers) => self.dns = servers,
+                    None => return false,
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The text form of a setting, as `set` accepts it
+    pub fn get(&self, key: &str) -> Option<String> {
+        Some(match key {
+            "mode" => String::from(self.mode.name()),
+            "address" => format_optional(self.address),
+            "prefix" => alloc::format!("{}", self.prefix),
+            "gateway" => format_optional(self.gateway),
+            "dns" => self.dns.iter().map(|ip| alloc::format!("{}", ip)).collect::<Vec<_>>().join(","),
+            _ => return None,
+        })
+    }
+}
+
+#[test_case]
+fn test_net_config() {
+    let mut config = NetConfig::default();
+    assert!(config.set("mode", "static"));
+    assert!(config.set("address", "192.168.1.20"));
+    assert!(config.set("dns", "1.1.1.1, 8.8.8.8"));
+    assert!(!config.set("prefix", "33"));
+    assert!(!config.set("gateway", "192.168.1"));
+    assert_eq!(config.get("dns").unwrap(), "1.1.1.1,8.8.8.8");
+    assert!(config.set("gateway", ""));
+    assert_eq!(config.gateway, None);
+    assert_eq!(config.mode, NetMode::Static);
+}
@@ -0,0 +1,182 @@
+//! DHCP lease bookkeeping around smoltcp's client.
+//!
+//! smoltcp runs the protocol itself: it renews at T1 and rebinds at T2 as
+//! long as the stack is polled, which `net_task` does in the background.
+//! This module gives it our hostname (option 12), keeps a copy of the lease
+//! for display and for its name servers, and sends DHCPRELEASE, which
+//! smoltcp has no support for.
+
+use alloc::vec::Vec;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use smoltcp::socket::dhcpv4;
+use smoltcp::wire::{DhcpOption, Ipv4Address};
+use super::socket::UdpSocket;
+use super::{NetError, NET, REPLY_TIMEOUT_MS};
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+// Option codes
+const OPT_HOSTNAME: u8 = 12;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_CLIENT_ID: u8 = 61;
+const OPT_END: u8 = 255;
+const DHCPRELEASE: u8 = 7;
+
+/// Enough for any offer or ack we'll see on an Ethernet link
+const PACKET_BUFFER_SIZE: usize = 1500;
+/// Host names are cut to a single DNS label
+const MAX_HOSTNAME: usize = 63;
+
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub address: Ipv4Address,
+    pub prefix_len: u8,
+    pub router: Option<Ipv4Address>,
+    /// The server that granted the lease, where renewals and the release go
+    pub server: Ipv4Address,
+    pub dns_servers: Vec<Ipv4Address>,
+    /// Lease length and the T1/T2 times in seconds, when the server said
+    pub lease_secs: Option<u32>,
+    pub renew_secs: Option<u32>,
+    pub rebind_secs: Option<u32>,
+    /// `now_ms` when the lease was granted or last changed
+    pub obtained_ms: i64,
+}
+
+impl Lease {
+    pub(super) fn new(config: &dhcpv4::Config, now_ms: i64) -> Lease {
+        let mut lease = Lease {
+            address: config.address.address(),
+            prefix_len: config.address.prefix_len(),
+            router: config.router,
+            server: config.server.identifier,
+            dns_servers: config.dns_servers.iter().copied().collect(),
+            lease_secs: None,
+            renew_secs: None,
+            rebind_secs: None,
+            obtained_ms: now_ms,
+        };
+        if let Some(packet) = &config.packet {
+            for option in packet.options() {
+                let secs = match option.data {
+                    &[a, b, c, d] => Some(u32::from_be_bytes([a, b, c, d])),
+                    _ => None,
+                };
+                match option.kind {
+                    OPT_LEASE_TIME => lease.lease_secs = secs,
+                    OPT_RENEWAL_TIME => lease.renew_secs = secs,
+                    OPT_REBINDING_TIME => lease.rebind_secs = secs,
+                    _ => {}
+                }
+            }
+        }
+        lease
+    }
+
+    /// Seconds from grant to renewal, rebinding and expiry, using the
+    /// RFC 2131 defaults of 1/2 and 7/8 of the lease where T1 and T2 weren't
+    /// given. None for an infinite lease.
+    pub fn timers(&self) -> Option<(u32, u32, u32)> {
+        let lease = self.lease_secs.filter(|&s| s != u32::MAX)?;
+        let renew = self.renew_secs.unwrap_or(lease / 2);
+        let rebind = self.rebind_secs.unwrap_or((lease as u64 * 7 / 8) as u32);
+        Some((renew, rebind, lease))
+    }
+}
+
+/// The hostname option's storage. smoltcp borrows it for as long as the
+/// socket lives; `apply_hostname` points the socket away before rewriting it.
+static mut HOSTNAME: [u8; MAX_HOSTNAME] = [0; MAX_HOSTNAME];
+static mut HOSTNAME_OPTION: [DhcpOption<'static>; 1] = [DhcpOption { kind: OPT_HOSTNAME, data: &[] }];
+/// The reply buffer `Lease::new` reads lease times from
+static mut RECEIVE_BUFFER: [u8; PACKET_BUFFER_SIZE] = [0; PACKET_BUFFER_SIZE];
+/// Set once the single DHCP socket exists. `NetStack` keeps it while DHCP is
+/// stopped instead of making another, so the statics above have one user.
+static SOCKET_CREATED: AtomicBool = AtomicBool::new(false);
+
+/// The DHCP client, sending `hostname` and keeping the server's reply so
+/// `Lease::new` can read its lease times. Only one can be made.
+pub(super) fn new_socket(hostname: &str) -> dhcpv4::Socket<'static> {
+    assert!(!SOCKET_CREATED.swap(true, Ordering::AcqRel), "DHCP socket already created");
+    let mut socket = dhcpv4::Socket::new();
+    apply_hostname(&mut socket, hostname);
+    // SAFETY: this is the only socket, so the buffer has no other borrower
+    socket.set_receive_packet_buffer(unsafe { &mut *addr_of_mut!(RECEIVE_BUFFER) });
+    socket
+}
+
+/// Point the client at a possibly changed hostname. Callers hold the `NET`
+/// lock, or own the socket before it's added to the stack.
+pub(super) fn apply_hostname(socket: &mut dhcpv4::Socket<'static>, hostname: &str) {
+    let name: Vec<u8> = hostname.bytes().filter(|c| c.is_ascii_alphanumeric() || *c == b'-').take(MAX_HOSTNAME).collect();
+    socket.set_outgoing_options(&[]);
+    // SAFETY: the only socket that borrows these has just let go of them,
+    // and nothing else can reach it meanwhile
+    let option: &'static [DhcpOption<'static>] = unsafe {
+        let buffer = &mut *addr_of_mut!(HOSTNAME);
+        buffer[..name.len()].copy_from_slice(&name);
+        let option = &mut *addr_of_mut!(HOSTNAME_OPTION);
+        option[0].data = &(&*addr_of!(HOSTNAME))[..name.len()];
+        &*addr_of!(HOSTNAME_OPTION)
+    };
+    socket.set_outgoing_options(option);
+}
+
+/// Send `hostname` from the next DHCP message on, such as the next renewal
+pub fn set_hostname(hostname: &str) {
+    if let Some(stack) = NET.lock().as_mut() {
+        if let Some(handle) = stack.dhcp_handle {
+            apply_hostname(stack.sockets.get_mut::<dhcpv4::Socket>(handle), hostname);
+        }
+    }
+}
+
+/// DHCPRELEASE for `lease`, built by hand
+fn release_packet(mac: [u8; 6], lease: &Lease) -> Vec<u8> {
+    let mut packet = alloc::vec![0u8; 236];
+    packet[0] = 1; // BOOTREQUEST
+    packet[1] = 1; // Ethernet
+    packet[2] = 6;
+    packet[4..8].copy_from_slice(&(super::now_ms() as u32).to_be_bytes());
+    packet[12..16].copy_from_slice(&lease.address.octets());
+    packet[28..34].copy_from_slice(&mac);
+    packet.extend_from_slice(&[99, 130, 83, 99]);
+    packet.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, DHCPRELEASE]);
+    packet.extend_from_slice(&[OPT_SERVER_ID, 4]);
+    packet.extend_from_slice(&lease.server.octets());
+    // smoltcp identifies itself by hardware address, so match that
+    packet.extend_from_slice(&[OPT_CLIENT_ID, 7, 1]);
+    packet.extend_from_slice(&mac);
+    packet.push(OPT_END);
+    packet
+}
+
+/// Hand the lease back to the server and stop the DHCP client, leaving the
+/// interface without an address until `renew`
+pub async fn release() -> Result<Ipv4Address, NetError> {
+    let lease = NET.lock().as_ref().ok_or(NetError::NoStack)?.lease.clone().ok_or(NetError::Unaddressable)?;
+    let mac = crate::device::e1000::E1000_DEV.lock().as_ref().ok_or(NetError::NoStack)?.mac;
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    socket.send_to(&release_packet(mac, &lease), lease.server, SERVER_PORT).await?;
+    // The release needs our address to go out, so wait before dropping it
+    socket.flush(REPLY_TIMEOUT_MS as u64).await?;
+    if let Some(stack) = NET.lock().as_mut() {
+        stack.stop_dhcp();
+    }
+    Ok(lease.address)
+}
+
+/// Start the DHCP exchange over, picking up the current hostname.
+/// smoltcp can't be made to renew early, so this asks for a lease from
+/// scratch; the server normally hands back the same address.
+pub fn renew() -> Result<(), NetError> {
+    let hostname = crate::CONFIG.lock().hostname.clone();
+    NET.lock().as_mut().ok_or(NetError::NoStack)?.restart_dhcp(&hostname);
+    Ok(())
+}
@@ -0,0 +1,597 @@
+//! DNS resolver and cache.
+//!
+//! Queries go out over UDP to each name server in turn. CNAME chains are
+//! followed, within one reply or with further queries when the server
+//! stops at an alias. Answers are cached for as long as their TTLs allow,
+//! and names that don't exist for as long as the zone's SOA says (RFC 2308),
+//! so a mistyped host isn't asked about on every attempt. Expiry is kept in
+//! wall-clock seconds, which lets dns.dat outlive a reboot.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use lazy_static::lazy_static;
+use smoltcp::wire::Ipv4Address;
+use spin::Mutex;
+use crate::serial_println;
+use super::socket::{self, NetError, UdpSocket};
+use super::{NET, REPLY_TIMEOUT_MS};
+
+pub const DNS_CACHE_FILE: &str = "dns.dat";
+
+const DNS_PORT: u16 = 53;
+/// Big enough for any reply to a query without EDNS
+const MAX_MESSAGE: usize = 1500;
+/// Most CNAMEs followed for one name
+const MAX_CNAME_HOPS: usize = 8;
+/// Longest anything is cached, whatever its TTL
+const MAX_TTL: u32 = 86_400;
+/// How long a missing name is remembered when the reply has no SOA
+const DEFAULT_NEGATIVE_TTL: u32 = 60;
+/// RFC 2308's suggested ceiling for negative answers
+const MAX_NEGATIVE_TTL: u32 = 3_600;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+const TYPE_NAMES: [(u16, &str); 8] = [
+    (TYPE_A, "A"),
+    (TYPE_NS, "NS"),
+    (TYPE_CNAME, "CNAME"),
+    (TYPE_SOA, "SOA"),
+    (TYPE_PTR, "PTR"),
+    (TYPE_MX, "MX"),
+    (TYPE_TXT, "TXT"),
+    (TYPE_AAAA, "AAAA"),
+];
+
+pub fn type_name(rtype: u16) -> Option<&'static str> {
+    TYPE_NAMES.iter().find(|(t, _)| *t == rtype).map(|(_, name)| *name)
+}
+
+/// A record type by name, or as a number for the ones we don't know
+pub fn parse_type(name: &str) -> Option<u16> {
+    TYPE_NAMES.iter().find(|(_, n)| n.eq_ignore_ascii_case(name)).map(|(t, _)| *t).or_else(|| name.parse().ok())
+}
+
+pub fn rcode_name(rcode: u8) -> &'static str {
+    match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsError {
+    Net(NetError),
+    /// Not something that can be sent as a DNS name
+    BadName,
+    /// Every server failed with this response code
+    Server(u8),
+    /// The CNAME chain was too long or went in circles
+    CnameLoop,
+}
+
+impl From<NetError> for DnsError {
+    fn from(e: NetError) -> Self {
+        DnsError::Net(e)
+    }
+}
+
+/// A reply that doesn't parse. Replies are only ever skipped for this, so
+/// it never reaches callers.
+#[derive(Debug)]
+struct Malformed;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RData {
+    A(Ipv4Address),
+    Aaaa(core::net::Ipv6Addr),
+    /// The target of a CNAME, NS or PTR
+    Name(String),
+    Mx(u16, String),
+    Txt(Vec<String>),
+    Soa { mname: String, rname: String, serial: u32, minimum: u32 },
+    Other(Vec<u8>),
+}
+
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RData::A(ip) => write!(f, "{}", ip),
+            RData::Aaaa(ip) => write!(f, "{}", ip),
+            RData::Name(name) => write!(f, "{}.", name),
+            RData::Mx(preference, name) => write!(f, "{} {}.", preference, name),
+            RData::Txt(strings) => {
+                for (i, s) in strings.iter().enumerate() {
+                    write!(f, "{}{:?}", if i > 0 { " " } else { "" }, s)?;
+                }
+                Ok(())
+            }
+            RData::Soa { mname, rname, serial, minimum } => write!(f, "{}. {}. {} {}", mname, rname, serial, minimum),
+            RData::Other(data) => write!(f, "\\# {} {}", data.len(), crate::crypto::hex(data)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub id: u16,
+    pub rcode: u8,
+    /// The server ran out of room; the sections hold what fitted
+    pub truncated: bool,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+}
+
+/// A recursive query for `name`
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, DnsError> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        return Err(DnsError::BadName);
+    }
+    let mut msg = Vec::with_capacity(18 + name.len());
+    msg.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::BadName);
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(msg)
+}
+
+struct Reader<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Malformed> {
+        let bytes = self.msg.get(self.pos..self.pos + n).ok_or(Malformed)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Malformed> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Malformed> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Malformed> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// A possibly compressed name, without the trailing dot
+    fn name(&mut self) -> Result<String, Malformed> {
+        let mut name = String::new();
+        let mut pos = self.pos;
+        // Where reading continues once the name is done: after the first
+        // pointer, if there is one
+        let mut resume = None;
+        // A name has at most 127 labels, so more steps than that means a
+        // pointer loop
+        for _ in 0..256 {
+            let len = *self.msg.get(pos).ok_or(Malformed)? as usize;
+            match len {
+                0 => {
+                    self.pos = resume.unwrap_or(pos + 1);
+                    return Ok(name);
+                }
+                l if l & 0xC0 == 0xC0 => {
+                    let low = *self.msg.get(pos + 1).ok_or(Malformed)? as usize;
+                    resume.get_or_insert(pos + 2);
+                    pos = (l & 0x3F) << 8 | low;
+                }
+                l if l & 0xC0 == 0 => {
+                    let label = self.msg.get(pos + 1..pos + 1 + l).ok_or(Malformed)?;
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    name.push_str(&String::from_utf8_lossy(label));
+                    pos += 1 + l;
+                }
+                _ => return Err(Malformed),
+            }
+        }
+        Err(Malformed)
+    }
+
+    fn record(&mut self) -> Result<Record, Malformed> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let _class = self.u16()?;
+        // RFC 2181: a TTL with the top bit set is treated as zero
+        let ttl = match self.u32()? {
+            ttl if ttl > i32::MAX as u32 => 0,
+            ttl => ttl,
+        };
+        let len = self.u16()? as usize;
+        let start = self.pos;
+        let end = start + len;
+        if end > self.msg.len() {
+            return Err(Malformed);
+        }
+        let data = match rtype {
+            TYPE_A if len == 4 => {
+                let b = self.bytes(4)?;
+                RData::A(Ipv4Address::new(b[0], b[1], b[2], b[3]))
+            }
+            TYPE_AAAA if len == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(self.bytes(16)?);
+                RData::Aaaa(core::net::Ipv6Addr::from(octets))
+            }
+            TYPE_CNAME | TYPE_NS | TYPE_PTR => RData::Name(self.name()?),
+            TYPE_MX => {
+                let preference = self.u16()?;
+                RData::Mx(preference, self.name()?)
+            }
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                while self.pos < end {
+                    let n = self.u8()? as usize;
+                    strings.push(String::from_utf8_lossy(self.bytes(n)?).into_owned());
+                }
+                RData::Txt(strings)
+            }
+            TYPE_SOA => {
+                let mname = self.name()?;
+                let rname = self.name()?;
+                let serial = self.u32()?;
+                // Refresh, retry and expire only matter to secondaries
+                self.bytes(12)?;
+                RData::Soa { mname, rname, serial, minimum: self.u32()? }
+            }
+            _ => RData::Other(self.msg[start..end].to_vec()),
+        };
+        if self.pos > end {
+            return Err(Malformed);
+        }
+        self.pos = end;
+        Ok(Record { name, rtype, ttl, data })
+    }
+}
+
+fn parse(msg: &[u8]) -> Result<Message, Malformed> {
+    let mut reader = Reader { msg, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    if flags & 0x8000 == 0 {
+        return Err(Malformed);
+    }
+    let truncated = flags & 0x0200 != 0;
+    let questions = reader.u16()?;
+    let answer_count = reader.u16()?;
+    let authority_count = reader.u16()?;
+    let _additional = reader.u16()?;
+    for _ in 0..questions {
+        reader.name()?;
+        reader.bytes(4)?;
+    }
+    let mut answers = Vec::new();
+    let mut authority = Vec::new();
+    for (section, count) in [(&mut answers, answer_count), (&mut authority, authority_count)] {
+        for _ in 0..count {
+            match reader.record() {
+                Ok(record) => section.push(record),
+                // A truncated reply stops wherever the server ran out of room
+                Err(_) if truncated => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(Message { id, rcode: (flags & 0x0F) as u8, truncated, answers, authority })
+}
+
+/// Wait for the reply to query `id`, ignoring anything else that arrives
+async fn recv_reply(socket: &UdpSocket, server: Ipv4Address, id: u16) -> Result<Message, DnsError> {
+    let mut buf = alloc::vec![0u8; MAX_MESSAGE];
+    loop {
+        let (n, from, port) = socket.recv_from(&mut buf).await?;
+        if from != server || port != DNS_PORT {
+            continue;
+        }
+        match parse(&buf[..n]) {
+            Ok(msg) if msg.id == id => return Ok(msg),
+            Ok(_) => {}
+            Err(Malformed) => serial_println!("[dns] Malformed reply from {}", server),
+        }
+    }
+}
+
+/// Ask `server` about `name`'s records of type `qtype`
+pub async fn query(name: &str, qtype: u16, server: Ipv4Address) -> Result<Message, DnsError> {
+    // A random ID and port make forged replies hard to slip in
+    let id = u16::from_be_bytes(crate::crypto::rng::random_bytes());
+    let request = encode_query(id, name, qtype)?;
+    let socket = UdpSocket::bind(0)?;
+    socket.send_to(&request, server, DNS_PORT).await?;
+    socket::timeout(REPLY_TIMEOUT_MS as u64, recv_reply(&socket, server, id)).await
+}
+
+/// Ask each name server in turn until one answers. Returns the server that
+/// did along with its reply.
+pub async fn query_servers(name: &str, qtype: u16) -> Result<(Ipv4Address, Message), DnsError> {
+    let servers = NET.lock().as_ref().ok_or(NetError::NoStack)?.name_servers();
+    let mut error = DnsError::Net(NetError::Unaddressable);
+    for server in servers {
+        match query(name, qtype, server).await {
+            Ok(msg) if msg.rcode == RCODE_NOERROR || msg.rcode == RCODE_NXDOMAIN => return Ok((server, msg)),
+            Ok(msg) => error = DnsError::Server(msg.rcode),
+            Err(e @ DnsError::Net(NetError::TimedOut | NetError::Unaddressable)) => error = e,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(error)
+}
+
+/// What a reply says about a name's addresses
+#[derive(Debug, PartialEq)]
+enum Answer {
+    Found { addrs: Vec<Ipv4Address>, canonical: Option<String>, ttl: u32 },
+    /// The reply stopped at an alias; `target` needs a query of its own
+    Alias { target: String, ttl: u32 },
+    /// No such name, or no address for it
+    Missing { canonical: Option<String>, ttl: u32 },
+}
+
+fn interpret(name: &str, msg: &Message) -> Answer {
+    let mut owner = String::from(name.trim_end_matches('.'));
+    let mut canonical = None;
+    let mut ttl = MAX_TTL;
+    for _ in 0..MAX_CNAME_HOPS {
+        let next = msg.answers.iter().find_map(|r| match &r.data {
+            RData::Name(target) if r.rtype == TYPE_CNAME && r.name.eq_ignore_ascii_case(&owner) => Some((target, r.ttl)),
+            _ => None,
+        });
+        match next {
+            Some((target, alias_ttl)) => {
+                owner = target.clone();
+                canonical = Some(target.clone());
+                ttl = ttl.min(alias_ttl);
+            }
+            None => break,
+        }
+    }
+
+    let mut addrs = Vec::new();
+    for record in &msg.answers {
+        if let RData::A(ip) = record.data {
+            if record.name.eq_ignore_ascii_case(&owner) {
+                addrs.push(ip);
+                ttl = ttl.min(record.ttl);
+            }
+        }
+    }
+    if !addrs.is_empty() {
+        return Answer::Found { addrs, canonical, ttl };
+    }
+
+    // RFC 2308: a negative answer lasts as long as the SOA record's TTL or
+    // its minimum field, whichever is less
+    let soa_ttl = msg.authority.iter().find_map(|r| match r.data {
+        RData::Soa { minimum, .. } => Some(r.ttl.min(minimum)),
+        _ => None,
+    });
+    match (msg.rcode, &canonical, soa_ttl) {
+        (RCODE_NOERROR, Some(_), None) => Answer::Alias { target: owner, ttl },
+        _ => {
+            let negative_ttl = soa_ttl.unwrap_or(DEFAULT_NEGATIVE_TTL).min(MAX_NEGATIVE_TTL);
+            Answer::Missing { canonical, ttl: ttl.min(negative_ttl) }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    /// Empty when the name doesn't exist or has no address
+    pub addrs: Vec<Ipv4Address>,
+    /// Where a CNAME chain led, if the name is an alias
+    pub canonical: Option<String>,
+    /// Seconds since the Unix epoch
+    pub expires: u64,
+}
+
+lazy_static! {
+    pub static ref DNS_CACHE: Mutex<BTreeMap<String, CacheEntry>> = Mutex::new(BTreeMap::new());
+}
+
+fn cache_key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn cache_get(name: &str) -> Option<CacheEntry> {
+    let now = crate::time::now();
+    DNS_CACHE.lock().get(&cache_key(name)).filter(|entry| entry.expires > now).cloned()
+}
+
+/// Everything cached that hasn't expired, with seconds left
+pub fn cache_entries() -> Vec<(String, CacheEntry, u64)> {
+    let now = crate::time::now();
+    DNS_CACHE
+        .lock()
+        .iter()
+        .filter(|(_, entry)| entry.expires > now)
+        .map(|(name, entry)| (name.clone(), entry.clone(), entry.expires - now))
+        .collect()
+}
+
+/// Forget every cached answer, on disk too
+pub fn flush_cache() -> usize {
+    let count = core::mem::take(&mut *DNS_CACHE.lock()).len();
+    if crate::fs::exists(DNS_CACHE_FILE) {
+        crate::fs::delete_file(DNS_CACHE_FILE);
+    }
+    count
+}
+
+/// Addresses, canonical name and TTL for `name`, following aliases the
+/// servers didn't
+async fn resolve_uncached(name: &str) -> Result<(Vec<Ipv4Address>, Option<String>, u32), DnsError> {
+    let mut target = String::from(name);
+    let mut canonical = None;
+    let mut ttl = MAX_TTL;
+    for _ in 0..MAX_CNAME_HOPS {
+        let (_, msg) = query_servers(&target, TYPE_A).await?;
+        match interpret(&target, &msg) {
+            Answer::Found { addrs, canonical: alias, ttl: answer_ttl } => {
+                return Ok((addrs, alias.or(canonical), ttl.min(answer_ttl)));
+            }
+            Answer::Missing { canonical: alias, ttl: answer_ttl } => {
+                return Ok((Vec::new(), alias.or(canonical), ttl.min(answer_ttl)));
+            }
+            Answer::Alias { target: next, ttl: alias_ttl } => {
+                ttl = ttl.min(alias_ttl);
+                canonical = Some(next.clone());
+                target = next;
+            }
+        }
+    }
+    Err(DnsError::CnameLoop)
+}
+
+/// `hostname`'s address, from the cache or the name servers
+pub async fn lookup(hostname: &str) -> Option<Ipv4Address> {
+    if let Ok(ip) = hostname.parse() {
+        return Some(ip);
+    }
+    if let Some(entry) = cache_get(hostname) {
+        serial_println!("[dns] Cache hit: {} -> {:?}", hostname, entry.addrs);
+        return entry.addrs.first().copied();
+    }
+    let (addrs, canonical, ttl) = match resolve_uncached(hostname).await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            serial_println!("[dns] {}: {:?}", hostname, e);
+            return None;
+        }
+    };
+    serial_println!("[dns] {} -> {:?} via {:?}, ttl {}s", hostname, addrs, canonical, ttl);
+    let first = addrs.first().copied();
+    if ttl > 0 {
+        let entry = CacheEntry { addrs, canonical, expires: crate::time::now() + ttl as u64 };
+        DNS_CACHE.lock().insert(cache_key(hostname), entry);
+        save_dns_cache();
+    }
+    first
+}
+
+/// Write out the unexpired entries, one per line:
+/// `name expires addr,addr|- [canonical]`
+pub fn save_dns_cache() {
+    let now = crate::time::now();
+    let mut cache = DNS_CACHE.lock();
+    cache.retain(|_, entry| entry.expires > now);
+    if cache.is_empty() {
+        return;
+    }
+
+    let mut data = String::new();
+    for (name, entry) in cache.iter() {
+        let addrs = if entry.addrs.is_empty() {
+            String::from("-")
+        } else {
+            entry.addrs.iter().map(|ip| alloc::format!("{}", ip)).collect::<Vec<_>>().join(",")
+        };
+        data.push_str(&alloc::format!("{} {} {}", name, entry.expires, addrs));
+        if let Some(canonical) = &entry.canonical {
+            data.push(' ');
+            data.push_str(canonical);
+        }
+        data.push('\n');
+    }
+
+    crate::fs::write_file(DNS_CACHE_FILE, data.as_bytes());
+    serial_println!("[dns] Cache saved ({} entries)", cache.len());
+}
+
+fn parse_cache_line(line: &str) -> Option<(String, CacheEntry)> {
+    let mut fields = line.split_whitespace();
+    let name = fields.next()?;
+    let expires = fields.next()?.parse().ok()?;
+    let addrs = match fields.next()? {
+        "-" => Vec::new(),
+        list => list.split(',').map(|ip| ip.parse().ok()).collect::<Option<Vec<_>>>()?,
+    };
+    let canonical = fields.next().map(String::from);
+    Some((String::from(name), CacheEntry { addrs, canonical, expires }))
+}
+
+pub fn load_dns_cache() {
+    let data = match crate::fs::read_file(DNS_CACHE_FILE) {
+        Some(d) => d,
+        None => { serial_println!("[dns] No cache file"); return; }
+    };
+    let text = match core::str::from_utf8(&data) {
+        Ok(t) => t,
+        Err(_) => return,
+    };
+
+    let now = crate::time::now();
+    let mut cache = DNS_CACHE.lock();
+    let mut count = 0;
+    for (name, entry) in text.lines().filter_map(parse_cache_line) {
+        if entry.expires > now {
+            cache.insert(name, entry);
+            count += 1;
+        }
+    }
+    serial_println!("[dns] Cache loaded ({} entries)", count);
+}
+
+#[test_case]
+fn test_parse_cname_reply() {
+    // www.example.com CNAME example.com (compressed), example.com A 192.0.2.1
+    let reply: &[u8] = &[
+        0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0,
+        3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1,
+        0xC0, 12, 0, 5, 0, 1, 0, 0, 0x0E, 0x10, 0, 2, 0xC0, 16,
+        0xC0, 16, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1,
+    ];
+    let msg = parse(reply).unwrap();
+    assert_eq!(msg.id, 0x1234);
+    assert_eq!(msg.answers[0].data, RData::Name(String::from("example.com")));
+    assert_eq!(
+        interpret("WWW.example.com.", &msg),
+        Answer::Found { addrs: alloc::vec![Ipv4Address::new(192, 0, 2, 1)], canonical: Some(String::from("example.com")), ttl: 60 }
+    );
+    // A pointer to itself must not hang
+    assert!(parse(&[0, 0, 0x80, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xC0, 12, 0, 1, 0, 1]).is_err());
+    assert_eq!(encode_query(1, "a..b", TYPE_A), Err(DnsError::BadName));
+}
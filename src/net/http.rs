@@ -0,0 +1,592 @@
+//! HTTP/1.1 client.
+//!
+//! Parses the status line and headers, reads bodies delimited by
+//! `Content-Length`, chunked transfer encoding or the connection closing, and
+//! follows redirects. `https` URLs go over `tls`. Bodies are handed to a `Sink` as they arrive, so a
+//! download can go straight to a file. A `Client` keeps its connection open
+//! between requests to the same host when the server allows it.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::compress::CompressError;
+use crate::serial_println;
+use super::socket::{timeout, NetError, TcpStream};
+use super::tls::{TlsError, TlsStream};
+use super::{lookup, REPLY_TIMEOUT_MS, RECV_IDLE_TIMEOUT_MS};
+
+/// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 5;
+/// Longest status or header line accepted
+const MAX_LINE: usize = 8192;
+const MAX_HEADERS: usize = 100;
+/// Largest body a `MemorySink` will hold
+pub const MAX_MEMORY_BODY: usize = 1024 * 1024;
+/// How much a `FileSink` collects before appending to the file
+const FILE_FLUSH_SIZE: usize = 32 * 1024;
+const USER_AGENT: &str = "myos-fetch/1.0";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpError {
+    Net(NetError),
+    Tls(TlsError),
+    BadUrl,
+    UnsupportedScheme,
+    /// The host name didn't resolve
+    Dns,
+    /// The server sent something that isn't HTTP
+    BadResponse,
+    TooManyRedirects,
+    BodyTooLarge,
+    /// The body couldn't be written to its file
+    Write,
+    Decode(CompressError),
+}
+
+impl From<NetError> for HttpError {
+    fn from(e: NetError) -> Self {
+        HttpError::Net(e)
+    }
+}
+
+impl From<TlsError> for HttpError {
+    fn from(e: TlsError) -> Self {
+        match e {
+            TlsError::Net(e) => HttpError::Net(e),
+            e => HttpError::Tls(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    /// Path and query, always starting with '/'
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, HttpError> {
+        let (scheme, rest) = match url.split_once("://") {
+            Some((scheme, rest)) => (scheme.to_ascii_lowercase(), rest),
+            None => (String::from("http"), url),
+        };
+        let default_port = default_port(&scheme).ok_or(HttpError::UnsupportedScheme)?;
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        // Fragments never go to the server
+        let path = path.split('#').next().unwrap_or("");
+        let path = if path.starts_with('/') { String::from(path) } else { alloc::format!("/{}", path) };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| HttpError::BadUrl)?),
+            None => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(HttpError::BadUrl);
+        }
+        Ok(Url { scheme, host: host.to_ascii_lowercase(), port, path })
+    }
+
+    /// Resolve a `Location` header against this URL
+    pub fn join(&self, location: &str) -> Result<Url, HttpError> {
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            return Url::parse(&alloc::format!("{}://{}", self.scheme, rest));
+        }
+        let path = if location.starts_with('/') {
+            String::from(location)
+        } else {
+            // Relative to the directory of the current path
+            let dir = self.path.split('?').next().unwrap_or("/");
+            let dir = &dir[..dir.rfind('/').map_or(0, |i| i + 1)];
+            alloc::format!("{}{}", dir, location)
+        };
+        Ok(Url { path, ..self.clone() })
+    }
+
+    fn is_https(&self) -> bool {
+        self.scheme == "https"
+    }
+
+    /// The Host header value: the port is left out when it is the default
+    fn host_header(&self) -> String {
+        if Some(self.port) == default_port(&self.scheme) {
+            self.host.clone()
+        } else {
+            alloc::format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+impl core::fmt::Display for Url {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}://{}{}", self.scheme, self.host_header(), self.path)
+    }
+}
+
+pub struct Request {
+    pub method: String,
+    pub url: Url,
+    /// Sent after the client's own headers, replacing any with the same name
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Ask for gzip or deflate. Only worth it when the body is buffered,
+    /// since `decode_body` needs all of it.
+    pub accept_compressed: bool,
+}
+
+impl Request {
+    pub fn new(method: &str, url: Url) -> Request {
+        Request {
+            method: method.to_ascii_uppercase(),
+            url,
+            headers: Vec::new(),
+            body: Vec::new(),
+            accept_compressed: false,
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut head = alloc::format!("{} {} HTTP/1.1\r\n", self.method, self.url.path);
+        let mut add = |name: &str, value: &str| {
+            if self.header(name).is_none() {
+                head.push_str(&alloc::format!("{}: {}\r\n", name, value));
+            }
+        };
+        add("Host", &self.url.host_header());
+        add("User-Agent", USER_AGENT);
+        add("Accept", "*/*");
+        add("Accept-Encoding", if self.accept_compressed { "gzip, deflate" } else { "identity" });
+        if !self.body.is_empty() || matches!(self.method.as_str(), "POST" | "PUT" | "PATCH") {
+            add("Content-Length", &alloc::format!("{}", self.body.len()));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&alloc::format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    /// Minor version from the status line: 0 for HTTP/1.0, 1 for HTTP/1.1
+    pub version: u8,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    /// Where the response finally came from, after any redirects
+    pub url: Url,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    fn header_has(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("content-length")?.trim().parse().ok()
+    }
+
+    fn is_redirect(&self) -> bool {
+        matches!(self.status, 301 | 302 | 303 | 307 | 308) && self.header("location").is_some()
+    }
+
+    /// Whether the server will leave the connection open after this response
+    fn keep_alive(&self) -> bool {
+        if self.version == 0 {
+            self.header_has("connection", "keep-alive")
+        } else {
+            !self.header_has("connection", "close")
+        }
+    }
+}
+
+/// Where a response body goes as it arrives
+pub trait Sink {
+    fn write(&mut self, data: &[u8]) -> Result<(), HttpError>;
+}
+
+/// Collects the body in memory, up to `MAX_MEMORY_BODY`
+#[derive(Default)]
+pub struct MemorySink {
+    pub data: Vec<u8>,
+}
+
+impl Sink for MemorySink {
+    fn write(&mut self, data: &[u8]) -> Result<(), HttpError> {
+        if self.data.len() + data.len() > MAX_MEMORY_BODY {
+            return Err(HttpError::BodyTooLarge);
+        }
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Appends the body to a file in `FILE_FLUSH_SIZE` pieces
+pub struct FileSink {
+    path: String,
+    pending: Vec<u8>,
+    pub written: u64,
+}
+
+impl FileSink {
+    /// Create (or truncate) `path`
+    pub fn create(path: &str) -> Result<FileSink, HttpError> {
+        if !crate::fs::write_file(path, &[]) {
+            return Err(HttpError::Write);
+        }
+        Ok(FileSink { path: String::from(path), pending: Vec::new(), written: 0 })
+    }
+
+    /// Write out whatever is still buffered
+    pub fn finish(&mut self) -> Result<(), HttpError> {
+        if !self.pending.is_empty() {
+            if !crate::fs::append_file(&self.path, &self.pending) {
+                return Err(HttpError::Write);
+            }
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, data: &[u8]) -> Result<(), HttpError> {
+        self.pending.extend_from_slice(data);
+        self.written += data.len() as u64;
+        if self.pending.len() >= FILE_FLUSH_SIZE {
+            self.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Throws the body away, for redirects
+struct Discard;
+
+impl Sink for Discard {
+    fn write(&mut self, _data: &[u8]) -> Result<(), HttpError> {
+        Ok(())
+    }
+}
+
+/// The socket under a connection, encrypted for https
+enum Transport {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Transport {
+    fn is_tls(&self) -> bool {
+        matches!(self, Transport::Tls(_))
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, HttpError> {
+        match self {
+            Transport::Plain(stream) => Ok(stream.read(buf).await?),
+            Transport::Tls(stream) => Ok(stream.read(buf).await?),
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), HttpError> {
+        match self {
+            Transport::Plain(stream) => Ok(stream.write_all(data).await?),
+            Transport::Tls(stream) => Ok(stream.write_all(data).await?),
+        }
+    }
+}
+
+/// A connection with the bytes read past the last line or body
+struct Conn {
+    stream: Transport,
+    buf: Vec<u8>,
+}
+
+impl Conn {
+    /// Read more into `buf`. False once the server has closed.
+    async fn fill(&mut self) -> Result<bool, HttpError> {
+        let mut chunk = [0u8; 2048];
+        let n = timeout(RECV_IDLE_TIMEOUT_MS as u64, self.stream.read(&mut chunk)).await?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    /// One line without its CRLF. None if the connection closed first.
+    async fn read_line(&mut self) -> Result<Option<String>, HttpError> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                return Ok(Some(String::from(line.trim_end_matches(['\r', '\n']))));
+            }
+            if self.buf.len() > MAX_LINE {
+                return Err(HttpError::BadResponse);
+            }
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Pass up to `limit` bytes on to `sink`, or everything until the
+    /// connection closes if there is no limit. Returns how many were passed.
+    async fn copy(&mut self, limit: Option<u64>, sink: &mut dyn Sink) -> Result<u64, HttpError> {
+        let mut copied = 0u64;
+        loop {
+            let want = limit.map_or(usize::MAX, |l| (l - copied).min(usize::MAX as u64) as usize);
+            if want == 0 {
+                return Ok(copied);
+            }
+            if self.buf.is_empty() && !self.fill().await? {
+                return match limit {
+                    Some(_) => Err(HttpError::Net(NetError::ConnectionReset)),
+                    None => Ok(copied),
+                };
+            }
+            let n = want.min(self.buf.len());
+            sink.write(&self.buf[..n])?;
+            self.buf.drain(..n);
+            copied += n as u64;
+        }
+    }
+}
+
+/// Sends requests, reusing one connection while the server keeps it open
+#[derive(Default)]
+pub struct Client {
+    /// (host, port, connection) of the open connection
+    conn: Option<(String, u16, Box<Conn>)>,
+    /// Skip checking https servers' certificates
+    pub insecure: bool,
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client::default()
+    }
+
+    /// Send `request`, following redirects, and stream the final body to
+    /// `sink`. Redirect bodies are dropped.
+    pub async fn send(&mut self, mut request: Request, sink: &mut dyn Sink) -> Result<Response, HttpError> {
+        for _ in 0..=MAX_REDIRECTS {
+            let response = self.exchange(&request).await?;
+            if !response.is_redirect() {
+                self.read_body(&request, &response, sink).await?;
+                return Ok(response);
+            }
+            self.read_body(&request, &response, &mut Discard).await?;
+            let location = response.header("location").unwrap_or("/");
+            let next = request.url.join(location)?;
+            serial_println!("[http] {} redirect to {}", response.status, next);
+            // Like browsers, 301 and 302 turn a POST into a GET; 303 always does
+            if response.status == 303 || (matches!(response.status, 301 | 302) && request.method == "POST") {
+                if request.method != "HEAD" {
+                    request.method = String::from("GET");
+                }
+                request.body.clear();
+                request.headers.retain(|(n, _)| !n.eq_ignore_ascii_case("content-type") && !n.eq_ignore_ascii_case("content-length"));
+            }
+            request.url = next;
+        }
+        Err(HttpError::TooManyRedirects)
+    }
+
+    /// Send the request and read the response head, on the kept-alive
+    /// connection if there is one for the host, else on a new one
+    async fn exchange(&mut self, request: &Request) -> Result<Response, HttpError> {
+        let url = &request.url;
+        let reused = match self.conn.take() {
+            Some((host, port, conn)) if host == url.host && port == url.port && conn.stream.is_tls() == url.is_https() => {
+                Some(conn)
+            }
+            _ => None,
+        };
+        let encoded = request.encode();
+        if let Some(mut conn) = reused {
+            // The server may have closed it in the meantime; then start over
+            if conn.stream.write_all(&encoded).await.is_ok() {
+                if let Ok(Some(response)) = read_head(&mut conn, url).await {
+                    self.conn = Some((url.host.clone(), url.port, conn));
+                    return Ok(response);
+                }
+            }
+        }
+
+        let ip = match url.host.parse() {
+            Ok(ip) => ip,
+            Err(_) => lookup(&url.host).await.ok_or(HttpError::Dns)?,
+        };
+        let tcp = TcpStream::connect(ip, url.port, REPLY_TIMEOUT_MS as u64).await?;
+        let stream = if url.is_https() {
+            // A handshake takes a few round trips and some signature checks
+            let tls = timeout(RECV_IDLE_TIMEOUT_MS as u64, TlsStream::connect(tcp, &url.host, self.insecure)).await?;
+            Transport::Tls(Box::new(tls))
+        } else {
+            Transport::Plain(tcp)
+        };
+        let mut conn = Box::new(Conn { stream, buf: Vec::new() });
+        conn.stream.write_all(&encoded).await?;
+        let response = read_head(&mut conn, url).await?.ok_or(HttpError::BadResponse)?;
+        self.conn = Some((url.host.clone(), url.port, conn));
+        Ok(response)
+    }
+
+    async fn read_body(&mut self, request: &Request, response: &Response, sink: &mut dyn Sink) -> Result<(), HttpError> {
+        let (host, port, mut conn) = match self.conn.take() {
+            Some(open) => open,
+            None => return Err(HttpError::BadResponse),
+        };
+        let no_body = request.method == "HEAD" || response.status / 100 == 1 || matches!(response.status, 204 | 304);
+        let reusable = if no_body {
+            true
+        } else if response.header_has("transfer-encoding", "chunked") {
+            read_chunked(&mut conn, sink).await?;
+            true
+        } else if let Some(length) = response.content_length() {
+            conn.copy(Some(length), sink).await?;
+            true
+        } else {
+            conn.copy(None, sink).await?;
+            false
+        };
+        if reusable && response.keep_alive() {
+            self.conn = Some((host, port, conn));
+        }
+        Ok(())
+    }
+}
+
+/// Status line and headers. Ok(None) if the server closed before sending any.
+async fn read_head(conn: &mut Conn, url: &Url) -> Result<Option<Response>, HttpError> {
+    loop {
+        let status_line = match conn.read_line().await? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let (version, status, reason) = parse_status_line(&status_line).ok_or(HttpError::BadResponse)?;
+        let mut headers = Vec::new();
+        loop {
+            let line = conn.read_line().await?.ok_or(HttpError::BadResponse)?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() >= MAX_HEADERS {
+                return Err(HttpError::BadResponse);
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((String::from(name.trim()), String::from(value.trim())));
+            }
+        }
+        // Interim responses such as 100 Continue come before the real one
+        if status / 100 == 1 && status != 101 {
+            continue;
+        }
+        return Ok(Some(Response { version, status, reason, headers, url: url.clone() }));
+    }
+}
+
+/// "HTTP/1.1 200 OK" as (minor version, status, reason)
+fn parse_status_line(line: &str) -> Option<(u8, u16, String)> {
+    let rest = line.strip_prefix("HTTP/1.")?;
+    let version = rest.chars().next()?.to_digit(10)? as u8;
+    let mut parts = rest[1..].trim_start().splitn(2, ' ');
+    let status = parts.next()?.parse().ok()?;
+    Some((version, status, String::from(parts.next().unwrap_or("").trim())))
+}
+
+async fn read_chunked(conn: &mut Conn, sink: &mut dyn Sink) -> Result<(), HttpError> {
+    loop {
+        let line = conn.read_line().await?.ok_or(HttpError::BadResponse)?;
+        // Chunk extensions after ';' are ignored
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| HttpError::BadResponse)?;
+        if size == 0 {
+            break;
+        }
+        conn.copy(Some(size), sink).await?;
+        if conn.read_line().await? != Some(String::new()) {
+            return Err(HttpError::BadResponse);
+        }
+    }
+    // Trailers, up to the blank line
+    while let Some(line) = conn.read_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Undo the response's `Content-Encoding` on a complete body
+pub fn decode_body(response: &Response, body: Vec<u8>) -> Result<Vec<u8>, HttpError> {
+    let encoding = response.header("content-encoding").map(|e| e.trim().to_ascii_lowercase());
+    match encoding.as_deref() {
+        Some("gzip") | Some("x-gzip") => crate::compress::gunzip(&body).map_err(HttpError::Decode),
+        Some("deflate") => crate::compress::zlib_decompress(&body).map_err(HttpError::Decode),
+        _ => Ok(body),
+    }
+}
+
+/// Decode a body a `FileSink` saved to `path` in place, for servers that
+/// compress it without being asked. Returns the decoded size, or None if the
+/// body wasn't encoded. Bodies over `MAX_MEMORY_BODY` can't be decoded.
+pub fn decode_file(response: &Response, path: &str, len: u64) -> Result<Option<usize>, HttpError> {
+    let encoding = response.header("content-encoding").map(|e| e.trim().to_ascii_lowercase());
+    if !matches!(encoding.as_deref(), Some("gzip") | Some("x-gzip") | Some("deflate")) {
+        return Ok(None);
+    }
+    if len > MAX_MEMORY_BODY as u64 {
+        return Err(HttpError::BodyTooLarge);
+    }
+    let body = crate::fs::read_file(path).ok_or(HttpError::Write)?;
+    let decoded = decode_body(response, body)?;
+    if !crate::fs::write_file(path, &decoded) {
+        return Err(HttpError::Write);
+    }
+    Ok(Some(decoded.len()))
+}
+
+#[test_case]
+fn test_url() {
+    let url = Url::parse("http://Example.com:8080/a/b.html?x=1#top").unwrap();
+    assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("example.com", 8080, "/a/b.html?x=1"));
+    assert_eq!(Url::parse("example.com").unwrap().path, "/");
+    assert_eq!(Url::parse("ftp://example.com/"), Err(HttpError::UnsupportedScheme));
+    let secure = Url::parse("https://example.com/x").unwrap();
+    assert_eq!((secure.port, secure.host_header().as_str()), (443, "example.com"));
+    assert_eq!(Url::parse("https://example.com:80/").unwrap().host_header(), "example.com:80");
+    assert_eq!(url.join("c.html").unwrap().path, "/a/c.html");
+    assert_eq!(url.join("/root").unwrap().path, "/root");
+    assert_eq!(url.join("//other.org/x").unwrap().host, "other.org");
+    assert_eq!(parse_status_line("HTTP/1.1 404 Not Found"), Some((1, 404, String::from("Not Found"))));
+    assert_eq!(parse_status_line("HTTP/1.0 200"), Some((0, 200, String::new())));
+}
@@ -0,0 +1,271 @@
+//! A small HTTP/1.0 file server for getting files out of a running VM.
+//!
+//! One task accepts connections and spawns a task per client, so a slow
+//! download doesn't hold up anyone else. Only GET and HEAD are supported,
+//! every response closes the connection, and request paths are normalized
+//! before being joined to the root, so `..` can't climb out of it.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::fs;
+use crate::serial_println;
+use crate::task::{executor, Task};
+use super::socket::{timeout, NetError, TcpListener, TcpStream};
+
+/// Largest request head accepted before answering 431
+const MAX_REQUEST: usize = 8192;
+/// How long a client gets to send its request
+const REQUEST_TIMEOUT_MS: u64 = 10_000;
+/// How often the accept loop checks whether it has been stopped
+const STOP_CHECK_MS: u64 = 1_000;
+
+/// (port, root) of the running server
+static SERVER: Mutex<Option<(u16, String)>> = Mutex::new(None);
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Start serving `root` (an absolute directory) on `port` in the background
+pub fn start(port: u16, root: String) -> Result<(), NetError> {
+    let mut server = SERVER.lock();
+    if server.is_some() {
+        return Err(NetError::AddrInUse);
+    }
+    let listener = TcpListener::bind(port)?;
+    STOP.store(false, Ordering::SeqCst);
+    *server = Some((port, root.clone()));
+    executor::spawn_task(Task::new(serve(listener, root)));
+    Ok(())
+}
+
+/// Ask the server to stop accepting connections. False if it isn't running.
+pub fn stop() -> bool {
+    if SERVER.lock().is_none() {
+        return false;
+    }
+    STOP.store(true, Ordering::SeqCst);
+    true
+}
+
+pub fn running() -> Option<(u16, String)> {
+    SERVER.lock().clone()
+}
+
+async fn serve(mut listener: TcpListener, root: String) {
+    serial_println!("[httpd] serving {} on port {}", root, listener.port());
+    while !STOP.load(Ordering::SeqCst) {
+        match timeout(STOP_CHECK_MS, listener.accept()).await {
+            Ok(stream) => {
+                let root = root.clone();
+                executor::spawn_task(Task::new(async move { handle(stream, &root).await }));
+            }
+            Err(NetError::TimedOut) => {}
+            Err(e) => {
+                serial_println!("[httpd] accept failed: {:?}", e);
+                break;
+            }
+        }
+    }
+    serial_println!("[httpd] stopped");
+    *SERVER.lock() = None;
+}
+
+async fn handle(mut stream: TcpStream, root: &str) {
+    let peer = stream.peer();
+    let head = match read_head(&mut stream).await {
+        Ok(Some(head)) => head,
+        Ok(None) => {
+            send_error(&mut stream, 431, "Request Header Fields Too Large").await;
+            return;
+        }
+        Err(_) => return,
+    };
+    let request_line = head.lines().next().unwrap_or("");
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => {
+            send_error(&mut stream, 400, "Bad Request").await;
+            return;
+        }
+    };
+    if let Some(peer) = peer {
+        serial_println!("[httpd] {} {} {}", peer, method, target);
+    }
+    let head_only = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            send_error(&mut stream, 405, "Method Not Allowed").await;
+            return;
+        }
+    };
+
+    let url_path = percent_decode(target.split(['?', '#']).next().unwrap_or("/"));
+    let path = fs::path::normalize(&url_path);
+    let full = if path == "/" { String::from(root) } else { alloc::format!("{}{}", root.trim_end_matches('/'), path) };
+
+    let (content_type, body) = if fs::is_dir(&full) {
+        if !url_path.ends_with('/') {
+            // Relative links in the listing need the trailing slash
+            let location = alloc::format!("{}/", path.trim_end_matches('/'));
+            let head = alloc::format!(
+                "HTTP/1.0 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                location
+            );
+            stream.write_all(head.as_bytes()).await.ok();
+            return;
+        }
+        let index = fs::join_path(&full, "index.html");
+        match fs::read_file(&index).filter(|_| !fs::is_dir(&index)) {
+            Some(data) => ("text/html; charset=utf-8", data),
+            None => ("text/html; charset=utf-8", listing(&full, &path).into_bytes()),
+        }
+    } else {
+        match fs::read_file(&full) {
+            Some(data) => (mime_type(&full), data),
+            None => {
+                send_error(&mut stream, 404, "Not Found").await;
+                return;
+            }
+        }
+    };
+
+    let header = alloc::format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        content_type,
+        body.len()
+    );
+    if stream.write_all(header.as_bytes()).await.is_ok() && !head_only {
+        stream.write_all(&body).await.ok();
+    }
+}
+
+/// The request line and headers, up to the blank line. Ok(None) if they
+/// don't fit in `MAX_REQUEST`.
+async fn read_head(stream: &mut TcpStream) -> Result<Option<String>, NetError> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+        let n = timeout(REQUEST_TIMEOUT_MS, stream.read(&mut buf)).await?;
+        if n == 0 {
+            return Err(NetError::ConnectionReset);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(String::from_utf8_lossy(&head).into_owned()))
+}
+
+async fn send_error(stream: &mut TcpStream, status: u16, reason: &str) {
+    let body = alloc::format!("<html><body><h1>{} {}</h1></body></html>\n", status, reason);
+    let response = alloc::format!(
+        "HTTP/1.0 {} {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await.ok();
+}
+
+/// An HTML index of `dir`, shown as `url_path`
+fn listing(dir: &str, url_path: &str) -> String {
+    let mut entries: Vec<fs::DirEntry> = fs::list_dir(dir).into_iter().filter(|e| e.name != "." && e.name != "..").collect();
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let title = html_escape(url_path);
+    let mut html = alloc::format!("<html><head><title>Index of {0}</title></head><body>\n<h1>Index of {0}</h1>\n<pre>\n", title);
+    if url_path != "/" {
+        html.push_str("<a href=\"../\">../</a>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let name = alloc::format!("{}{}", html_escape(&entry.name), slash);
+        let size = if entry.is_dir { String::from("-") } else { alloc::format!("{}", entry.size) };
+        // Pad by the displayed name, not the escaped one
+        let pad = 40usize.saturating_sub(entry.name.chars().count() + slash.len());
+        html.push_str(&alloc::format!(
+            "<a href=\"{}{}\">{}</a>{:pad$} {:>12}\n",
+            percent_encode(&entry.name), slash, name, "", size, pad = pad
+        ));
+    }
+    html.push_str("</pre>\n</body></html>\n");
+    html
+}
+
+/// Content-Type for a file name, by extension
+pub fn mime_type(name: &str) -> &'static str {
+    let ext = match name.rsplit_once('.') {
+        Some((_, ext)) if !ext.contains('/') => ext.to_ascii_lowercase(),
+        _ => return "application/octet-stream",
+    };
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" | "log" | "ini" | "md" | "rs" | "c" | "h" | "sh" | "csv" => "text/plain; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "bmp" => "image/bmp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Undo `%XX` escapes; malformed ones are kept as they are
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |at: usize| bytes.get(at).and_then(|&b| (b as char).to_digit(16));
+        if bytes[i] == b'%' {
+            if let (Some(hi), Some(lo)) = (hex(i + 1), hex(i + 2)) {
+                out.push((hi * 16 + lo) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&alloc::format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[test_case]
+fn test_request_paths() {
+    assert_eq!(percent_decode("/a%20b/%2e%2e/c"), "/a b/../c");
+    assert_eq!(percent_decode("/100%"), "/100%");
+    assert_eq!(fs::path::normalize(&percent_decode("/%2e%2e/%2e%2e/etc")), "/etc");
+    assert_eq!(percent_encode("a b&c.txt"), "a%20b%26c.txt");
+    assert_eq!(mime_type("/logs/boot.LOG"), "text/plain; charset=utf-8");
+    assert_eq!(mime_type("/dir.d/file"), "application/octet-stream");
+}
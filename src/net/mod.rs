@@ -1,16 +1,59 @@
 use smoltcp::iface::{Config, Interface, SocketSet, SocketHandle};
-use smoltcp::socket::{dhcpv4, icmp, dns, tcp};
+use smoltcp::socket::{dhcpv4, icmp, tcp};
 use smoltcp::time::Instant;
 use smoltcp::wire::*;
 use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
 use alloc::vec::Vec;
-use alloc::string::{String, ToString};
-use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::task::AtomicWaker;
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::serial_println;
+use crate::task::timer;
+
+pub mod config;
+pub mod dhcp;
+pub mod dns;
+pub mod http;
+pub mod httpd;
+pub mod sntp;
+pub mod socket;
+pub mod telnetd;
+pub mod tls;
+
+pub use config::{NetConfig, NetMode};
+pub use dns::{load_dns_cache, lookup, save_dns_cache, DNS_CACHE_FILE};
+pub use socket::{NetError, TcpListener, TcpStream, UdpSocket};
+
+/// How long connects, DNS queries and pings wait for an answer
+const REPLY_TIMEOUT_MS: i64 = 5_000;
+/// How long a download may go without receiving anything
+const RECV_IDLE_TIMEOUT_MS: i64 = 10_000;
+/// How long boot waits for a DHCP lease before carrying on without one
+const DHCP_BOOT_TIMEOUT_MS: i64 = 10_000;
+/// How long a dropped TCP socket may take to finish closing before it's reset
+const CLOSE_TIMEOUT_MS: i64 = 30_000;
+/// Longest the background task sleeps between polls with nothing scheduled
+const MAX_POLL_DELAY_MS: u64 = 1_000;
+/// QEMU's user-mode name server, used when none is configured
+const DEFAULT_DNS_SERVER: Ipv4Address = Ipv4Address::new(10, 0, 2, 3);
+
+/// smoltcp's clock: milliseconds since boot, from the PIT tick count
+pub fn now_ms() -> i64 {
+    timer::uptime_ms() as i64
+}
 
-pub const DNS_CACHE_FILE: &str = "dns.dat";
+/// Wait for the next interrupt: a received packet, a key or a timer tick
+fn idle() {
+    if x86_64::instructions::interrupts::are_enabled() {
+        x86_64::instructions::hlt();
+    } else {
+        core::hint::spin_loop();
+    }
+}
 
 // ---- Device wrapper ----
 
@@ -58,189 +101,177 @@ impl Device for E1000Device {
     }
 }
 
-// ---- DNS Cache ----
-
-pub struct DnsCacheEntry {
-    pub ip: Ipv4Address,
-    pub expires_ms: i64,
-}
-
-lazy_static! {
-    pub static ref DNS_CACHE: Mutex<BTreeMap<String, DnsCacheEntry>> = Mutex::new(BTreeMap::new());
-}
-
-fn cache_get(hostname: &str) -> Option<Ipv4Address> {
-    let cache = DNS_CACHE.lock();
-    let now = NET.lock().as_ref()?.time_ms;
-    serial_println!("[dns] cache_get: {} entries, now={}ms", cache.len(), now);
-    let entry = cache.get(hostname)?;
-    serial_println!("[dns] found entry expires_ms={}", entry.expires_ms);
-    if now < entry.expires_ms {
-        Some(entry.ip)
-    } else {
-        serial_println!("[dns] entry expired");
-        None
-    }
-}
-
-fn cache_set(hostname: &str, ip: Ipv4Address) {
-    let ttl_ms = 300_000i64; // 5 minutes
-    let now = NET.lock().as_ref().map(|s| s.time_ms).unwrap_or(0);
-    DNS_CACHE.lock().insert(
-        String::from(hostname),
-        DnsCacheEntry { ip, expires_ms: now + ttl_ms },
-    );
-}
-
-pub fn save_dns_cache() {
-    let cache = DNS_CACHE.lock();
-    if cache.is_empty() { return; }
-
-    let now = NET.lock().as_ref().map(|s| s.time_ms).unwrap_or(0);
-    let mut data = String::new();
-
-    for (host, entry) in cache.iter() {
-        if entry.expires_ms > now {
-            let remaining = entry.expires_ms - now;
-            data.push_str(&alloc::format!("{}={},{}\n", host, entry.ip, remaining));
-        }
-    }
-
-    crate::fs::write_file(DNS_CACHE_FILE, data.as_bytes());
-    serial_println!("[dns] Cache saved ({} entries)", cache.len());
-}
-
-pub fn load_dns_cache() {
-    let data = match crate::fs::read_file(DNS_CACHE_FILE) {
-        Some(d) => d,
-        None => { serial_println!("[dns] No cache file"); return; }
-    };
-
-    let text = match core::str::from_utf8(&data) {
-        Ok(t) => t,
-        Err(_) => return,
-    };
-
-    let mut cache = DNS_CACHE.lock();
-    let mut count = 0;
-
-    for line in text.lines() {
-        let line = line.trim();
-        if line.is_empty() { continue; }
-        if let Some(eq) = line.find('=') {
-            let host = &line[..eq];
-            let rest = &line[eq+1..];
-            if let Some(comma) = rest.find(',') {
-                let ip_str = &rest[..comma];
-                let ttl_str = &rest[comma+1..];
-                if let (Ok(ip), Ok(ttl)) = (
-                    ip_str.parse::<Ipv4Address>(),
-                    ttl_str.parse::<i64>(),
-                ) {
-                    // At boot, time_ms will start near 0, so use ttl directly
-                    // as the expiry — it represents remaining ms from last boot
-                    cache.insert(String::from(host), DnsCacheEntry {
-                        ip,
-                        expires_ms: ttl, // ttl IS the remaining time, treat as absolute for now
-                    });
-                    count += 1;
-                }
-            }
-        }
-    }
-
-    serial_println!("[dns] Cache loaded ({} entries)", count);
-}
-
 // ---- Network stack ----
 
 pub struct NetStack {
     pub iface: Interface,
     pub sockets: SocketSet<'static>,
-    pub time_ms: i64,
     pub ip: Option<Ipv4Address>,
     pub gateway: Option<Ipv4Address>,
     pub prefix_len: u8,
-    dhcp_handle: SocketHandle,
+    /// Name servers from the configuration
+    pub dns_servers: Vec<Ipv4Address>,
+    pub mode: NetMode,
+    /// The DHCP client, absent with a static address or after a release
+    dhcp_handle: Option<SocketHandle>,
+    /// The DHCP client while it's stopped, kept to be reused
+    idle_dhcp: Option<dhcpv4::Socket<'static>>,
+    pub lease: Option<dhcp::Lease>,
+    /// Dropped TCP sockets still finishing their close, with the time they
+    /// get reset if the peer hasn't finished it by then
+    pub(crate) closing: Vec<(SocketHandle, i64)>,
 }
 
 impl NetStack {
     pub fn poll(&mut self) {
         let mut device = E1000Device;
-        let timestamp = Instant::from_millis(self.time_ms);
+        let timestamp = Instant::from_millis(now_ms());
         self.iface.poll(timestamp, &mut device, &mut self.sockets);
-
-        if let Some(event) = self.sockets.get_mut::<dhcpv4::Socket>(self.dhcp_handle).poll() {
-            match event {
-                dhcpv4::Event::Configured(config) => {
-                    let ip = config.address.address();
-                    let prefix = config.address.prefix_len();
-                    let gw = config.router.unwrap_or(Ipv4Address::UNSPECIFIED);
-                    self.iface.update_ip_addrs(|addrs| {
-                        addrs.clear();
-                        addrs.push(IpCidr::Ipv4(Ipv4Cidr::new(ip, prefix))).ok();
-                    });
-                    self.iface.routes_mut().add_default_ipv4_route(gw).ok();
-                    self.ip = Some(ip);
-                    self.gateway = Some(gw);
-                    self.prefix_len = prefix;
-                    serial_println!("[net] DHCP: {}/{} gw {}", ip, prefix, gw);
-                }
-                dhcpv4::Event::Deconfigured => {
-                    self.ip = None;
-                    self.iface.update_ip_addrs(|addrs| addrs.clear());
-                }
+        self.reap_closed();
+
+        // The event borrows the socket, so take what's needed out of it first
+        let event = match self.dhcp_handle {
+            Some(handle) => match self.sockets.get_mut::<dhcpv4::Socket>(handle).poll() {
+                Some(dhcpv4::Event::Configured(config)) => Some(Some(dhcp::Lease::new(&config, now_ms()))),
+                Some(dhcpv4::Event::Deconfigured) => Some(None),
+                None => None,
+            },
+            None => None,
+        };
+        match event {
+            Some(Some(lease)) => {
+                self.set_address(lease.address, lease.prefix_len, lease.router);
+                serial_println!("[net] DHCP: {}/{} gw {:?} from {}, lease {:?}s, dns {:?}",
+                                lease.address, lease.prefix_len, lease.router, lease.server, lease.lease_secs, lease.dns_servers);
+                self.lease = Some(lease);
+            }
+            Some(None) => {
+                serial_println!("[net] DHCP: lease lost");
+                self.clear_address();
+                self.lease = None;
             }
+            None => {}
         }
     }
 
-    pub fn tick(&mut self, ms: i64) {
-        self.time_ms += ms;
+    fn set_address(&mut self, ip: Ipv4Address, prefix: u8, gateway: Option<Ipv4Address>) {
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.clear();
+            addrs.push(IpCidr::Ipv4(Ipv4Cidr::new(ip, prefix))).ok();
+        });
+        match gateway {
+            Some(gw) => { self.iface.routes_mut().add_default_ipv4_route(gw).ok(); }
+            None => { self.iface.routes_mut().remove_default_ipv4_route(); }
+        }
+        self.ip = Some(ip);
+        self.gateway = gateway;
+        self.prefix_len = prefix;
     }
 
-    pub fn ping(&mut self, target: Ipv4Address) -> Option<u128> {
-        let icmp_socket = icmp::Socket::new(
-            icmp::PacketBuffer::new(
-                alloc::vec![icmp::PacketMetadata::EMPTY; 4],
-                alloc::vec![0u8; 1024],
-            ),
-            icmp::PacketBuffer::new(
-                alloc::vec![icmp::PacketMetadata::EMPTY; 4],
-                alloc::vec![0u8; 1024],
-            ),
-        );
-        let handle = self.sockets.add(icmp_socket);
+    fn clear_address(&mut self) {
+        self.iface.update_ip_addrs(|addrs| addrs.clear());
+        self.iface.routes_mut().remove_default_ipv4_route();
+        self.ip = None;
+        self.gateway = None;
+        self.prefix_len = 0;
+    }
 
-        {
-            let socket = self.sockets.get_mut::<icmp::Socket>(handle);
-            socket.bind(icmp::Endpoint::Ident(0x1234)).ok()?;
+    /// Switch to `config`'s addressing: drop the DHCP client and take the
+    /// static address, or start DHCP afresh. Connections on the old
+    /// address stop working.
+    pub fn configure(&mut self, config: &NetConfig) -> Result<(), NetError> {
+        match config.mode {
+            NetMode::Static => {
+                let ip = config.address.ok_or(NetError::Unaddressable)?;
+                self.stop_dhcp();
+                self.set_address(ip, config.prefix, config.gateway);
+                serial_println!("[net] Static: {}/{} gw {:?}", ip, config.prefix, config.gateway);
+            }
+            NetMode::Dhcp => {
+                let hostname = crate::CONFIG.lock().hostname.clone();
+                self.restart_dhcp(&hostname);
+            }
         }
+        self.mode = config.mode;
+        self.dns_servers = config.dns.clone();
+        Ok(())
+    }
 
-        let payload = b"ping from myos!";
-        {
-            let socket = self.sockets.get_mut::<icmp::Socket>(handle);
-            let repr = Icmpv4Repr::EchoRequest { ident: 0x1234, seq_no: 1, data: payload };
-            let buf = socket.send(repr.buffer_len(), IpAddress::Ipv4(target)).ok()?;
-            let mut packet = Icmpv4Packet::new_unchecked(buf);
-            repr.emit(&mut packet, &smoltcp::phy::ChecksumCapabilities::default());
+    /// Drop the DHCP client along with its lease and address
+    pub(crate) fn stop_dhcp(&mut self) {
+        if let Some(handle) = self.dhcp_handle.take() {
+            if let smoltcp::socket::Socket::Dhcpv4(socket) = self.sockets.remove(handle) {
+                self.idle_dhcp = Some(socket);
+            }
         }
+        self.clear_address();
+        self.lease = None;
+    }
 
-        let start = self.time_ms;
-        for _ in 0..5000 {
-            self.poll();
-            self.tick(1);
-            for _ in 0..100_000 { core::hint::spin_loop(); }
-
-            let socket = self.sockets.get_mut::<icmp::Socket>(handle);
-            if socket.can_recv() {
-                let rtt = self.time_ms - start;
-                self.sockets.remove(handle);
-                return Some(rtt as u128);
+    /// Forget any lease and ask for a new one, sending `hostname`
+    pub(crate) fn restart_dhcp(&mut self, hostname: &str) {
+        self.clear_address();
+        self.lease = None;
+        match self.dhcp_handle {
+            Some(handle) => {
+                let socket = self.sockets.get_mut::<dhcpv4::Socket>(handle);
+                dhcp::apply_hostname(socket, hostname);
+                socket.reset();
             }
+            None => {
+                let socket = match self.idle_dhcp.take() {
+                    Some(mut socket) => {
+                        dhcp::apply_hostname(&mut socket, hostname);
+                        socket.reset();
+                        socket
+                    }
+                    None => dhcp::new_socket(hostname),
+                };
+                self.dhcp_handle = Some(self.sockets.add(socket));
+            }
+        }
+    }
+
+    pub fn dhcp_enabled(&self) -> bool {
+        self.mode == NetMode::Dhcp
+    }
+
+    /// Servers to send DNS queries to, in order: the configured ones, else
+    /// the lease's
+    pub fn name_servers(&self) -> Vec<Ipv4Address> {
+        if !self.dns_servers.is_empty() {
+            return self.dns_servers.clone();
         }
+        match &self.lease {
+            Some(lease) if !lease.dns_servers.is_empty() => lease.dns_servers.clone(),
+            _ => alloc::vec![DEFAULT_DNS_SERVER],
+        }
+    }
 
-        self.sockets.remove(handle);
-        None
+    /// Free closed sockets, and reset the ones whose peer never finished the
+    /// close. An aborted socket stays until the next poll has sent its RST.
+    fn reap_closed(&mut self) {
+        let sockets = &mut self.sockets;
+        let now = now_ms();
+        self.closing.retain(|&(handle, deadline)| {
+            let socket = sockets.get_mut::<tcp::Socket>(handle);
+            let done = matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait);
+            if done {
+                sockets.remove(handle);
+            } else if now >= deadline {
+                serial_println!("[net] Resetting a connection stuck in {}", socket.state());
+                socket.abort();
+            }
+            !done
+        });
+    }
+
+    /// Milliseconds until smoltcp next has timed work to do, capped so the
+    /// background task still looks in regularly
+    fn poll_delay_ms(&mut self) -> u64 {
+        self.iface
+            .poll_delay(Instant::from_millis(now_ms()), &self.sockets)
+            .map_or(MAX_POLL_DELAY_MS, |d| d.total_millis().min(MAX_POLL_DELAY_MS))
     }
 }
 
@@ -260,217 +291,238 @@ pub fn init() {
     let mut iface = Interface::new(config, &mut device, Instant::ZERO);
     iface.set_any_ip(true);
 
-    let mut sockets = SocketSet::new(alloc::vec![]);
-    let mut dhcp = dhcpv4::Socket::new();
-    dhcp.reset();
-    let dhcp_handle = sockets.add(dhcp);
-
-    *NET.lock() = Some(NetStack {
+    let mut stack = NetStack {
         iface,
-        sockets,
-        time_ms: 0,
+        sockets: SocketSet::new(alloc::vec![]),
         ip: None,
         gateway: None,
         prefix_len: 0,
-        dhcp_handle,
-    });
+        dns_servers: Vec::new(),
+        mode: NetMode::Dhcp,
+        dhcp_handle: None,
+        idle_dhcp: None,
+        lease: None,
+        closing: Vec::new(),
+    };
+    let config = crate::CONFIG.lock().net.clone();
+    if stack.configure(&config).is_err() {
+        serial_println!("[net] net.mode=static needs net.address, using DHCP");
+        stack.configure(&NetConfig { mode: NetMode::Dhcp, ..config }).ok();
+    }
+    *NET.lock() = Some(stack);
 
     serial_println!("[net] Stack initialized");
 }
 
+/// Reconfigure the running interface, see `NetStack::configure`
+pub fn configure(config: &NetConfig) -> Result<(), NetError> {
+    NET.lock().as_mut().ok_or(NetError::NoStack)?.configure(config)
+}
+
 pub fn poll() {
     if let Some(stack) = NET.lock().as_mut() {
         stack.poll();
     }
 }
 
+/// Wait for a DHCP lease, unless the address is static. True once there
+/// is an address.
 pub fn wait_for_dhcp() -> bool {
+    match NET.lock().as_ref() {
+        Some(stack) if !stack.dhcp_enabled() => return stack.ip.is_some(),
+        Some(_) => {}
+        None => return false,
+    }
     serial_println!("[net] Waiting for DHCP...");
-    for _ in 0..30_000 {
+    let deadline = now_ms() + DHCP_BOOT_TIMEOUT_MS;
+    while now_ms() < deadline {
         {
             let mut guard = NET.lock();
-            if let Some(stack) = guard.as_mut() {
-                stack.poll();
-                stack.tick(1);
-                if stack.ip.is_some() {
-                    return true;
+            match guard.as_mut() {
+                Some(stack) => {
+                    stack.poll();
+                    if stack.ip.is_some() {
+                        return true;
+                    }
                 }
+                None => return false,
             }
         }
-        for _ in 0..100_000 { core::hint::spin_loop(); }
+        idle();
     }
+    // The background task keeps the DHCP client running, so a lease can still arrive
     serial_println!("[net] DHCP timed out");
     false
 }
 
-pub fn get_ip() -> Option<Ipv4Address> {
-    NET.lock().as_ref()?.ip
+// ---- Background polling ----
+
+/// Woken by the e1000 interrupt and by the timer once smoltcp's next deadline passes
+static NET_WAKER: AtomicWaker = AtomicWaker::new();
+static WAKE_PENDING: AtomicBool = AtomicBool::new(false);
+/// Tick count at which `net_task` wants polling regardless of traffic
+static NEXT_POLL_TICK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Ask the network task to poll soon. Safe to call from interrupt handlers.
+pub fn wake() {
+    WAKE_PENDING.store(true, Ordering::SeqCst);
+    NET_WAKER.wake();
 }
 
-pub fn resolve(hostname: &str) -> Option<Ipv4Address> {
-    serial_println!("[dns] Resolving {}", hostname);
-    if let Some(ip) = cache_get(hostname) {
-        serial_println!("[dns] Cache hit: {} -> {}", hostname, ip);
-        return Some(ip);
+/// Called from the timer interrupt after `TICKS` has been bumped
+pub(crate) fn on_tick() {
+    let now = crate::interrupts::TICKS.load(Ordering::Relaxed);
+    if now >= NEXT_POLL_TICK.load(Ordering::Relaxed) {
+        NEXT_POLL_TICK.store(u64::MAX, Ordering::Relaxed);
+        wake();
     }
-    serial_println!("[dns] Cache miss, querying...");
-    let ip = resolve_uncached(hostname)?;
-    cache_set(hostname, ip);
-    save_dns_cache();
-    serial_println!("[dns] Cached {} -> {}", hostname, ip);
-    Some(ip)
 }
 
-fn resolve_uncached(hostname: &str) -> Option<Ipv4Address> {
-    use smoltcp::wire::DnsQueryType;
-
-    let dns_server = IpAddress::Ipv4(Ipv4Address::new(10, 0, 2, 3));
-
-    let mut guard = NET.lock();
-    let stack = guard.as_mut()?;
+/// Futures waiting for the stack to make progress, woken after every poll
+static WAITERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
 
-    let socket = dns::Socket::new(&[dns_server], alloc::vec![]);
-    let handle = stack.sockets.add(socket);
-
-    let query = {
-        let socket = stack.sockets.get_mut::<dns::Socket>(handle);
-        match socket.start_query(stack.iface.context(), hostname, DnsQueryType::A) {
-            Ok(q) => q,
-            Err(e) => {
-                serial_println!("[net] DNS start_query error: {:?}", e);
-                stack.sockets.remove(handle);
-                return None;
-            }
-        }
-    };
-
-    for _ in 0..5000 {
-        stack.poll();
-        stack.tick(1);
-        for _ in 0..100_000 { core::hint::spin_loop(); }
-
-        let socket = stack.sockets.get_mut::<dns::Socket>(handle);
-        match socket.get_query_result(query) {
-            Ok(addrs) => {
-                stack.sockets.remove(handle);
-                for addr in addrs.iter() {
-                    if let IpAddress::Ipv4(v4) = addr {
-                        return Some(*v4);
-                    }
-                }
-                return None;
-            }
-            Err(dns::GetQueryResultError::Pending) => continue,
-            Err(e) => {
-                serial_println!("[net] DNS error: {:?}", e);
-                break;
-            }
+/// Wake the task behind `cx` after the next poll of the stack, and make sure
+/// that poll happens by `deadline_ms` (on the `now_ms` clock) at the latest
+pub(crate) fn park(cx: &mut Context, deadline_ms: Option<i64>) {
+    {
+        let mut waiters = WAITERS.lock();
+        if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            waiters.push(cx.waker().clone());
         }
     }
-
-    stack.sockets.remove(handle);
-    None
+    if let Some(deadline) = deadline_ms {
+        let ticks = timer::ms_to_ticks((deadline - now_ms()).max(0) as u64);
+        let tick = crate::interrupts::TICKS.load(Ordering::Relaxed) + ticks;
+        NEXT_POLL_TICK.fetch_min(tick, Ordering::Relaxed);
+    }
 }
 
-pub fn http_get(host: &str, path: &str, ip: Ipv4Address, port: u16) -> Option<Vec<u8>> {
-    let mut guard = NET.lock();
-    let stack = guard.as_mut()?;
-
-    let tcp_rx_buf = tcp::SocketBuffer::new(alloc::vec![0u8; 4096]);
-    let tcp_tx_buf = tcp::SocketBuffer::new(alloc::vec![0u8; 4096]);
-    let socket = tcp::Socket::new(tcp_rx_buf, tcp_tx_buf);
-    let handle = stack.sockets.add(socket);
-
-    {
-        let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
-        let local_port = 49152 + (stack.time_ms as u16 % 16383);
-        socket.connect(
-            stack.iface.context(),
-            (IpAddress::Ipv4(ip), port),
-            local_port,
-        ).ok()?;
+/// Poll the stack once and wake everything parked on it. Returns how long
+/// the stack can be left alone.
+fn service() -> u64 {
+    let delay = match NET.lock().as_mut() {
+        Some(stack) => {
+            stack.poll();
+            stack.poll_delay_ms()
+        }
+        None => MAX_POLL_DELAY_MS,
+    };
+    let waiters = core::mem::take(&mut *WAITERS.lock());
+    for waker in waiters {
+        waker.wake();
     }
+    delay
+}
 
-    let mut connected = false;
-    for _ in 0..5000 {
+/// Run a network future to completion from synchronous code such as a shell
+/// command, which keeps `net_task` from running, so poll the stack here
+/// instead. None if Ctrl-C was pressed first.
+pub fn block_on<F: Future>(future: F) -> Option<F::Output> {
+    let mut future = core::pin::pin!(future);
+    let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+    loop {
+        service();
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
         crate::task::keyboard::process_pending_scancodes();
         if crate::task::keyboard::check_ctrlc() {
             crate::task::keyboard::clear_ctrlc();
             crate::println!("^C");
-            stack.sockets.remove(handle);
             return None;
         }
+        idle();
+    }
+}
 
-        stack.poll();
-        stack.tick(1);
-        for _ in 0..100_000 { core::hint::spin_loop(); }
+struct NetWait;
 
-        let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
-        if socket.is_active() && socket.may_send() {
-            connected = true;
-            break;
+impl Future for NetWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if WAKE_PENDING.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(());
         }
-        if socket.state() == tcp::State::Closed {
-            break;
+        NET_WAKER.register(cx.waker());
+        // The interrupt may have fired between the check and registering
+        if WAKE_PENDING.swap(false, Ordering::SeqCst) {
+            NET_WAKER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
         }
     }
+}
 
-    if !connected {
-        serial_println!("[net] TCP connect failed");
-        stack.sockets.remove(handle);
-        return None;
+/// Keeps the stack running while nothing else is using it: answers ARP and
+/// pings, retransmits TCP and renews the DHCP lease. Sleeps until a packet
+/// arrives or smoltcp's next timer is due.
+pub async fn net_task() {
+    if NET.lock().is_none() {
+        return;
     }
-
-    {
-        let request = alloc::format!(
-            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
-            path, host
-        );
-        let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
-        socket.send_slice(request.as_bytes()).ok()?;
+    loop {
+        let delay = service();
+        let ticks = timer::ms_to_ticks(delay);
+        // Sockets parked since the poll may already have asked for an earlier one
+        NEXT_POLL_TICK.fetch_min(crate::interrupts::TICKS.load(Ordering::Relaxed) + ticks, Ordering::Relaxed);
+        NetWait.await;
     }
+}
 
-    let mut response = alloc::vec![0u8; 8192];
-    let mut total = 0;
+pub fn get_ip() -> Option<Ipv4Address> {
+    NET.lock().as_ref()?.ip
+}
 
-    for _ in 0..30_000 {
-        crate::task::keyboard::process_pending_scancodes();
-        if crate::task::keyboard::check_ctrlc() {
-            crate::task::keyboard::clear_ctrlc();
-            crate::println!("^C");
-            stack.sockets.remove(handle);
-            return None;
-        }
+pub fn resolve(hostname: &str) -> Option<Ipv4Address> {
+    block_on(lookup(hostname)).flatten()
+}
 
-        stack.poll();
-        stack.tick(1);
-        for _ in 0..100_000 { core::hint::spin_loop(); }
-
-        let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
-        if socket.can_recv() {
-            let n = socket.recv_slice(&mut response[total..]).unwrap_or(0);
-            total += n;
-            if total >= response.len() { break; }
+/// Send one echo request and wait for the reply. Returns the round trip in
+/// milliseconds, at timer-tick resolution.
+pub async fn ping(target: Ipv4Address) -> Result<u64, NetError> {
+    const IDENT: u16 = 0x1234;
+    let handle = {
+        let mut guard = NET.lock();
+        let stack = guard.as_mut().ok_or(NetError::NoStack)?;
+        let icmp_socket = icmp::Socket::new(
+            icmp::PacketBuffer::new(alloc::vec![icmp::PacketMetadata::EMPTY; 4], alloc::vec![0u8; 1024]),
+            icmp::PacketBuffer::new(alloc::vec![icmp::PacketMetadata::EMPTY; 4], alloc::vec![0u8; 1024]),
+        );
+        let handle = stack.sockets.add(icmp_socket);
+        let socket = stack.sockets.get_mut::<icmp::Socket>(handle);
+        let sent = socket.bind(icmp::Endpoint::Ident(IDENT)).is_ok() && {
+            let payload = b"ping from myos!";
+            let repr = Icmpv4Repr::EchoRequest { ident: IDENT, seq_no: 1, data: payload };
+            match socket.send(repr.buffer_len(), IpAddress::Ipv4(target)) {
+                Ok(buf) => {
+                    let mut packet = Icmpv4Packet::new_unchecked(buf);
+                    repr.emit(&mut packet, &smoltcp::phy::ChecksumCapabilities::default());
+                    true
+                }
+                Err(_) => false,
+            }
+        };
+        if !sent {
+            stack.sockets.remove(handle);
+            return Err(NetError::Unaddressable);
         }
+        handle
+    };
 
-        // Break when server has closed and we've read everything
-        if !socket.may_recv() && !socket.can_recv() {
-            break;
+    let start = now_ms();
+    let result = socket::retry(Some(REPLY_TIMEOUT_MS as u64), |stack| {
+        if stack.sockets.get_mut::<icmp::Socket>(handle).can_recv() {
+            Some(Ok((now_ms() - start) as u64))
+        } else {
+            None
         }
+    })
+    .await;
 
-        if !socket.is_active() { break; }
+    if let Some(stack) = NET.lock().as_mut() {
+        stack.sockets.remove(handle);
     }
-
-    stack.sockets.remove(handle);
-
-    if total == 0 { return None; }
-    response.truncate(total);
-    Some(response)
+    result
 }
-
-pub fn http_get_string(host: &str, path: &str, ip: Ipv4Address, port: u16) -> Option<String> {
-    match http_get(host, path, ip, port) {
-        Some(bits) => Some(String::from_utf8(bits).unwrap().to_string()),
-        None => None,
-    }
-}
\ No newline at end of file
@@ -0,0 +1,164 @@
+//! SNTP client (RFC 4330) for setting the clock.
+//!
+//! One request and reply gives the offset between our clock and the
+//! server's, with the network delay taken out. Applying it moves the
+//! clock `time::now_ms` runs and writes the RTC, so the next boot starts
+//! from the corrected time.
+
+use smoltcp::wire::Ipv4Address;
+use crate::serial_println;
+use super::socket::{self, NetError, UdpSocket};
+use super::{lookup, REPLY_TIMEOUT_MS};
+
+pub const DEFAULT_SERVER: &str = "pool.ntp.org";
+
+const NTP_PORT: u16 = 123;
+const PACKET_SIZE: usize = 48;
+/// Seconds from the NTP epoch, 1900, to the Unix one
+const NTP_TO_UNIX_SECS: i64 = 2_208_988_800;
+/// Version 4, client mode
+const CLIENT_REQUEST: u8 = (4 << 3) | 3;
+const MODE_SERVER: u8 = 4;
+/// Leap indicator value for a server that isn't synchronised itself
+const LEAP_UNSYNCHRONISED: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SntpError {
+    Net(NetError),
+    /// The server name didn't resolve
+    Dns,
+    /// The server told us to go away, with its four-letter reason
+    KissOfDeath([u8; 4]),
+    /// The server isn't synchronised itself
+    Unsynchronised,
+}
+
+impl From<NetError> for SntpError {
+    fn from(e: NetError) -> Self {
+        SntpError::Net(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub server: Ipv4Address,
+    pub stratum: u8,
+    /// How far the server's clock is ahead of ours
+    pub offset_ms: i64,
+    /// Round trip, less the time the server spent on the request
+    pub delay_ms: i64,
+}
+
+/// Unix milliseconds for a 64-bit NTP timestamp. Seconds with the top bit
+/// clear are taken to be past 2036, when the 32-bit count wraps.
+fn to_unix_ms(timestamp: &[u8]) -> i64 {
+    let secs = u32::from_be_bytes([timestamp[0], timestamp[1], timestamp[2], timestamp[3]]) as i64;
+    let fraction = u32::from_be_bytes([timestamp[4], timestamp[5], timestamp[6], timestamp[7]]) as i64;
+    let secs = if secs & 0x8000_0000 != 0 { secs } else { secs + (1 << 32) };
+    (secs - NTP_TO_UNIX_SECS) * 1000 + ((fraction * 1000) >> 32)
+}
+
+/// Check a reply and work out the sample. `nonce` is what we sent as our
+/// transmit time, which the server must echo; `sent` and `received` are our
+/// clock's readings either side of the exchange.
+fn sample(server: Ipv4Address, reply: &[u8], nonce: &[u8], sent: i64, received: i64) -> Option<Result<Sample, SntpError>> {
+    if reply.len() < PACKET_SIZE || reply[0] & 0x07 != MODE_SERVER || &reply[24..32] != nonce {
+        return None;
+    }
+    let stratum = reply[1];
+    if stratum == 0 {
+        let mut code = [0u8; 4];
+        code.copy_from_slice(&reply[12..16]);
+        return Some(Err(SntpError::KissOfDeath(code)));
+    }
+    if reply[0] >> 6 == LEAP_UNSYNCHRONISED || reply[40..48] == [0; 8] {
+        return Some(Err(SntpError::Unsynchronised));
+    }
+    let server_received = to_unix_ms(&reply[32..40]);
+    let server_sent = to_unix_ms(&reply[40..48]);
+    Some(Ok(Sample {
+        server,
+        stratum,
+        offset_ms: ((server_received - sent) + (server_sent - received)) / 2,
+        delay_ms: (received - sent) - (server_sent - server_received),
+    }))
+}
+
+async fn recv_sample(socket: &UdpSocket, server: Ipv4Address, nonce: &[u8], sent: i64) -> Result<Sample, SntpError> {
+    let mut buf = [0u8; 128];
+    loop {
+        let (n, from, port) = socket.recv_from(&mut buf).await?;
+        let received = crate::time::now_ms() as i64;
+        if from != server || port != NTP_PORT {
+            continue;
+        }
+        if let Some(result) = sample(server, &buf[..n], nonce, sent, received) {
+            return result;
+        }
+    }
+}
+
+/// Ask `server` for the time
+pub async fn query(server: Ipv4Address) -> Result<Sample, SntpError> {
+    // A random transmit time instead of our clock's, which the server echoes
+    // back, both avoids revealing the clock and lets forged replies be spotted
+    let nonce: [u8; 8] = crate::crypto::rng::random_bytes();
+    let mut request = [0u8; PACKET_SIZE];
+    request[0] = CLIENT_REQUEST;
+    request[40..48].copy_from_slice(&nonce);
+
+    let socket = UdpSocket::bind(0)?;
+    let sent = crate::time::now_ms() as i64;
+    socket.send_to(&request, server, NTP_PORT).await?;
+    socket::timeout(REPLY_TIMEOUT_MS as u64, recv_sample(&socket, server, &nonce, sent)).await
+}
+
+/// Look up `server`, ask it the time and set the clock from the answer
+pub async fn sync(server: &str) -> Result<Sample, SntpError> {
+    let ip = lookup(server).await.ok_or(SntpError::Dns)?;
+    let sample = query(ip).await?;
+    crate::time::set_time((crate::time::now_ms() as i64 + sample.offset_ms) as u64);
+    serial_println!("[ntp] {} ({}): offset {} ms, delay {} ms, stratum {}",
+                    server, ip, sample.offset_ms, sample.delay_ms, sample.stratum);
+    Ok(sample)
+}
+
+/// Set the clock at boot if system.ini asks for it and there's an address
+pub fn boot_sync() {
+    let (enabled, server) = {
+        let config = crate::CONFIG.lock();
+        (config.ntp_sync, config.ntp_server.clone())
+    };
+    if !enabled || super::get_ip().is_none() {
+        return;
+    }
+    match super::block_on(sync(&server)) {
+        Some(Ok(_)) => serial_println!("[ntp] Clock set: {}", crate::time::get_time()),
+        Some(Err(e)) => serial_println!("[ntp] Sync with {} failed: {:?}", server, e),
+        None => {}
+    }
+}
+
+#[test_case]
+fn test_sample() {
+    let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+    let mut reply = [0u8; PACKET_SIZE];
+    reply[0] = (4 << 3) | MODE_SERVER;
+    reply[1] = 2;
+    reply[24..32].copy_from_slice(&nonce);
+    // Received at 2024-01-01T00:00:00.500Z, answered at .750
+    let secs = (1_704_067_200 + NTP_TO_UNIX_SECS) as u32;
+    reply[32..36].copy_from_slice(&secs.to_be_bytes());
+    reply[36..40].copy_from_slice(&(1u32 << 31).to_be_bytes());
+    reply[40..44].copy_from_slice(&secs.to_be_bytes());
+    reply[44..48].copy_from_slice(&(3u32 << 30).to_be_bytes());
+
+    // Our clock is a second behind, with 20 ms each way
+    let sent = 1_704_067_199_480;
+    let server = Ipv4Address::new(192, 0, 2, 1);
+    let result = sample(server, &reply, &nonce, sent, sent + 290).unwrap().unwrap();
+    assert_eq!(result.delay_ms, 40);
+    assert_eq!(result.offset_ms, 1_000);
+    // Not an answer to our request
+    assert!(sample(server, &reply, &[0; 8], sent, sent + 290).is_none());
+}
@@ -0,0 +1,361 @@
+//! Async sockets on top of the shared smoltcp stack.
+//!
+//! Every operation is a future that tries the socket once under the `NET`
+//! lock and, if it can't make progress, parks its waker until `net_task`
+//! next polls the interface. Nothing holds the lock across an `.await`, so
+//! any number of tasks can use the network at once. Shell commands, which
+//! run synchronously, drive these futures with `net::block_on`.
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::{tcp, udp};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use super::{now_ms, park, NetStack, CLOSE_TIMEOUT_MS, NET};
+
+const TCP_BUFFER_SIZE: usize = 8192;
+const UDP_BUFFER_SIZE: usize = 4096;
+const UDP_PACKETS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetError {
+    /// The stack isn't up, or there's no e1000
+    NoStack,
+    /// No address yet, or the destination can't be routed
+    Unaddressable,
+    /// The port is already in use by one of our sockets
+    AddrInUse,
+    ConnectionRefused,
+    /// The peer closed or reset the connection while writing
+    ConnectionReset,
+    TimedOut,
+    /// The reply or datagram didn't fit the socket's buffers
+    BufferFull,
+}
+
+/// Ephemeral ports handed out in turn, starting somewhere different each boot
+static NEXT_PORT: AtomicU16 = AtomicU16::new(0);
+
+/// A local port from the dynamic range 49152-65535
+pub fn ephemeral_port() -> u16 {
+    if NEXT_PORT.load(Ordering::Relaxed) == 0 {
+        NEXT_PORT.store(now_ms() as u16 % 16384, Ordering::Relaxed);
+    }
+    49152 + NEXT_PORT.fetch_add(1, Ordering::Relaxed) % 16384
+}
+
+/// Run `f` on the stack, or fail if there isn't one
+fn with_stack<T>(f: impl FnOnce(&mut NetStack) -> Result<T, NetError>) -> Result<T, NetError> {
+    match NET.lock().as_mut() {
+        Some(stack) => f(stack),
+        None => Err(NetError::NoStack),
+    }
+}
+
+/// Poll `attempt` until it gives an answer, failing with `TimedOut` after
+/// `timeout_ms` milliseconds if one is given
+pub(crate) async fn retry<T>(timeout_ms: Option<u64>, mut attempt: impl FnMut(&mut NetStack) -> Option<Result<T, NetError>>) -> Result<T, NetError> {
+    let deadline = timeout_ms.map(|ms| now_ms() + ms as i64);
+    poll_fn(move |cx: &mut Context| {
+        match with_stack(|stack| Ok(attempt(stack))) {
+            Err(e) => return Poll::Ready(Err(e)),
+            Ok(Some(result)) => return Poll::Ready(result),
+            Ok(None) => {}
+        }
+        if deadline.map_or(false, |d| now_ms() >= d) {
+            return Poll::Ready(Err(NetError::TimedOut));
+        }
+        park(cx, deadline);
+        Poll::Pending
+    })
+    .await
+}
+
+/// `future`, or `TimedOut` if it hasn't finished after `ms` milliseconds
+pub async fn timeout<T, E: From<NetError>>(ms: u64, future: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let deadline = now_ms() + ms as i64;
+    let mut future = core::pin::pin!(future);
+    poll_fn(move |cx: &mut Context| {
+        if let Poll::Ready(result) = future.as_mut().poll(cx) {
+            return Poll::Ready(result);
+        }
+        if now_ms() >= deadline {
+            return Poll::Ready(Err(NetError::TimedOut.into()));
+        }
+        park(cx, Some(deadline));
+        Poll::Pending
+    })
+    .await
+}
+
+fn new_tcp_socket() -> tcp::Socket<'static> {
+    tcp::Socket::new(
+        tcp::SocketBuffer::new(alloc::vec![0u8; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(alloc::vec![0u8; TCP_BUFFER_SIZE]),
+    )
+}
+
+pub struct TcpStream {
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    /// Open a connection, waiting up to `timeout_ms` for the handshake
+    pub async fn connect(ip: Ipv4Address, port: u16, timeout_ms: u64) -> Result<TcpStream, NetError> {
+        let handle = with_stack(|stack| {
+            let handle = stack.sockets.add(new_tcp_socket());
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            match socket.connect(stack.iface.context(), (IpAddress::Ipv4(ip), port), ephemeral_port()) {
+                Ok(()) => Ok(handle),
+                Err(_) => {
+                    stack.sockets.remove(handle);
+                    Err(NetError::Unaddressable)
+                }
+            }
+        })?;
+        // Dropping the stream on any error below also frees the socket
+        let stream = TcpStream { handle };
+        retry(Some(timeout_ms), |stack| {
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            if socket.may_send() {
+                Some(Ok(()))
+            } else if socket.state() == tcp::State::Closed {
+                Some(Err(NetError::ConnectionRefused))
+            } else {
+                None
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    /// Read whatever has arrived, waiting for at least one byte. Ok(0) means
+    /// the peer has closed its side.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        let handle = self.handle;
+        retry(None, |stack| {
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            if socket.can_recv() {
+                Some(Ok(socket.recv_slice(buf).unwrap_or(0)))
+            } else if !socket.may_recv() {
+                Some(Ok(0))
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// Everything up to the peer closing the connection, at most `limit` bytes
+    pub async fn read_to_end(&mut self, limit: usize) -> Result<Vec<u8>, NetError> {
+        let mut data = Vec::new();
+        let mut buf = alloc::vec![0u8; 4096];
+        while data.len() < limit {
+            let n = self.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+        data.truncate(limit);
+        Ok(data)
+    }
+
+    /// Queue as much of `data` as fits, waiting for room for at least one byte
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, NetError> {
+        let handle = self.handle;
+        retry(None, |stack| {
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            if !socket.may_send() {
+                Some(Err(NetError::ConnectionReset))
+            } else if socket.can_send() {
+                Some(socket.send_slice(data).map_err(|_| NetError::ConnectionReset))
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    pub async fn write_all(&mut self, mut data: &[u8]) -> Result<(), NetError> {
+        while !data.is_empty() {
+            let n = self.write(data).await?;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    /// Send a FIN once everything queued has gone out. Reading still works
+    /// until the peer closes too.
+    pub fn shutdown(&mut self) {
+        if let Some(stack) = NET.lock().as_mut() {
+            stack.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        }
+    }
+
+    pub fn peer(&self) -> Option<IpEndpoint> {
+        NET.lock().as_mut()?.sockets.get_mut::<tcp::Socket>(self.handle).remote_endpoint()
+    }
+}
+
+impl Drop for TcpStream {
+    /// Close gracefully; the stack frees the socket once the close completes,
+    /// or resets it if the peer hasn't finished closing within `CLOSE_TIMEOUT_MS`
+    fn drop(&mut self) {
+        if let Some(stack) = NET.lock().as_mut() {
+            stack.sockets.get_mut::<tcp::Socket>(self.handle).close();
+            stack.closing.push((self.handle, now_ms() + CLOSE_TIMEOUT_MS));
+        }
+    }
+}
+
+/// Accepts connections on a port, one at a time: smoltcp sockets each take a
+/// single connection, so a fresh listening socket replaces each accepted one.
+pub struct TcpListener {
+    port: u16,
+    handle: SocketHandle,
+}
+
+fn listen_on(stack: &mut NetStack, port: u16) -> Result<SocketHandle, NetError> {
+    let handle = stack.sockets.add(new_tcp_socket());
+    match stack.sockets.get_mut::<tcp::Socket>(handle).listen(port) {
+        Ok(()) => Ok(handle),
+        Err(_) => {
+            stack.sockets.remove(handle);
+            Err(NetError::AddrInUse)
+        }
+    }
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        with_stack(|stack| {
+            let taken = stack.sockets.iter().any(|(_, s)| match s {
+                smoltcp::socket::Socket::Tcp(tcp) => tcp.listen_endpoint().port == port && tcp.is_listening(),
+                _ => false,
+            });
+            if taken {
+                return Err(NetError::AddrInUse);
+            }
+            Ok(TcpListener { port, handle: listen_on(stack, port)? })
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for the next client to connect
+    pub async fn accept(&mut self) -> Result<TcpStream, NetError> {
+        let port = self.port;
+        let listening = &mut self.handle;
+        retry(None, |stack| {
+            let socket = stack.sockets.get_mut::<tcp::Socket>(*listening);
+            if socket.is_listening() || socket.state() == tcp::State::SynReceived {
+                return None;
+            }
+            let accepted = *listening;
+            Some(listen_on(stack, port).map(|fresh| {
+                *listening = fresh;
+                TcpStream { handle: accepted }
+            }))
+        })
+        .await
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        if let Some(stack) = NET.lock().as_mut() {
+            stack.sockets.remove(self.handle);
+        }
+    }
+}
+
+pub struct UdpSocket {
+    handle: SocketHandle,
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Bind to `port`, or to an ephemeral port if it is 0
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let port = if port == 0 { ephemeral_port() } else { port };
+        with_stack(|stack| {
+            let socket = udp::Socket::new(
+                udp::PacketBuffer::new(alloc::vec![udp::PacketMetadata::EMPTY; UDP_PACKETS], alloc::vec![0u8; UDP_BUFFER_SIZE]),
+                udp::PacketBuffer::new(alloc::vec![udp::PacketMetadata::EMPTY; UDP_PACKETS], alloc::vec![0u8; UDP_BUFFER_SIZE]),
+            );
+            let handle = stack.sockets.add(socket);
+            match stack.sockets.get_mut::<udp::Socket>(handle).bind(port) {
+                Ok(()) => Ok(UdpSocket { handle, port }),
+                Err(_) => {
+                    stack.sockets.remove(handle);
+                    Err(NetError::AddrInUse)
+                }
+            }
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn send_to(&self, data: &[u8], ip: Ipv4Address, port: u16) -> Result<(), NetError> {
+        let handle = self.handle;
+        let endpoint = IpEndpoint::new(IpAddress::Ipv4(ip), port);
+        retry(None, |stack| {
+            let socket = stack.sockets.get_mut::<udp::Socket>(handle);
+            match socket.send_slice(data, endpoint) {
+                Ok(()) => Some(Ok(())),
+                Err(udp::SendError::BufferFull) if data.len() <= UDP_BUFFER_SIZE => None,
+                Err(udp::SendError::BufferFull) => Some(Err(NetError::BufferFull)),
+                Err(udp::SendError::Unaddressable) => Some(Err(NetError::Unaddressable)),
+            }
+        })
+        .await
+    }
+
+    /// Wait until everything sent has left the socket, which can take an
+    /// ARP exchange
+    pub async fn flush(&self, timeout_ms: u64) -> Result<(), NetError> {
+        let handle = self.handle;
+        retry(Some(timeout_ms), |stack| {
+            if stack.sockets.get::<udp::Socket>(handle).send_queue() == 0 {
+                Some(Ok(()))
+            } else {
+                None
+            }
+        })
+        .await
+    }
+
+    /// Wait for a datagram: (length, sender address, sender port)
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Ipv4Address, u16), NetError> {
+        let handle = self.handle;
+        retry(None, |stack| {
+            let socket = stack.sockets.get_mut::<udp::Socket>(handle);
+            match socket.recv_slice(buf) {
+                Ok((n, meta)) => match meta.endpoint.addr {
+                    IpAddress::Ipv4(ip) => Some(Ok((n, ip, meta.endpoint.port))),
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                },
+                Err(udp::RecvError::Truncated) => Some(Err(NetError::BufferFull)),
+                Err(udp::RecvError::Exhausted) => None,
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        if let Some(stack) = NET.lock().as_mut() {
+            stack.sockets.remove(self.handle);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! A telnet-style remote shell, for driving headless VMs.
+//!
+//! Each connection gets its own task running a prompt loop: bytes from the
+//! client go through a small line editor, and each finished line is handed to
+//! the same dispatch as the keyboard shell with its output captured and sent
+//! back. There is no authentication, so only expose the port through QEMU's
+//! user-net forwarding or another trusted network.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::serial_println;
+use crate::task::{executor, Task};
+use super::socket::{timeout, NetError, TcpListener, TcpStream};
+
+/// How often the accept loop checks whether it has been stopped
+const STOP_CHECK_MS: u64 = 1_000;
+const MAX_SESSIONS: usize = 4;
+const MAX_LINE: usize = 1024;
+const HISTORY_MAX: usize = 50;
+/// Commands that take over the local screen and keyboard
+const CONSOLE_ONLY: &[&str] = &["edit"];
+
+// Telnet protocol bytes (RFC 854)
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const IP: u8 = 244;
+const EC: u8 = 247;
+const OPT_ECHO: u8 = 1;
+const OPT_SUPPRESS_GO_AHEAD: u8 = 3;
+const OPT_LINEMODE: u8 = 34;
+
+static PORT: Mutex<Option<u16>> = Mutex::new(None);
+static STOP: AtomicBool = AtomicBool::new(false);
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Start accepting remote shell sessions on `port` in the background
+pub fn start(port: u16) -> Result<(), NetError> {
+    let mut running = PORT.lock();
+    if running.is_some() {
+        return Err(NetError::AddrInUse);
+    }
+    let listener = TcpListener::bind(port)?;
+    STOP.store(false, Ordering::SeqCst);
+    *running = Some(port);
+    executor::spawn_task(Task::new(serve(listener)));
+    Ok(())
+}
+
+/// Stop accepting new sessions; open ones carry on until they log out.
+/// False if the server isn't running.
+pub fn stop() -> bool {
+    if PORT.lock().is_none() {
+        return false;
+    }
+    STOP.store(true, Ordering::SeqCst);
+    true
+}
+
+/// (port, open sessions) while the server is running
+pub fn running() -> Option<(u16, usize)> {
+    PORT.lock().map(|port| (port, SESSIONS.load(Ordering::SeqCst)))
+}
+
+async fn serve(mut listener: TcpListener) {
+    serial_println!("[telnetd] listening on port {}", listener.port());
+    while !STOP.load(Ordering::SeqCst) {
+        match timeout(STOP_CHECK_MS, listener.accept()).await {
+            Ok(mut stream) => {
+                if SESSIONS.load(Ordering::SeqCst) >= MAX_SESSIONS {
+                    stream.write_all(b"Too many sessions, try again later.\r\n").await.ok();
+                    continue;
+                }
+                SESSIONS.fetch_add(1, Ordering::SeqCst);
+                executor::spawn_task(Task::new(async move {
+                    session(stream).await;
+                    SESSIONS.fetch_sub(1, Ordering::SeqCst);
+                }));
+            }
+            Err(NetError::TimedOut) => {}
+            Err(e) => {
+                serial_println!("[telnetd] accept failed: {:?}", e);
+                break;
+            }
+        }
+    }
+    serial_println!("[telnetd] stopped");
+    *PORT.lock() = None;
+}
+
+async fn session(mut stream: TcpStream) {
+    let peer = stream.peer();
+    if let Some(peer) = peer {
+        serial_println!("[telnetd] session from {}", peer);
+    }
+    // We echo and the client sends characters as they are typed
+    let greeting = [
+        IAC, WILL, OPT_ECHO,
+        IAC, WILL, OPT_SUPPRESS_GO_AHEAD,
+        IAC, DONT, OPT_LINEMODE,
+    ];
+    if stream.write_all(&greeting).await.is_err() {
+        return;
+    }
+    let banner = alloc::format!("Connected. Type logout to end the session.\r\n{}", crate::shell::SHELL_PROMPT);
+    if stream.write_all(banner.as_bytes()).await.is_err() {
+        return;
+    }
+
+    let mut editor = LineEditor::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let mut reply = Vec::new();
+        let mut lines = Vec::new();
+        for &byte in &buf[..n] {
+            match editor.feed(byte, &mut reply) {
+                Some(Event::Line(line)) => lines.push(line),
+                Some(Event::Interrupt) => reply.extend_from_slice(crate::shell::SHELL_PROMPT.as_bytes()),
+                Some(Event::Eof) => lines.push(String::from("logout")),
+                None => {}
+            }
+        }
+        for line in lines {
+            reply.extend_from_slice(b"\r\n");
+            let command = line.split_whitespace().next().unwrap_or("");
+            if command == "logout" {
+                stream.write_all(&reply).await.ok();
+                stream.write_all(b"Bye.\r\n").await.ok();
+                if let Some(peer) = peer {
+                    serial_println!("[telnetd] {} logged out", peer);
+                }
+                return;
+            }
+            if CONSOLE_ONLY.contains(&command) {
+                reply.extend_from_slice(alloc::format!("{}: only available on the console\r\n", command).as_bytes());
+            } else if !command.is_empty() {
+                let ((), output) = crate::vga::capture_output(|| crate::shell::pass_to_shell(line.into_bytes()));
+                reply.extend_from_slice(output.replace('\n', "\r\n").as_bytes());
+            }
+            reply.extend_from_slice(crate::shell::SHELL_PROMPT.as_bytes());
+        }
+        if !reply.is_empty() && stream.write_all(&reply).await.is_err() {
+            break;
+        }
+    }
+    if let Some(peer) = peer {
+        serial_println!("[telnetd] {} disconnected", peer);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Line(String),
+    /// Ctrl-C or telnet's Interrupt Process: the line was thrown away
+    Interrupt,
+    /// Ctrl-D on an empty line
+    Eof,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    /// After a CR, which may be followed by LF or NUL
+    Cr,
+    Iac,
+    /// Waiting for the option byte of WILL/WONT/DO/DONT
+    Option,
+    Sub,
+    SubIac,
+    Escape,
+    Csi,
+}
+
+/// Turns the client's byte stream into lines, filtering out telnet commands
+/// and echoing edits back
+struct LineEditor {
+    line: Vec<u8>,
+    state: State,
+    history: Vec<Vec<u8>>,
+    /// Position while browsing history with the arrow keys
+    index: Option<usize>,
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        LineEditor { line: Vec::new(), state: State::Data, history: Vec::new(), index: None }
+    }
+
+    /// Handle one byte from the client, appending anything to echo to `echo`
+    fn feed(&mut self, byte: u8, echo: &mut Vec<u8>) -> Option<Event> {
+        match self.state {
+            State::Data => self.data(byte, echo),
+            State::Cr => {
+                self.state = State::Data;
+                if byte == b'\n' || byte == 0 {
+                    None
+                } else {
+                    self.data(byte, echo)
+                }
+            }
+            State::Iac => {
+                self.state = State::Data;
+                match byte {
+                    WILL..=DONT => self.state = State::Option,
+                    SB => self.state = State::Sub,
+                    IP => return Some(self.interrupt(echo)),
+                    EC => self.backspace(echo),
+                    _ => {}
+                }
+                None
+            }
+            State::Option => {
+                self.state = State::Data;
+                None
+            }
+            State::Sub => {
+                if byte == IAC {
+                    self.state = State::SubIac;
+                }
+                None
+            }
+            State::SubIac => {
+                self.state = if byte == SE { State::Data } else { State::Sub };
+                None
+            }
+            State::Escape => {
+                self.state = if byte == b'[' || byte == b'O' { State::Csi } else { State::Data };
+                None
+            }
+            State::Csi => {
+                if !(byte.is_ascii_digit() || byte == b';') {
+                    self.state = State::Data;
+                    match byte {
+                        b'A' => self.browse(true, echo),
+                        b'B' => self.browse(false, echo),
+                        _ => {}
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    fn data(&mut self, byte: u8, echo: &mut Vec<u8>) -> Option<Event> {
+        match byte {
+            IAC => self.state = State::Iac,
+            0x1b => self.state = State::Escape,
+            b'\r' | b'\n' => {
+                if byte == b'\r' {
+                    self.state = State::Cr;
+                }
+                let line = core::mem::take(&mut self.line);
+                self.index = None;
+                if !line.is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                    if self.history.len() > HISTORY_MAX {
+                        self.history.remove(0);
+                    }
+                }
+                return Some(Event::Line(String::from_utf8_lossy(&line).into_owned()));
+            }
+            0x08 | 0x7f => self.backspace(echo),
+            0x03 => return Some(self.interrupt(echo)),
+            0x04 if self.line.is_empty() => return Some(Event::Eof),
+            // Ctrl-U: erase the line
+            0x15 => {
+                while !self.line.is_empty() {
+                    self.backspace(echo);
+                }
+            }
+            b if b >= 0x20 && self.line.len() < MAX_LINE => {
+                self.line.push(b);
+                echo.push(b);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Remove the last character, which may be several UTF-8 bytes
+    fn backspace(&mut self, echo: &mut Vec<u8>) {
+        while let Some(b) = self.line.pop() {
+            if b & 0xC0 != 0x80 {
+                echo.extend_from_slice(b"\x08 \x08");
+                break;
+            }
+        }
+    }
+
+    fn interrupt(&mut self, echo: &mut Vec<u8>) -> Event {
+        self.line.clear();
+        self.index = None;
+        echo.extend_from_slice(b"^C\r\n");
+        Event::Interrupt
+    }
+
+    /// Step through history, replacing the line being edited
+    fn browse(&mut self, back: bool, echo: &mut Vec<u8>) {
+        let index = match (self.index, back) {
+            (None, true) if !self.history.is_empty() => Some(self.history.len() - 1),
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < self.history.len() => Some(i + 1),
+            (Some(_), false) => None,
+            _ => return,
+        };
+        self.index = index;
+        while !self.line.is_empty() {
+            self.backspace(echo);
+        }
+        self.line = index.map_or_else(Vec::new, |i| self.history[i].clone());
+        echo.extend_from_slice(&self.line);
+    }
+}
+
+#[test_case]
+fn test_line_editor() {
+    let mut editor = LineEditor::new();
+    let mut echo = Vec::new();
+    let mut feed = |editor: &mut LineEditor, bytes: &[u8]| -> Vec<Event> {
+        bytes.iter().filter_map(|&b| editor.feed(b, &mut echo)).collect()
+    };
+    // Option negotiation is skipped and CR LF ends one line, not two
+    assert_eq!(feed(&mut editor, b"\xff\xfd\x01ls -l\r\n"), alloc::vec![Event::Line(String::from("ls -l"))]);
+    assert_eq!(feed(&mut editor, b"cax\x7ft\r\0"), alloc::vec![Event::Line(String::from("cat"))]);
+    assert_eq!(feed(&mut editor, b"oops\x03"), alloc::vec![Event::Interrupt]);
+    // Up arrow brings back the previous line
+    assert_eq!(feed(&mut editor, b"\x1b[A\r"), alloc::vec![Event::Line(String::from("cat"))]);
+    assert_eq!(feed(&mut editor, b"\x04"), alloc::vec![Event::Eof]);
+}
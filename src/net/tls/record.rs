@@ -0,0 +1,170 @@
+//! Record protection: AEAD encryption of TLS records under one direction's
+//! keys, in either the TLS 1.2 or the TLS 1.3 layout.
+
+use alloc::vec::Vec;
+use crate::crypto::aes::AesGcm;
+use crate::crypto::chacha20;
+use super::{ContentType, TlsError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Aead {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Aead {
+    pub(super) fn key_len(self) -> usize {
+        match self {
+            Aead::Aes128Gcm => 16,
+            _ => 32,
+        }
+    }
+
+    /// IV bytes taken from the key schedule. TLS 1.2 GCM only derives the
+    /// first four and sends the rest with each record.
+    pub(super) fn fixed_iv_len(self, tls13: bool) -> usize {
+        match self {
+            Aead::Aes128Gcm | Aead::Aes256Gcm if !tls13 => 4,
+            _ => 12,
+        }
+    }
+}
+
+enum Cipher {
+    Aes(AesGcm),
+    ChaCha([u8; 32]),
+}
+
+pub(super) struct RecordKeys {
+    cipher: Cipher,
+    iv: Vec<u8>,
+    seq: u64,
+    tls13: bool,
+}
+
+/// Record header length: type, version, length
+pub(super) const HEADER_LEN: usize = 5;
+const TAG_LEN: usize = 16;
+/// TLS 1.2 GCM's per-record nonce
+const EXPLICIT_NONCE_LEN: usize = 8;
+
+impl RecordKeys {
+    pub(super) fn new(aead: Aead, key: &[u8], iv: &[u8], tls13: bool) -> RecordKeys {
+        let cipher = match aead {
+            Aead::ChaCha20Poly1305 => {
+                let mut k = [0u8; 32];
+                k.copy_from_slice(key);
+                Cipher::ChaCha(k)
+            }
+            // The key length comes from `Aead::key_len`
+            _ => Cipher::Aes(AesGcm::new(key).unwrap()),
+        };
+        RecordKeys { cipher, iv: Vec::from(iv), seq: 0, tls13 }
+    }
+
+    /// The per-record nonce: the IV XORed with the sequence number, or for
+    /// TLS 1.2 GCM the fixed IV followed by the explicit part
+    fn nonce(&self, explicit: Option<&[u8]>) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        match explicit {
+            Some(explicit) => {
+                nonce[..4].copy_from_slice(&self.iv);
+                nonce[4..].copy_from_slice(explicit);
+            }
+            None => {
+                nonce.copy_from_slice(&self.iv);
+                for (n, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+                    *n ^= s;
+                }
+            }
+        }
+        nonce
+    }
+
+    fn explicit_nonce(&self) -> bool {
+        !self.tls13 && matches!(self.cipher, Cipher::Aes(_))
+    }
+
+    /// TLS 1.2's additional data: sequence number and the plaintext's header
+    fn tls12_aad(&self, content_type: ContentType, len: usize) -> [u8; 13] {
+        let mut aad = [0u8; 13];
+        aad[..8].copy_from_slice(&self.seq.to_be_bytes());
+        aad[8] = content_type as u8;
+        aad[9..11].copy_from_slice(&[3, 3]);
+        aad[11..].copy_from_slice(&(len as u16).to_be_bytes());
+        aad
+    }
+
+    fn seal_raw(&self, nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        match &self.cipher {
+            Cipher::Aes(gcm) => gcm.seal(nonce, aad, plaintext),
+            Cipher::ChaCha(key) => chacha20::seal(key, nonce, aad, plaintext),
+        }
+    }
+
+    fn open_raw(&self, nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        match &self.cipher {
+            Cipher::Aes(gcm) => gcm.open(nonce, aad, sealed),
+            Cipher::ChaCha(key) => chacha20::open(key, nonce, aad, sealed),
+        }
+    }
+
+    /// Encrypt `payload` into a complete record
+    pub(super) fn seal(&mut self, content_type: ContentType, payload: &[u8]) -> Vec<u8> {
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len() + EXPLICIT_NONCE_LEN + TAG_LEN + 1);
+        if self.tls13 {
+            // The real type goes inside; outside every record looks like data
+            let mut inner = Vec::from(payload);
+            inner.push(content_type as u8);
+            let len = inner.len() + TAG_LEN;
+            record.extend_from_slice(&[ContentType::ApplicationData as u8, 3, 3]);
+            record.extend_from_slice(&(len as u16).to_be_bytes());
+            let sealed = self.seal_raw(&self.nonce(None), &record, &inner);
+            record.extend_from_slice(&sealed);
+        } else {
+            let aad = self.tls12_aad(content_type, payload.len());
+            let explicit = self.seq.to_be_bytes();
+            let (nonce, prefix) = if self.explicit_nonce() {
+                (self.nonce(Some(&explicit)), &explicit[..])
+            } else {
+                (self.nonce(None), &[][..])
+            };
+            let sealed = self.seal_raw(&nonce, &aad, payload);
+            record.extend_from_slice(&[content_type as u8, 3, 3]);
+            record.extend_from_slice(&((prefix.len() + sealed.len()) as u16).to_be_bytes());
+            record.extend_from_slice(prefix);
+            record.extend_from_slice(&sealed);
+        }
+        self.seq += 1;
+        record
+    }
+
+    /// Decrypt a record's body, returning its real content type and payload
+    pub(super) fn open(&mut self, header: &[u8], body: &[u8]) -> Result<(ContentType, Vec<u8>), TlsError> {
+        let result = if self.tls13 {
+            let mut inner = self.open_raw(&self.nonce(None), header, body).ok_or(TlsError::BadRecordMac)?;
+            // Strip the zero padding back to the content type byte
+            let end = inner.iter().rposition(|&b| b != 0).ok_or(TlsError::Protocol)?;
+            let content_type = ContentType::from_u8(inner[end]).ok_or(TlsError::Protocol)?;
+            inner.truncate(end);
+            (content_type, inner)
+        } else {
+            let content_type = ContentType::from_u8(header[0]).ok_or(TlsError::Protocol)?;
+            let (nonce, sealed) = if self.explicit_nonce() {
+                if body.len() < EXPLICIT_NONCE_LEN {
+                    return Err(TlsError::BadRecordMac);
+                }
+                let (explicit, sealed) = body.split_at(EXPLICIT_NONCE_LEN);
+                (self.nonce(Some(explicit)), sealed)
+            } else {
+                (self.nonce(None), body)
+            };
+            let len = sealed.len().checked_sub(TAG_LEN).ok_or(TlsError::BadRecordMac)?;
+            let aad = self.tls12_aad(content_type, len);
+            (content_type, self.open_raw(&nonce, &aad, sealed).ok_or(TlsError::BadRecordMac)?)
+        };
+        self.seq += 1;
+        Ok(result)
+    }
+}
@@ -0,0 +1,66 @@
+//! Where trust comes from: a PEM bundle of CA certificates, and a list of
+//! pinned server certificates for hosts that have no CA behind them.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::crypto::sha2::Sha256;
+use crate::crypto::unhex;
+use crate::serial_println;
+use super::x509::{pem_certificates, Certificate};
+
+/// Trusted CA certificates, concatenated PEM as in most distributions'
+/// `ca-certificates.crt`
+pub const CA_BUNDLE_FILE: &str = "/ca.pem";
+/// One `host sha256-fingerprint` per line. The fingerprint is of the DER
+/// certificate, with or without colons, as `openssl x509 -fingerprint
+/// -sha256` prints it.
+pub const PINS_FILE: &str = "/tls_pins.txt";
+
+pub(super) struct TrustStore {
+    pub anchors: Vec<Certificate>,
+    pins: Vec<(String, Vec<u8>)>,
+}
+
+impl TrustStore {
+    /// Read both files. Missing files just mean no anchors or no pins;
+    /// certificates that don't parse are skipped.
+    pub(super) fn load() -> TrustStore {
+        let anchors = crate::fs::read_file(CA_BUNDLE_FILE)
+            .map(|data| {
+                pem_certificates(&String::from_utf8_lossy(&data))
+                    .iter()
+                    .filter_map(|der| Certificate::parse(der).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut pins = Vec::new();
+        if let Some(data) = crate::fs::read_file(PINS_FILE) {
+            for line in String::from_utf8_lossy(&data).lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next().and_then(unhex)) {
+                    (Some(host), Some(fingerprint)) if fingerprint.len() == 32 => {
+                        pins.push((host.to_ascii_lowercase(), fingerprint))
+                    }
+                    _ => serial_println!("[tls] {}: ignoring line: {}", PINS_FILE, line),
+                }
+            }
+        }
+        TrustStore { anchors, pins }
+    }
+
+    /// The fingerprints pinned for `host`, if it has any
+    pub(super) fn pins(&self, host: &str) -> Option<Vec<&[u8]>> {
+        let host = host.to_ascii_lowercase();
+        let pins: Vec<&[u8]> = self.pins.iter().filter(|(h, _)| *h == host).map(|(_, f)| f.as_slice()).collect();
+        if pins.is_empty() { None } else { Some(pins) }
+    }
+}
+
+/// SHA-256 of a DER certificate, the form pins are written in
+pub fn fingerprint(der: &[u8]) -> [u8; 32] {
+    Sha256::digest(der)
+}
@@ -0,0 +1,488 @@
+//! Just enough DER and X.509 to check a server's certificate chain: names,
+//! validity, CA flag, public keys and signatures.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::crypto::bignum::BigUint;
+use crate::crypto::ecdsa::{self, Curve};
+use crate::crypto::rsa::RsaPublicKey;
+use crate::crypto::sha2::HashAlg;
+use crate::time::cmos::RTCDateTime;
+use super::TlsError;
+
+/// Longest chain walked from the leaf to a trust anchor
+const MAX_CHAIN: usize = 8;
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+/// Context-specific constructed [n]
+const fn explicit(n: u8) -> u8 {
+    0xA0 | n
+}
+/// GeneralName choices in subjectAltName
+const SAN_DNS: u8 = 0x82;
+const SAN_IP: u8 = 0x87;
+
+const OID_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_RSA_PSS: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a];
+const OID_RSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const OID_RSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const OID_RSA_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_ECDSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const OID_ECDSA_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04];
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+
+/// Reads DER elements one after another
+pub(super) struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Der { data }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// The next element as (tag, contents, whole encoding)
+    fn read_any(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), TlsError> {
+        let data = self.data;
+        if data.len() < 2 {
+            return Err(TlsError::BadCertificate);
+        }
+        let (len, header) = match data[1] {
+            n if n < 0x80 => (n as usize, 2),
+            0x81..=0x83 => {
+                let count = (data[1] & 0x7f) as usize;
+                let bytes = data.get(2..2 + count).ok_or(TlsError::BadCertificate)?;
+                (bytes.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize), 2 + count)
+            }
+            _ => return Err(TlsError::BadCertificate),
+        };
+        let end = header + len;
+        if end > data.len() {
+            return Err(TlsError::BadCertificate);
+        }
+        self.data = &data[end..];
+        Ok((data[0], &data[header..end], &data[..end]))
+    }
+
+    pub(super) fn read(&mut self, tag: u8) -> Result<&'a [u8], TlsError> {
+        match self.read_any()? {
+            (t, contents, _) if t == tag => Ok(contents),
+            _ => Err(TlsError::BadCertificate),
+        }
+    }
+
+    fn read_optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, TlsError> {
+        if self.peek_tag() == Some(tag) {
+            self.read(tag).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// A BIT STRING with no unused bits, as keys and signatures are
+    fn read_bits(&mut self) -> Result<&'a [u8], TlsError> {
+        match self.read(TAG_BIT_STRING)?.split_first() {
+            Some((0, bits)) => Ok(bits),
+            _ => Err(TlsError::BadCertificate),
+        }
+    }
+}
+
+/// How a certificate (or a TLS handshake message) was signed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum SignatureScheme {
+    RsaPkcs1(HashAlg),
+    RsaPss(HashAlg),
+    Ecdsa(HashAlg),
+}
+
+impl SignatureScheme {
+    /// From the TLS SignatureScheme code point
+    pub(super) fn from_tls(code: u16) -> Option<SignatureScheme> {
+        Some(match code {
+            0x0401 => SignatureScheme::RsaPkcs1(HashAlg::Sha256),
+            0x0501 => SignatureScheme::RsaPkcs1(HashAlg::Sha384),
+            0x0601 => SignatureScheme::RsaPkcs1(HashAlg::Sha512),
+            0x0403 => SignatureScheme::Ecdsa(HashAlg::Sha256),
+            0x0503 => SignatureScheme::Ecdsa(HashAlg::Sha384),
+            0x0603 => SignatureScheme::Ecdsa(HashAlg::Sha512),
+            0x0804 => SignatureScheme::RsaPss(HashAlg::Sha256),
+            0x0805 => SignatureScheme::RsaPss(HashAlg::Sha384),
+            0x0806 => SignatureScheme::RsaPss(HashAlg::Sha512),
+            _ => return None,
+        })
+    }
+
+    /// From a certificate's AlgorithmIdentifier
+    fn from_algorithm(alg: &[u8]) -> Result<SignatureScheme, TlsError> {
+        let mut der = Der::new(alg);
+        let oid = der.read(TAG_OID)?;
+        Ok(match oid {
+            OID_RSA_SHA256 => SignatureScheme::RsaPkcs1(HashAlg::Sha256),
+            OID_RSA_SHA384 => SignatureScheme::RsaPkcs1(HashAlg::Sha384),
+            OID_RSA_SHA512 => SignatureScheme::RsaPkcs1(HashAlg::Sha512),
+            OID_ECDSA_SHA256 => SignatureScheme::Ecdsa(HashAlg::Sha256),
+            OID_ECDSA_SHA384 => SignatureScheme::Ecdsa(HashAlg::Sha384),
+            OID_ECDSA_SHA512 => SignatureScheme::Ecdsa(HashAlg::Sha512),
+            OID_RSA_PSS => {
+                // RSASSA-PSS-params: the hash is in [0]; its default, SHA-1, isn't supported
+                let mut params = Der::new(der.read(TAG_SEQUENCE)?);
+                let hash = params.read(explicit(0))?;
+                let hash_oid = Der::new(Der::new(hash).read(TAG_SEQUENCE)?).read(TAG_OID)?;
+                SignatureScheme::RsaPss(hash_from_oid(hash_oid)?)
+            }
+            _ => return Err(TlsError::UnsupportedCertificate),
+        })
+    }
+}
+
+fn hash_from_oid(oid: &[u8]) -> Result<HashAlg, TlsError> {
+    match oid {
+        OID_SHA256 => Ok(HashAlg::Sha256),
+        OID_SHA384 => Ok(HashAlg::Sha384),
+        OID_SHA512 => Ok(HashAlg::Sha512),
+        _ => Err(TlsError::UnsupportedCertificate),
+    }
+}
+
+pub(super) enum PublicKey {
+    Rsa(RsaPublicKey),
+    /// Curve and uncompressed SEC1 point
+    Ec(Curve, Vec<u8>),
+}
+
+impl PublicKey {
+    /// Parse the contents of a SubjectPublicKeyInfo
+    fn parse(spki: &[u8]) -> Result<PublicKey, TlsError> {
+        let mut der = Der::new(spki);
+        let mut alg = Der::new(der.read(TAG_SEQUENCE)?);
+        let key = der.read_bits()?;
+        match alg.read(TAG_OID)? {
+            OID_RSA => {
+                let mut fields = Der::new(Der::new(key).read(TAG_SEQUENCE)?);
+                let n = BigUint::from_be_bytes(fields.read(TAG_INTEGER)?);
+                let e = BigUint::from_be_bytes(fields.read(TAG_INTEGER)?);
+                Ok(PublicKey::Rsa(RsaPublicKey { n, e }))
+            }
+            OID_EC_PUBLIC_KEY => {
+                let curve = match alg.read(TAG_OID)? {
+                    OID_P256 => Curve::P256,
+                    OID_P384 => Curve::P384,
+                    _ => return Err(TlsError::UnsupportedCertificate),
+                };
+                Ok(PublicKey::Ec(curve, Vec::from(key)))
+            }
+            _ => Err(TlsError::UnsupportedCertificate),
+        }
+    }
+
+    /// Check `signature` over `message`. TLS 1.3 CertificateVerify only
+    /// allows PSS for RSA keys and ties each ECDSA hash to its curve; the
+    /// caller enforces that.
+    pub(super) fn verify(&self, scheme: SignatureScheme, message: &[u8], signature: &[u8]) -> Result<(), TlsError> {
+        let ok = match (self, scheme) {
+            (PublicKey::Rsa(key), SignatureScheme::RsaPkcs1(hash)) => key.verify_pkcs1(hash, message, signature),
+            (PublicKey::Rsa(key), SignatureScheme::RsaPss(hash)) => key.verify_pss(hash, message, signature),
+            (PublicKey::Ec(curve, point), SignatureScheme::Ecdsa(hash)) => {
+                ecdsa::verify(*curve, point, hash, message, signature)
+            }
+            _ => false,
+        };
+        if ok { Ok(()) } else { Err(TlsError::BadSignature) }
+    }
+
+    pub(super) fn curve(&self) -> Option<Curve> {
+        match self {
+            PublicKey::Ec(curve, _) => Some(*curve),
+            PublicKey::Rsa(_) => None,
+        }
+    }
+}
+
+pub(super) struct Certificate {
+    /// The whole DER encoding, for pins and comparisons
+    pub der: Vec<u8>,
+    /// Offsets of the signed tbsCertificate within `der`
+    tbs: (usize, usize),
+    signature_scheme: SignatureScheme,
+    signature: Vec<u8>,
+    /// Raw DER of the issuer and subject Names, compared byte for byte
+    issuer: Vec<u8>,
+    subject: Vec<u8>,
+    not_before: u64,
+    not_after: u64,
+    pub public_key: PublicKey,
+    dns_names: Vec<String>,
+    ip_addresses: Vec<[u8; 4]>,
+    common_name: Option<String>,
+    is_ca: bool,
+}
+
+impl Certificate {
+    pub(super) fn parse(der: &[u8]) -> Result<Certificate, TlsError> {
+        let mut outer = Der::new(der);
+        let mut cert = Der::new(outer.read(TAG_SEQUENCE)?);
+        let (_, tbs, tbs_full) = cert.read_any()?;
+        let signature_scheme = SignatureScheme::from_algorithm(cert.read(TAG_SEQUENCE)?)?;
+        let signature = Vec::from(cert.read_bits()?);
+        let tbs_start = tbs_full.as_ptr() as usize - der.as_ptr() as usize;
+
+        let mut tbs = Der::new(tbs);
+        tbs.read_optional(explicit(0))?;
+        tbs.read(TAG_INTEGER)?;
+        tbs.read(TAG_SEQUENCE)?;
+        let (_, _, issuer) = tbs.read_any()?;
+        let mut validity = Der::new(tbs.read(TAG_SEQUENCE)?);
+        let not_before = read_time(&mut validity)?;
+        let not_after = read_time(&mut validity)?;
+        let (_, subject_contents, subject) = tbs.read_any()?;
+        let public_key = PublicKey::parse(tbs.read(TAG_SEQUENCE)?)?;
+
+        let mut certificate = Certificate {
+            der: Vec::from(der),
+            tbs: (tbs_start, tbs_start + tbs_full.len()),
+            signature_scheme,
+            signature,
+            issuer: Vec::from(issuer),
+            subject: Vec::from(subject),
+            not_before,
+            not_after,
+            public_key,
+            dns_names: Vec::new(),
+            ip_addresses: Vec::new(),
+            common_name: common_name(subject_contents),
+            is_ca: false,
+        };
+
+        // Skip the unique IDs, then walk the extensions
+        tbs.read_optional(0x81)?;
+        tbs.read_optional(0x82)?;
+        if let Some(extensions) = tbs.read_optional(explicit(3))? {
+            let mut list = Der::new(Der::new(extensions).read(TAG_SEQUENCE)?);
+            while !list.is_empty() {
+                let mut ext = Der::new(list.read(TAG_SEQUENCE)?);
+                let oid = ext.read(TAG_OID)?;
+                ext.read_optional(TAG_BOOLEAN)?;
+                let value = ext.read(TAG_OCTET_STRING)?;
+                match oid {
+                    OID_SUBJECT_ALT_NAME => certificate.read_alt_names(value)?,
+                    OID_BASIC_CONSTRAINTS => {
+                        let mut fields = Der::new(Der::new(value).read(TAG_SEQUENCE)?);
+                        certificate.is_ca = fields.read_optional(TAG_BOOLEAN)?.is_some_and(|v| v != [0]);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(certificate)
+    }
+
+    fn read_alt_names(&mut self, value: &[u8]) -> Result<(), TlsError> {
+        let mut names = Der::new(Der::new(value).read(TAG_SEQUENCE)?);
+        while !names.is_empty() {
+            match names.read_any()? {
+                (SAN_DNS, name, _) => self.dns_names.push(String::from_utf8_lossy(name).to_ascii_lowercase()),
+                (SAN_IP, ip, _) if ip.len() == 4 => self.ip_addresses.push([ip[0], ip[1], ip[2], ip[3]]),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn tbs(&self) -> &[u8] {
+        &self.der[self.tbs.0..self.tbs.1]
+    }
+
+    /// Whether `issuer`'s key signed this certificate
+    fn signed_by(&self, issuer: &Certificate) -> bool {
+        self.issuer == issuer.subject
+            && issuer.public_key.verify(self.signature_scheme, self.tbs(), &self.signature).is_ok()
+    }
+
+    fn check_validity(&self, now: u64) -> Result<(), TlsError> {
+        if now < self.not_before || now > self.not_after {
+            return Err(TlsError::CertificateExpired);
+        }
+        Ok(())
+    }
+
+    /// Whether the certificate names `host`: an exact or single-label
+    /// wildcard DNS name, an IP address, or the common name if there are no
+    /// alternative names at all
+    pub(super) fn matches_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        if let Ok(ip) = host.parse::<smoltcp::wire::Ipv4Address>() {
+            return self.ip_addresses.contains(&ip.octets());
+        }
+        let names: Vec<&str> = if self.dns_names.is_empty() {
+            self.common_name.as_deref().into_iter().collect()
+        } else {
+            self.dns_names.iter().map(|n| n.as_str()).collect()
+        };
+        names.iter().any(|name| name_matches(name, &host))
+    }
+}
+
+fn name_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.');
+    let host = host.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        // The wildcard stands for exactly one label, and never a public suffix
+        Some(suffix) if suffix.contains('.') => match host.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest == suffix,
+            None => false,
+        },
+        Some(_) => false,
+        None => pattern == host,
+    }
+}
+
+/// The first CN in a Name, if any
+fn common_name(name: &[u8]) -> Option<String> {
+    let mut rdns = Der::new(name);
+    while !rdns.is_empty() {
+        let mut set = Der::new(rdns.read(TAG_SET).ok()?);
+        while !set.is_empty() {
+            let mut attr = Der::new(set.read(TAG_SEQUENCE).ok()?);
+            if attr.read(TAG_OID).ok()? == OID_COMMON_NAME {
+                let (_, value, _) = attr.read_any().ok()?;
+                return Some(String::from_utf8_lossy(value).to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+/// UTCTime or GeneralizedTime as seconds since the epoch
+fn read_time(der: &mut Der) -> Result<u64, TlsError> {
+    let (tag, text, _) = der.read_any()?;
+    let digits = |s: &[u8]| -> Option<usize> {
+        s.iter().try_fold(0usize, |acc, &c| if c.is_ascii_digit() { Some(acc * 10 + (c - b'0') as usize) } else { None })
+    };
+    let (year, rest) = match tag {
+        TAG_UTC_TIME if text.len() >= 12 => {
+            let yy = digits(&text[..2]).ok_or(TlsError::BadCertificate)?;
+            (if yy < 50 { 2000 + yy } else { 1900 + yy }, &text[2..])
+        }
+        TAG_GENERALIZED_TIME if text.len() >= 14 => (digits(&text[..4]).ok_or(TlsError::BadCertificate)?, &text[4..]),
+        _ => return Err(TlsError::BadCertificate),
+    };
+    let field = |i: usize| digits(&rest[2 * i..2 * i + 2]).ok_or(TlsError::BadCertificate);
+    let dt = RTCDateTime {
+        year,
+        month: field(0)? as u8,
+        day: field(1)? as u8,
+        hour: field(2)? as u8,
+        minute: field(3)? as u8,
+        second: field(4)? as u8,
+    };
+    Ok(crate::time::to_unix(&dt))
+}
+
+/// Check that `chain` (leaf first, as the server sent it) leads from a
+/// certificate for `host` to one of `anchors`, with everything in date at
+/// `now`
+pub(super) fn verify_chain(chain: &[Certificate], anchors: &[Certificate], host: &str, now: u64) -> Result<(), TlsError> {
+    let leaf = chain.first().ok_or(TlsError::BadCertificate)?;
+    if !leaf.matches_host(host) {
+        return Err(TlsError::NameMismatch);
+    }
+    leaf.check_validity(now)?;
+    let mut current = leaf;
+    for _ in 0..MAX_CHAIN {
+        if anchors.iter().any(|a| a.der == current.der) {
+            return Ok(());
+        }
+        if let Some(anchor) = anchors.iter().find(|a| current.signed_by(a)) {
+            return anchor.check_validity(now);
+        }
+        // Servers may send the chain out of order or with extra certificates
+        let issuer = chain[1..]
+            .iter()
+            .find(|c| c.is_ca && current.signed_by(c))
+            .ok_or(TlsError::UntrustedCertificate)?;
+        issuer.check_validity(now)?;
+        current = issuer;
+    }
+    Err(TlsError::UntrustedCertificate)
+}
+
+/// The DER bodies of every `CERTIFICATE` block in PEM text
+pub(super) fn pem_certificates(text: &str) -> Vec<Vec<u8>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let mut certificates = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(BEGIN) {
+        let body = &rest[start + BEGIN.len()..];
+        let end = match body.find(END) {
+            Some(end) => end,
+            None => break,
+        };
+        if let Some(der) = base64_decode(&body[..end]) {
+            certificates.push(der);
+        }
+        rest = &body[end + END.len()..];
+    }
+    certificates
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            c if c.is_ascii_whitespace() => continue,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[test_case]
+fn test_name_matching() {
+    assert!(name_matches("example.com", "example.com"));
+    assert!(name_matches("*.example.com", "www.example.com"));
+    assert!(!name_matches("*.example.com", "a.b.example.com"));
+    assert!(!name_matches("*.example.com", "example.com"));
+    assert!(!name_matches("*.com", "example.com"));
+    assert_eq!(base64_decode("aGVs\nbG8="), Some(Vec::from(&b"hello"[..])));
+}
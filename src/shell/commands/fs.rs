@@ -79,11 +79,20 @@ impl Command for LsCommand {
 pub struct MkdirCommand;
 impl Command for MkdirCommand {
     fn name(&self) -> &'static str { "mkdir" }
-    fn description(&self) -> &'static str { "Create a directory: mkdir <dirname>" }
+    fn description(&self) -> &'static str { "Create a directory: mkdir [-p] <dirname>" }
     fn execute(&self, args: &[String]) {
-        if args.is_empty() { println!("Usage: mkdir <dirname>"); return; }
-        if !create_dir(&args[0]) {
-            println!("Failed to create directory");
+        let flags = Flags::parse(args);
+        let path = match flags.first() {
+            Some(p) => p,
+            None => { println!("Usage: mkdir [-p] <dirname>"); return; }
+        };
+        let ok = if flags.has('p') {
+            crate::fs::create_dir_all(path)
+        } else {
+            create_dir(path)
+        };
+        if !ok {
+            println!("mkdir: failed to create {}", path);
         }
     }
 }
@@ -108,16 +117,29 @@ impl Command for EditCommand {
 pub struct DeleteCommand;
 impl Command for DeleteCommand {
     fn name(&self) -> &'static str { "rm" }
-    fn description(&self) -> &'static str { "Delete a file or directory: rm <path>" }
+    fn description(&self) -> &'static str { "Delete a file or directory: rm [-r] [-f] <path>" }
     fn execute(&self, args: &[String]) {
         let flags = Flags::parse(args);
         let path = match flags.first() {
             Some(p) => p,
-            None => { println!("Usage: rm <path>"); return; }
+            None => { println!("Usage: rm [-r] [-f] <path>"); return; }
         };
-        if !crate::fs::delete_file(path) {
-            println!("Failed to delete {}", path);
+        let force = flags.has('f');
+        if force && !crate::fs::exists(path) {
+            return;
         }
+
+        if !flags.has('r') {
+            if crate::fs::is_dir(path) {
+                println!("rm: {} is a directory, use rm -r", path);
+            } else if !crate::fs::delete_file(path) {
+                println!("rm: failed to delete {}", path);
+            }
+            return;
+        }
+
+        let result = crate::fs::remove_recursive(path);
+        report_failures("rm", "remove", &result);
     }
 }
 
@@ -133,14 +155,29 @@ impl Command for CatCommand {
 pub struct CpCommand;
 impl Command for CpCommand {
     fn name(&self) -> &'static str { "cp" }
-    fn description(&self) -> &'static str { "Copy a file: cp <src> <dst>" }
+    fn description(&self) -> &'static str { "Copy a file: cp [-r] <src> <dst>" }
     fn execute(&self, args: &[String]) {
         let flags = Flags::parse(args);
-        if flags.args.len() < 2 { println!("Usage: cp <src> <dst>"); return; }
-        if crate::fs::copy_file(&flags.args[0], &flags.args[1]) {
-            println!("Copied {} -> {}", flags.args[0], flags.args[1]);
+        if flags.args.len() < 2 { println!("Usage: cp [-r] <src> <dst>"); return; }
+        let src = &flags.args[0];
+        let dst = target_path(src, &flags.args[1]);
+
+        if crate::fs::is_dir(src) {
+            if !flags.has('r') {
+                println!("cp: {} is a directory, use cp -r", src);
+                return;
+            }
+            let result = crate::fs::copy_recursive(src, &dst);
+            if report_failures("cp", "copy", &result) {
+                println!("Copied {} entries {} -> {}", result.ok, src, dst);
+            }
+            return;
+        }
+
+        if crate::fs::copy_file(src, &dst) {
+            println!("Copied {} -> {}", src, dst);
         } else {
-            println!("cp: failed to copy {} to {}", flags.args[0], flags.args[1]);
+            println!("cp: failed to copy {} to {}", src, dst);
         }
     }
 }
@@ -148,14 +185,16 @@ impl Command for CpCommand {
 pub struct MvCommand;
 impl Command for MvCommand {
     fn name(&self) -> &'static str { "mv" }
-    fn description(&self) -> &'static str { "Move or rename a file: mv <src> <dst>" }
+    fn description(&self) -> &'static str { "Move or rename a file or directory: mv <src> <dst>" }
     fn execute(&self, args: &[String]) {
         let flags = Flags::parse(args);
         if flags.args.len() < 2 { println!("Usage: mv <src> <dst>"); return; }
-        if crate::fs::move_file(&flags.args[0], &flags.args[1]) {
-            println!("Moved {} -> {}", flags.args[0], flags.args[1]);
+        let src = &flags.args[0];
+        let dst = target_path(src, &flags.args[1]);
+        if crate::fs::rename(src, &dst) {
+            println!("Moved {} -> {}", src, dst);
         } else {
-            println!("mv: failed to move {} to {}", flags.args[0], flags.args[1]);
+            println!("mv: failed to move {} to {}", src, dst);
         }
     }
 }
@@ -174,21 +213,570 @@ impl Command for CdCommand {
     fn name(&self) -> &'static str { "cd" }
     fn description(&self) -> &'static str { "Change directory: cd <path>" }
     fn execute(&self, args: &[String]) {
-        let path = args.first().map(|s| s.as_str()).unwrap_or("/");
+        let path = args.first().map(|s| s.as_str()).unwrap_or("~");
         if !crate::fs::set_current_dir(path) {
             println!("cd: {}: No such directory", path);
         }
     }
 }
 
+pub struct RealpathCommand;
+impl Command for RealpathCommand {
+    fn name(&self) -> &'static str { "realpath" }
+    fn description(&self) -> &'static str { "Print canonical paths: realpath <path>..." }
+    fn execute(&self, args: &[String]) {
+        if args.is_empty() { println!("Usage: realpath <path>..."); return; }
+        for path in args {
+            match crate::fs::path::canonicalize(path) {
+                Some(p) => println!("{}", p),
+                None => println!("realpath: {}: No such file or directory", path),
+            }
+        }
+    }
+}
+
 pub struct TouchCommand;
 impl Command for TouchCommand {
     fn name(&self) -> &'static str { "touch" }
-    fn description(&self) -> &'static str { "Create empty file: touch <filename>" }
+    fn description(&self) -> &'static str { "Create files or update their times: touch <path>..." }
+    fn execute(&self, args: &[String]) {
+        if args.is_empty() { println!("Usage: touch <path>..."); return; }
+        for path in args {
+            if !crate::fs::touch(path) {
+                println!("touch: failed to touch {}", path);
+            }
+        }
+    }
+}
+
+pub struct StatCommand;
+impl Command for StatCommand {
+    fn name(&self) -> &'static str { "stat" }
+    fn description(&self) -> &'static str { "Show file details: stat <path>..." }
+    fn execute(&self, args: &[String]) {
+        use crate::fs::fat::{ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM};
+
+        if args.is_empty() { println!("Usage: stat <path>..."); return; }
+        for path in args {
+            let st = match crate::fs::stat(path) {
+                Some(st) => st,
+                None => { println!("stat: {}: not found", path); continue; }
+            };
+            let names: alloc::vec::Vec<&str> = [
+                (ATTR_READ_ONLY, "readonly"),
+                (ATTR_HIDDEN, "hidden"),
+                (ATTR_SYSTEM, "system"),
+                (ATTR_DIRECTORY, "directory"),
+                (ATTR_ARCHIVE, "archive"),
+            ].iter().filter(|(bit, _)| st.attributes & bit != 0).map(|(_, name)| *name).collect();
+            let (ay, am, ad) = st.accessed;
+
+            println!("  File: {}", st.name);
+            if !st.short_name.is_empty() && st.short_name != st.name {
+                println!(" Short: {}", st.short_name);
+            }
+            println!("  Size: {:<12} {}", st.size, if st.is_dir { "directory" } else { "regular file" });
+            println!(" Attrs: {:#04x} ({})", st.attributes, names.join(", "));
+            println!("Cluster: {}", st.first_cluster);
+            println!("Access: {}-{:02}-{:02}", ay, am, ad);
+            println!("Modify: {}", format_stamp(st.modified));
+            println!("Create: {}", format_stamp(st.created));
+        }
+    }
+}
+
+fn format_stamp((year, month, day, hour, min, sec): (u16, u16, u16, u16, u16, u16)) -> String {
+    alloc::format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, min, sec)
+}
+
+pub struct MkfsCommand;
+impl Command for MkfsCommand {
+    fn name(&self) -> &'static str { "mkfs" }
+    fn description(&self) -> &'static str { "Format the disk: mkfs -y [--type=12|16|32] [--label=NAME] [--cluster=BYTES]" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        if !flags.has('y') && !flags.has_long("yes") {
+            println!("mkfs: this erases everything on the disk, run `mkfs -y` to confirm");
+            return;
+        }
+
+        let mut options = crate::fs::FormatOptions::default();
+        if let Some(t) = flags.value("type") {
+            options.fat_type = match t.trim_start_matches("fat") {
+                "12" => Some(fatfs::FatType::Fat12),
+                "16" => Some(fatfs::FatType::Fat16),
+                "32" => Some(fatfs::FatType::Fat32),
+                _ => { println!("mkfs: unknown FAT type {}", t); return; }
+            };
+        }
+        if let Some(label) = flags.value("label") {
+            if label.len() > 11 {
+                println!("mkfs: label can be at most 11 characters");
+                return;
+            }
+            options.label = Some(String::from(label));
+        }
+        if let Some(size) = flags.value("cluster") {
+            match size.parse::<u32>() {
+                Ok(n) if n.is_power_of_two() && n >= 512 => options.bytes_per_cluster = Some(n),
+                _ => { println!("mkfs: cluster size must be a power of two >= 512"); return; }
+            }
+        }
+
+        println!("Formatting...");
+        if crate::fs::format_disk(&options) {
+            println!("Disk formatted and mounted");
+        } else {
+            println!("mkfs: format failed");
+        }
+    }
+}
+
+pub struct FsckCommand;
+impl Command for FsckCommand {
+    fn name(&self) -> &'static str { "fsck" }
+    fn description(&self) -> &'static str { "Check the filesystem: fsck [-r]" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        let repair = flags.has('r');
+
+        println!("Checking filesystem{}...", if repair { " (repairing)" } else { "" });
+        let report = match crate::fs::check_disk(repair) {
+            Ok(r) => r,
+            Err(e) => { println!("fsck: {:?}", e); return; }
+        };
+
+        println!("{}: {} files, {} directories", report.kind.name(), report.files, report.dirs);
+        println!("{} of {} clusters used ({} bytes each)",
+                 report.used_clusters, report.total_clusters, report.cluster_bytes);
+        for link in &report.cross_links {
+            println!("cross-linked: {}", link);
+        }
+        for chain in &report.bad_chains {
+            println!("bad chain: {}", chain);
+        }
+        if report.lost_clusters > 0 {
+            println!("{} lost clusters", report.lost_clusters);
+        }
+
+        if report.is_clean() {
+            println!("Filesystem is clean");
+        } else if repair {
+            println!("Made {} repairs", report.repaired);
+        } else {
+            println!("Errors found, run `fsck -r` to repair");
+        }
+    }
+}
+
+pub struct LsblkCommand;
+impl Command for LsblkCommand {
+    fn name(&self) -> &'static str { "lsblk" }
+    fn description(&self) -> &'static str { "List the disk and its partitions" }
+    fn execute(&self, _args: &[String]) {
+        let capacity = crate::fs::disk_capacity();
+        if capacity == 0 {
+            println!("No disk");
+            return;
+        }
+        let table = crate::fs::partition_table();
+        let current = crate::fs::current_partition();
+
+        println!("{:<6} {:>10} {:>10} {:>7}  {}", "NAME", "START", "SECTORS", "SIZE", "TYPE");
+        let label = match &table {
+            Some(t) => alloc::format!("{:?}", t.kind),
+            None => String::from("bare volume"),
+        };
+        let mounted = if current.is_none() && crate::fs::is_mounted() { "  [mounted]" } else { "" };
+        println!("{:<6} {:>10} {:>10} {:>7}  {}{}", "disk", 0, capacity / 512, human_size(capacity), label, mounted);
+
+        if let Some(table) = table {
+            for part in &table.partitions {
+                let mounted = if current == Some(part.number) && crate::fs::is_mounted() { "  [mounted]" } else { "" };
+                let name = if part.name.is_empty() { String::new() } else { alloc::format!(" \"{}\"", part.name) };
+                println!("{:<6} {:>10} {:>10} {:>7}  {}{}{}{}",
+                         alloc::format!("p{}", part.number),
+                         part.start_lba, part.sectors, human_size(part.len_bytes()),
+                         part.type_name(), name,
+                         if part.bootable { " (boot)" } else { "" },
+                         mounted);
+            }
+        }
+
+        let mounts = crate::fs::loop_mounts();
+        for (unit, path, sectors) in crate::fs::loopdev::list() {
+            let mounted = match mounts.iter().find(|(_, u)| *u == unit) {
+                Some((dir, _)) => alloc::format!("  [mounted on {}]", dir),
+                None => String::new(),
+            };
+            println!("{:<6} {:>10} {:>10} {:>7}  {}{}",
+                     alloc::format!("loop{}", unit), 0, sectors, human_size(sectors * 512), path, mounted);
+        }
+    }
+}
+
+pub struct FdiskCommand;
+impl Command for FdiskCommand {
+    fn name(&self) -> &'static str { "fdisk" }
+    fn description(&self) -> &'static str { "Show partition table: fdisk -l" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        if !flags.has('l') {
+            println!("Usage: fdisk -l");
+            return;
+        }
+        LsblkCommand.execute(&[]);
+    }
+}
+
+pub struct MountCommand;
+impl Command for MountCommand {
+    fn name(&self) -> &'static str { "mount" }
+    fn description(&self) -> &'static str { "Mount a partition or image: mount [partition] | mount -o loop <image|loopN> <dir>" }
     fn execute(&self, args: &[String]) {
-        if args.is_empty() { println!("Usage: touch <filename>"); return; }
-        if !crate::fs::write_file(&args[0], b"") {
-            println!("touch: failed to create {}", args[0]);
+        let flags = Flags::parse(args);
+        if flags.has('o') {
+            mount_loop(&flags);
+            return;
+        }
+        let arg = match flags.first() {
+            Some(a) => a,
+            None => {
+                match (crate::fs::is_mounted(), crate::fs::current_partition()) {
+                    (false, _) => println!("Nothing mounted"),
+                    (true, Some(n)) => println!("Partition {} mounted on /", n),
+                    (true, None) => println!("Disk mounted on /"),
+                }
+                for (dir, unit) in crate::fs::loop_mounts() {
+                    println!("loop{} mounted on {}", unit, dir);
+                }
+                return;
+            }
+        };
+        let number = match arg.trim_start_matches('p').parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => { println!("Usage: mount [partition]"); return; }
+        };
+        if crate::fs::mount_partition(number) {
+            println!("Mounted partition {} on /", number);
+        } else {
+            println!("mount: failed to mount partition {}", number);
         }
     }
-}
\ No newline at end of file
+}
+
+/// `mount -o loop <image|loopN> <dir>`. An image attached here is detached again by umount.
+fn mount_loop(flags: &Flags) {
+    let (source, dir) = match (flags.get(0), flags.get(1), flags.get(2)) {
+        (Some("loop"), Some(source), Some(dir)) => (source, dir),
+        _ => { println!("Usage: mount -o loop <image|loopN> <dir>"); return; }
+    };
+    let (unit, attached) = match crate::fs::loopdev::parse_unit(source) {
+        Some(unit) => (unit, false),
+        None => match crate::fs::attach_loop(source, true) {
+            Ok(unit) => (unit, true),
+            Err(e) => { println!("mount: {}: {:?}", source, e); return; }
+        },
+    };
+    match crate::fs::mount_loop(unit, dir) {
+        Ok(()) => println!("Mounted loop{} on {}", unit, dir),
+        Err(e) => {
+            println!("mount: {}: {:?}", dir, e);
+            if attached {
+                let _ = crate::fs::detach_loop(unit);
+            }
+        }
+    }
+}
+
+pub struct UmountCommand;
+impl Command for UmountCommand {
+    fn name(&self) -> &'static str { "umount" }
+    fn description(&self) -> &'static str { "Unmount a loop mount: umount <dir>" }
+    fn execute(&self, args: &[String]) {
+        let dir = match args.first() {
+            Some(d) => d,
+            None => { println!("Usage: umount <dir>"); return; }
+        };
+        if let Err(e) = crate::fs::umount(dir) {
+            println!("umount: {}: {:?}", dir, e);
+        }
+    }
+}
+
+pub struct LosetupCommand;
+impl Command for LosetupCommand {
+    fn name(&self) -> &'static str { "losetup" }
+    fn description(&self) -> &'static str { "Loop devices: losetup [image] | losetup -d loopN" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        if flags.has('d') {
+            let unit = match flags.first().and_then(crate::fs::loopdev::parse_unit) {
+                Some(u) => u,
+                None => { println!("Usage: losetup -d loopN"); return; }
+            };
+            if let Err(e) = crate::fs::detach_loop(unit) {
+                println!("losetup: loop{}: {:?}", unit, e);
+            }
+            return;
+        }
+        match flags.first() {
+            Some(image) => match crate::fs::attach_loop(image, false) {
+                Ok(unit) => println!("loop{}", unit),
+                Err(e) => println!("losetup: {}: {:?}", image, e),
+            },
+            None => {
+                for (unit, path, sectors) in crate::fs::loopdev::list() {
+                    println!("loop{}: {} ({})", unit, path, human_size(sectors * 512));
+                }
+            }
+        }
+    }
+}
+
+pub struct TarCommand;
+impl Command for TarCommand {
+    fn name(&self) -> &'static str { "tar" }
+    fn description(&self) -> &'static str { "Archive files: tar -c|-x|-t [-vz] <archive> [paths... | dir]" }
+    fn execute(&self, args: &[String]) {
+        use crate::fs::tar;
+
+        let flags = Flags::parse(args);
+        let verbose = flags.has('v');
+        let archive = match flags.first() {
+            Some(a) => a,
+            None => { println!("Usage: tar -c|-x|-t [-vz] <archive> [paths... | dir]"); return; }
+        };
+
+        if flags.has('c') {
+            let paths: alloc::vec::Vec<&str> = flags.args[1..].iter().map(|s| s.as_str()).collect();
+            if paths.is_empty() {
+                println!("Usage: tar -c <archive> <paths...>");
+                return;
+            }
+            let (mut data, result) = tar::create(&paths);
+            if flags.has('z') || archive.ends_with(".gz") || archive.ends_with(".tgz") {
+                data = crate::compress::gzip(&data, crate::time::now() as u32);
+            }
+            if !write_file(archive, &data) {
+                println!("tar: failed to write {}", archive);
+                return;
+            }
+            report_failures("tar", "add", &result);
+            println!("Archived {} entries into {} ({})", result.ok, archive, human_size(data.len() as u64));
+            return;
+        }
+
+        let mut data = match read_file(archive) {
+            Some(d) => d,
+            None => { println!("tar: failed to read {}", archive); return; }
+        };
+        // Compressed archives are recognised by their magic, -z is optional here
+        if crate::compress::is_gzip(&data) {
+            data = match crate::compress::gunzip(&data) {
+                Ok(d) => d,
+                Err(e) => { println!("tar: {}: {:?}", archive, e); return; }
+            };
+        }
+
+        if flags.has('t') {
+            let entries = match tar::parse(&data) {
+                Ok(e) => e,
+                Err(e) => { println!("tar: {}: {:?}", archive, e); return; }
+            };
+            for entry in entries {
+                if verbose {
+                    let t = crate::time::from_unix(entry.mtime);
+                    println!("{:>8} {}-{:02}-{:02} {:02}:{:02}  {}",
+                             entry.size, t.year, t.month, t.day, t.hour, t.minute, entry.path);
+                } else {
+                    println!("{}", entry.path);
+                }
+            }
+        } else if flags.has('x') {
+            let dest = flags.get(1).unwrap_or(".");
+            match tar::extract(&data, dest) {
+                Ok(result) => {
+                    report_failures("tar", "extract", &result);
+                    println!("Extracted {} entries", result.ok);
+                }
+                Err(e) => println!("tar: {}: {:?}", archive, e),
+            }
+        } else {
+            println!("tar: one of -c, -x or -t is required");
+        }
+    }
+}
+
+pub struct GzipCommand;
+impl Command for GzipCommand {
+    fn name(&self) -> &'static str { "gzip" }
+    fn description(&self) -> &'static str { "Compress a file to <file>.gz: gzip [-d] [-k] <file>" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        let path = match flags.first() {
+            Some(p) => p,
+            None => { println!("Usage: gzip [-d] [-k] <file>"); return; }
+        };
+        if flags.has('d') {
+            gunzip_file(path, flags.has('k'));
+            return;
+        }
+
+        let data = match read_file(path) {
+            Some(d) => d,
+            None => { println!("gzip: failed to read {}", path); return; }
+        };
+        let target = alloc::format!("{}.gz", path);
+        let packed = crate::compress::gzip(&data, crate::time::now() as u32);
+        if !write_file(&target, &packed) {
+            println!("gzip: failed to write {}", target);
+            return;
+        }
+        if !flags.has('k') && !crate::fs::delete_file(path) {
+            println!("gzip: failed to remove {}", path);
+        }
+        let saved = 100 - (packed.len() as u64 * 100 / (data.len() as u64).max(1)).min(100);
+        println!("{}: {} -> {} ({}% saved)", target, human_size(data.len() as u64), human_size(packed.len() as u64), saved);
+    }
+}
+
+pub struct GunzipCommand;
+impl Command for GunzipCommand {
+    fn name(&self) -> &'static str { "gunzip" }
+    fn description(&self) -> &'static str { "Decompress a .gz file: gunzip [-k] <file.gz>" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        match flags.first() {
+            Some(path) => gunzip_file(path, flags.has('k')),
+            None => println!("Usage: gunzip [-k] <file.gz>"),
+        }
+    }
+}
+
+/// Shared by `gunzip` and `gzip -d`: unpack `path` next to itself
+fn gunzip_file(path: &str, keep: bool) {
+    let target = if path.ends_with(".gz") {
+        String::from(&path[..path.len() - 3])
+    } else if path.ends_with(".tgz") {
+        alloc::format!("{}.tar", &path[..path.len() - 4])
+    } else {
+        println!("gunzip: {} has no .gz suffix", path);
+        return;
+    };
+    let data = match read_file(path) {
+        Some(d) => d,
+        None => { println!("gunzip: failed to read {}", path); return; }
+    };
+    let unpacked = match crate::compress::gunzip(&data) {
+        Ok(d) => d,
+        Err(e) => { println!("gunzip: {}: {:?}", path, e); return; }
+    };
+    if !write_file(&target, &unpacked) {
+        println!("gunzip: failed to write {}", target);
+        return;
+    }
+    if !keep && !crate::fs::delete_file(path) {
+        println!("gunzip: failed to remove {}", path);
+    }
+    println!("{}: {} -> {}", target, human_size(data.len() as u64), human_size(unpacked.len() as u64));
+}
+
+pub struct SyncCommand;
+impl Command for SyncCommand {
+    fn name(&self) -> &'static str { "sync" }
+    fn description(&self) -> &'static str { "Write cached data to disk: sync [-s]" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        let (sectors, dirty, policy) = match crate::fs::cache_status() {
+            Some(s) => s,
+            None => { println!("No disk"); return; }
+        };
+        if flags.has('s') {
+            println!("Cache: {} sectors ({}), {}, {} dirty",
+                     sectors, human_size(sectors as u64 * 512), policy.name(), dirty);
+            return;
+        }
+        if crate::fs::sync() {
+            println!("Flushed {} sectors", dirty);
+        } else {
+            println!("sync: flush failed");
+        }
+    }
+}
+
+pub struct IostatCommand;
+impl Command for IostatCommand {
+    fn name(&self) -> &'static str { "iostat" }
+    fn description(&self) -> &'static str { "Show block I/O counters: iostat [-z]" }
+    fn execute(&self, args: &[String]) {
+        use crate::fs::cache::*;
+
+        let flags = Flags::parse(args);
+        let counters = [
+            &BLK_READS, &BLK_WRITES, &BLK_READ_SECTORS, &BLK_WRITE_SECTORS,
+            &BLK_TICKS, &R_CALLS, &R_CYCLES, &W_CALLS, &W_CYCLES,
+        ];
+        if flags.has('z') {
+            for counter in counters.iter() {
+                counter.store(0, Ordering::Relaxed);
+            }
+            println!("Counters reset");
+            return;
+        }
+
+        let reads = BLK_READS.load(Ordering::Relaxed);
+        let writes = BLK_WRITES.load(Ordering::Relaxed);
+        let read_sectors = BLK_READ_SECTORS.load(Ordering::Relaxed);
+        let write_sectors = BLK_WRITE_SECTORS.load(Ordering::Relaxed);
+        let r_calls = R_CALLS.load(Ordering::Relaxed);
+        let w_calls = W_CALLS.load(Ordering::Relaxed);
+
+        println!("Disk reads:  {} requests, {} sectors ({:.1} per request)",
+                 reads, read_sectors, read_sectors as f64 / reads.max(1) as f64);
+        println!("Disk writes: {} requests, {} sectors ({:.1} per request)",
+                 writes, write_sectors, write_sectors as f64 / writes.max(1) as f64);
+        println!("Time in disk I/O: {} ticks", BLK_TICKS.load(Ordering::Relaxed));
+        println!("read():  {} calls, {} cycles/call", r_calls, R_CYCLES.load(Ordering::Relaxed) / r_calls.max(1));
+        println!("write(): {} calls, {} cycles/call", w_calls, W_CYCLES.load(Ordering::Relaxed) / w_calls.max(1));
+        if let Some((sectors, dirty, policy)) = crate::fs::cache_status() {
+            println!("Cache: {} sectors, {} dirty, {}", sectors, dirty, policy.name());
+        }
+    }
+}
+
+/// Format a byte count as 512B / 1.5K / 20.0M / 1.2G
+pub(super) fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return alloc::format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    alloc::format!("{:.1}{}", value, UNITS[unit])
+}
+
+/// `cp a dir` and `mv a dir` put `a` inside `dir` when it already exists
+fn target_path(src: &str, dst: &str) -> String {
+    if crate::fs::is_dir(dst) {
+        crate::fs::join_path(dst, crate::fs::base_name(src))
+    } else {
+        String::from(dst)
+    }
+}
+
+/// Print every path a recursive operation failed on. Returns true if nothing failed.
+fn report_failures(cmd: &str, verb: &str, result: &crate::fs::TreeResult) -> bool {
+    if result.is_ok() {
+        return true;
+    }
+    for path in &result.failed {
+        println!("{}: failed to {} {}", cmd, verb, path);
+    }
+    println!("{}: {} of {} entries failed", cmd, result.failed.len(), result.ok + result.failed.len());
+    false
+}
@@ -63,6 +63,9 @@ impl Command for ExitCommand {
     fn name(&self) -> &'static str { "exit" }
     fn description(&self) -> &'static str { "Exit QEMU" }
     fn execute(&self, _args: &[String]) {
+        if !crate::fs::sync() {
+            println!("exit: failed to flush the disk cache");
+        }
         exit_qemu(QemuExitCode::Success);
     }
 }
@@ -4,6 +4,8 @@ mod fs;
 mod misc;
 mod net;
 mod prog;
+mod search;
+mod text;
 
 use alloc::string::String;
 use alloc::vec;
@@ -34,7 +36,31 @@ pub fn get_commands() -> Vec<&'static dyn Command> {
         &fs::MvCommand,
         &fs::PwdCommand,
         &fs::CdCommand,
+        &fs::RealpathCommand,
         &fs::TouchCommand,
+        &fs::StatCommand,
+        &fs::MkfsCommand,
+        &fs::FsckCommand,
+        &fs::LsblkCommand,
+        &fs::FdiskCommand,
+        &fs::MountCommand,
+        &fs::UmountCommand,
+        &fs::LosetupCommand,
+        &fs::TarCommand,
+        &fs::GzipCommand,
+        &fs::GunzipCommand,
+        &fs::SyncCommand,
+        &fs::IostatCommand,
+        &search::FindCommand,
+        &search::DuCommand,
+        &search::DfCommand,
+        &search::TreeCommand,
+        &text::GrepCommand,
+        &text::HeadCommand,
+        &text::TailCommand,
+        &text::WcCommand,
+        &text::SortCommand,
+        &text::UniqCommand,
         &misc::ClearCommand,
         &misc::RandCommand,
         &misc::TimeCommand,
@@ -43,8 +69,14 @@ pub fn get_commands() -> Vec<&'static dyn Command> {
         &misc::ExitCommand,
         &misc::EchoCommand,
         &net::NetCommand,
+        &net::IfconfigCommand,
         &net::PingCommand,
+        &net::DnsCommand,
+        &net::DigCommand,
+        &net::NtpdateCommand,
         &net::FetchCommand,
+        &net::HttpdCommand,
+        &net::TelnetdCommand,
         &prog::RunCommand,
     ]
 }
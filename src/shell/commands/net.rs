@@ -1,4 +1,5 @@
 use alloc::string::String;
+use alloc::vec::Vec;
 use smoltcp::wire::Ipv4Address;
 use crate::{print, println};
 use crate::shell::commands::Command;
@@ -6,7 +7,7 @@ use crate::shell::commands::Command;
 pub struct NetCommand;
 impl Command for NetCommand {
     fn name(&self) -> &'static str { "net" }
-    fn description(&self) -> &'static str { "Network info: net <status|mac|ip>" }
+    fn description(&self) -> &'static str { "Network info and setup: net <status|mac|ip|set|dhcp>" }
     fn execute(&self, args: &[String]) {
         let flags = crate::shell::flags::Flags::parse(args);
         let subcmd = flags.get(0).unwrap_or("status");
@@ -31,10 +32,15 @@ impl Command for NetCommand {
                         println!("MAC:   {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
                                  e.mac[0], e.mac[1], e.mac[2],
                                  e.mac[3], e.mac[4], e.mac[5]);
+                        println!("Mode:  {}", if stack.dhcp_enabled() { "dhcp" } else { "static" });
                         match stack.ip {
                             Some(ip) => println!("IP:    {}/{}", ip, stack.prefix_len),
                             None => println!("IP:    not configured"),
                         }
+                        if let Some(gw) = stack.gateway {
+                            println!("GW:    {}", gw);
+                        }
+                        println!("DNS:   {}", join_addrs(&stack.name_servers()));
                     }
                     None => println!("network not initialized"),
                 }
@@ -54,9 +60,156 @@ impl Command for NetCommand {
                     None => println!("not configured"),
                 }
             }
+            "set" => set_network(&flags.args[1..]),
+            "dhcp" => dhcp_command(flags.get(1).unwrap_or("status")),
+
+            _ => println!("Usage: net <status|mac|ip|set|dhcp>"),
+        }
+    }
+}
+
+const NET_SET_USAGE: &str = "Usage: net set dhcp | net set static <ip>[/prefix] [gateway] [dns,...]";
+
+fn join_addrs(addrs: &[Ipv4Address]) -> String {
+    addrs.iter().map(|ip| alloc::format!("{}", ip)).collect::<Vec<_>>().join(", ")
+}
+
+/// Switch the interface to DHCP or a static address now, and save the
+/// choice to system.ini for the next boot
+fn set_network(args: &[String]) {
+    use crate::net::NetMode;
+
+    let mut config = crate::CONFIG.lock().net.clone();
+    match args.first().map(String::as_str) {
+        Some("dhcp") if args.len() == 1 => config.mode = NetMode::Dhcp,
+        Some("static") if (2..=4).contains(&args.len()) => {
+            let (address, prefix) = match args[1].split_once('/') {
+                Some((address, prefix)) => (address, prefix),
+                None => (args[1].as_str(), "24"),
+            };
+            config.mode = NetMode::Static;
+            let valid = config.set("address", address)
+                && config.set("prefix", prefix)
+                && config.set("gateway", args.get(2).map_or("", String::as_str))
+                && args.get(3).map_or(true, |dns| config.set("dns", dns));
+            if !valid || config.address.is_none() {
+                println!("net set: bad address in: {}", args[1..].join(" "));
+                return;
+            }
+        }
+        _ => { println!("{}", NET_SET_USAGE); return; }
+    }
+
+    if let Err(e) = crate::net::configure(&config) {
+        println!("net set: {:?}", e);
+        return;
+    }
+    let saved = {
+        let mut cfg = crate::CONFIG.lock();
+        cfg.net = config.clone();
+        cfg.save()
+    };
+    match config.mode {
+        NetMode::Dhcp => println!("DHCP started, `net status` shows the lease once it arrives"),
+        NetMode::Static => println!("IP {}/{}", config.address.unwrap(), config.prefix),
+    }
+    if !saved {
+        println!("Failed to save config");
+    }
+}
+
+/// `net dhcp status|release|renew`
+fn dhcp_command(subcmd: &str) {
+    use crate::net::dhcp;
+
+    let dhcp_enabled = crate::net::NET.lock().as_ref().map(|stack| stack.dhcp_enabled());
+    match dhcp_enabled {
+        None => { println!("network not initialized"); return; }
+        Some(false) => { println!("net dhcp: the address is static (net set dhcp to switch)"); return; }
+        Some(true) => {}
+    }
+    match subcmd {
+        "status" => {
+            let guard = crate::net::NET.lock();
+            let lease = match guard.as_ref().and_then(|stack| stack.lease.as_ref()) {
+                Some(lease) => lease,
+                None => { println!("No lease (net dhcp renew to ask for one)"); return; }
+            };
+            println!("Address: {}/{}", lease.address, lease.prefix_len);
+            if let Some(router) = lease.router {
+                println!("Router:  {}", router);
+            }
+            println!("Server:  {}", lease.server);
+            println!("DNS:     {}", join_addrs(&lease.dns_servers));
+            let age = ((crate::net::now_ms() - lease.obtained_ms) / 1000) as u32;
+            match lease.timers() {
+                Some((renew, rebind, expiry)) => {
+                    println!("Lease:   {}s, granted {}s ago", expiry, age);
+                    // Past T1 smoltcp is already renewing in the background
+                    for (what, at) in [("Renew:", renew), ("Rebind:", rebind), ("Expiry:", expiry)] {
+                        match at.checked_sub(age) {
+                            Some(secs) => println!("{:<8} in {}s", what, secs),
+                            None => println!("{:<8} due", what),
+                        }
+                    }
+                }
+                None => println!("Lease:   infinite"),
+            }
+        }
+        "release" => match crate::net::block_on(dhcp::release()) {
+            Some(Ok(ip)) => println!("Released {} (net dhcp renew to get a new lease)", ip),
+            Some(Err(crate::net::NetError::Unaddressable)) => println!("net dhcp: no lease to release"),
+            Some(Err(e)) => println!("net dhcp: release failed: {:?}", e),
+            None => {}
+        },
+        "renew" => match dhcp::renew() {
+            Ok(()) => {
+                if crate::net::wait_for_dhcp() {
+                    dhcp_command("status");
+                } else {
+                    println!("No answer yet, the client keeps trying in the background");
+                }
+            }
+            Err(e) => println!("net dhcp: {:?}", e),
+        },
+        _ => println!("Usage: net dhcp <status|release|renew>"),
+    }
+}
 
-            _ => println!("Usage: net <status|mac|ip>"),
+pub struct IfconfigCommand;
+impl Command for IfconfigCommand {
+    fn name(&self) -> &'static str { "ifconfig" }
+    fn description(&self) -> &'static str {
+        "Show or set the interface: ifconfig [dhcp | <ip>[/prefix] [gateway] [dns,...]]"
+    }
+    fn execute(&self, args: &[String]) {
+        if args.is_empty() {
+            let guard = crate::net::NET.lock();
+            let stack = match guard.as_ref() {
+                Some(stack) => stack,
+                None => { println!("network not initialized"); return; }
+            };
+            let mac = crate::device::e1000::E1000_DEV.lock().as_ref().map_or([0; 6], |e| e.mac);
+            println!("eth0: {} ether {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                     if stack.dhcp_enabled() { "dhcp" } else { "static" },
+                     mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]);
+            match stack.ip {
+                Some(ip) => println!("      inet {}/{}", ip, stack.prefix_len),
+                None => println!("      inet not configured"),
+            }
+            if let Some(gw) = stack.gateway {
+                println!("      gateway {}", gw);
+            }
+            println!("      dns {}", join_addrs(&stack.name_servers()));
+            return;
+        }
+        // Like `net set`, with "static" implied by an address
+        let mut set_args = Vec::new();
+        if args[0] != "dhcp" {
+            set_args.push(String::from("static"));
         }
+        set_args.extend_from_slice(args);
+        set_network(&set_args);
     }
 }
 
@@ -80,81 +233,381 @@ impl Command for PingCommand {
         };
 
         println!("Pinging {}...", target);
-        let result = crate::net::NET.lock().as_mut().and_then(|s| s.ping(target));
-        match result {
-            Some(rtt) => println!("Reply from {}: time={}ms", target, rtt),
-            None => println!("Request timed out"),
+        match crate::net::block_on(crate::net::ping(target)) {
+            Some(Ok(rtt)) => println!("Reply from {}: time={}ms", target, rtt),
+            Some(Err(crate::net::NetError::TimedOut)) => println!("Request timed out"),
+            Some(Err(e)) => println!("ping: {:?}", e),
+            None => {}
         }
     }
 }
 
-pub struct FetchCommand;
-impl Command for FetchCommand {
-    fn name(&self) -> &'static str { "fetch" }
-    fn description(&self) -> &'static str { "HTTP GET: fetch <url>" }
+pub struct DnsCommand;
+impl Command for DnsCommand {
+    fn name(&self) -> &'static str { "dns" }
+    fn description(&self) -> &'static str { "Name lookups: dns <host> | dns show | dns flush" }
     fn execute(&self, args: &[String]) {
-        if args.is_empty() { println!("Usage: fetch <url> [-o <file>]"); return; }
-
-        let mut out_file: Option<&str> = None;
-        if let Some(pos) = args.iter().position(|a| a == "-o") {
-            match args.get(pos + 1) {
-                Some(f) => out_file = Some(f),
-                None => { println!("Usage: fetch <url> -o <file>"); return; }
+        use crate::net::dns;
+        match args.first().map(String::as_str) {
+            None | Some("show") => {
+                let entries = dns::cache_entries();
+                if entries.is_empty() {
+                    println!("DNS cache is empty");
+                    return;
+                }
+                for (name, entry, remaining) in entries {
+                    let addrs = if entry.addrs.is_empty() { String::from("no address") } else { join_addrs(&entry.addrs) };
+                    match entry.canonical {
+                        Some(canonical) => println!("{:<32} {:>6}s  {} (alias for {})", name, remaining, addrs, canonical),
+                        None => println!("{:<32} {:>6}s  {}", name, remaining, addrs),
+                    }
+                }
             }
+            Some("flush") => println!("Flushed {} entries", dns::flush_cache()),
+            Some(host) => match crate::net::resolve(host) {
+                Some(ip) => println!("{} has address {}", host, ip),
+                None => println!("{}: not found", host),
+            },
         }
+    }
+}
 
-        if args[0].starts_with("https://") {
-            println!("HTTPS not supported yet, try http://");
-            return;
-        }
+pub struct DigCommand;
+impl Command for DigCommand {
+    fn name(&self) -> &'static str { "dig" }
+    fn description(&self) -> &'static str { "Query name servers, bypassing the cache: dig [@server] <name> [type]" }
+    fn execute(&self, args: &[String]) {
+        use crate::net::dns::{self, TYPE_A};
+        const USAGE: &str = "Usage: dig [@server] <name> [A|AAAA|CNAME|MX|NS|PTR|SOA|TXT|<number>]";
 
-        let url = args[0].trim_start_matches("http://");
+        let mut server = None;
+        let mut name = None;
+        let mut qtype = TYPE_A;
+        for arg in args {
+            if let Some(s) = arg.strip_prefix('@') {
+                match s.parse::<Ipv4Address>() {
+                    Ok(ip) => server = Some(ip),
+                    Err(_) => { println!("dig: bad server address: {}", s); return; }
+                }
+            } else if name.is_none() {
+                name = Some(arg.as_str());
+            } else {
+                match dns::parse_type(arg) {
+                    Some(t) => qtype = t,
+                    None => { println!("dig: unknown type: {}", arg); return; }
+                }
+            }
+        }
+        let name = match name {
+            Some(n) => n,
+            None => { println!("{}", USAGE); return; }
+        };
 
-        // Split host:port from path
-        let (hostport, path) = match url.find('/') {
-            Some(pos) => (&url[..pos], &url[pos..]),
-            None => (url, "/"),
+        let start = crate::net::now_ms();
+        let result = crate::net::block_on(async {
+            match server {
+                Some(server) => dns::query(name, qtype, server).await.map(|msg| (server, msg)),
+                None => dns::query_servers(name, qtype).await,
+            }
+        });
+        let (server, msg) = match result {
+            Some(Ok(reply)) => reply,
+            Some(Err(e)) => { println!("dig: {:?}", e); return; }
+            None => return,
         };
+        println!(";; {} from {} in {} ms{}", dns::rcode_name(msg.rcode), server, crate::net::now_ms() - start,
+                 if msg.truncated { ", truncated" } else { "" });
+        for (title, records) in [("ANSWER", &msg.answers), ("AUTHORITY", &msg.authority)] {
+            if records.is_empty() {
+                continue;
+            }
+            println!(";; {}", title);
+            for record in records {
+                let rtype = match dns::type_name(record.rtype) {
+                    Some(t) => String::from(t),
+                    None => alloc::format!("TYPE{}", record.rtype),
+                };
+                println!("{}.\t{}\t{}\t{}", record.name, record.ttl, rtype, record.data);
+            }
+        }
+    }
+}
 
-        // Split host and port
-        let (host, port) = match hostport.rfind(':') {
-            Some(pos) => (&hostport[..pos], hostport[pos+1..].parse::<u16>().unwrap_or(80)),
-            None => (hostport, 80u16),
+pub struct NtpdateCommand;
+impl Command for NtpdateCommand {
+    fn name(&self) -> &'static str { "ntpdate" }
+    fn description(&self) -> &'static str { "Set the clock over SNTP: ntpdate [-q] [server]" }
+    fn execute(&self, args: &[String]) {
+        use crate::net::sntp;
+        let query_only = args.iter().any(|a| a == "-q");
+        let server = match args.iter().find(|a| !a.starts_with('-')) {
+            Some(s) => s.clone(),
+            None => crate::CONFIG.lock().ntp_server.clone(),
         };
 
-        // Resolve host
-        let ip = match host.parse::<smoltcp::wire::Ipv4Address>() {
-            Ok(ip) => ip,
-            Err(_) => {
-                print!("Resolving {}... ", host);
-                match crate::net::resolve(host) {
-                    Some(ip) => { println!("{}", ip); ip }
-                    None => { println!("failed"); return; }
+        let result = crate::net::block_on(async {
+            if query_only {
+                match crate::net::lookup(&server).await {
+                    Some(ip) => sntp::query(ip).await,
+                    None => Err(sntp::SntpError::Dns),
                 }
+            } else {
+                sntp::sync(&server).await
             }
+        });
+        match result {
+            Some(Ok(sample)) => {
+                println!("{} ({}): offset {:+} ms, delay {} ms, stratum {}",
+                         server, sample.server, sample.offset_ms, sample.delay_ms, sample.stratum);
+                if !query_only {
+                    println!("Clock set to {}", crate::time::get_time());
+                }
+            }
+            Some(Err(sntp::SntpError::KissOfDeath(code))) => {
+                println!("ntpdate: {} refused the request ({})", server, String::from_utf8_lossy(&code));
+            }
+            Some(Err(e)) => println!("ntpdate: {}: {:?}", server, e),
+            None => {}
+        }
+    }
+}
+
+pub struct FetchCommand;
+impl Command for FetchCommand {
+    fn name(&self) -> &'static str { "fetch" }
+    fn description(&self) -> &'static str {
+        "HTTP(S) request: fetch [-X method] [-d data|@file] [-H Name:value]... [-i] [-k] [-o file] <url>"
+    }
+    fn execute(&self, args: &[String]) {
+        use crate::net::http::{self, Client, FileSink, MemorySink, Request, Url};
+        const USAGE: &str = "Usage: fetch [-X method] [-d data|@file] [-H Name:value]... [-i] [-k] [-o file] <url>";
+
+        let mut method = None;
+        let mut body: Option<Vec<u8>> = None;
+        let mut headers = Vec::new();
+        let mut out_file = None;
+        let mut include_headers = false;
+        let mut insecure = false;
+        let mut url = None;
+        let mut i = 0;
+        while i < args.len() {
+            let arg = args[i].as_str();
+            if arg == "-i" {
+                include_headers = true;
+                i += 1;
+                continue;
+            }
+            if arg == "-k" {
+                insecure = true;
+                i += 1;
+                continue;
+            }
+            if !arg.starts_with('-') {
+                url = Some(arg);
+                i += 1;
+                continue;
+            }
+            let value = match args.get(i + 1) {
+                Some(v) => v.as_str(),
+                None => { println!("{}", USAGE); return; }
+            };
+            match arg {
+                "-X" => method = Some(value),
+                "-o" => out_file = Some(value),
+                "-d" => match value.strip_prefix('@') {
+                    Some(path) => match crate::fs::read_file(path) {
+                        Some(data) => body = Some(data),
+                        None => { println!("fetch: {}: No such file", path); return; }
+                    },
+                    None => body = Some(Vec::from(value.as_bytes())),
+                },
+                "-H" => match value.split_once(':') {
+                    Some((name, v)) if !name.trim().is_empty() => {
+                        headers.push((String::from(name.trim()), String::from(v.trim())))
+                    }
+                    _ => { println!("fetch: bad header, expected Name:value: {}", value); return; }
+                },
+                _ => { println!("fetch: unknown option: {}", arg); return; }
+            }
+            i += 2;
+        }
+        let url = match url {
+            Some(u) => u,
+            None => { println!("{}", USAGE); return; }
+        };
+        let url = match Url::parse(url) {
+            Ok(u) => u,
+            Err(e) => { println!("fetch: bad URL: {:?}", e); return; }
         };
 
-        println!("Connecting to {}:{}...", ip, port);
+        let method = method.unwrap_or(if body.is_some() { "POST" } else { "GET" });
+        let mut request = Request::new(method, url);
+        if let Some(body) = body {
+            if !headers.iter().any(|(n, _): &(String, String)| n.eq_ignore_ascii_case("content-type")) {
+                headers.push((String::from("Content-Type"), String::from("application/x-www-form-urlencoded")));
+            }
+            request.body = body;
+        }
+        request.headers = headers;
+        // Compressed bodies can only be decoded whole, so files are fetched
+        // as-is, and decoded afterwards if the server compresses them anyway
+        request.accept_compressed = out_file.is_none();
 
-        match crate::net::http_get(host, path, ip, port) {
-            Some(response) => {
-                let body = match response.windows(4).position(|w| w == b"\r\n\r\n") {
-                    Some(pos) => &response[pos + 4..],
-                    None => &response[..],
+        println!("{} {}", request.method, request.url);
+        let mut client = Client::new();
+        client.insecure = insecure;
+        match out_file {
+            Some(filename) => {
+                let mut sink = match FileSink::create(filename) {
+                    Ok(sink) => sink,
+                    Err(_) => { println!("Failed to write {}", filename); return; }
                 };
-
-                match out_file {
-                    Some(filename) => {
-                        if crate::fs::write_file(filename, body) {
-                            println!("Saved {} bytes to {}", body.len(), filename);
-                        } else {
-                            println!("Failed to write {}", filename);
+                let result = crate::net::block_on(client.send(request, &mut sink));
+                let flushed = sink.finish();
+                match result {
+                    Some(Ok(response)) => {
+                        if include_headers {
+                            print_head(&response);
+                        }
+                        if flushed.is_err() {
+                            println!("fetch: failed writing {}", filename);
+                            return;
                         }
+                        match http::decode_file(&response, filename, sink.written) {
+                            Ok(None) => println!("{} {}: saved {} bytes to {}", response.status, response.reason, sink.written, filename),
+                            Ok(Some(size)) => println!("{} {}: saved {} bytes ({} compressed) to {}",
+                                response.status, response.reason, size, sink.written, filename),
+                            Err(e) => println!("fetch: {} is saved still encoded as {}: {:?}",
+                                filename, response.header("content-encoding").unwrap_or("?"), e),
+                        }
+                    }
+                    Some(Err(e)) => {
+                        println!("fetch failed: {:?} ({} bytes saved to {})", e, sink.written, filename);
+                        print_tls_hint(e);
                     }
-                    None => println!("{}", String::from_utf8_lossy(body)),
+                    None => {}
+                }
+            }
+            None => {
+                let mut sink = MemorySink::default();
+                let response = match crate::net::block_on(client.send(request, &mut sink)) {
+                    Some(Ok(response)) => response,
+                    Some(Err(e)) => {
+                        println!("fetch failed: {:?}", e);
+                        print_tls_hint(e);
+                        return;
+                    }
+                    None => return,
+                };
+                if include_headers {
+                    print_head(&response);
+                }
+                match http::decode_body(&response, sink.data) {
+                    Ok(body) => println!("{}", String::from_utf8_lossy(&body)),
+                    Err(e) => println!("fetch: failed to decode response: {:?}", e),
                 }
             }
-            None => println!("fetch failed"),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Explain TLS failures the user can do something about
+fn print_tls_hint(e: crate::net::http::HttpError) {
+    use crate::net::tls::{self, TlsError};
+    match e {
+        crate::net::http::HttpError::Tls(TlsError::Alert(code)) => println!("The server sent alert {}", tls::alert_name(code)),
+        crate::net::http::HttpError::Tls(TlsError::UntrustedCertificate | TlsError::NameMismatch | TlsError::CertificateExpired) => {
+            println!("Add its CA to {}, pin its certificate in {} (the fingerprint is in the serial log), or use -k", tls::CA_BUNDLE_FILE, tls::PINS_FILE);
+        }
+        _ => {}
+    }
+}
+
+fn print_head(response: &crate::net::http::Response) {
+    println!("HTTP/1.{} {} {}", response.version, response.status, response.reason);
+    for (name, value) in &response.headers {
+        println!("{}: {}", name, value);
+    }
+    println!();
+}
+
+pub struct HttpdCommand;
+impl Command for HttpdCommand {
+    fn name(&self) -> &'static str { "httpd" }
+    fn description(&self) -> &'static str { "Serve files over HTTP in the background: httpd [port] [root] | httpd stop" }
+    fn execute(&self, args: &[String]) {
+        use crate::net::httpd;
+
+        if args.first().map(|a| a.as_str()) == Some("stop") {
+            if httpd::stop() {
+                println!("httpd: stopping");
+            } else {
+                println!("httpd: not running");
+            }
+            return;
+        }
+        if let Some((port, root)) = httpd::running() {
+            println!("httpd: already serving {} on port {} (httpd stop to end it)", root, port);
+            return;
+        }
+
+        let port = match args.first() {
+            Some(p) => match p.parse::<u16>() {
+                Ok(port) if port > 0 => port,
+                _ => { println!("httpd: invalid port: {}", p); return; }
+            },
+            None => 80,
+        };
+        let root = args.get(1).map_or(".", |r| r.as_str());
+        let root = match crate::fs::path::canonicalize(root) {
+            Some(root) if crate::fs::is_dir(&root) => root,
+            _ => { println!("httpd: {}: No such directory", root); return; }
+        };
+        match httpd::start(port, root.clone()) {
+            Ok(()) => match crate::net::get_ip() {
+                Some(ip) => println!("Serving {} at http://{}:{}/", root, ip, port),
+                None => println!("Serving {} on port {} (no address yet)", root, port),
+            },
+            Err(crate::net::NetError::AddrInUse) => println!("httpd: port {} is in use", port),
+            Err(e) => println!("httpd: {:?}", e),
+        }
+    }
+}
+
+pub struct TelnetdCommand;
+impl Command for TelnetdCommand {
+    fn name(&self) -> &'static str { "telnetd" }
+    fn description(&self) -> &'static str { "Accept remote shell sessions over TCP: telnetd [port] | telnetd stop" }
+    fn execute(&self, args: &[String]) {
+        use crate::net::telnetd;
+
+        if args.first().map(|a| a.as_str()) == Some("stop") {
+            if telnetd::stop() {
+                println!("telnetd: no longer accepting sessions");
+            } else {
+                println!("telnetd: not running");
+            }
+            return;
+        }
+        if let Some((port, sessions)) = telnetd::running() {
+            println!("telnetd: listening on port {}, {} session(s) open (telnetd stop to end it)", port, sessions);
+            return;
+        }
+
+        let port = match args.first() {
+            Some(p) => match p.parse::<u16>() {
+                Ok(port) if port > 0 => port,
+                _ => { println!("telnetd: invalid port: {}", p); return; }
+            },
+            None => 23,
+        };
+        match telnetd::start(port) {
+            Ok(()) => {
+                println!("telnetd: listening on port {}", port);
+                println!("telnetd: there is no login, so only forward this port from a trusted host");
+            }
+            Err(crate::net::NetError::AddrInUse) => println!("telnetd: port {} is in use", port),
+            Err(e) => println!("telnetd: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! Finding files and measuring space: find, du, df and tree.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::{print, println, reset_color};
+use crate::fs::{self, DirEntry};
+use crate::shell::flags::Flags;
+use crate::util::glob;
+use super::Command;
+use super::fs::human_size;
+
+/// Entries of `path` without "." and ".."
+fn children(path: &str) -> Vec<DirEntry> {
+    fs::list_dir(path).into_iter().filter(|e| e.name != "." && e.name != "..").collect()
+}
+
+/// `-size [+|-]N[c|k|M|G]`, in 512-byte blocks without a suffix. A file's size
+/// is rounded up to whole units before comparing, as find does.
+struct SizeTest {
+    cmp: char,
+    count: u64,
+    unit: u64,
+}
+
+impl SizeTest {
+    fn parse(arg: &str) -> Option<Self> {
+        let (cmp, rest) = match arg.chars().next()? {
+            c @ ('+' | '-') => (c, &arg[1..]),
+            _ => ('=', arg),
+        };
+        let (digits, unit) = match rest.chars().last()? {
+            'c' => (&rest[..rest.len() - 1], 1),
+            'k' => (&rest[..rest.len() - 1], 1024),
+            'M' => (&rest[..rest.len() - 1], 1024 * 1024),
+            'G' => (&rest[..rest.len() - 1], 1024 * 1024 * 1024),
+            'b' => (&rest[..rest.len() - 1], 512),
+            _ => (rest, 512),
+        };
+        Some(SizeTest { cmp, count: digits.parse().ok()?, unit })
+    }
+
+    fn matches(&self, size: u64) -> bool {
+        let units = (size + self.unit - 1) / self.unit;
+        match self.cmp {
+            '+' => units > self.count,
+            '-' => units < self.count,
+            _ => units == self.count,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FindFilter {
+    name: Option<String>,
+    kind: Option<char>,
+    size: Option<SizeTest>,
+}
+
+impl FindFilter {
+    fn matches(&self, name: &str, is_dir: bool, size: u64) -> bool {
+        if let Some(pattern) = &self.name {
+            if !glob::matches(pattern, name, !fs::is_case_sensitive()) {
+                return false;
+            }
+        }
+        match self.kind {
+            Some('f') if is_dir => return false,
+            Some('d') if !is_dir => return false,
+            _ => {}
+        }
+        self.size.as_ref().map_or(true, |test| !is_dir && test.matches(size))
+    }
+}
+
+fn find_walk(dir: &str, filter: &FindFilter) {
+    for entry in children(dir) {
+        let path = fs::join_path(dir, &entry.name);
+        if filter.matches(&entry.name, entry.is_dir, entry.size) {
+            println!("{}", path);
+        }
+        if entry.is_dir {
+            find_walk(&path, filter);
+        }
+    }
+}
+
+pub struct FindCommand;
+impl Command for FindCommand {
+    fn name(&self) -> &'static str { "find" }
+    fn description(&self) -> &'static str { "Search for files: find [dir] [-name glob] [-type f|d] [-size [+-]N[ckMG]]" }
+    fn execute(&self, args: &[String]) {
+        let mut dir = ".";
+        let mut filter = FindFilter::default();
+        let mut i = 0;
+        if let Some(first) = args.first().filter(|a| !a.starts_with('-')) {
+            dir = first.as_str();
+            i = 1;
+        }
+        while i < args.len() {
+            let value = match args.get(i + 1) {
+                Some(v) => v.as_str(),
+                None => { println!("find: {} needs a value", args[i]); return; }
+            };
+            match args[i].as_str() {
+                "-name" => filter.name = Some(String::from(value)),
+                "-type" => match value {
+                    "f" | "d" => filter.kind = value.chars().next(),
+                    _ => { println!("find: -type takes f or d"); return; }
+                },
+                "-size" => match SizeTest::parse(value) {
+                    Some(test) => filter.size = Some(test),
+                    None => { println!("find: invalid size: {}", value); return; }
+                },
+                other => { println!("find: unknown option: {}", other); return; }
+            }
+            i += 2;
+        }
+
+        if !fs::is_dir(dir) {
+            println!("find: {}: No such directory", dir);
+            return;
+        }
+        if filter.matches(fs::base_name(dir), true, 0) {
+            println!("{}", dir);
+        }
+        find_walk(dir, &filter);
+    }
+}
+
+/// Bytes `size` takes up once rounded to whole allocation units
+fn allocated(size: u64, block: u64) -> u64 {
+    (size + block - 1) / block * block
+}
+
+/// Total of everything below `dir`, printing each directory unless `summary`
+fn du_walk(dir: &str, block: u64, all: bool, summary: bool, human: bool) -> u64 {
+    let mut total = 0;
+    for entry in children(dir) {
+        let path = fs::join_path(dir, &entry.name);
+        if entry.is_dir {
+            let size = du_walk(&path, block, all, summary, human);
+            if !summary {
+                print_usage(size, &path, human);
+            }
+            total += size;
+        } else {
+            let size = allocated(entry.size, block);
+            if all && !summary {
+                print_usage(size, &path, human);
+            }
+            total += size;
+        }
+    }
+    total
+}
+
+fn print_usage(bytes: u64, path: &str, human: bool) {
+    if human {
+        println!("{:<8} {}", human_size(bytes), path);
+    } else {
+        println!("{:<8} {}", (bytes + 1023) / 1024, path);
+    }
+}
+
+pub struct DuCommand;
+impl Command for DuCommand {
+    fn name(&self) -> &'static str { "du" }
+    fn description(&self) -> &'static str { "Show disk usage in KiB: du [-h] [-s] [-a] [path...]" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        let human = flags.has('h');
+        let summary = flags.has('s');
+        let all = flags.has('a');
+        let paths: Vec<&str> = if flags.args.is_empty() {
+            alloc::vec!["."]
+        } else {
+            flags.args.iter().map(|s| s.as_str()).collect()
+        };
+
+        for path in paths {
+            let block = match fs::usage_of(path) {
+                Some(u) => u.block_size.max(1),
+                None => { println!("du: no filesystem mounted"); return; }
+            };
+            if fs::is_dir(path) {
+                let total = du_walk(path, block, all, summary, human);
+                print_usage(total, path, human);
+            } else {
+                match fs::stat(path) {
+                    Some(st) => print_usage(allocated(st.size, block), path, human),
+                    None => println!("du: {}: No such file or directory", path),
+                }
+            }
+        }
+    }
+}
+
+pub struct DfCommand;
+impl Command for DfCommand {
+    fn name(&self) -> &'static str { "df" }
+    fn description(&self) -> &'static str { "Show free space on mounted filesystems: df [-h]" }
+    fn execute(&self, args: &[String]) {
+        let human = Flags::parse(args).has('h');
+        let list = fs::usage();
+        if list.is_empty() {
+            println!("df: no filesystem mounted");
+            return;
+        }
+
+        let size_header = if human { "Size" } else { "1K-blocks" };
+        println!("{:<10} {:<6} {:>10} {:>10} {:>10} {:>5}  {}",
+                 "Filesystem", "Type", size_header, "Used", "Avail", "Use%", "Mounted on");
+        for u in list {
+            let total = u.total_blocks * u.block_size;
+            let free = u.free_blocks * u.block_size;
+            let used = total.saturating_sub(free);
+            let percent = if total == 0 { 0 } else { (used * 100 + total - 1) / total };
+            let show = |bytes: u64| if human { human_size(bytes) } else { alloc::format!("{}", bytes / 1024) };
+            println!("{:<10} {:<6} {:>10} {:>10} {:>10} {:>4}%  {}",
+                     u.device, u.kind, show(total), show(used), show(free), percent, u.mount);
+        }
+    }
+}
+
+fn tree_walk(dir: &str, prefix: &str, depth: usize, opts: &TreeOptions, counts: &mut (usize, usize)) {
+    if opts.max_depth.map_or(false, |max| depth > max) {
+        return;
+    }
+    let mut entries: Vec<DirEntry> = children(dir)
+        .into_iter()
+        .filter(|e| opts.all || !e.name.starts_with('.'))
+        .filter(|e| !opts.dirs_only || e.is_dir)
+        .collect();
+    entries.sort_by(|a, b| a.name.to_ascii_lowercase().cmp(&b.name.to_ascii_lowercase()));
+
+    let last = entries.len().saturating_sub(1);
+    for (i, entry) in entries.iter().enumerate() {
+        let (branch, indent) = if i == last { ("└── ", "    ") } else { ("├── ", "│   ") };
+        print!("{}{}", prefix, branch);
+        if entry.is_dir {
+            print!("\x1b[32m{}", entry.name);
+            reset_color!();
+            println!();
+            counts.0 += 1;
+            tree_walk(&fs::join_path(dir, &entry.name), &alloc::format!("{}{}", prefix, indent), depth + 1, opts, counts);
+        } else {
+            println!("{}", entry.name);
+            counts.1 += 1;
+        }
+    }
+}
+
+struct TreeOptions {
+    all: bool,
+    dirs_only: bool,
+    max_depth: Option<usize>,
+}
+
+pub struct TreeCommand;
+impl Command for TreeCommand {
+    fn name(&self) -> &'static str { "tree" }
+    fn description(&self) -> &'static str { "Show a directory tree: tree [-a] [-d] [-L depth] [dir]" }
+    fn execute(&self, args: &[String]) {
+        let mut opts = TreeOptions { all: false, dirs_only: false, max_depth: None };
+        let mut dir = ".";
+        let mut i = 0;
+        while i < args.len() {
+            match args[i].as_str() {
+                "-a" => opts.all = true,
+                "-d" => opts.dirs_only = true,
+                "-L" => {
+                    i += 1;
+                    match args.get(i).and_then(|n| n.parse::<usize>().ok()).filter(|&n| n > 0) {
+                        Some(n) => opts.max_depth = Some(n),
+                        None => { println!("tree: -L takes a depth of at least 1"); return; }
+                    }
+                }
+                arg if arg.starts_with('-') => { println!("tree: unknown option: {}", arg); return; }
+                arg => dir = arg,
+            }
+            i += 1;
+        }
+
+        if !fs::is_dir(dir) {
+            println!("tree: {}: No such directory", dir);
+            return;
+        }
+        println!("\x1b[32m{}", dir);
+        reset_color!();
+        let mut counts = (0, 0);
+        tree_walk(dir, "", 1, &opts, &mut counts);
+        println!();
+        println!("{} directories, {} files", counts.0, counts.1);
+    }
+}
@@ -1,6 +1,6 @@
 use alloc::string::{String, ToString};
 use crate::{println, print};
-use crate::device::ahci::{find_ahci_controller, find_sata_devices, read_ahci_memory, AHCI_MEMORY_SIZE};
+use crate::device::ahci::{find_ahci_controller, read_ahci_memory, AhciController, DeviceKind, AHCI_MEMORY_SIZE};
 use crate::device::get_all_devices;
 use crate::memory::{dump_memory, test_memory_access};
 use crate::allocator::HEAP_KIB;
@@ -44,9 +44,22 @@ impl Command for AhciCommand {
     fn name(&self) -> &'static str { "ahci" }
     fn description(&self) -> &'static str { "Show AHCI devices" }
     fn execute(&self, _args: &[String]) {
-        match find_ahci_controller() {
-            Some((_bus, _slot, _function, base_addr)) => { find_sata_devices(base_addr); }
-            None => println!("No AHCI controller found"),
+        let (bus, slot, function, abar) = match find_ahci_controller() {
+            Some(found) => found,
+            None => { println!("No AHCI controller found"); return; }
+        };
+        let hba = AhciController::new(abar);
+        let (major, minor) = hba.version();
+        println!("AHCI {}.{} controller at {:02x}:{:02x}.{}, ABAR {:#x}{}",
+            major, minor, bus, slot, function, abar, if hba.supports_64bit() { ", 64-bit" } else { "" });
+        for port in hba.ports() {
+            match hba.device_kind(port) {
+                DeviceKind::Empty => println!("  port {}: empty", port),
+                kind => {
+                    let gbps = ["?", "1.5", "3", "6"].get(hba.link_generation(port) as usize).unwrap_or(&"?");
+                    println!("  port {}: {}, {} Gbps", port, kind.name(), gbps);
+                }
+            }
         }
     }
 }
@@ -77,6 +90,15 @@ impl Command for ConfigCommand {
             let cfg = crate::CONFIG.lock();
             println!("hostname={}", cfg.hostname);
             println!("keyboard_layout={}", cfg.keyboard_layout);
+            println!("home={}", cfg.home);
+            println!("cache_sectors={}", cfg.cache_sectors);
+            println!("cache_policy={}", cfg.cache_policy.name());
+            println!("flush_interval={}", cfg.flush_interval);
+            println!("ntp_server={}", cfg.ntp_server);
+            println!("ntp_sync={}", cfg.ntp_sync);
+            for key in crate::net::NetConfig::KEYS {
+                println!("net.{}={}", key, cfg.net.get(key).unwrap_or_default());
+            }
             return;
         }
 
@@ -91,7 +113,16 @@ impl Command for ConfigCommand {
             match key.as_str() {
                 "hostname"         => println!("{}", cfg.hostname),
                 "keyboard_layout"  => println!("{}", cfg.keyboard_layout),
-                _ => println!("Unknown key: {}", key),
+                "home"             => println!("{}", cfg.home),
+                "cache_sectors"    => println!("{}", cfg.cache_sectors),
+                "cache_policy"     => println!("{}", cfg.cache_policy.name()),
+                "flush_interval"   => println!("{}", cfg.flush_interval),
+                "ntp_server"       => println!("{}", cfg.ntp_server),
+                "ntp_sync"         => println!("{}", cfg.ntp_sync),
+                _ => match key.strip_prefix("net.").and_then(|k| cfg.net.get(k)) {
+                    Some(value) => println!("{}", value),
+                    None => println!("Unknown key: {}", key),
+                },
             }
             return;
         }
@@ -102,6 +133,14 @@ impl Command for ConfigCommand {
             match key.as_str() {
                 "hostname"        => cfg.hostname = value.clone(),
                 "keyboard_layout" => cfg.keyboard_layout = value.clone(),
+                "home"            => cfg.home = crate::fs::path::normalize(&value),
+                "ntp_server"      => cfg.ntp_server = value.clone(),
+                k if matches!(k, "cache_sectors" | "cache_policy" | "flush_interval" | "ntp_sync") || k.starts_with("net.") => {
+                    if !cfg.set(&key, &value) {
+                        println!("Invalid value for {}: {}", key, value);
+                        return;
+                    }
+                }
                 _ => { println!("Unknown key: {}", key); return; }
             }
             if !cfg.save() {
@@ -109,6 +148,20 @@ impl Command for ConfigCommand {
                 return;
             }
         }
+        if key.starts_with("cache_") {
+            crate::fs::apply_cache_config();
+        }
+        if key == "hostname" {
+            crate::net::dhcp::set_hostname(&value);
+        }
+        if key.starts_with("net.") {
+            let net = crate::CONFIG.lock().net.clone();
+            match crate::net::configure(&net) {
+                Ok(()) => {}
+                Err(crate::net::NetError::Unaddressable) => println!("Not applied: static mode needs net.address"),
+                Err(e) => println!("Not applied: {:?}", e),
+            }
+        }
         println!("Saved {} = {}", key, value);
     }
 }
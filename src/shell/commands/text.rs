@@ -0,0 +1,311 @@
+//! Line-oriented text tools: grep, head, tail, wc, sort and uniq.
+//!
+//! Each one reads its input through `read_inputs`, so standard input only has
+//! to be wired up there once the shell can pipe commands together.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::{print, println, reset_color};
+use crate::fs::read_file;
+use crate::shell::flags::Flags;
+use crate::util::regex::Regex;
+use super::Command;
+
+/// Where a command's text comes from
+enum Source<'a> {
+    File(&'a str),
+    Stdin,
+}
+
+/// (name, text) of every input, files in order or standard input if none are
+/// given. Prints an error and returns None if one can't be read.
+fn read_inputs(cmd: &str, files: &[&str]) -> Option<Vec<(String, String)>> {
+    let sources: Vec<Source> = if files.is_empty() {
+        alloc::vec![Source::Stdin]
+    } else {
+        files.iter().map(|f| Source::File(f)).collect()
+    };
+    let mut inputs = Vec::new();
+    for source in sources {
+        match source {
+            Source::File(path) => match read_file(path) {
+                Some(data) => inputs.push((String::from(path), String::from_utf8_lossy(&data).into_owned())),
+                None => {
+                    println!("{}: {}: No such file", cmd, path);
+                    return None;
+                }
+            },
+            Source::Stdin => {
+                println!("{}: no file given, and the shell has no pipes to read from yet", cmd);
+                return None;
+            }
+        }
+    }
+    Some(inputs)
+}
+
+/// Split a `-n N`, `-nN` or `-N` line count out of `args`, leaving the file names
+fn line_count(cmd: &str, args: &[String], default: usize) -> Option<(usize, Vec<String>)> {
+    let mut count = default;
+    let mut files = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        let value = if arg == "-n" {
+            i += 1;
+            args.get(i).map(|s| s.as_str())
+        } else if let Some(n) = arg.strip_prefix("-n") {
+            Some(n)
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            Some(&arg[1..])
+        } else {
+            files.push(args[i].clone());
+            None
+        };
+        if let Some(value) = value {
+            match value.parse() {
+                Ok(n) => count = n,
+                Err(_) => {
+                    println!("{}: invalid line count: {}", cmd, value);
+                    return None;
+                }
+            }
+        } else if arg == "-n" {
+            println!("{}: -n needs a line count", cmd);
+            return None;
+        }
+        i += 1;
+    }
+    Some((count, files))
+}
+
+fn as_strs(args: &[String]) -> Vec<&str> {
+    args.iter().map(|s| s.as_str()).collect()
+}
+
+pub struct GrepCommand;
+impl Command for GrepCommand {
+    fn name(&self) -> &'static str { "grep" }
+    fn description(&self) -> &'static str { "Search text with a regex: grep [-i] [-v] [-n] [-c] <pattern> [file...]" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        let pattern = match flags.first() {
+            Some(p) => p,
+            None => { println!("Usage: grep [-i] [-v] [-n] [-c] <pattern> [file...]"); return; }
+        };
+        let regex = match Regex::new(pattern, flags.has('i')) {
+            Ok(r) => r,
+            Err(e) => { println!("grep: bad pattern: {:?}", e); return; }
+        };
+        let invert = flags.has('v');
+        let numbers = flags.has('n');
+        let count_only = flags.has('c');
+
+        let files = as_strs(&flags.args[1..]);
+        let inputs = match read_inputs("grep", &files) {
+            Some(inputs) => inputs,
+            None => return,
+        };
+        let show_name = inputs.len() > 1;
+        for (name, text) in &inputs {
+            let mut count = 0;
+            for (i, line) in text.lines().enumerate() {
+                let found = regex.find(line);
+                if found.is_some() == invert {
+                    continue;
+                }
+                count += 1;
+                if count_only {
+                    continue;
+                }
+                if show_name {
+                    print!("{}:", name);
+                }
+                if numbers {
+                    print!("{}:", i + 1);
+                }
+                match found {
+                    Some((start, end)) if end > start => {
+                        print!("{}\x1b[31m{}", &line[..start], &line[start..end]);
+                        reset_color!();
+                        println!("{}", &line[end..]);
+                    }
+                    _ => println!("{}", line),
+                }
+            }
+            if count_only {
+                if show_name {
+                    print!("{}:", name);
+                }
+                println!("{}", count);
+            }
+        }
+    }
+}
+
+pub struct HeadCommand;
+impl Command for HeadCommand {
+    fn name(&self) -> &'static str { "head" }
+    fn description(&self) -> &'static str { "Print the first lines: head [-n N] [file...]" }
+    fn execute(&self, args: &[String]) {
+        let (count, files) = match line_count("head", args, 10) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        let inputs = match read_inputs("head", &as_strs(&files)) {
+            Some(inputs) => inputs,
+            None => return,
+        };
+        for (i, (name, text)) in inputs.iter().enumerate() {
+            if inputs.len() > 1 {
+                println!("{}==> {} <==", if i > 0 { "\n" } else { "" }, name);
+            }
+            for line in text.lines().take(count) {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+pub struct TailCommand;
+impl Command for TailCommand {
+    fn name(&self) -> &'static str { "tail" }
+    fn description(&self) -> &'static str { "Print the last lines: tail [-n N] [file...]" }
+    fn execute(&self, args: &[String]) {
+        let (count, files) = match line_count("tail", args, 10) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        let inputs = match read_inputs("tail", &as_strs(&files)) {
+            Some(inputs) => inputs,
+            None => return,
+        };
+        for (i, (name, text)) in inputs.iter().enumerate() {
+            if inputs.len() > 1 {
+                println!("{}==> {} <==", if i > 0 { "\n" } else { "" }, name);
+            }
+            let lines: Vec<&str> = text.lines().collect();
+            for line in &lines[lines.len().saturating_sub(count)..] {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+pub struct WcCommand;
+impl Command for WcCommand {
+    fn name(&self) -> &'static str { "wc" }
+    fn description(&self) -> &'static str { "Count lines, words and bytes: wc [-l] [-w] [-c] [file...]" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        let (mut lines, mut words, mut bytes) = (flags.has('l'), flags.has('w'), flags.has('c'));
+        if !lines && !words && !bytes {
+            lines = true;
+            words = true;
+            bytes = true;
+        }
+        let inputs = match read_inputs("wc", &as_strs(&flags.args)) {
+            Some(inputs) => inputs,
+            None => return,
+        };
+
+        let show = |counts: (usize, usize, usize), name: &str| {
+            if lines { print!("{:>7} ", counts.0); }
+            if words { print!("{:>7} ", counts.1); }
+            if bytes { print!("{:>7} ", counts.2); }
+            println!("{}", name);
+        };
+        let mut total = (0, 0, 0);
+        for (name, text) in &inputs {
+            let counts = (text.matches('\n').count(), text.split_whitespace().count(), text.len());
+            show(counts, name);
+            total = (total.0 + counts.0, total.1 + counts.1, total.2 + counts.2);
+        }
+        if inputs.len() > 1 {
+            show(total, "total");
+        }
+    }
+}
+
+/// Leading number of a line for `sort -n`; lines without one sort first
+fn numeric_key(line: &str) -> f64 {
+    let line = line.trim_start();
+    let end = line
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' || (i == 0 && c == '-')))
+        .map_or(line.len(), |(i, _)| i);
+    line[..end].parse().unwrap_or(f64::MIN)
+}
+
+pub struct SortCommand;
+impl Command for SortCommand {
+    fn name(&self) -> &'static str { "sort" }
+    fn description(&self) -> &'static str { "Sort lines: sort [-r] [-n] [-f] [-u] [file...]" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        let inputs = match read_inputs("sort", &as_strs(&flags.args)) {
+            Some(inputs) => inputs,
+            None => return,
+        };
+        let mut lines: Vec<&str> = inputs.iter().flat_map(|(_, text)| text.lines()).collect();
+
+        let fold = flags.has('f');
+        if flags.has('n') {
+            lines.sort_by(|a, b| {
+                let by_number = numeric_key(a).partial_cmp(&numeric_key(b)).unwrap_or(core::cmp::Ordering::Equal);
+                by_number.then_with(|| a.cmp(b))
+            });
+        } else if fold {
+            lines.sort_by_cached_key(|l| l.to_lowercase());
+        } else {
+            lines.sort();
+        }
+        if flags.has('r') {
+            lines.reverse();
+        }
+        if flags.has('u') {
+            lines.dedup_by(|a, b| if fold { a.eq_ignore_ascii_case(b) } else { a == b });
+        }
+        for line in lines {
+            println!("{}", line);
+        }
+    }
+}
+
+pub struct UniqCommand;
+impl Command for UniqCommand {
+    fn name(&self) -> &'static str { "uniq" }
+    fn description(&self) -> &'static str { "Collapse repeated lines: uniq [-c] [-d] [-u] [-i] [file]" }
+    fn execute(&self, args: &[String]) {
+        let flags = Flags::parse(args);
+        if flags.args.len() > 1 {
+            println!("Usage: uniq [-c] [-d] [-u] [-i] [file]");
+            return;
+        }
+        let inputs = match read_inputs("uniq", &as_strs(&flags.args)) {
+            Some(inputs) => inputs,
+            None => return,
+        };
+        let ignore_case = flags.has('i');
+        let same = |a: &str, b: &str| if ignore_case { a.eq_ignore_ascii_case(b) } else { a == b };
+
+        // Runs of adjacent equal lines as (first line, length)
+        let mut runs: Vec<(&str, usize)> = Vec::new();
+        for line in inputs[0].1.lines() {
+            match runs.last_mut() {
+                Some((first, n)) if same(first, line) => *n += 1,
+                _ => runs.push((line, 1)),
+            }
+        }
+        for (line, n) in runs {
+            if (flags.has('d') && n == 1) || (flags.has('u') && n > 1) {
+                continue;
+            }
+            if flags.has('c') {
+                println!("{:>7} {}", n, line);
+            } else {
+                println!("{}", line);
+            }
+        }
+    }
+}
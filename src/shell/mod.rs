@@ -9,7 +9,7 @@ use core::sync::atomic::Ordering;
 use crate::{print, println, serial_println};
 use crate::interrupts::TICKS;
 
-const SHELL_PROMPT: &str = "> ";
+pub const SHELL_PROMPT: &str = "> ";
 
 pub fn prompt() {
 	print!("{}", SHELL_PROMPT);
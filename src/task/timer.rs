@@ -0,0 +1,127 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::interrupts::TICKS;
+
+/// The PIT runs at its default ~18.2 Hz
+pub const TICKS_PER_SECOND: u64 = 18;
+
+/// Exact PIT rate: the 1.193182 MHz input clock divided by 65536
+const PIT_HZ: u64 = 1_193_182;
+const PIT_DIVISOR: u64 = 65_536;
+
+/// Milliseconds since the timer started, at tick (~55 ms) resolution
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(TICKS.load(Ordering::Relaxed))
+}
+
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * PIT_DIVISOR * 1000 / PIT_HZ
+}
+
+/// Ticks covering at least `ms` milliseconds
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * PIT_HZ + PIT_DIVISOR * 1000 - 1) / (PIT_DIVISOR * 1000)
+}
+
+/// A sleeper's id, deadline, waker and whether the timer has woken it yet.
+/// Entries belong to their `Sleep`, which removes its own when it completes
+/// or is dropped, so the interrupt never drops a waker.
+static SLEEPERS: Mutex<Vec<(u64, u64, Waker, bool)>> = Mutex::new(Vec::new());
+static NEXT_SLEEP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Called from the timer interrupt after `TICKS` has been bumped
+pub(crate) fn on_tick() {
+    let now = TICKS.load(Ordering::Relaxed);
+    // A task holding the lock will re-check the deadline itself, so skip this tick
+    if let Some(mut sleepers) = SLEEPERS.try_lock() {
+        for (_, deadline, waker, woken) in sleepers.iter_mut() {
+            if *deadline <= now && !*woken {
+                waker.wake_by_ref();
+                *woken = true;
+            }
+        }
+    }
+}
+
+/// Drop `id`'s entry, if it has one. The waker goes with interrupts back on.
+fn remove_sleeper(id: u64) {
+    let removed = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut sleepers = SLEEPERS.lock();
+        let index = sleepers.iter().position(|entry| entry.0 == id)?;
+        Some(sleepers.swap_remove(index))
+    });
+    drop(removed);
+}
+
+pub struct Sleep {
+    id: u64,
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if TICKS.load(Ordering::Relaxed) >= self.deadline {
+            remove_sleeper(self.id);
+            return Poll::Ready(());
+        }
+        // Interrupts stay off while the lock is held so on_tick never spins on it
+        let replaced = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            match sleepers.iter_mut().find(|entry| entry.0 == self.id) {
+                Some(entry) if entry.2.will_wake(cx.waker()) => {
+                    entry.3 = false;
+                    None
+                }
+                Some(entry) => {
+                    entry.3 = false;
+                    Some(core::mem::replace(&mut entry.2, cx.waker().clone()))
+                }
+                None => {
+                    sleepers.push((self.id, self.deadline, cx.waker().clone(), false));
+                    None
+                }
+            }
+        });
+        drop(replaced);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        remove_sleeper(self.id);
+    }
+}
+
+pub fn sleep_ticks(ticks: u64) -> Sleep {
+    Sleep {
+        id: NEXT_SLEEP_ID.fetch_add(1, Ordering::Relaxed),
+        deadline: TICKS.load(Ordering::Relaxed) + ticks.max(1),
+    }
+}
+
+pub fn sleep_secs(secs: u64) -> Sleep {
+    sleep_ticks(secs * TICKS_PER_SECOND)
+}
+
+#[test_case]
+fn test_sleep_keeps_one_entry() {
+    let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+    let entries = |id| SLEEPERS.lock().iter().filter(|entry| entry.0 == id).count();
+    let mut sleep = sleep_secs(60);
+    let id = sleep.id;
+    for _ in 0..3 {
+        assert_eq!(Pin::new(&mut sleep).poll(&mut cx), Poll::Pending);
+    }
+    assert_eq!(entries(id), 1);
+    drop(sleep);
+    assert_eq!(entries(id), 0);
+}
@@ -190,9 +190,10 @@ impl CMOS {
 			}
 		}
 
-		// Convert 12 hour clock to 24 hour clock if necessary
-		if ((register_b & 0x02) == 0) && ((rtc_time.hour & 0x80) != 0) {
-			rtc_time.hour = ((rtc_time.hour & 0x7F) + 12) % 24;
+		// Convert 12 hour clock to 24 hour clock if necessary: 12 AM is 0 and 12 PM is 12
+		if (register_b & 0x02) == 0 {
+			let pm = (rtc_time.hour & 0x80) != 0;
+			rtc_time.hour = (rtc_time.hour & 0x7F) % 12 + if pm { 12 } else { 0 };
 		}
 
 		// Calculate the full (4-digit) year
@@ -210,6 +211,46 @@ impl CMOS {
 
 		rtc_time
 	}
+
+	/// Writes to the RTC part of CMOS
+	/// Converts to BCD and to the 12 hour clock if the RTC is set up for them, and stores the
+	/// century in `century_reg` if one is given.
+	///
+	/// # Examples
+	/// ```rust,no_run
+	/// # use cmos::{CMOS, RTCDateTime};
+	/// let mut cmos = unsafe { CMOS::new() };
+	/// let rtc = RTCDateTime { year: 2026, month: 10, day: 19, hour: 12, minute: 0, second: 0 };
+	/// cmos.write_rtc(&rtc, Some(0x32));
+	/// ```
+	pub fn write_rtc(&mut self, rtc_time: &RTCDateTime, century_reg: Option<u8>) {
+		let register_b = self.read(0x0B);
+		let bcd = |value: u8| if (register_b & 0x04) == 0 { ((value / 10) << 4) | (value % 10) } else { value };
+
+		let mut hour = rtc_time.hour;
+		let mut pm = 0;
+		if (register_b & 0x02) == 0 {
+			// 12 hour clock: 1-12 with the top bit set after noon
+			pm = if hour >= 12 { 0x80 } else { 0 };
+			hour = match hour % 12 {
+				0 => 12,
+				h => h,
+			};
+		}
+
+		// Hold off updates while the registers are inconsistent
+		self.write(0x0B, register_b | 0x80);
+		self.write(0x00, bcd(rtc_time.second));
+		self.write(0x02, bcd(rtc_time.minute));
+		self.write(0x04, bcd(hour) | pm);
+		self.write(0x07, bcd(rtc_time.day));
+		self.write(0x08, bcd(rtc_time.month));
+		self.write(0x09, bcd((rtc_time.year % 100) as u8));
+		if let Some(century_reg) = century_reg {
+			self.write(century_reg, bcd((rtc_time.year / 100) as u8));
+		}
+		self.write(0x0B, register_b & !0x80);
+	}
 }
 
 /// Enum for determining how to calculate the year when reading the RTC
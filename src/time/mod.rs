@@ -1,7 +1,20 @@
 pub mod cmos;
 
+use core::sync::atomic::{AtomicI64, Ordering};
+use crate::task::timer;
 use crate::time::cmos::{CMOS, CMOSCenturyHandler, RTCDateTime};
 
+/// Where PCs and QEMU keep the century. The ACPI FADT can name another
+/// register, which we don't look at.
+const CENTURY_REGISTER: u8 = 0x32;
+/// Earliest year assumed when the century register doesn't hold one
+const MIN_YEAR: usize = 2026;
+
+/// Unix time in milliseconds minus the uptime, so the clock runs off the
+/// timer instead of reading the RTC each time. Taken from the RTC on first
+/// use (0 until then) and corrected by `set_time`.
+static UTC_OFFSET_MS: AtomicI64 = AtomicI64::new(0);
+
 pub fn get_time_with_year(year: u8) -> RTCDateTime {
 	let mut cmos = unsafe { CMOS::new() };
 
@@ -10,7 +23,87 @@ pub fn get_time_with_year(year: u8) -> RTCDateTime {
 	rtc
 }
 
+/// What the RTC says, using its century register when that holds something sensible
+pub fn read_rtc() -> RTCDateTime {
+	let mut cmos = unsafe { CMOS::new() };
+	let rtc = cmos.read_rtc(CMOSCenturyHandler::CenturyRegister(CENTURY_REGISTER));
+	if (2000..2200).contains(&rtc.year) {
+		rtc
+	} else {
+		cmos.read_rtc(CMOSCenturyHandler::CurrentYear(MIN_YEAR))
+	}
+}
+
+/// Milliseconds since the Unix epoch
+pub fn now_ms() -> u64 {
+	let uptime = timer::uptime_ms() as i64;
+	let mut offset = UTC_OFFSET_MS.load(Ordering::Relaxed);
+	if offset == 0 {
+		let rtc_offset = to_unix(&read_rtc()) as i64 * 1000 - uptime;
+		// Unless `set_time` got there first
+		offset = match UTC_OFFSET_MS.compare_exchange(0, rtc_offset, Ordering::Relaxed, Ordering::Relaxed) {
+			Ok(_) => rtc_offset,
+			Err(current) => current,
+		};
+	}
+	(uptime + offset).max(0) as u64
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+	now_ms() / 1000
+}
+
 pub fn get_time() -> RTCDateTime {
+	from_unix(now())
+}
+
+/// Set the clock to `unix_ms`, in the RTC too so it holds across reboots
+pub fn set_time(unix_ms: u64) {
+	UTC_OFFSET_MS.store(unix_ms as i64 - timer::uptime_ms() as i64, Ordering::Relaxed);
 	let mut cmos = unsafe { CMOS::new() };
-	cmos.read_rtc(CMOSCenturyHandler::CurrentYear(2026))
-}
\ No newline at end of file
+	cmos.write_rtc(&from_unix(unix_ms / 1000), Some(CENTURY_REGISTER));
+}
+
+/// Seconds since the Unix epoch for a UTC `RTCDateTime`
+pub fn to_unix(dt: &RTCDateTime) -> u64 {
+	// days_from_civil, counting from 0000-03-01 so leap days fall at year end
+	let (y, m) = if dt.month <= 2 { (dt.year as i64 - 1, dt.month as i64 + 9) } else { (dt.year as i64, dt.month as i64 - 3) };
+	let era = y.div_euclid(400);
+	let yoe = y - era * 400;
+	let doy = (153 * m + 2) / 5 + dt.day as i64 - 1;
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	let days = era * 146097 + doe - 719468;
+	let secs = days * 86400 + dt.hour as i64 * 3600 + dt.minute as i64 * 60 + dt.second as i64;
+	secs.max(0) as u64
+}
+
+/// UTC `RTCDateTime` for seconds since the Unix epoch
+pub fn from_unix(ts: u64) -> RTCDateTime {
+	let days = (ts / 86400) as i64 + 719468;
+	let rem = ts % 86400;
+	let era = days.div_euclid(146097);
+	let doe = days - era * 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+	let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+	let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as usize;
+	RTCDateTime {
+		year,
+		month,
+		day,
+		hour: (rem / 3600) as u8,
+		minute: (rem / 60 % 60) as u8,
+		second: (rem % 60) as u8,
+	}
+}
+
+#[test_case]
+fn test_unix_round_trip() {
+	let dt = from_unix(1_700_000_000);
+	assert_eq!(dt.as_tuple(), (2023, 11, 14, 22, 13, 20));
+	assert_eq!(to_unix(&dt), 1_700_000_000);
+	assert_eq!(from_unix(0).as_tuple(), (1970, 1, 1, 0, 0, 0));
+}
@@ -0,0 +1,91 @@
+//! Shell-style wildcard matching for names: `*`, `?`, `[abc]`, `[a-z]` and
+//! `[!abc]`. A backslash makes the next character literal.
+
+/// Match `name` against the whole of `pattern`
+pub fn matches(pattern: &str, name: &str, ignore_case: bool) -> bool {
+    let pattern: alloc::vec::Vec<char> = pattern.chars().collect();
+    let name: alloc::vec::Vec<char> = name.chars().collect();
+    let eq = |a: char, b: char| if ignore_case { a.eq_ignore_ascii_case(&b) } else { a == b };
+
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` when the rest fails to match
+    let mut retry: Option<(usize, usize)> = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                retry = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => class(&pattern, p, name[n], &eq),
+            Some('\\') if p + 1 < pattern.len() => {
+                if eq(pattern[p + 1], name[n]) { Some(p + 2) } else { None }
+            }
+            Some(&c) => if eq(c, name[n]) { Some(p + 1) } else { None },
+            None => None,
+        };
+        match (step, retry) {
+            (Some(next), _) => {
+                p = next;
+                n += 1;
+            }
+            (None, Some((star, from))) => {
+                // Let the `*` swallow one more character and try again
+                p = star + 1;
+                n = from + 1;
+                retry = Some((star, from + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Match `c` against the bracket expression starting at `pattern[start]`.
+/// Returns the index after it on a match. An unclosed `[` is a literal.
+fn class(pattern: &[char], start: usize, c: char, eq: &impl Fn(char, char) -> bool) -> Option<usize> {
+    let mut i = start + 1;
+    let negate = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+    let mut found = false;
+    let mut first = true;
+    while i < pattern.len() && (pattern[i] != ']' || first) {
+        first = false;
+        let lo = pattern[i];
+        if pattern.get(i + 1) == Some(&'-') && i + 2 < pattern.len() && pattern[i + 2] != ']' {
+            let hi = pattern[i + 2];
+            let folded = [c, c.to_ascii_lowercase(), c.to_ascii_uppercase()];
+            if folded.iter().any(|&f| (lo..=hi).contains(&f) && eq(f, c)) {
+                found = true;
+            }
+            i += 3;
+        } else {
+            if eq(lo, c) {
+                found = true;
+            }
+            i += 1;
+        }
+    }
+    if i >= pattern.len() {
+        // No closing bracket: treat `[` as an ordinary character
+        return if eq('[', c) { Some(start + 1) } else { None };
+    }
+    if found != negate { Some(i + 1) } else { None }
+}
+
+#[test_case]
+fn test_glob() {
+    assert!(matches("*.txt", "notes.txt", false));
+    assert!(!matches("*.txt", "notes.txt.bak", false));
+    assert!(matches("a*b*c", "aXXbYYc", false));
+    assert!(matches("?at", "cat", false));
+    assert!(matches("[ch]at", "hat", false));
+    assert!(!matches("[!ch]at", "hat", false));
+    assert!(matches("file[0-9]", "file7", false));
+    assert!(matches("*.TXT", "readme.txt", true));
+    assert!(matches("\\*", "*", false));
+    assert!(!matches("\\*", "x", false));
+}
@@ -1,2 +1,4 @@
 pub mod bitfield;
 
+pub mod glob;
+pub mod regex;
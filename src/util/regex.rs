@@ -0,0 +1,308 @@
+//! A small backtracking regular expression engine for `grep`.
+//!
+//! Supports literals, `.`, `^`, `$`, bracket classes with ranges and `^`
+//! negation, the escapes `\d \w \s` (and upper-case negations), groups with
+//! `|` alternation, and the `* + ? {n} {n,} {n,m}` quantifiers, all greedy.
+//! There are no captures or backreferences.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegexError {
+    UnclosedGroup,
+    UnmatchedParen,
+    UnclosedClass,
+    NothingToRepeat,
+    BadRepeat,
+    TrailingBackslash,
+}
+
+#[derive(Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negate: bool },
+    Start,
+    End,
+    /// Alternatives, each a sequence
+    Group(Vec<Vec<Node>>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize> },
+}
+
+pub struct Regex {
+    alts: Vec<Vec<Node>>,
+    ignore_case: bool,
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('a', 'z'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+/// Ranges for `\d`, `\w` and `\s`, with whether the letter was upper case
+fn escape_class(c: char) -> Option<(&'static [(char, char)], bool)> {
+    match c {
+        'd' | 'D' => Some((DIGIT, c == 'D')),
+        'w' | 'W' => Some((WORD, c == 'W')),
+        's' | 'S' => Some((SPACE, c == 'S')),
+        _ => None,
+    }
+}
+
+fn escaped(c: char) -> char {
+    match c {
+        't' => '\t',
+        'n' => '\n',
+        'r' => '\r',
+        c => c,
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn alternation(&mut self) -> Result<Vec<Vec<Node>>, RegexError> {
+        let mut alts = alloc::vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, RegexError> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, RegexError> {
+        Ok(match self.next() {
+            Some('(') => {
+                let alts = self.alternation()?;
+                if self.next() != Some(')') {
+                    return Err(RegexError::UnclosedGroup);
+                }
+                Node::Group(alts)
+            }
+            Some(')') => return Err(RegexError::UnmatchedParen),
+            Some('[') => self.class()?,
+            Some('.') => Node::Any,
+            Some('^') => Node::Start,
+            Some('$') => Node::End,
+            Some('*') | Some('+') | Some('?') => return Err(RegexError::NothingToRepeat),
+            Some('\\') => match self.next() {
+                Some(c) => match escape_class(c) {
+                    Some((ranges, negate)) => Node::Class { ranges: ranges.to_vec(), negate },
+                    None => Node::Char(escaped(c)),
+                },
+                None => return Err(RegexError::TrailingBackslash),
+            },
+            Some(c) => Node::Char(c),
+            None => unreachable!("sequence stops at the end of the pattern"),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, RegexError> {
+        let negate = self.peek() == Some('^');
+        if negate {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next().ok_or(RegexError::UnclosedClass)?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = if c == '\\' {
+                let e = self.next().ok_or(RegexError::UnclosedClass)?;
+                if let Some((class, false)) = escape_class(e) {
+                    ranges.extend_from_slice(class);
+                    continue;
+                }
+                escaped(e)
+            } else {
+                c
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).map_or(false, |&h| h != ']') {
+                self.pos += 1;
+                let hi = match self.next() {
+                    Some('\\') => escaped(self.next().ok_or(RegexError::UnclosedClass)?),
+                    Some(h) => h,
+                    None => return Err(RegexError::UnclosedClass),
+                };
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Node::Class { ranges, negate })
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, RegexError> {
+        let (min, max) = match self.peek() {
+            Some('{') => match self.bounds() {
+                Some(bounds) => bounds,
+                // Not a valid repeat, so the brace is a literal
+                None => return Ok(atom),
+            },
+            Some(c @ ('*' | '+' | '?')) => {
+                self.pos += 1;
+                match c {
+                    '*' => (0, None),
+                    '+' => (1, None),
+                    _ => (0, Some(1)),
+                }
+            }
+            _ => return Ok(atom),
+        };
+        if matches!(atom, Node::Start | Node::End) {
+            return Err(RegexError::NothingToRepeat);
+        }
+        if max.map_or(false, |max| max < min) {
+            return Err(RegexError::BadRepeat);
+        }
+        Ok(Node::Repeat { node: Box::new(atom), min, max })
+    }
+
+    /// Parse `{n}`, `{n,}` or `{n,m}`, moving past it on success
+    fn bounds(&mut self) -> Option<(usize, Option<usize>)> {
+        let rest: alloc::string::String = self.chars[self.pos + 1..].iter().collect();
+        let close = rest.find('}')?;
+        let inner = &rest[..close];
+        let (min, max) = match inner.split_once(',') {
+            Some((lo, "")) => (lo.parse().ok()?, None),
+            Some((lo, hi)) => (lo.parse().ok()?, Some(hi.parse().ok()?)),
+            None => {
+                let n = inner.parse().ok()?;
+                (n, Some(n))
+            }
+        };
+        self.pos += inner.chars().count() + 2;
+        Some((min, max))
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str, ignore_case: bool) -> Result<Regex, RegexError> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0 };
+        let alts = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(RegexError::UnmatchedParen);
+        }
+        Ok(Regex { alts, ignore_case })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.find(text).is_some()
+    }
+
+    /// Byte range of the leftmost match in `text`
+    pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        let chars: Vec<char> = text.chars().collect();
+        for start in 0..=chars.len() {
+            let mut end = None;
+            let found = self.alts.iter().any(|alt| self.match_here(alt, &chars, start, &mut |p| {
+                end = Some(p);
+                true
+            }));
+            if found {
+                let offset = |i: usize| chars[..i].iter().map(|c| c.len_utf8()).sum();
+                return end.map(|end| (offset(start), offset(end)));
+            }
+        }
+        None
+    }
+
+    /// Match `nodes` at `pos`, then hand the end position to `k`, backtracking
+    /// into earlier choices whenever `k` rejects it
+    fn match_here(&self, nodes: &[Node], text: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+        let (node, rest) = match nodes.split_first() {
+            Some(split) => split,
+            None => return k(pos),
+        };
+        match node {
+            Node::Start => pos == 0 && self.match_here(rest, text, pos, k),
+            Node::End => pos == text.len() && self.match_here(rest, text, pos, k),
+            Node::Group(alts) => alts.iter().any(|alt| {
+                self.match_here(alt, text, pos, &mut |p| self.match_here(rest, text, p, k))
+            }),
+            Node::Repeat { node, min, max } => match **node {
+                Node::Group(_) => self.repeat(node, *min, *max, 0, rest, text, pos, k),
+                // Single characters repeat without recursing once per character
+                _ => {
+                    let limit = max.unwrap_or(usize::MAX);
+                    let run = text[pos..].iter().take(limit).take_while(|&&c| self.single(node, c)).count();
+                    (*min..=run).rev().any(|n| self.match_here(rest, text, pos + n, k))
+                }
+            },
+            _ => pos < text.len() && self.single(node, text[pos]) && self.match_here(rest, text, pos + 1, k),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn repeat(&self, node: &Node, min: usize, max: Option<usize>, count: usize,
+              rest: &[Node], text: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+        if max.map_or(true, |max| count < max) {
+            let more = self.match_here(core::slice::from_ref(node), text, pos, &mut |p| {
+                // An empty match would repeat forever once the minimum is met
+                (p != pos || count < min) && self.repeat(node, min, max, count + 1, rest, text, p, k)
+            });
+            if more {
+                return true;
+            }
+        }
+        count >= min && self.match_here(rest, text, pos, k)
+    }
+
+    fn single(&self, node: &Node, c: char) -> bool {
+        match node {
+            Node::Any => c != '\n',
+            Node::Char(x) => *x == c || (self.ignore_case && x.eq_ignore_ascii_case(&c)),
+            Node::Class { ranges, negate } => {
+                let hit = |c: char| ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+                let found = hit(c) || (self.ignore_case && (hit(c.to_ascii_lowercase()) || hit(c.to_ascii_uppercase())));
+                found != *negate
+            }
+            _ => false,
+        }
+    }
+}
+
+#[test_case]
+fn test_regex() {
+    let m = |p: &str, t: &str| Regex::new(p, false).unwrap().find(t);
+    assert_eq!(m("b+", "abbbc"), Some((1, 4)));
+    assert_eq!(m("^a.c$", "abc"), Some((0, 3)));
+    assert_eq!(m("^a.c$", "abcd"), None);
+    assert_eq!(m("(cat|dog)s?", "hotdogs"), Some((3, 7)));
+    assert_eq!(m("[0-9]{2,3}", "x12345"), Some((1, 4)));
+    assert_eq!(m("\\d+\\.\\d+", "v1.25"), Some((1, 5)));
+    assert_eq!(m("a.*b", "xaybzb"), Some((1, 6)));
+    assert_eq!(m("(ab)*c", "ababc"), Some((0, 5)));
+    assert_eq!(m("[^a-z]", "abcD"), Some((3, 4)));
+    assert!(Regex::new("ERROR", true).unwrap().is_match("an error here"));
+    assert_eq!(Regex::new("(a", false).err(), Some(RegexError::UnclosedGroup));
+    assert_eq!(Regex::new("*a", false).err(), Some(RegexError::NothingToRepeat));
+}
@@ -76,6 +76,10 @@ fn unicode_to_cp437(c: char) -> u8 {
         '€' => 0xEE,
         '½' => 0xAB,
         '¼' => 0xAC,
+        '│' => 0xB3,
+        '└' => 0xC0,
+        '├' => 0xC3,
+        '─' => 0xC4,
         _ => 0xFE, // unknown
     }
 }
@@ -288,11 +292,29 @@ macro_rules! reset_color {
     () => ($crate::vga::_reset_color());
 }
 
+/// While set, `print!` output is collected here instead of going to the screen
+static CAPTURE: Mutex<Option<alloc::string::String>> = Mutex::new(None);
+
+/// Run `f` with everything it prints collected rather than shown, colour
+/// changes included as ANSI escapes. Used to send command output elsewhere,
+/// such as to a remote shell session.
+pub fn capture_output<R>(f: impl FnOnce() -> R) -> (R, alloc::string::String) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| *CAPTURE.lock() = Some(alloc::string::String::new()));
+    let result = f();
+    let output = interrupts::without_interrupts(|| CAPTURE.lock().take()).unwrap_or_default();
+    (result, output)
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
+        if let Some(output) = CAPTURE.lock().as_mut() {
+            output.write_fmt(args).ok();
+            return;
+        }
         WRITER.lock().write_fmt(args).unwrap();
     });
 }
@@ -301,6 +323,10 @@ pub fn _print(args: fmt::Arguments) {
 pub fn _reset_color() {
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
+        if let Some(output) = CAPTURE.lock().as_mut() {
+            output.push_str("\x1b[0m");
+            return;
+        }
         WRITER.lock().clear_color();
     });
 }